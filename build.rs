@@ -0,0 +1,14 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    compile_protos();
+}
+
+/// Generate the gRPC service and messages from the protobuf definitions,
+/// using a vendored `protoc` so that one does not need to be installed.
+#[cfg(feature = "grpc")]
+fn compile_protos() {
+    let protoc = protoc_bin_vendored::protoc_bin_path().expect("locating vendored protoc");
+    std::env::set_var("PROTOC", protoc);
+
+    tonic_build::compile_protos("proto/wall_a.proto").expect("compiling protobuf definitions");
+}
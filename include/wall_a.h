@@ -0,0 +1,59 @@
+/*
+ * The C API of the wall_a library, which is built into the cdylib when the
+ * `ffi` feature is enabled.
+ *
+ * Functions which fail record an error message, which can be read with
+ * walla_last_error() from the same thread.
+ */
+
+#ifndef WALL_A_H
+#define WALL_A_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* A data directory which records can be appended to */
+typedef struct Store Store;
+
+/*
+ * Open the store in the given data directory, creating the directory if it
+ * does not exist. Returns NULL on error.
+ */
+Store *walla_store_open(const char *data_dir);
+
+/*
+ * Append a single JSON record to the store. Returns 0 if the record was
+ * appended or was a duplicate, 1 if the record was rejected, and -1 on error.
+ */
+int walla_store_append(Store *store, const char *json);
+
+/*
+ * Read the merged value of the store as a JSON string, which is "null" if
+ * nothing has been appended. Returns NULL on error. The string must be freed
+ * with walla_string_free().
+ */
+char *walla_store_read(Store *store);
+
+/* Free a string returned by walla_store_read(). Does nothing for NULL. */
+void walla_string_free(char *value);
+
+/*
+ * Flush any buffered records and close the store. Returns 0 on success and -1
+ * if the records could not be flushed, in which case the store is still
+ * closed. Does nothing for NULL.
+ */
+int walla_store_close(Store *store);
+
+/*
+ * Return the message of the last error on this thread, or NULL if there has
+ * been none. The string is valid until the next call into the library on this
+ * thread.
+ */
+const char *walla_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* WALL_A_H */
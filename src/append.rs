@@ -1,30 +1,46 @@
 //! This module contains the implementation of the `append` CLI command
 
 use std::{
-    io::{self, BufRead, StdinLock, Write},
-    ops::ControlFlow,
+    fs::File,
+    io::{self, BufRead, BufReader},
+    num::{NonZeroU64, NonZeroUsize},
     path::PathBuf,
+    str::FromStr,
+    time::Duration,
 };
 
 use anyhow::Context;
 use argh::FromArgs;
-use uom::si::{
-    information::{byte, megabyte},
-    u64::Information,
-};
+use uom::si::{information::byte, u64::Information};
 
-use super::{
-    archive::write_archive_value,
-    staging::{delete_staging_file, StagingFileReader, StagingFileWriter},
+use self::{
+    input::{append_csv, append_logfmt, ColumnTypes, CsvOptions, InputFormat},
+    kafka::KafkaOptions,
+    syslog::SyslogOptions,
+};
+use crate::{
+    archive::ArchiveNaming,
+    compression::Compression,
+    merge_settings::read_merge_settings,
+    output::Output,
+    sources::SourceLabel,
+    store::{ErrorPolicy, Settings, State, Wrap, DEFAULT_STAGING_LIMIT_BYTES},
+    value::DEFAULT_MAX_DEPTH,
 };
-use crate::value::Value;
 
-fn default_staging_limit() -> Information {
-    Information::new::<megabyte>(1)
+mod input;
+mod kafka;
+mod syslog;
+
+pub fn default_staging_limit() -> Information {
+    Information::new::<byte>(DEFAULT_STAGING_LIMIT_BYTES)
 }
 
-/// The `append` sub-command reads new lines of JSON data from stdin
-/// and archives it.
+/// The `append` sub-command reads new lines of JSON data from stdin or a
+/// file, messages from a Kafka topic, or syslog messages, and archives it.
+///
+/// With `--input-format csv` the input is read as CSV instead, where each
+/// row after the header becomes an object keyed by the column names.
 ///
 /// If the total amount of data in the staging area passes a configurable
 /// limit, then the staging file is converted to a binary format and
@@ -36,141 +52,340 @@ pub struct AppendCommand {
     /// before it is archived and a new staging file is created.
     #[argh(option, default = "default_staging_limit()")]
     staging_limit: Information,
+    /// this option gives the maximum number of bytes a single input record
+    /// (line) may contain. Longer records are handled according to the
+    /// `--on-error` policy.
+    #[argh(option)]
+    max_record_bytes: Option<u64>,
+    /// the maximum number of bytes a record may take once it is parsed and
+    /// normalized, like `--max-record-bytes` but after keys are normalized
+    /// and records are unflattened or wrapped.
+    #[argh(option)]
+    max_normalized_bytes: Option<u64>,
+    /// the maximum number of elements in any array of a record, records with
+    /// a longer array are rejected.
+    #[argh(option)]
+    max_array_elements: Option<usize>,
+    /// the maximum number of keys in any object of a record, records with a
+    /// larger object are rejected.
+    #[argh(option)]
+    max_object_keys: Option<usize>,
+    /// this option controls what happens when an input record is rejected,
+    /// either "abort" (the default) or "skip". Skipped records are reported
+    /// in a JSON lines file in the "rejected" directory of the data
+    /// directory.
+    #[argh(option, default = "ErrorPolicy::Abort")]
+    on_error: ErrorPolicy,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// an input record, deeper records are rejected.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+    /// this option controls how new archive files are named, either
+    /// "timestamp" (the default), "content" to name them by the hash of
+    /// their contents, which skips writing archives identical to an existing
+    /// one, or "sequence" to name them by their sequence number, like
+    /// `000042.bin`.
+    #[argh(option, default = "ArchiveNaming::Timestamp")]
+    archive_naming: ArchiveNaming,
+    /// write archive files with direct IO on Linux, bypassing the page cache
+    /// so that archiving does not evict the cached pages of other files.
+    #[argh(switch)]
+    direct_io: bool,
+    /// archive the staging file on a background thread, renaming it aside
+    /// and appending to a new staging file meanwhile, so that appending never
+    /// waits for archiving.
+    #[argh(switch)]
+    background_archive: bool,
+    /// archive the staging file in the background like --background-archive,
+    /// and if the new staging file fills up while the last one is still being
+    /// archived for longer than this many milliseconds, log a warning and
+    /// pause reading input until it finishes.
+    #[argh(option)]
+    backpressure_wait_ms: Option<u64>,
+    /// skip staging a record if its normalized form is identical to the
+    /// previously staged record.
+    #[argh(switch)]
+    dedup_consecutive: bool,
+    /// when archiving, drop the records which are identical to an earlier
+    /// record in the staging file, like records sent again by a producer
+    /// retrying.
+    #[argh(switch)]
+    dedup_on_archive: bool,
+    /// the name of a top-level field which uniquely identifies each record,
+    /// a record is skipped if another record with the same identifier is
+    /// already in the staging file. Identifiers which are objects are the
+    /// same whatever order their keys are in.
+    #[argh(option)]
+    id_field: Option<String>,
+    /// nest every record under the given top-level key before staging it.
+    #[argh(option)]
+    wrap_key: Option<String>,
+    /// nest every record under a top-level key taken from the value of the
+    /// given field of the record, records without that field are rejected.
+    #[argh(option)]
+    wrap_key_from: Option<String>,
+    /// turn the top-level keys of each record which contain dots into nested
+    /// objects, so "a.b" becomes "a" then "b". A literal dot in a key is
+    /// written "\." and a literal backslash "\\".
+    #[argh(switch)]
+    unflatten: bool,
+    /// accept records containing tombstones, objects with only the key
+    /// "$wall-a:unset" set to true, which delete the fields they are merged
+    /// over. Otherwise records with
+    /// any key starting with "$wall-a:" are rejected, since those keys are
+    /// reserved for the markers of wall-a.
+    #[argh(switch)]
+    allow_tombstones: bool,
+    /// keep the merged value of the staging file in memory, and after every
+    /// given number of appended records rewrite the staging file as a single
+    /// line containing that merged value. If other writers appended to the
+    /// staging file meanwhile, it is merged again from the file instead.
+    #[argh(option)]
+    pre_merge_every: Option<NonZeroU64>,
+    /// write the staged records to the staging file in batches of this many
+    /// records, each with a single vectored write, instead of a buffered
+    /// write for every record. Records are only in the staging file once
+    /// their batch is written, or when the input ends.
+    #[argh(option)]
+    write_batch: Option<NonZeroUsize>,
+    /// write every staged record to stdout as the normalized JSON line that
+    /// was staged, so that `append` can pass records on down a pipeline.
+    /// Rejected and skipped records are not written.
+    #[argh(switch)]
+    echo: bool,
+    /// label the records with the source which produced them, like
+    /// "edge-42", so that `read --source` can read them on their own. They
+    /// are staged and archived apart from the records of other sources.
+    #[argh(option)]
+    source: Option<SourceLabel>,
+    /// merge the records with the settings in this TOML file instead of the
+    /// ones in the MANIFEST, including array behaviors for some paths, see
+    /// the README.
+    #[argh(option)]
+    merge_settings: Option<PathBuf>,
+    /// consume JSON messages from a Kafka topic instead of reading stdin,
+    /// given as "brokers=<host:port,...>,topic=<name>" with optional
+    /// "group=<id>" and "idle-timeout=<seconds>". Offsets are only committed
+    /// after the records are flushed to the staging file. Requires the
+    /// `kafka` feature.
+    #[argh(option)]
+    kafka: Option<KafkaOptions>,
+    /// receive syslog messages instead of reading stdin, given as
+    /// "udp=<host:port>" or "unix=<path>" with optional
+    /// "idle-timeout=<seconds>". Each message is staged as an object with
+    /// its facility, severity, timestamp, host, app, and message. Requires
+    /// the `syslog` feature.
+    #[argh(option)]
+    syslog: Option<SyslogOptions>,
+    /// read lines of JSON from this file instead of stdin.
+    #[argh(option)]
+    input: Option<PathBuf>,
+    /// how the input is compressed, either "auto" (the default) to detect
+    /// gzip or zstd from the start of the input, "none", "gzip", or "zstd".
+    #[argh(option, default = "InputCompression::Auto")]
+    input_compression: InputCompression,
+    /// how records are read from the input, either "json" (the default) for
+    /// one JSON record on each line, "csv" for comma separated values with
+    /// a header row naming the fields of each record, or "logfmt" for
+    /// key=value pairs on each line.
+    #[argh(option, default = "InputFormat::Json")]
+    input_format: InputFormat,
+    /// with --input-format csv or logfmt, convert values which look like
+    /// numbers or true and false into those, and empty cells into null,
+    /// instead of keeping every value as a string.
+    #[argh(switch)]
+    infer_types: bool,
+    /// with --input-format csv, the types of some of the columns, like
+    /// 'zip:string,count:number', which override --infer-types. The types
+    /// are "string", "number", "bool", and "json" for cells holding JSON.
+    #[argh(option)]
+    columns: Option<ColumnTypes>,
 }
 
-impl AppendCommand {
-    /// This function executes the append command.
-    #[tracing::instrument]
-    pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
-        let staging_limit_bytes = self.staging_limit.get::<byte>();
-        let stdin = io::stdin();
-        let handle = stdin.lock();
-
-        let mut state = State::new(data_dir, staging_limit_bytes, handle);
-
-        loop {
-            match state.read_and_append() {
-                Ok(ControlFlow::Continue(())) => {
-                    continue;
-                }
-                Ok(ControlFlow::Break(())) => {
-                    StagingFileWriter::flush_if_present(&mut state.staging_file)?;
-
-                    break Ok(());
-                }
-                Err(err) => {
-                    StagingFileWriter::flush_if_present(&mut state.staging_file)?;
-
-                    break Err(err);
-                }
-            }
-        }
-    }
+/// This enum controls how `append` decompresses its input
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum InputCompression {
+    /// Detect the compression format from the first bytes of the input
+    #[default]
+    Auto,
+    /// Read the input as it is
+    None,
+    /// Decompress the input with the given format
+    Known(Compression),
 }
 
-#[derive(Debug)]
-struct State {
-    data_dir: PathBuf,
-    handle: StdinLock<'static>,
-    line: String,
-    line_bytes: Vec<u8>,
-    staging_file: Option<StagingFileWriter>,
-    added_bytes: u64,
-    staging_limit_bytes: u64,
+impl FromStr for InputCompression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => Self::Auto,
+            "none" => Self::None,
+            x => Self::Known(x.parse()?),
+        })
+    }
 }
 
-impl State {
-    fn new(data_dir: PathBuf, staging_limit_bytes: u64, handle: StdinLock<'static>) -> Self {
-        Self {
-            data_dir,
-            handle,
-            line: String::new(),
-            line_bytes: Vec::new(),
-            staging_file: None,
-            added_bytes: 0,
-            staging_limit_bytes,
+/// Wrap the reader so that it reads the decompressed input.
+fn decompress_input<'r>(
+    mut reader: impl BufRead + 'r,
+    compression: InputCompression,
+) -> anyhow::Result<Box<dyn BufRead + 'r>> {
+    let compression = match compression {
+        InputCompression::Auto => {
+            let header = reader.fill_buf().context("reading start of input")?;
+            Compression::detect(header)
         }
-    }
+        InputCompression::None => None,
+        InputCompression::Known(compression) => Some(compression),
+    };
 
-    fn read_and_append(&mut self) -> anyhow::Result<ControlFlow<()>> {
-        self.line.clear();
-        self.line_bytes.clear();
-
-        let num_bytes = self
-            .handle
-            .read_line(&mut self.line)
-            .context("reading line from stdin")?;
-        if num_bytes == 0 {
-            tracing::debug!("Reached EOF in stdin");
-            return Ok(ControlFlow::Break(()));
+    match compression {
+        Some(compression) => {
+            tracing::debug!(?compression, "Decompressing input");
+            Ok(compression
+                .decoder(reader)
+                .context("starting to decompress input")?)
         }
-        tracing::trace!(%num_bytes, "Read line with non-zero bytes");
-
-        let value: Value =
-            serde_json::from_str(&self.line).context("converting line to JSON value")?;
-        tracing::trace!(?value, "Got JSON value");
-
-        serde_json::to_writer(&mut self.line_bytes, &value)
-            .context("converting JSON value to bytes")?;
-        self.line_bytes.push(b'\n');
-        let line_num_bytes = self.line_bytes.len() as u64;
-        tracing::trace!(num_bytes = ?line_num_bytes, "Converted JSON value back to bytes");
-
-        let staging_file =
-            StagingFileWriter::get_mut_or_open(&mut self.staging_file, &self.data_dir)
-                .context("accessing staging file")?;
-        let staging_initial_len = staging_file.initial_len();
-
-        staging_file
-            .writer()
-            .write_all(&self.line_bytes)
-            .context("writing JSON bytes to staging")?;
-        self.added_bytes += line_num_bytes;
-        tracing::trace!(%self.added_bytes, %line_num_bytes, "Wrote JSON bytes with newline to staging file");
-
-        if staging_initial_len + self.added_bytes > self.staging_limit_bytes {
-            tracing::info!(
-                staging_file_length_bytes = %staging_initial_len,
-                %self.added_bytes,
-                %self.staging_limit_bytes,
-                "Staging file size has increased past provided limit, going to archive"
+        None => Ok(Box::new(reader)),
+    }
+}
+
+impl AppendCommand {
+    /// This function executes the append command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf, output: Output) -> anyhow::Result<()> {
+        if self.echo && output.is_json() {
+            anyhow::bail!(
+                "--echo writes the staged records to stdout, so it cannot be used with \
+                 --output json"
             );
+        }
+        if self.input_format == InputFormat::Json && self.infer_types {
+            anyhow::bail!("--infer-types requires --input-format csv or logfmt");
+        }
+        if self.input_format != InputFormat::Csv && self.columns.is_some() {
+            anyhow::bail!("--columns requires --input-format csv");
+        }
+        if self.input_format != InputFormat::Json && self.kafka.is_some() {
+            anyhow::bail!("--kafka only reads JSON messages, without --input-format");
+        }
+        if self.input_format != InputFormat::Json && self.syslog.is_some() {
+            anyhow::bail!("--syslog reads syslog messages, without --input-format");
+        }
+        let sources = [
+            self.kafka.is_some(),
+            self.syslog.is_some(),
+            self.input.is_some(),
+        ];
+        if sources.into_iter().filter(|given| *given).count() > 1 {
+            anyhow::bail!("only one of --kafka, --syslog, and --input may be given");
+        }
+        let csv_options = CsvOptions {
+            infer_types: self.infer_types,
+            column_types: self.columns.unwrap_or_default(),
+        };
 
-            staging_file
-                .writer()
-                .flush()
-                .context("flushing staging file before archiving")?;
+        let wrap = match (self.wrap_key, self.wrap_key_from) {
+            (None, None) => None,
+            (Some(key), None) => Some(Wrap::Key(key)),
+            (None, Some(field)) => Some(Wrap::KeyFromField(field)),
+            (Some(_), Some(_)) => {
+                anyhow::bail!("only one of --wrap-key and --wrap-key-from may be given")
+            }
+        };
 
-            self.archive_staging_file()
-                .context("archiving staging file")?;
-        }
+        let settings = Settings {
+            staging_limit_bytes: self.staging_limit.get::<byte>(),
+            max_record_bytes: self.max_record_bytes,
+            max_normalized_bytes: self.max_normalized_bytes,
+            max_array_elements: self.max_array_elements,
+            max_object_keys: self.max_object_keys,
+            on_error: self.on_error,
+            max_nesting_depth: self.max_nesting_depth,
+            archive_naming: self.archive_naming,
+            direct_io: self.direct_io,
+            dedup_consecutive: self.dedup_consecutive,
+            dedup_on_archive: self.dedup_on_archive,
+            id_field: self.id_field,
+            unflatten: self.unflatten,
+            wrap,
+            pre_merge_every: self.pre_merge_every,
+            write_batch: self.write_batch,
+            echo: self.echo,
+            source: self.source,
+            background_archive: self.background_archive,
+            backpressure_wait: self.backpressure_wait_ms.map(Duration::from_millis),
+            merge_config: self
+                .merge_settings
+                .as_deref()
+                .map(read_merge_settings)
+                .transpose()?,
+            allow_tombstones: self.allow_tombstones,
+        };
+        let mut state = State::new(data_dir, settings)?;
 
-        Ok(ControlFlow::Continue(()))
-    }
+        let result = match (&self.kafka, &self.syslog, &self.input) {
+            (Some(options), ..) => kafka::consume(&mut state, options),
+            (None, Some(options), _) => syslog::receive(&mut state, options),
+            (None, None, input) => {
+                let reader: Box<dyn BufRead> = match input {
+                    Some(path) => {
+                        Box::new(BufReader::new(File::open(path).with_context(|| {
+                            format!("opening input file '{}'", path.display())
+                        })?))
+                    }
+                    None => Box::new(io::stdin().lock()),
+                };
 
-    /// Take the current contents of the staging file and buffered updates
-    fn archive_staging_file(&mut self) -> anyhow::Result<()> {
-        // Drop the append-only staging file reference if it exists
-        drop(self.staging_file.take());
+                decompress_input(reader, self.input_compression).and_then(|reader| {
+                    match self.input_format {
+                        InputFormat::Json => state.append_from_reader(reader),
+                        InputFormat::Csv => append_csv(&mut state, reader, &csv_options),
+                        InputFormat::Logfmt => {
+                            append_logfmt(&mut state, reader, csv_options.infer_types)
+                        }
+                    }
+                })
+            }
+        };
+        state.flush()?;
+        result?;
+        state.finish_archive()?;
 
-        // Set the added bytes to be zero since we just closed out the
-        // staging file
-        self.added_bytes = 0;
+        state.log_summary();
 
-        let staging_value = StagingFileReader::read_merged_value(&self.data_dir)
-            .context("opening staging file for archiving")?;
+        output.write_result("append", state.summary_json())
+    }
+}
 
-        let Some(staging_value) = staging_value else {
-            // No values in staging file
-            tracing::warn!("Staging file was empty, not continuing with archiving");
-            return Ok(());
-        };
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn decompress_detected_input() {
+        let compressed = zstd::encode_all(&b"{\"a\":1}\n"[..], 0).unwrap();
 
-        write_archive_value(&self.data_dir, staging_value)
-            .context("writing CBOR value to archive")?;
+        let mut lines = Vec::new();
+        decompress_input(compressed.as_slice(), InputCompression::Auto)
+            .unwrap()
+            .read_to_end(&mut lines)
+            .unwrap();
+        assert_eq!(lines, b"{\"a\":1}\n");
 
-        delete_staging_file(&self.data_dir).context("cleaning up staging file")?;
+        let mut lines = Vec::new();
+        decompress_input(&b"{\"a\":1}\n"[..], InputCompression::Auto)
+            .unwrap()
+            .read_to_end(&mut lines)
+            .unwrap();
+        assert_eq!(lines, b"{\"a\":1}\n");
 
-        Ok(())
+        assert!(decompress_input(&b"{}"[..], "gzip".parse().unwrap())
+            .unwrap()
+            .read_to_end(&mut lines)
+            .is_err());
     }
 }
@@ -0,0 +1,483 @@
+//! This module contains the formats `append` reads besides lines of JSON,
+//! whose records are converted into JSON objects before they are staged.
+
+use std::{collections::HashSet, io::BufRead, str::FromStr};
+
+use anyhow::Context;
+
+use crate::store::{rejected::RecordLocation, State};
+
+/// This enum controls how `append` reads records from its input
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum InputFormat {
+    /// One JSON record on each line
+    #[default]
+    Json,
+    /// Comma separated values, with a header row naming the fields of each
+    /// record
+    Csv,
+    /// One record on each line, written as `key=value` pairs like
+    /// `level=info msg="request done" took=12ms`
+    Logfmt,
+}
+
+impl FromStr for InputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "json" => Self::Json,
+            "csv" => Self::Csv,
+            "logfmt" => Self::Logfmt,
+            x => anyhow::bail!("'{x}' is an unknown option for the input format"),
+        })
+    }
+}
+
+/// How the cells of a CSV column are converted into JSON values
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum CellType {
+    String,
+    Number,
+    Bool,
+    /// The cell holds a JSON value of its own
+    Json,
+}
+
+impl FromStr for CellType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "string" => Self::String,
+            "number" => Self::Number,
+            "bool" => Self::Bool,
+            "json" => Self::Json,
+            x => anyhow::bail!(
+                "'{x}' is an unknown column type, expected string, number, bool, or json"
+            ),
+        })
+    }
+}
+
+impl CellType {
+    /// Convert the cell into a JSON value of this type, where an empty cell
+    /// is `null` unless the type is a string.
+    fn convert(self, cell: &str) -> anyhow::Result<serde_json::Value> {
+        if cell.is_empty() && self != Self::String {
+            return Ok(serde_json::Value::Null);
+        }
+
+        Ok(match self {
+            Self::String => serde_json::Value::String(cell.to_string()),
+            Self::Number => serde_json::Value::Number(
+                cell.trim()
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("'{cell}' is not a number"))?,
+            ),
+            Self::Bool => match cell.trim().to_ascii_lowercase().as_str() {
+                "true" => serde_json::Value::Bool(true),
+                "false" => serde_json::Value::Bool(false),
+                _ => anyhow::bail!("'{cell}' is not true or false"),
+            },
+            Self::Json => serde_json::from_str(cell)
+                .map_err(|err| anyhow::anyhow!("'{cell}' is not valid JSON: {err}"))?,
+        })
+    }
+
+    /// Convert the cell into the JSON value it looks like: `null` if it is
+    /// empty, a boolean or number if it is written like one, or else a
+    /// string.
+    fn infer(cell: &str) -> serde_json::Value {
+        [Self::Bool, Self::Number]
+            .into_iter()
+            .find_map(|cell_type| cell_type.convert(cell).ok())
+            .unwrap_or_else(|| serde_json::Value::String(cell.to_string()))
+    }
+}
+
+/// The types of some of the columns of a CSV input, given like
+/// `zip:string,count:number`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ColumnTypes(pub Vec<(String, CellType)>);
+
+impl FromStr for ColumnTypes {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|column| {
+                let Some((name, cell_type)) = column.rsplit_once(':') else {
+                    anyhow::bail!("expected '<column>:<type>' in '{column}'");
+                };
+                Ok((name.to_string(), cell_type.parse()?))
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Self)
+    }
+}
+
+/// How CSV input is converted into records
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CsvOptions {
+    /// Convert cells which look like numbers or booleans, and empty cells,
+    /// instead of keeping every cell as a string
+    pub infer_types: bool,
+    /// The type of each of these columns, which overrides the inference
+    pub column_types: ColumnTypes,
+}
+
+/// Read CSV rows from the reader and append each one as a record, until the
+/// reader reaches EOF.
+///
+/// Rows which cannot be converted are rejected like invalid records, and are
+/// reported with the line they start on and its byte offset in the input.
+pub fn append_csv(
+    state: &mut State,
+    reader: impl BufRead,
+    options: &CsvOptions,
+) -> anyhow::Result<()> {
+    read_csv_records(reader, options, |record, location| {
+        state.pause_for_archive()?;
+        match record {
+            Ok(record) => state.append_record(&record, location),
+            Err(err) => state.reject_record(err, location, None),
+        }
+    })
+}
+
+/// Convert each row after the header into a JSON object, keyed by the names
+/// in the header, and pass it as a line of JSON to the given function.
+fn read_csv_records(
+    reader: impl BufRead,
+    options: &CsvOptions,
+    mut f: impl FnMut(anyhow::Result<Vec<u8>>, RecordLocation) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(reader);
+
+    let header = reader.headers().context("reading CSV header")?.clone();
+    let mut names = HashSet::new();
+    if let Some(name) = header.iter().find(|name| !names.insert(*name)) {
+        anyhow::bail!("the CSV header has more than one column named '{name}'");
+    }
+    for (name, _) in &options.column_types.0 {
+        if !names.contains(name.as_str()) {
+            anyhow::bail!("the column '{name}' given in --columns is not in the CSV header");
+        }
+    }
+    let cell_types = header
+        .iter()
+        .map(|name| {
+            options
+                .column_types
+                .0
+                .iter()
+                .find(|(column, _)| column == name)
+                .map(|(_, cell_type)| *cell_type)
+        })
+        .collect::<Vec<_>>();
+
+    let mut row = csv::StringRecord::new();
+    loop {
+        let location = RecordLocation::Line {
+            number: reader.position().line(),
+            offset: reader.position().byte(),
+        };
+        if !reader.read_record(&mut row).context("reading CSV row")? {
+            return Ok(());
+        }
+
+        let record = if row.len() == header.len() {
+            header
+                .iter()
+                .zip(&cell_types)
+                .zip(&row)
+                .map(|((name, cell_type), cell)| {
+                    let value = match cell_type {
+                        Some(cell_type) => cell_type
+                            .convert(cell)
+                            .with_context(|| format!("converting column '{name}'"))?,
+                        None if options.infer_types => CellType::infer(cell),
+                        None => serde_json::Value::String(cell.to_string()),
+                    };
+                    Ok((name.to_string(), value))
+                })
+                .collect::<anyhow::Result<serde_json::Map<_, _>>>()
+                .and_then(|object| {
+                    serde_json::to_vec(&object).context("converting CSV row to JSON")
+                })
+        } else {
+            Err(anyhow::anyhow!(
+                "the CSV row has {} fields, but the header has {}",
+                row.len(),
+                header.len()
+            ))
+        };
+
+        f(record, location)?;
+    }
+}
+
+/// Read logfmt lines from the reader and append each one as a record, until
+/// the reader reaches EOF.
+///
+/// Lines which cannot be parsed are rejected like invalid records, and blank
+/// lines are skipped.
+pub fn append_logfmt(
+    state: &mut State,
+    reader: impl BufRead,
+    infer_types: bool,
+) -> anyhow::Result<()> {
+    read_logfmt_records(reader, infer_types, |record, location| {
+        state.pause_for_archive()?;
+        match record {
+            Ok(record) => state.append_record(&record, location),
+            Err(err) => state.reject_record(err, location, None),
+        }
+    })
+}
+
+/// Convert each line into a JSON object with a field for each pair, and pass
+/// it as a line of JSON to the given function.
+fn read_logfmt_records(
+    reader: impl BufRead,
+    infer_types: bool,
+    mut f: impl FnMut(anyhow::Result<Vec<u8>>, RecordLocation) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut offset = 0;
+    for (index, line) in reader.split(b'\n').enumerate() {
+        let line = line.context("reading line from input")?;
+        let location = RecordLocation::Line {
+            number: index as u64 + 1,
+            offset,
+        };
+        offset += line.len() as u64 + 1;
+
+        let record = std::str::from_utf8(&line)
+            .context("the line is not valid UTF-8")
+            .and_then(|line| {
+                let line = line.strip_suffix('\r').unwrap_or(line);
+                if line.trim().is_empty() {
+                    return Ok(None);
+                }
+                let object = parse_logfmt(line, infer_types)?;
+                serde_json::to_vec(&object)
+                    .context("converting logfmt line to JSON")
+                    .map(Some)
+            })
+            .transpose();
+        if let Some(record) = record {
+            f(record, location)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse a line of `key=value` pairs separated by spaces into an object.
+///
+/// Values may be quoted to hold spaces, with `\"` and `\\` escaping a quote
+/// and a backslash. A key without `=` is `true`, like `debug` in
+/// `level=info debug`, and the value of a repeated key replaces the earlier
+/// one. Values are strings unless `infer_types` is set, like the cells of CSV
+/// input.
+fn parse_logfmt(
+    line: &str,
+    infer_types: bool,
+) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    let mut object = serde_json::Map::new();
+    let mut rest = line;
+
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return Ok(object);
+        }
+
+        let key_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        if key.is_empty() || key.contains('"') {
+            anyhow::bail!(
+                "expected a key at byte {} of the line",
+                line.len() - rest.len()
+            );
+        }
+        rest = &rest[key_end..];
+
+        let Some(value_start) = rest.strip_prefix('=') else {
+            object.insert(key.to_string(), serde_json::Value::Bool(true));
+            continue;
+        };
+
+        let value = if let Some(quoted) = value_start.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((index, '"')) => break index,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => value.push('\n'),
+                        Some((_, 't')) => value.push('\t'),
+                        Some((_, c)) => value.push(c),
+                        None => anyhow::bail!("the value of '{key}' has no closing quote"),
+                    },
+                    Some((_, c)) => value.push(c),
+                    None => anyhow::bail!("the value of '{key}' has no closing quote"),
+                }
+            };
+            rest = &quoted[end + 1..];
+            if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+                anyhow::bail!("expected a space after the quoted value of '{key}'");
+            }
+            // Quoting keeps a value a string, like `code="200"`
+            serde_json::Value::String(value)
+        } else {
+            let value_end = value_start
+                .find(char::is_whitespace)
+                .unwrap_or(value_start.len());
+            let value = &value_start[..value_end];
+            rest = &value_start[value_end..];
+            if infer_types {
+                CellType::infer(value)
+            } else {
+                serde_json::Value::String(value.to_string())
+            }
+        };
+        object.insert(key.to_string(), value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(input: &str, options: &CsvOptions) -> anyhow::Result<Vec<Result<String, String>>> {
+        let mut records = Vec::new();
+        read_csv_records(input.as_bytes(), options, |record, location| {
+            records.push(
+                record
+                    .map(|record| String::from_utf8(record).unwrap())
+                    .map_err(|err| format!("{location}: {err:#}")),
+            );
+            Ok(())
+        })?;
+
+        Ok(records)
+    }
+
+    #[test]
+    fn csv_rows_to_records() {
+        let input = "name,count,active,zip\nalpha,3,true,02134\n\"b, c\",,false,\n";
+
+        assert_eq!(
+            records(input, &CsvOptions::default()).unwrap(),
+            [
+                Ok(r#"{"name":"alpha","count":"3","active":"true","zip":"02134"}"#.into()),
+                Ok(r#"{"name":"b, c","count":"","active":"false","zip":""}"#.into()),
+            ]
+        );
+
+        let options = CsvOptions {
+            infer_types: true,
+            column_types: "zip:string".parse().unwrap(),
+        };
+        assert_eq!(
+            records(input, &options).unwrap(),
+            [
+                Ok(r#"{"name":"alpha","count":3,"active":true,"zip":"02134"}"#.into()),
+                Ok(r#"{"name":"b, c","count":null,"active":false,"zip":""}"#.into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reject_csv_rows() {
+        let options = CsvOptions {
+            infer_types: false,
+            column_types: "count:number".parse().unwrap(),
+        };
+        let input = "name,count\na,1\nb,two\nc\n";
+
+        assert_eq!(
+            records(input, &options).unwrap(),
+            [
+                Ok(r#"{"name":"a","count":1}"#.into()),
+                Err(
+                    "input line 3 starting at byte 15: converting column 'count': 'two' is not \
+                     a number"
+                        .into()
+                ),
+                Err(
+                    "input line 4 starting at byte 21: the CSV row has 1 fields, but the header \
+                     has 2"
+                        .into()
+                ),
+            ]
+        );
+
+        let err = records("a,a\n1,2\n", &CsvOptions::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the CSV header has more than one column named 'a'"
+        );
+        let err = records("a\n1\n", &options).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the column 'count' given in --columns is not in the CSV header"
+        );
+        assert!("count".parse::<ColumnTypes>().is_err());
+        assert!("count:date".parse::<ColumnTypes>().is_err());
+    }
+
+    #[test]
+    fn logfmt_lines_to_records() {
+        let logfmt = |input: &str, infer_types: bool| {
+            let mut records = Vec::new();
+            read_logfmt_records(input.as_bytes(), infer_types, |record, location| {
+                records.push(
+                    record
+                        .map(|record| String::from_utf8(record).unwrap())
+                        .map_err(|err| format!("{location}: {err:#}")),
+                );
+                Ok(())
+            })
+            .unwrap();
+            records
+        };
+        let input = "level=info msg=\"request \\\"done\\\"\" took=12 cached\n\n\
+                     level=warn code=\"404\" path=\r\nmsg=\"unterminated\n";
+
+        assert_eq!(
+            logfmt(input, false),
+            [
+                Ok(r#"{"level":"info","msg":"request \"done\"","took":"12","cached":true}"#.into()),
+                Ok(r#"{"level":"warn","code":"404","path":""}"#.into()),
+                Err(
+                    "input line 4 starting at byte 79: the value of 'msg' has no closing quote"
+                        .into()
+                ),
+            ]
+        );
+        assert_eq!(
+            logfmt(input, true)[..2],
+            [
+                Ok(r#"{"level":"info","msg":"request \"done\"","took":12,"cached":true}"#.into()),
+                Ok(r#"{"level":"warn","code":"404","path":null}"#.into()),
+            ]
+        );
+
+        assert_eq!(
+            logfmt("a=1 =2\nb=\"x\"y\n", false),
+            [
+                Err("input line 1 starting at byte 0: expected a key at byte 4 of the line".into()),
+                Err(
+                    "input line 2 starting at byte 7: expected a space after the quoted value \
+                     of 'b'"
+                        .into()
+                ),
+            ]
+        );
+    }
+}
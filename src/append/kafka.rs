@@ -0,0 +1,228 @@
+//! This module contains the Kafka source for the `append` command, which is
+//! only available with the `kafka` feature.
+//!
+//! Messages are consumed with automatic offset commits disabled, and the
+//! consumer position is only committed after every record consumed so far has
+//! been flushed to the staging file (or archived). If `wall-a` stops before a
+//! commit, those messages are consumed again on the next run, so ingestion is
+//! at-least-once.
+
+use std::{str::FromStr, time::Duration};
+
+use crate::store::State;
+
+/// The default consumer group used when none is given
+const DEFAULT_GROUP: &str = "wall-a";
+
+/// The options for consuming from a Kafka topic, parsed from
+/// `brokers=...,topic=...[,group=...][,idle-timeout=...]`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KafkaOptions {
+    /// The comma separated list of bootstrap brokers
+    brokers: String,
+    /// The topic to consume from
+    topic: String,
+    /// The consumer group whose offsets are committed
+    group: String,
+    /// Stop consuming once no message has arrived for this long, or never
+    /// stop if `None`
+    idle_timeout: Option<Duration>,
+}
+
+impl FromStr for KafkaOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut brokers: Option<String> = None;
+        let mut topic = None;
+        let mut group = None;
+        let mut idle_timeout = None;
+        // The key of the previous pair, so that a list of brokers like
+        // `brokers=a:9092,b:9092` can contain the separator
+        let mut previous_key = None;
+
+        for part in s.split(',') {
+            let Some((key, value)) = part.split_once('=') else {
+                match (previous_key, brokers.as_mut()) {
+                    (Some("brokers"), Some(brokers)) if !part.is_empty() => {
+                        brokers.push(',');
+                        brokers.push_str(part);
+                        continue;
+                    }
+                    _ => anyhow::bail!("'{part}' is not a 'key=value' Kafka option"),
+                }
+            };
+
+            match key {
+                "brokers" => brokers = Some(value.to_string()),
+                "topic" => topic = Some(value.to_string()),
+                "group" => group = Some(value.to_string()),
+                "idle-timeout" => {
+                    let secs = value.parse().map_err(|err| {
+                        anyhow::anyhow!("'{value}' is not a valid idle timeout in seconds: {err}")
+                    })?;
+                    idle_timeout = Some(Duration::from_secs(secs));
+                }
+                x => anyhow::bail!("'{x}' is an unknown Kafka option"),
+            }
+            previous_key = Some(key);
+        }
+
+        let Some(brokers) = brokers.filter(|brokers| !brokers.is_empty()) else {
+            anyhow::bail!("the Kafka options are missing 'brokers'");
+        };
+        let Some(topic) = topic.filter(|topic| !topic.is_empty()) else {
+            anyhow::bail!("the Kafka options are missing 'topic'");
+        };
+
+        Ok(Self {
+            brokers,
+            topic,
+            group: group.unwrap_or_else(|| DEFAULT_GROUP.to_string()),
+            idle_timeout,
+        })
+    }
+}
+
+/// Consume messages from the Kafka topic and append each one as a record.
+#[cfg(not(feature = "kafka"))]
+pub fn consume(_state: &mut State, _options: &KafkaOptions) -> anyhow::Result<()> {
+    anyhow::bail!("wall-a was built without the `kafka` feature, so --kafka is not available")
+}
+
+/// Consume messages from the Kafka topic and append each one as a record.
+#[cfg(feature = "kafka")]
+pub fn consume(state: &mut State, options: &KafkaOptions) -> anyhow::Result<()> {
+    use std::time::Instant;
+
+    use anyhow::Context;
+    use rdkafka::{
+        config::ClientConfig,
+        consumer::{BaseConsumer, CommitMode, Consumer},
+        Message,
+    };
+
+    use crate::store::rejected::RecordLocation;
+
+    /// How long to wait for each message before flushing and committing
+    const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+    /// The most records to consume between commits while messages keep
+    /// arriving
+    const COMMIT_INTERVAL_RECORDS: u64 = 1000;
+
+    let consumer: BaseConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &options.brokers)
+        .set("group.id", &options.group)
+        .set("enable.auto.commit", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()
+        .context("creating Kafka consumer")?;
+    consumer
+        .subscribe(&[&options.topic])
+        .with_context(|| format!("subscribing to Kafka topic '{}'", options.topic))?;
+    tracing::info!(
+        brokers = %options.brokers,
+        topic = %options.topic,
+        group = %options.group,
+        "Consuming from Kafka"
+    );
+
+    let mut uncommitted_records = 0u64;
+    let mut last_message = Instant::now();
+
+    // Make every consumed record durable in the data directory, then commit
+    // the consumer position so that those messages are not delivered again
+    let commit = |state: &mut State, uncommitted_records: &mut u64| -> anyhow::Result<()> {
+        if *uncommitted_records == 0 {
+            return Ok(());
+        }
+
+        state.sync()?;
+        consumer
+            .commit_consumer_state(CommitMode::Sync)
+            .context("committing Kafka offsets")?;
+        tracing::debug!(records = %uncommitted_records, "Committed Kafka offsets");
+        *uncommitted_records = 0;
+
+        Ok(())
+    };
+
+    loop {
+        let Some(message) = consumer.poll(POLL_TIMEOUT) else {
+            commit(state, &mut uncommitted_records)?;
+
+            if let Some(idle_timeout) = options.idle_timeout {
+                if last_message.elapsed() >= idle_timeout {
+                    tracing::debug!("No Kafka messages within the idle timeout, stopping");
+                    return Ok(());
+                }
+            }
+            continue;
+        };
+        let message = message.context("receiving Kafka message")?;
+        last_message = Instant::now();
+        uncommitted_records += 1;
+
+        match message.payload() {
+            None => {
+                tracing::trace!(offset = %message.offset(), "Skipping Kafka message without a payload")
+            }
+            Some(payload) => state.append_record(
+                payload,
+                RecordLocation::Kafka {
+                    partition: message.partition(),
+                    offset: message.offset(),
+                },
+            )?,
+        }
+
+        if uncommitted_records >= COMMIT_INTERVAL_RECORDS {
+            commit(state, &mut uncommitted_records)?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_options() {
+        assert_eq!(
+            "brokers=a:9092,b:9092,topic=events,idle-timeout=30"
+                .parse::<KafkaOptions>()
+                .unwrap(),
+            KafkaOptions {
+                brokers: "a:9092,b:9092".into(),
+                topic: "events".into(),
+                group: "wall-a".into(),
+                idle_timeout: Some(Duration::from_secs(30)),
+            }
+        );
+        assert_eq!(
+            "topic=events,group=ingest,brokers=a:9092"
+                .parse::<KafkaOptions>()
+                .unwrap(),
+            KafkaOptions {
+                brokers: "a:9092".into(),
+                topic: "events".into(),
+                group: "ingest".into(),
+                idle_timeout: None,
+            }
+        );
+
+        let err = "brokers=a:9092".parse::<KafkaOptions>().unwrap_err();
+        assert_eq!(err.to_string(), "the Kafka options are missing 'topic'");
+
+        let err = "topic=events,b:9092".parse::<KafkaOptions>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'b:9092' is not a 'key=value' Kafka option"
+        );
+
+        let err = "brokers=a:9092,topic=events,offset=0"
+            .parse::<KafkaOptions>()
+            .unwrap_err();
+        assert_eq!(err.to_string(), "'offset' is an unknown Kafka option");
+    }
+}
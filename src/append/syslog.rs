@@ -0,0 +1,496 @@
+//! This module contains the syslog source for the `append` command, which is
+//! only available with the `syslog` feature.
+//!
+//! Messages are received as datagrams on a UDP or unix socket, in either the
+//! RFC 5424 format or the older BSD format of RFC 3164, like the messages the
+//! `syslog(3)` function writes to `/dev/log`. Each message becomes an object
+//! with its facility, severity, timestamp, host, application, and text. The
+//! records are flushed to the staging file whenever no message has arrived
+//! for a second, and are lost if `wall-a` stops before that.
+
+use std::{path::PathBuf, str::FromStr, time::Duration};
+
+use crate::store::State;
+
+/// The address a syslog socket listens on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyslogListen {
+    /// A UDP socket bound to a `host:port` address
+    Udp(String),
+    /// A unix datagram socket created at a path
+    Unix(PathBuf),
+}
+
+/// The options for receiving syslog messages, parsed from
+/// `udp=<host:port>` or `unix=<path>`, with an optional `idle-timeout=...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyslogOptions {
+    /// The socket to receive messages on
+    listen: SyslogListen,
+    /// Stop receiving once no message has arrived for this long, or never
+    /// stop if `None`
+    idle_timeout: Option<Duration>,
+}
+
+impl FromStr for SyslogOptions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut listen = None;
+        let mut idle_timeout = None;
+
+        for part in s.split(',') {
+            let Some((key, value)) = part.split_once('=') else {
+                anyhow::bail!("'{part}' is not a 'key=value' syslog option");
+            };
+
+            let socket = match key {
+                "udp" => SyslogListen::Udp(value.to_string()),
+                "unix" => SyslogListen::Unix(PathBuf::from(value)),
+                "idle-timeout" => {
+                    let secs = value.parse().map_err(|err| {
+                        anyhow::anyhow!("'{value}' is not a valid idle timeout in seconds: {err}")
+                    })?;
+                    idle_timeout = Some(Duration::from_secs(secs));
+                    continue;
+                }
+                x => anyhow::bail!("'{x}' is an unknown syslog option"),
+            };
+            if value.is_empty() {
+                anyhow::bail!("the syslog option '{key}' is missing an address");
+            }
+            if listen.replace(socket).is_some() {
+                anyhow::bail!("only one of the syslog options 'udp' and 'unix' may be given");
+            }
+        }
+
+        let Some(listen) = listen else {
+            anyhow::bail!("the syslog options are missing 'udp' or 'unix'");
+        };
+
+        Ok(Self {
+            listen,
+            idle_timeout,
+        })
+    }
+}
+
+/// Receive syslog messages and append each one as a record.
+#[cfg(not(feature = "syslog"))]
+pub fn receive(_state: &mut State, _options: &SyslogOptions) -> anyhow::Result<()> {
+    anyhow::bail!("wall-a was built without the `syslog` feature, so --syslog is not available")
+}
+
+/// Receive syslog messages and append each one as a record.
+#[cfg(feature = "syslog")]
+pub fn receive(state: &mut State, options: &SyslogOptions) -> anyhow::Result<()> {
+    use std::{io, net::UdpSocket, time::Instant};
+
+    use anyhow::Context;
+
+    use crate::store::rejected::RecordLocation;
+
+    /// How long to wait for each message before flushing the staging file
+    const POLL_TIMEOUT: Duration = Duration::from_secs(1);
+    /// The largest datagram which can be received, longer messages are cut
+    /// off
+    const MAX_MESSAGE_BYTES: usize = 64 * 1024;
+
+    /// A bound socket, which removes the file of a unix socket when dropped
+    enum Socket {
+        Udp(UdpSocket),
+        #[cfg(unix)]
+        Unix(std::os::unix::net::UnixDatagram, PathBuf),
+    }
+
+    impl Socket {
+        fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+            match self {
+                Socket::Udp(socket) => socket.recv(buf),
+                #[cfg(unix)]
+                Socket::Unix(socket, _) => socket.recv(buf),
+            }
+        }
+    }
+
+    impl Drop for Socket {
+        fn drop(&mut self) {
+            #[cfg(unix)]
+            if let Socket::Unix(_, path) = self {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
+
+    let socket = match &options.listen {
+        SyslogListen::Udp(address) => {
+            let socket = UdpSocket::bind(address)
+                .with_context(|| format!("binding syslog UDP socket to '{address}'"))?;
+            socket.set_read_timeout(Some(POLL_TIMEOUT))?;
+            Socket::Udp(socket)
+        }
+        #[cfg(unix)]
+        SyslogListen::Unix(path) => {
+            use std::os::unix::fs::FileTypeExt;
+
+            // A socket left behind by an earlier run would fail the bind
+            if std::fs::symlink_metadata(path)
+                .is_ok_and(|metadata| metadata.file_type().is_socket())
+            {
+                std::fs::remove_file(path)
+                    .with_context(|| format!("removing old syslog socket '{}'", path.display()))?;
+            }
+            let socket = std::os::unix::net::UnixDatagram::bind(path)
+                .with_context(|| format!("binding syslog unix socket '{}'", path.display()))?;
+            socket.set_read_timeout(Some(POLL_TIMEOUT))?;
+            Socket::Unix(socket, path.clone())
+        }
+        #[cfg(not(unix))]
+        SyslogListen::Unix(_) => {
+            anyhow::bail!("syslog unix sockets are only available on unix platforms")
+        }
+    };
+    tracing::info!(listen = ?options.listen, "Receiving syslog messages");
+
+    let mut buf = vec![0; MAX_MESSAGE_BYTES];
+    let mut number = 0u64;
+    let mut unflushed = false;
+    let mut last_message = Instant::now();
+
+    loop {
+        let len = match socket.recv(&mut buf) {
+            Ok(len) => len,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                if unflushed {
+                    state.flush()?;
+                    unflushed = false;
+                }
+
+                if let Some(idle_timeout) = options.idle_timeout {
+                    if last_message.elapsed() >= idle_timeout {
+                        tracing::debug!("No syslog messages within the idle timeout, stopping");
+                        return Ok(());
+                    }
+                }
+                continue;
+            }
+            Err(err) => return Err(err).context("receiving syslog message"),
+        };
+        last_message = Instant::now();
+        number += 1;
+        unflushed = true;
+
+        let message = &buf[..len];
+        let location = RecordLocation::Syslog { number };
+        state.pause_for_archive()?;
+        let record = message::parse(&String::from_utf8_lossy(message)).and_then(|object| {
+            serde_json::to_vec(&object).context("converting syslog message to JSON")
+        });
+        match record {
+            Ok(record) => state.append_record(&record, location)?,
+            Err(err) => state.reject_record(err, location, Some(message))?,
+        }
+    }
+}
+
+/// The parsing of syslog messages into objects
+#[cfg(feature = "syslog")]
+mod message {
+    use serde_json::{Map, Value};
+
+    /// The names of the facilities, by their number
+    const FACILITIES: [&str; 24] = [
+        "kern",
+        "user",
+        "mail",
+        "daemon",
+        "auth",
+        "syslog",
+        "lpr",
+        "news",
+        "uucp",
+        "cron",
+        "authpriv",
+        "ftp",
+        "ntp",
+        "security",
+        "console",
+        "solaris-cron",
+        "local0",
+        "local1",
+        "local2",
+        "local3",
+        "local4",
+        "local5",
+        "local6",
+        "local7",
+    ];
+    /// The names of the severities, by their number
+    const SEVERITIES: [&str; 8] = [
+        "emerg", "alert", "crit", "err", "warning", "notice", "info", "debug",
+    ];
+    /// The priority of a message without one, `user.notice` as RFC 3164
+    /// suggests
+    const DEFAULT_PRIORITY: usize = 13;
+
+    /// Parse a syslog message into an object with a field for each of its
+    /// parts, leaving out the parts which the message does not have.
+    pub fn parse(message: &str) -> anyhow::Result<Map<String, Value>> {
+        let message = message.trim_end_matches(['\n', '\r', '\0']);
+        let (priority, rest) = match message
+            .strip_prefix('<')
+            .and_then(|rest| rest.split_once('>'))
+        {
+            Some((priority, rest)) => match priority.parse::<usize>() {
+                Ok(priority) if priority < FACILITIES.len() * SEVERITIES.len() => (priority, rest),
+                _ => anyhow::bail!("'<{priority}>' is not a valid syslog priority"),
+            },
+            None => (DEFAULT_PRIORITY, message),
+        };
+
+        let mut object = Map::new();
+        object.insert("facility".into(), FACILITIES[priority / 8].into());
+        object.insert("severity".into(), SEVERITIES[priority % 8].into());
+        match rest.strip_prefix("1 ") {
+            Some(rest) => parse_rfc5424(rest, &mut object)?,
+            None => parse_rfc3164(rest, &mut object),
+        }
+
+        Ok(object)
+    }
+
+    /// Parse the header, structured data, and text of an RFC 5424 message,
+    /// like `2024-06-20T19:22:45Z host app 123 ID47 [id k="v"] text`.
+    fn parse_rfc5424(rest: &str, object: &mut Map<String, Value>) -> anyhow::Result<()> {
+        let mut parts = rest.splitn(6, ' ');
+        for key in ["timestamp", "hostname", "app", "pid", "msgid"] {
+            match parts.next() {
+                // A `-` is a part the sender left out
+                Some("-") => {}
+                Some(part) if !part.is_empty() => {
+                    object.insert(key.into(), part.into());
+                }
+                _ => anyhow::bail!("the syslog message ends before its {key}"),
+            }
+        }
+
+        let mut rest = parts.next().unwrap_or_default();
+        if let Some(after) = rest.strip_prefix('-') {
+            rest = after;
+        } else if rest.starts_with('[') {
+            let mut structured_data = Map::new();
+            while let Some(element) = rest.strip_prefix('[') {
+                rest = parse_sd_element(element, &mut structured_data)?;
+            }
+            object.insert("structured_data".into(), structured_data.into());
+        }
+
+        if let Some(text) = rest.strip_prefix(' ') {
+            // The text may start with a byte order mark to mark it as UTF-8
+            let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+            object.insert("message".into(), text.into());
+        } else if !rest.is_empty() {
+            anyhow::bail!("expected a space before the text of the syslog message");
+        }
+
+        Ok(())
+    }
+
+    /// Parse a structured data element after its `[`, like
+    /// `exampleSDID@32473 iut="3" eventSource="App"]`, and return the rest of
+    /// the message after its `]`.
+    fn parse_sd_element<'m>(
+        element: &'m str,
+        structured_data: &mut Map<String, Value>,
+    ) -> anyhow::Result<&'m str> {
+        let id_end = element
+            .find([' ', ']'])
+            .ok_or_else(|| anyhow::anyhow!("a structured data element has no closing ']'"))?;
+        let id = &element[..id_end];
+        let mut rest = &element[id_end..];
+
+        let mut params = Map::new();
+        loop {
+            if let Some(after) = rest.strip_prefix(']') {
+                structured_data.insert(id.to_string(), params.into());
+                return Ok(after);
+            }
+            let Some((name, after)) = rest
+                .strip_prefix(' ')
+                .and_then(|param| param.split_once("=\""))
+            else {
+                anyhow::bail!("the structured data element '{id}' is not valid");
+            };
+
+            let mut value = String::new();
+            let mut chars = after.char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((index, '"')) => break index,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c @ ('"' | '\\' | ']'))) => value.push(c),
+                        Some((_, c)) => {
+                            value.push('\\');
+                            value.push(c);
+                        }
+                        None => break after.len(),
+                    },
+                    Some((_, c)) => value.push(c),
+                    None => break after.len(),
+                }
+            };
+            if end == after.len() {
+                anyhow::bail!("the structured data parameter '{name}' has no closing quote");
+            }
+            params.insert(name.to_string(), value.into());
+            rest = &after[end + 1..];
+        }
+    }
+
+    /// Parse the header and text of a BSD message, like
+    /// `Jun 20 19:22:45 host sshd[123]: text`, where the host is left out of
+    /// messages sent to a local socket.
+    fn parse_rfc3164(rest: &str, object: &mut Map<String, Value>) {
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+
+        // The timestamp has no year, so it is kept as it was written
+        let mut rest = rest;
+        if let Some(timestamp) = rest.get(..15) {
+            let bytes = timestamp.as_bytes();
+            if timestamp
+                .get(..3)
+                .is_some_and(|month| MONTHS.contains(&month))
+                && bytes[3] == b' '
+                && bytes[9] == b':'
+            {
+                object.insert("timestamp".into(), timestamp.into());
+                rest = rest[15..].trim_start_matches(' ');
+            }
+        }
+
+        let tag_end = |token: &str| token.ends_with(':') || token.contains('[');
+        if let Some((host, after)) = rest.split_once(' ') {
+            if !host.is_empty() && !tag_end(host) && object.contains_key("timestamp") {
+                let tag = after.split(' ').next().unwrap_or_default();
+                if tag_end(tag) {
+                    object.insert("hostname".into(), host.into());
+                    rest = after;
+                }
+            }
+        }
+
+        if let Some((tag, text)) = rest.split_once(": ") {
+            if !tag.is_empty() && !tag.contains(' ') {
+                let (app, pid) = match tag.strip_suffix(']').and_then(|tag| tag.split_once('[')) {
+                    Some((app, pid)) => (app, Some(pid)),
+                    None => (tag, None),
+                };
+                object.insert("app".into(), app.into());
+                if let Some(pid) = pid {
+                    object.insert("pid".into(), pid.into());
+                }
+                rest = text;
+            }
+        }
+        object.insert("message".into(), rest.into());
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn parsed(message: &str) -> String {
+            Value::from(parse(message).unwrap()).to_string()
+        }
+
+        #[test]
+        fn parse_rfc5424_messages() {
+            assert_eq!(
+                parsed(
+                    "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 \
+                     [exampleSDID@32473 iut=\"3\" eventSource=\"App\\]\"][meta seq=\"1\"] \
+                     \u{feff}An application event"
+                ),
+                r#"{"facility":"local4","severity":"notice","timestamp":"2003-10-11T22:14:15.003Z","hostname":"mymachine.example.com","app":"evntslog","msgid":"ID47","structured_data":{"exampleSDID@32473":{"iut":"3","eventSource":"App]"},"meta":{"seq":"1"}},"message":"An application event"}"#
+            );
+            assert_eq!(
+                parsed("<34>1 - - su - - -\n"),
+                r#"{"facility":"auth","severity":"crit","app":"su"}"#
+            );
+
+            let err = parse("<34>1 2003-10-11T22:14:15Z host").unwrap_err();
+            assert_eq!(err.to_string(), "the syslog message ends before its app");
+            let err = parse("<34>1 - - - - - [id a=\"1]").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "the structured data parameter 'a' has no closing quote"
+            );
+            let err = parse("<192>hello").unwrap_err();
+            assert_eq!(err.to_string(), "'<192>' is not a valid syslog priority");
+        }
+
+        #[test]
+        fn parse_rfc3164_messages() {
+            assert_eq!(
+                parsed("<38>Oct 11 22:14:15 mymachine su[230]: 'su root' failed on /dev/pts/8"),
+                r#"{"facility":"auth","severity":"info","timestamp":"Oct 11 22:14:15","hostname":"mymachine","app":"su","pid":"230","message":"'su root' failed on /dev/pts/8"}"#
+            );
+            // Messages to a local socket leave out the host
+            assert_eq!(
+                parsed("<30>Jun  2 09:01:02 cron: job done"),
+                r#"{"facility":"daemon","severity":"info","timestamp":"Jun  2 09:01:02","app":"cron","message":"job done"}"#
+            );
+            assert_eq!(
+                parsed("no header at all"),
+                r#"{"facility":"user","severity":"notice","message":"no header at all"}"#
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_options() {
+        assert_eq!(
+            "udp=127.0.0.1:5514,idle-timeout=30"
+                .parse::<SyslogOptions>()
+                .unwrap(),
+            SyslogOptions {
+                listen: SyslogListen::Udp("127.0.0.1:5514".into()),
+                idle_timeout: Some(Duration::from_secs(30)),
+            }
+        );
+        assert_eq!(
+            "unix=/run/wall-a.sock".parse::<SyslogOptions>().unwrap(),
+            SyslogOptions {
+                listen: SyslogListen::Unix("/run/wall-a.sock".into()),
+                idle_timeout: None,
+            }
+        );
+
+        let err = "idle-timeout=5".parse::<SyslogOptions>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the syslog options are missing 'udp' or 'unix'"
+        );
+        let err = "udp=:514,unix=/dev/log"
+            .parse::<SyslogOptions>()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "only one of the syslog options 'udp' and 'unix' may be given"
+        );
+        let err = "tcp=:514".parse::<SyslogOptions>().unwrap_err();
+        assert_eq!(err.to_string(), "'tcp' is an unknown syslog option");
+    }
+}
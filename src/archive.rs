@@ -1,23 +1,266 @@
 //! This module contains things relating to reading and writing to archive file
 
+mod compaction;
+mod direct;
+mod index;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+
 use std::{
+    collections::BTreeMap,
     fs::{self, OpenOptions},
-    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
-    path::Path,
+    io::{BufRead, BufReader, BufWriter, Cursor, ErrorKind, Read, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use anyhow::Context;
-use crc32fast::Hasher;
-use jiff::{fmt::temporal::DateTimePrinter, Timestamp};
-use zerocopy::{AsBytes, FromBytes, FromZeroes, Unaligned};
+use jiff::{tz::TimeZone, Timestamp};
+
+pub use self::compaction::{
+    compaction_journal_path, write_compacted_archive_value, write_compacted_archive_values,
+    CompactionJournal,
+};
+use self::{compaction::hidden_archives, index::ArchiveIndex};
+use crate::{
+    checksums::{forget_archive, record_archive, ChecksumEntry},
+    format::{footer_len, BodyHasher, KeySizes, Metadata, FOOTER_TRAILER_LEN},
+    manifest::Manifest,
+    value::{self, Value},
+};
+
+/// This enum controls how new archive files are named
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ArchiveNaming {
+    /// Name archives by the time they were created
+    #[default]
+    Timestamp,
+    /// Name archives by the BLAKE3 hash of their body, and record the time
+    /// they were created in the archive index
+    Content,
+    /// Name archives by their sequence number, like `000042.bin`, which never
+    /// depends on the clock
+    Sequence,
+}
+
+impl FromStr for ArchiveNaming {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "timestamp" => Self::Timestamp,
+            "content" => Self::Content,
+            "sequence" => Self::Sequence,
+            x => anyhow::bail!("'{x}' is an unknown option for naming archive files"),
+        })
+    }
+}
+
+/// Return the path of the directory containing all the archive files.
+pub fn archive_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("archived")
+}
+
+/// Return the paths of all the archive files in the data directory, ordered by
+/// their sequence numbers.
+///
+/// Archives without a sequence number were written before any that have one,
+/// and are ordered by the time they were created. For archives named by
+/// timestamp this is the filename, for content-addressed archives it is the
+/// timestamp recorded in the archive index.
+///
+/// Archives replaced by a compaction are left out as soon as the compacted
+/// archives are all in place, and the compacted archives are left out until
+/// then, see [`write_compacted_archive_values`].
+///
+/// Returns an empty list if the archive directory does not exist.
+pub fn list_archive_files(data_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let archive_dir_entries = match archive_dir(data_dir).read_dir() {
+        Ok(entries) => entries,
+        Err(err) => {
+            if matches!(err.kind(), ErrorKind::NotFound) {
+                // archived directory does not exist
+                return Ok(Vec::new());
+            } else {
+                return Err(err).context("reading archived directory entries");
+            }
+        }
+    };
 
-use crate::value::Value;
+    let index = ArchiveIndex::read(data_dir)?;
+    let hidden = hidden_archives(data_dir)?;
 
-/// TODO
+    let all_entries = archive_dir_entries
+        // Skip archives which are still being written, or were left behind by
+        // a crash, see `store::recovery`, and any other files which are not
+        // archives, like the partial files of a copy into the directory
+        .filter(|res| match res {
+            Ok(entry) => {
+                let path = entry.path();
+                let is_archive = is_archive_path(&path);
+                if !is_archive {
+                    tracing::debug!(path = %path.display(), "Skipping file which is not an archive");
+                }
+                // Archives replaced by a compaction are only deleted after
+                // the compacted archives are all in place, and until then
+                // the ones in place are not listed
+                is_archive && !hidden.contains(&archive_name(&path))
+            }
+            Err(_) => true,
+        })
+        .map(|res| {
+            res.map(|entry| {
+                let path = entry.path();
+                let name = archive_name(&path);
+                let order_key = match index.timestamp(&name) {
+                    Some(timestamp) => format!("{timestamp}.bin"),
+                    None => name.clone(),
+                };
+                // An archive with unreadable metadata is reported when it is
+                // read, not when listing
+                let sequence = read_archive_metadata(&path)
+                    .ok()
+                    .and_then(|metadata| metadata.sequence());
+
+                ((sequence, order_key, name), path)
+            })
+        })
+        .collect::<Result<BTreeMap<_, _>, _>>()
+        .context("reading all dir entries into set")?;
+
+    Ok(all_entries.into_values().collect())
+}
+
+/// Return true if the path names a complete archive file, which ends with
+/// `.bin` and is not hidden.
+///
+/// Archives are written to a temporary file and renamed once complete, so
+/// this never matches a file which is still being written.
+fn is_archive_path(path: &Path) -> bool {
+    let Some(file_name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+
+    !file_name.starts_with('.') && path.extension().is_some_and(|ext| ext == "bin")
+}
+
+/// Return the paths of all the archive files in the data directory along with
+/// the time each was created, ordered by that time.
+///
+/// The time is taken from the filename or the archive index like
+/// [`list_archive_files`], then from the creation time in the metadata, like
+/// for archives named by sequence number, falling back to the modification
+/// time of the file if none of them has one.
+pub fn list_archive_files_with_timestamps(
+    data_dir: &Path,
+) -> anyhow::Result<Vec<(Timestamp, PathBuf)>> {
+    let index = ArchiveIndex::read(data_dir)?;
+
+    list_archive_files(data_dir)?
+        .into_iter()
+        .map(|path| {
+            let name = archive_name(&path);
+            let timestamp = index
+                .timestamp(&name)
+                .or_else(|| name.strip_suffix(".bin"))
+                .and_then(parse_archive_timestamp)
+                .or_else(|| {
+                    let created = read_archive_metadata(&path).ok()?.created()?;
+                    Timestamp::from_second(created).ok()
+                });
+
+            let timestamp = match timestamp {
+                Some(timestamp) => timestamp,
+                None => modified_timestamp(&path)?,
+            };
+
+            Ok((timestamp, path))
+        })
+        .collect()
+}
+
+/// Return the name that identifies an archive file, which is its filename.
+pub fn archive_name(archive_path: &Path) -> String {
+    archive_path
+        .file_name()
+        .unwrap_or(archive_path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Return the sequence number after the greatest one of any archive in the
+/// data directory, or zero if none have one.
+pub fn next_archive_sequence(data_dir: &Path) -> anyhow::Result<u64> {
+    let mut next_sequence = 0;
+    for path in list_archive_files(data_dir)? {
+        if let Some(sequence) = read_archive_metadata(&path)?.sequence() {
+            next_sequence = next_sequence.max(sequence + 1);
+        }
+    }
+
+    Ok(next_sequence)
+}
+
+/// Return the number of bytes an archive file containing the given value
+/// would take up.
+pub fn archive_len(value: &Value) -> u64 {
+    let metadata = Metadata::new(0, minicbor::len(value) as u64, 0, 0);
+    metadata.encoded_len() as u64
+        + metadata.content_len().unwrap_or_default()
+        + KeySizes::of(value).encoded_len() as u64
+}
+
+/// Read the archive file at the given path, verify its checksum, and decode
+/// the CBOR value it contains.
+///
+/// The `max_depth` limits how deeply arrays and objects may be nested in the
+/// decoded value.
 pub fn read_archive_value(
     archive_path: &Path,
     scratch_buffer: &mut Vec<u8>,
+    max_depth: usize,
 ) -> anyhow::Result<Value> {
+    read_archive_value_inner(archive_path, scratch_buffer, max_depth, true).map(|(value, _)| value)
+}
+
+/// Like [`read_archive_value`], but without verifying the checksums of the
+/// body, which saves hashing every byte.
+///
+/// Only the metadata and the length of the body are checked, so a corrupted
+/// body may fail to decode or be decoded as the wrong value.
+pub fn read_unverified_archive_value(
+    archive_path: &Path,
+    scratch_buffer: &mut Vec<u8>,
+    max_depth: usize,
+) -> anyhow::Result<Value> {
+    read_archive_value_inner(archive_path, scratch_buffer, max_depth, false).map(|(value, _)| value)
+}
+
+/// Like [`read_archive_value`], but also return the BLAKE3 hash of the body,
+/// which is equal for archives holding identical values even if their
+/// metadata differs. The checksums of the body are only verified if
+/// `verify_checksums` is true.
+pub fn read_archive_value_and_hash(
+    archive_path: &Path,
+    scratch_buffer: &mut Vec<u8>,
+    max_depth: usize,
+    verify_checksums: bool,
+) -> anyhow::Result<(Value, blake3::Hash)> {
+    let (value, body) =
+        read_archive_value_inner(archive_path, scratch_buffer, max_depth, verify_checksums)?;
+
+    Ok((value, blake3::hash(&scratch_buffer[body])))
+}
+
+fn read_archive_value_inner(
+    archive_path: &Path,
+    scratch_buffer: &mut Vec<u8>,
+    max_depth: usize,
+    verify_checksums: bool,
+) -> anyhow::Result<(Value, Range<usize>)> {
+    let span = read_archive_span(archive_path);
+    let _entered = span.enter();
     let start_index = scratch_buffer.len();
 
     let archive_file = OpenOptions::new()
@@ -25,153 +268,625 @@ pub fn read_archive_value(
         .open(archive_path)
         .context("opening archive file for reading")?;
 
+    let file_len = archive_file.metadata().map_or(0, |metadata| metadata.len());
+    span.record("file_len", file_len);
     let mut reader = ArchiveReader::new(archive_file).context("starting to read archive")?;
+    reader.reserve_body(scratch_buffer, file_len);
 
     reader
         .read_to_end(scratch_buffer)
         .context("reading content of archive file")?;
 
-    let body = &scratch_buffer[start_index..];
-
-    reader.metadata.assert_checksum(body)?;
-    let mut cbor_reader = minicbor::Decoder::new(body);
-    let value = cbor_reader.decode().context("decoding CBOR value")?;
+    let content = &scratch_buffer[start_index..];
+    let body = if verify_checksums {
+        reader.metadata.verify_content(content)?
+    } else {
+        reader.metadata.body(content)?
+    };
+    let value = value::cbor::from_cbor_slice(body, max_depth)?;
+    span.record("body_len", body.len());
+    span.record("keys", value.key_count());
 
-    Ok(value)
+    Ok((value, start_index..start_index + body.len()))
 }
 
-/// Write a new archive file to the given data directory, with the content of
-/// the given CBOR value.
-#[tracing::instrument(skip_all)]
-pub fn write_archive_value(data_dir: &Path, value: Value) -> anyhow::Result<()> {
-    // 2024-06-19-19:22:45Z
-    let mut now = String::with_capacity(20);
-    DateTimePrinter::new()
-        .separator(b'-')
-        .print_timestamp(&Timestamp::now(), &mut now)
-        .context("formatting now for archive filename")?;
-    // 2024-06-19-19-22-45
-    now = now.replace(':', "-").replace('Z', "");
-    let archive_file_path = data_dir.join(format!("archived/{now}.bin"));
-
-    fs::create_dir_all(
-        archive_file_path
-            .parent()
-            .expect("path created with parent"),
+/// Return the span of reading and decoding a single archive, whose lengths
+/// and number of top-level keys are recorded once they are known. Closing
+/// the span logs the time spent on the archive.
+fn read_archive_span(archive_path: &Path) -> tracing::Span {
+    tracing::debug_span!(
+        "read_archive",
+        archive = %archive_name(archive_path),
+        file_len = tracing::field::Empty,
+        body_len = tracing::field::Empty,
+        keys = tracing::field::Empty,
     )
-    .context("creating 'archived' folder if not present")?;
+}
 
-    // Choosing to ignore AlreadyExists errors, it should be retried by the caller
-    // TODO: Could improve this by adding a `.{counter}` to the filename, but
-    // its a bit annoying
-    tracing::debug!(archive_file = %archive_file_path.display(), "Creating new archive file");
-    let archive_file = OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(&archive_file_path)
-        .context("creating new archive file")?;
+/// The number of archive files which are opened and read together with the
+/// `io-uring` feature
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+const URING_BATCH_LEN: usize = 64;
 
-    // Create the writer and it will handle writing and updating the metadata
-    let writer = ArchiveWriter::new(archive_file).context("creating archive file writer")?;
+/// Read the archive files in order, returning the value and the hash of the
+/// body of each one like [`read_archive_value_and_hash`].
+///
+/// With the `io-uring` feature on Linux the files are opened and read in
+/// batches through io_uring, instead of with several system calls for each
+/// file, which makes reading data directories with many archives faster. If
+/// io_uring is not available, like in some containers, the files are read one
+/// at a time.
+pub fn read_archive_values<'a>(
+    paths: &'a [PathBuf],
+    scratch_buffer: &'a mut Vec<u8>,
+    max_depth: usize,
+    verify_checksums: bool,
+) -> ArchiveValues<'a> {
+    ArchiveValues {
+        paths,
+        next: 0,
+        scratch_buffer,
+        max_depth,
+        verify_checksums,
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        uring: UringState::Unopened,
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        prefetched: std::collections::VecDeque::new(),
+    }
+}
 
-    // Add the CBOR value content
-    let mut cbor_writer = minicbor::encode::write::Writer::new(writer);
-    minicbor::encode(value, &mut cbor_writer).context("writing CBOR value")?;
+/// An iterator over the values of archive files, along with the path of each
+/// one, see [`read_archive_values`]
+#[derive(Debug)]
+pub struct ArchiveValues<'a> {
+    paths: &'a [PathBuf],
+    next: usize,
+    scratch_buffer: &'a mut Vec<u8>,
+    max_depth: usize,
+    verify_checksums: bool,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: UringState,
+    /// The contents of the files after `next` which were already read
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    prefetched: std::collections::VecDeque<std::io::Result<Vec<u8>>>,
+}
 
-    // Close out the metadata, write the checksum, flush the file
-    cbor_writer
-        .into_inner()
-        .finish()
-        .context("finishing file and writing metadata")?;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+#[derive(Debug)]
+enum UringState {
+    Unopened,
+    Open(Box<uring::UringReader>),
+    Unavailable,
+}
 
-    tracing::debug!(archive_file = %archive_file_path.display(), "Completed writing archive file");
+impl<'a> Iterator for ArchiveValues<'a> {
+    type Item = (&'a Path, anyhow::Result<(Value, blake3::Hash)>);
 
-    Ok(())
+    fn next(&mut self) -> Option<Self::Item> {
+        let path = self.paths.get(self.next)?;
+
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        if let Some(content) = self.prefetched_content() {
+            self.next += 1;
+            let span = read_archive_span(path);
+            let _entered = span.enter();
+            let result = content.context("reading archive file").and_then(|content| {
+                span.record("file_len", content.len());
+                decode_archive_value_and_hash(&content, self.max_depth, self.verify_checksums)
+            });
+            return Some((path, result));
+        }
+
+        self.next += 1;
+        self.scratch_buffer.clear();
+        let result = read_archive_value_and_hash(
+            path,
+            self.scratch_buffer,
+            self.max_depth,
+            self.verify_checksums,
+        );
+
+        Some((path, result))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.paths.len() - self.next;
+        (len, Some(len))
+    }
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+impl ArchiveValues<'_> {
+    /// Return the contents of the next file, reading the next batch of files
+    /// through io_uring if needed, or `None` if io_uring is not available.
+    fn prefetched_content(&mut self) -> Option<std::io::Result<Vec<u8>>> {
+        if self.prefetched.is_empty() {
+            if matches!(self.uring, UringState::Unopened) {
+                self.uring = match uring::UringReader::new(URING_BATCH_LEN) {
+                    Ok(reader) => UringState::Open(Box::new(reader)),
+                    Err(err) => {
+                        tracing::debug!(%err, "io_uring is not available, reading archives one at a time");
+                        UringState::Unavailable
+                    }
+                };
+            }
+            let UringState::Open(reader) = &mut self.uring else {
+                return None;
+            };
+
+            let end = (self.next + reader.batch_len()).min(self.paths.len());
+            match reader.read_files(&self.paths[self.next..end]) {
+                Ok(contents) => {
+                    tracing::debug!(archives = contents.len(), "Read archives through io_uring");
+                    self.prefetched.extend(contents);
+                }
+                Err(err) => {
+                    tracing::warn!(%err, "Reading archives through io_uring failed, reading them one at a time");
+                    self.uring = UringState::Unavailable;
+                    return None;
+                }
+            }
+        }
+
+        self.prefetched.pop_front()
+    }
+}
+
+/// Decode the value of a whole archive file which was already read, like
+/// [`read_archive_value_and_hash`].
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+fn decode_archive_value_and_hash(
+    mut content: &[u8],
+    max_depth: usize,
+    verify_checksums: bool,
+) -> anyhow::Result<(Value, blake3::Hash)> {
+    let metadata = Metadata::from_reader(&mut content).context("starting to read archive")?;
+    let body = if verify_checksums {
+        metadata.verify_content(content)?
+    } else {
+        metadata.body(content)?
+    };
+    let value = value::cbor::from_cbor_slice(body, max_depth)?;
+    tracing::Span::current().record("body_len", body.len());
+    tracing::Span::current().record("keys", value.key_count());
+
+    Ok((value, blake3::hash(body)))
 }
 
-const VERSION: [u8; 4] = u32::to_be_bytes(1);
-// WALL•A
-const MAGIC: [u8; 8] = *b"WALL\xE2\x80\xA2A";
+/// Read only the metadata of the archive file at the given path.
+pub fn read_archive_metadata(archive_path: &Path) -> anyhow::Result<Metadata> {
+    let archive_file = OpenOptions::new()
+        .read(true)
+        .open(archive_path)
+        .context("opening archive file for reading")?;
+
+    let reader = ArchiveReader::new(archive_file).context("starting to read archive")?;
 
-/// This struct contains metadata used to protect the archive file integrity.
-#[derive(Debug, FromZeroes, FromBytes, Unaligned, AsBytes, PartialEq, Eq, Hash)]
-#[repr(C)]
-struct Metadata {
-    magic: [u8; 8],
-    version: [u8; 4],
-    checksum: [u8; 4],
+    Ok(reader.metadata)
 }
 
-impl Metadata {
-    fn from_reader(mut reader: impl BufRead) -> anyhow::Result<Self> {
-        let mut buf = Metadata::default();
-        reader
-            .read_exact(buf.as_bytes_mut())
-            .context("trying to read metadata")?;
+/// Read only the metadata and the footer of the archive file at the given
+/// path and return the size of each top-level key recorded in the footer,
+/// without reading the body.
+///
+/// Returns `None` for archives before version 3, which have no footer.
+pub fn read_archive_key_sizes(archive_path: &Path) -> anyhow::Result<Option<KeySizes>> {
+    let mut archive_file = OpenOptions::new()
+        .read(true)
+        .open(archive_path)
+        .context("opening archive file for reading")?;
+    let file_len = archive_file
+        .metadata()
+        .context("reading archive file metadata")?
+        .len();
 
-        Ok(buf)
+    let metadata =
+        Metadata::from_reader(BufReader::new(&mut archive_file)).context("reading metadata")?;
+    let (Some(content_len), true) = (metadata.content_len(), metadata.has_footer()) else {
+        return Ok(None);
+    };
+    let max_footer_len = file_len.saturating_sub(metadata.encoded_len() as u64 + content_len);
+
+    let mut trailer = [0; FOOTER_TRAILER_LEN];
+    archive_file
+        .seek(SeekFrom::End(-(FOOTER_TRAILER_LEN as i64)))
+        .and_then(|_| archive_file.read_exact(&mut trailer))
+        .context("reading end of footer")?;
+    let footer_len = footer_len(&trailer)?;
+    if footer_len > max_footer_len {
+        anyhow::bail!(
+            "Length from the footer [{footer_len}] is longer than the [{max_footer_len}] bytes \
+             after the block checksums"
+        );
     }
 
-    fn for_checksum(checksum: u32) -> Self {
-        Self {
-            magic: MAGIC,
-            version: VERSION,
-            checksum: checksum.to_be_bytes(),
+    let mut footer = vec![0; footer_len as usize];
+    archive_file
+        .seek(SeekFrom::End(-(footer_len as i64)))
+        .and_then(|_| archive_file.read_exact(&mut footer))
+        .context("reading footer")?;
+
+    KeySizes::decode(&footer).map(Some)
+}
+
+/// Read only the metadata of the archive file at the given path and return
+/// the checksum it records for the archive body.
+pub fn read_archive_checksum(archive_path: &Path) -> anyhow::Result<u32> {
+    Ok(read_archive_metadata(archive_path)?.checksum())
+}
+
+/// Read the archive file at the given path and verify that its body matches
+/// the checksum in its metadata, without decoding the body.
+///
+/// Returns the checksum and the length of the whole file.
+pub fn verify_archive_checksum(
+    archive_path: &Path,
+    scratch_buffer: &mut Vec<u8>,
+) -> anyhow::Result<ChecksumEntry> {
+    let start_index = scratch_buffer.len();
+
+    let archive_file = OpenOptions::new()
+        .read(true)
+        .open(archive_path)
+        .context("opening archive file for reading")?;
+
+    let file_len = archive_file.metadata().map_or(0, |metadata| metadata.len());
+    let mut reader = ArchiveReader::new(archive_file).context("starting to read archive")?;
+    reader.reserve_body(scratch_buffer, file_len);
+
+    reader
+        .read_to_end(scratch_buffer)
+        .context("reading content of archive file")?;
+
+    let content = &scratch_buffer[start_index..];
+
+    reader.metadata.verify_content(content)?;
+
+    Ok(ChecksumEntry {
+        checksum: reader.metadata.checksum(),
+        len: (reader.metadata.encoded_len() + content.len()) as u64,
+    })
+}
+
+/// Write a new archive file to the given data directory, with the content of
+/// the given CBOR value and the next sequence number from the manifest, and
+/// return its path.
+///
+/// When using [`ArchiveNaming::Content`], no new file is written if an archive
+/// with an identical body already exists, and its path is returned instead.
+///
+/// With `direct_io` the archive is encoded in memory and then written with
+/// direct IO on Linux, bypassing the page cache, see [`direct`].
+#[tracing::instrument(skip_all)]
+pub fn write_archive_value(
+    data_dir: &Path,
+    value: Value,
+    naming: ArchiveNaming,
+    direct_io: bool,
+) -> anyhow::Result<PathBuf> {
+    let time_zone = Manifest::read(data_dir)?.time_zone()?;
+    let now = archive_timestamp(time_zone.as_ref())?;
+    let created = Timestamp::now().as_second();
+    let sequence = Manifest::reserve_sequence(data_dir).context("reserving sequence number")?;
+    let key_sizes = KeySizes::of(&value);
+
+    fs::create_dir_all(archive_dir(data_dir))
+        .context("creating 'archived' folder if not present")?;
+
+    match naming {
+        ArchiveNaming::Timestamp => {
+            let archive_file_path = archive_dir(data_dir).join(format!("{now}.bin"));
+
+            write_archive_file(
+                data_dir,
+                &archive_file_path,
+                sequence,
+                created,
+                direct_io,
+                key_sizes,
+                |cbor_writer| minicbor::encode(value, cbor_writer).context("writing CBOR value"),
+            )?;
+
+            Ok(archive_file_path)
+        }
+        ArchiveNaming::Sequence => {
+            let archive_file_path = archive_dir(data_dir).join(format!("{sequence:06}.bin"));
+
+            write_archive_file(
+                data_dir,
+                &archive_file_path,
+                sequence,
+                created,
+                direct_io,
+                key_sizes,
+                |cbor_writer| minicbor::encode(value, cbor_writer).context("writing CBOR value"),
+            )?;
+
+            Ok(archive_file_path)
+        }
+        ArchiveNaming::Content => {
+            let body = minicbor::to_vec(value).context("encoding CBOR value")?;
+            let hash = blake3::hash(&body);
+            let archive_file_path = archive_dir(data_dir).join(format!("{}.bin", hash.to_hex()));
+            let name = archive_name(&archive_file_path);
+
+            let mut index = ArchiveIndex::read(data_dir)?;
+
+            if archive_file_path.exists() {
+                tracing::info!(
+                    archive_file = %archive_file_path.display(),
+                    "An archive with identical content already exists, skipping"
+                );
+            } else {
+                write_archive_file(
+                    data_dir,
+                    &archive_file_path,
+                    sequence,
+                    created,
+                    direct_io,
+                    key_sizes,
+                    |cbor_writer| {
+                        minicbor::encode::Write::write_all(cbor_writer, &body)
+                            .context("writing CBOR value")
+                    },
+                )?;
+            }
+
+            // The archive might exist without an index entry if a previous run
+            // stopped before updating the index
+            if index.timestamp(&name).is_none() {
+                index.insert(name, now);
+                index.write(data_dir)?;
+            }
+
+            Ok(archive_file_path)
         }
     }
+}
+
+/// Format the current time for use in an archive filename or the archive
+/// index, in the given time zone or in unlabeled UTC.
+pub fn archive_timestamp(time_zone: Option<&TimeZone>) -> anyhow::Result<String> {
+    format_archive_timestamp(Timestamp::now(), time_zone)
+        .context("formatting now for archive filename")
+}
+
+/// Format the timestamp with microseconds, like `2024-06-19-19-22-45.123456`
+/// in UTC, or like `2024-06-19-21-22-45.123456+0200` labeled with the offset
+/// of the given time zone.
+///
+/// The fraction always has 6 digits so that archives created within the same
+/// second get different names, which still sort in the order they were
+/// created.
+fn format_archive_timestamp(
+    timestamp: Timestamp,
+    time_zone: Option<&TimeZone>,
+) -> Result<String, std::fmt::Error> {
+    use std::fmt::Write as _;
 
-    /// Create a new metadata based on the content of the given archive body.
-    #[cfg(test)]
-    fn for_body(body: &[u8]) -> Self {
-        Self::for_checksum(crc32fast::hash(body))
+    let mut formatted = String::with_capacity(31);
+    match time_zone {
+        Some(time_zone) => {
+            let zoned = timestamp.to_zoned(time_zone.clone());
+            write!(
+                formatted,
+                "{}.{:06}{}",
+                zoned.strftime("%Y-%m-%d-%H-%M-%S"),
+                timestamp.subsec_microsecond(),
+                zoned.strftime("%z")
+            )?;
+        }
+        None => write!(
+            formatted,
+            "{}.{:06}",
+            timestamp.strftime("%Y-%m-%d-%H-%M-%S"),
+            timestamp.subsec_microsecond()
+        )?,
     }
 
-    /// Returns `Ok(())` if the given archive body matches the checksum in this metadata.
-    ///
-    /// Otherwise it returns an error with a custom message about the checksum mismatch.
-    fn assert_checksum(&self, body: &[u8]) -> anyhow::Result<()> {
-        let checksum = crc32fast::hash(body).to_be_bytes();
-
-        if self.checksum != checksum {
-            Err(anyhow::anyhow!(
-                "Checksum for given body [{:08x}] did not match checksum from the file metadata [{:08x}]",
-                u32::from_be_bytes(checksum),
-                u32::from_be_bytes(self.checksum),
+    Ok(formatted)
+}
+
+/// Parse a timestamp formatted by [`archive_timestamp`], returning `None` if
+/// it is not in that format.
+fn parse_archive_timestamp(timestamp: &str) -> Option<Timestamp> {
+    // 2024-06-19-21-22-45.123456+0200
+    let (timestamp, offset) = match timestamp
+        .len()
+        .checked_sub(5)
+        .map(|i| timestamp.split_at(i))
+    {
+        Some((rest, offset))
+            if offset.starts_with(['+', '-'])
+                && offset[1..].bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            (rest, format!("{}:{}", &offset[..3], &offset[3..]))
+        }
+        _ => (timestamp, "Z".to_string()),
+    };
+
+    let date = timestamp.get(..10)?;
+    let time = timestamp.get(10..)?.strip_prefix('-')?.replace('-', ":");
+
+    format!("{date}T{time}{offset}").parse().ok()
+}
+
+/// Return the archive timestamp which is the given number of microseconds
+/// after the given one, with the same offset from UTC, or `None` if it cannot
+/// be parsed.
+fn later_archive_timestamp(timestamp: &str, microseconds: i64) -> Option<String> {
+    let later = parse_archive_timestamp(timestamp)?
+        .checked_add(jiff::Span::new().microseconds(microseconds))
+        .ok()?;
+    let time_zone = match timestamp
+        .len()
+        .checked_sub(5)
+        .and_then(|i| timestamp.get(i..))
+    {
+        Some(offset) if offset.starts_with(['+', '-']) => {
+            let hours: i32 = offset.get(1..3)?.parse().ok()?;
+            let minutes: i32 = offset.get(3..)?.parse().ok()?;
+            let seconds = (hours * 60 + minutes) * 60;
+            let seconds = if offset.starts_with('-') {
+                -seconds
+            } else {
+                seconds
+            };
+            Some(TimeZone::fixed(
+                jiff::tz::Offset::from_seconds(seconds).ok()?,
             ))
-        } else {
-            Ok(())
         }
-    }
+        _ => None,
+    };
+
+    format_archive_timestamp(later, time_zone.as_ref()).ok()
+}
 
-    /// Return true if the given archive body matches the checksum in this metadata.
-    #[cfg(test)]
-    fn matches_body(&self, body: &[u8]) -> bool {
-        let checksum = crc32fast::hash(body).to_be_bytes();
-        self.checksum == checksum
+/// Format the timestamp for a report, like `2024-06-19T19:22:45Z` in UTC or
+/// `2024-06-19T21:22:45+02:00[Europe/Paris]` in the given time zone.
+pub fn display_timestamp(timestamp: Timestamp, time_zone: Option<&TimeZone>) -> String {
+    match time_zone {
+        Some(time_zone) => timestamp.to_zoned(time_zone.clone()).to_string(),
+        None => timestamp.to_string(),
     }
 }
 
-impl Default for Metadata {
-    fn default() -> Self {
-        Self {
-            magic: MAGIC,
-            version: VERSION,
-            checksum: [0; 4],
+/// Return the time that the file at the given path was last modified.
+pub fn modified_timestamp(path: &Path) -> anyhow::Result<Timestamp> {
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .with_context(|| format!("reading modification time of '{}'", path.display()))?;
+
+    Timestamp::try_from(modified).context("converting modification time to a timestamp")
+}
+
+/// Return the path an archive is written to before it is complete, like
+/// `archived/<name>.bin.tmp`.
+pub fn temp_archive_path(archive_path: &Path) -> PathBuf {
+    let mut path = archive_path.as_os_str().to_owned();
+    path.push(".tmp");
+    PathBuf::from(path)
+}
+
+/// Move a complete archive from its temporary path to its final path, failing
+/// if an archive already exists there.
+pub fn finalize_archive(temp_path: &Path, archive_path: &Path) -> anyhow::Result<()> {
+    // Linking fails if the destination exists, unlike renaming
+    fs::hard_link(temp_path, archive_path).context("moving archive file to its final path")?;
+    fs::remove_file(temp_path).context("removing temporary archive file")
+}
+
+/// Create a new archive file at the given path, with a body written by the
+/// `write_body` closure, and record it in the `CHECKSUMS` manifest.
+///
+/// The archive is written to a temporary file first, see
+/// [`temp_archive_path`], so an archive at its final path is always complete,
+/// and recorded before it is moved there, so it is always listed.
+fn write_archive_file(
+    data_dir: &Path,
+    archive_file_path: &Path,
+    sequence: u64,
+    created: i64,
+    direct_io: bool,
+    key_sizes: KeySizes,
+    write_body: impl FnOnce(
+        &mut minicbor::encode::write::Writer<ArchiveWriter<ArchiveSink>>,
+    ) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    // Choosing to ignore AlreadyExists errors, it should be retried by the caller
+    // TODO: Could improve this by adding a `.{counter}` to the filename, but
+    // its a bit annoying
+    tracing::debug!(archive_file = %archive_file_path.display(), "Creating new archive file");
+    if archive_file_path.exists() {
+        anyhow::bail!(
+            "archive file '{}' already exists",
+            archive_file_path.display()
+        );
+    }
+
+    let temp_file_path = temp_archive_path(archive_file_path);
+    let entry = write_new_archive_file(
+        &temp_file_path,
+        sequence,
+        created,
+        direct_io,
+        key_sizes,
+        write_body,
+    )?;
+    record_archive(data_dir, archive_file_path, entry)
+        .context("recording archive file in CHECKSUMS")?;
+    if let Err(err) = finalize_archive(&temp_file_path, archive_file_path) {
+        if let Err(forget_err) = forget_archive(data_dir, archive_file_path) {
+            tracing::warn!(
+                archive_file = %archive_file_path.display(),
+                "Failed to remove the archive from CHECKSUMS: {forget_err:#}"
+            );
         }
+        return Err(err);
     }
+
+    tracing::debug!(archive_file = %archive_file_path.display(), "Completed writing archive file");
+
+    Ok(())
+}
+
+/// Create a new archive file at the given path, which must not exist yet,
+/// with a body written by the `write_body` closure.
+///
+/// Returns the checksum and the total length of the archive.
+fn write_new_archive_file(
+    temp_file_path: &Path,
+    sequence: u64,
+    created: i64,
+    direct_io: bool,
+    key_sizes: KeySizes,
+    write_body: impl FnOnce(
+        &mut minicbor::encode::write::Writer<ArchiveWriter<ArchiveSink>>,
+    ) -> anyhow::Result<()>,
+) -> anyhow::Result<ChecksumEntry> {
+    let sink = if direct_io {
+        ArchiveSink::Direct {
+            path: temp_file_path.to_path_buf(),
+            contents: Cursor::new(Vec::new()),
+        }
+    } else {
+        let archive_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(temp_file_path)
+            .context("creating new archive file")?;
+        ArchiveSink::File(archive_file)
+    };
+
+    // Create the writer and it will handle writing and updating the metadata
+    let writer =
+        ArchiveWriter::new(sink, sequence, created).context("creating archive file writer")?;
+
+    // Add the CBOR value content
+    let mut cbor_writer = minicbor::encode::write::Writer::new(writer);
+    write_body(&mut cbor_writer)?;
+
+    // Close out the metadata, write the checksum, flush the file
+    cbor_writer
+        .into_inner()
+        .finish(&key_sizes)
+        .context("finishing file and writing metadata")
 }
 
 #[derive(Debug)]
 struct ArchiveWriter<W: Write> {
     start_position: u64,
-    hasher: Hasher,
+    sequence: u64,
+    created: i64,
+    hasher: BodyHasher,
     inner: BufWriter<W>,
 }
 
 impl<W: Write> Write for ArchiveWriter<W> {
     fn write(&mut self, buf: &[u8]) -> Result<usize, std::io::Error> {
-        self.hasher.update(buf);
-        self.inner.write(buf)
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
     }
 
     fn flush(&mut self) -> Result<(), std::io::Error> {
@@ -179,32 +894,98 @@ impl<W: Write> Write for ArchiveWriter<W> {
     }
 }
 
-impl<W: Write + Seek> ArchiveWriter<W> {
+/// Where an [`ArchiveWriter`] writes the archive
+#[derive(Debug)]
+enum ArchiveSink {
+    /// Write to the file through the page cache as the archive is encoded
+    File(fs::File),
+    /// Encode the whole archive in memory, then create the file at the path
+    /// with direct IO
+    Direct {
+        path: PathBuf,
+        contents: Cursor<Vec<u8>>,
+    },
+}
+
+impl Write for ArchiveSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::File(file) => file.write(buf),
+            Self::Direct { contents, .. } => contents.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::File(file) => file.flush(),
+            Self::Direct { contents, .. } => contents.flush(),
+        }
+    }
+}
+
+impl Seek for ArchiveSink {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match self {
+            Self::File(file) => file.seek(pos),
+            Self::Direct { contents, .. } => contents.seek(pos),
+        }
+    }
+}
+
+impl ArchiveSink {
+    /// Make sure the whole archive is durably written to its file.
+    fn sync(self) -> std::io::Result<()> {
+        match self {
+            Self::File(file) => file.sync_all(),
+            Self::Direct { path, contents } => direct::write_new_file(&path, contents.get_ref()),
+        }
+    }
+}
+
+impl ArchiveWriter<ArchiveSink> {
     /// Write a new value archive to the given writer, starting by writing an
     /// empty version of the file metadata.
-    fn new(mut writer: W) -> Result<Self, std::io::Error> {
+    fn new(mut writer: ArchiveSink, sequence: u64, created: i64) -> Result<Self, std::io::Error> {
         let start_position = writer.stream_position()?;
         let mut inner = BufWriter::new(writer);
         // Write a dummy metadata to the start of the file, we'll overwrite this
         // in the `finish` method.
-        inner.write_all(Metadata::default().as_bytes())?;
+        inner.write_all(Metadata::default().to_bytes())?;
         Ok(Self {
             inner,
-            hasher: Hasher::new(),
+            sequence,
+            created,
+            hasher: BodyHasher::default(),
             start_position,
         })
     }
 
-    /// Finish this archive file by finalizing the CRC32 checksum, writing the
-    /// full metadata again, and flushing the buffers to the file.
-    fn finish(mut self) -> Result<(), std::io::Error> {
+    /// Finish this archive file by finalizing the CRC32 checksums, writing
+    /// the block checksums and the footer with the given key sizes after the
+    /// body and the full metadata again, and flushing the buffers to the
+    /// file.
+    ///
+    /// Returns the checksum and the total length of the archive.
+    fn finish(mut self, key_sizes: &KeySizes) -> Result<ChecksumEntry, std::io::Error> {
+        let (metadata, block_checksums) = self.hasher.finish(self.sequence, self.created);
+        self.inner.write_all(&block_checksums)?;
+        let footer = key_sizes.encode();
+        self.inner.write_all(&footer)?;
+
         // Rewind to the position where we recorded the metadata the first time
         self.inner.seek(SeekFrom::Start(self.start_position))?;
+        self.inner.write_all(metadata.to_bytes())?;
+        self.inner
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync()?;
 
-        let metadata = Metadata::for_checksum(self.hasher.finalize());
-        self.inner.write_all(metadata.as_bytes())?;
-
-        Ok(())
+        Ok(ChecksumEntry {
+            checksum: metadata.checksum(),
+            len: metadata.encoded_len() as u64
+                + metadata.content_len().unwrap_or_default()
+                + footer.len() as u64,
+        })
     }
 }
 
@@ -231,81 +1012,140 @@ impl<R: Read> BufRead for ArchiveReader<R> {
 }
 
 impl<R: Read> ArchiveReader<R> {
+    /// Start reading an archive by reading its metadata, which fails if the
+    /// metadata itself is corrupted.
     fn new(reader: R) -> anyhow::Result<Self> {
         let mut inner = BufReader::new(reader);
         let metadata = Metadata::from_reader(&mut inner)?;
 
         Ok(Self { metadata, inner })
     }
+
+    /// Reserve space in the buffer for the body and block checksums of the
+    /// archive, using the length in the metadata.
+    ///
+    /// The length is only checked after the body is read, so no more than the
+    /// length of the whole file is reserved.
+    fn reserve_body(&self, buffer: &mut Vec<u8>, file_len: u64) {
+        if let Some(content_len) = self.metadata.content_len() {
+            buffer.reserve(usize::try_from(content_len.min(file_len)).unwrap_or(0));
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use jiff::Span;
+
     use super::*;
 
     #[test]
-    fn create_metadata() {
-        let md = Metadata::for_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh");
-        assert_eq!(md.checksum, [191, 106, 231, 136]);
-        assert_eq!(md.magic, MAGIC);
-        assert_eq!(md.version, VERSION);
+    fn archive_paths() {
+        assert!(is_archive_path(Path::new(
+            "data/archived/2024-06-19-19-22-45.123456.bin"
+        )));
+        assert!(is_archive_path(Path::new("000042.bin")));
+
+        assert!(!is_archive_path(Path::new("000042.bin.tmp")));
+        assert!(!is_archive_path(Path::new("000042.bin.partial")));
+        assert!(!is_archive_path(Path::new(".000042.bin.Xy12ab")));
+        assert!(!is_archive_path(Path::new(".000042.bin")));
+        assert!(!is_archive_path(Path::new("000042")));
+    }
+
+    #[test]
+    fn archive_timestamps_round_trip() {
+        let timestamp = archive_timestamp(None).unwrap();
+        let parsed = parse_archive_timestamp(&timestamp).unwrap();
+        assert!(Timestamp::now().as_second() - parsed.as_second() <= 1);
 
         assert_eq!(
-            Metadata::for_body(b"hello sun goodbye moon").checksum,
-            [204, 119, 81, 28]
+            parse_archive_timestamp("2024-06-19-19-22-45"),
+            Some("2024-06-19T19:22:45Z".parse().unwrap())
+        );
+        assert_eq!(
+            parse_archive_timestamp("2024-06-19-19-22-45.5"),
+            Some("2024-06-19T19:22:45.5Z".parse().unwrap())
         );
+        assert_eq!(parse_archive_timestamp("0a1b2c"), None);
+        assert_eq!(parse_archive_timestamp("2024-06-19T19:22:45"), None);
+        assert_eq!(parse_archive_timestamp("000042"), None);
         assert_eq!(
-            Metadata::for_body(b"hello moon goodbye sun").checksum,
-            [4, 104, 210, 191]
+            parse_archive_timestamp("2024-06-19-21-22-45.000000+0200"),
+            Some("2024-06-19T19:22:45Z".parse().unwrap())
         );
         assert_eq!(
-            Metadata::for_body(b"hello mo0n goodbye sun").checksum,
-            [117, 247, 173, 212]
+            parse_archive_timestamp("2024-06-19-15-22-45.500000-0400"),
+            Some("2024-06-19T19:22:45.5Z".parse().unwrap())
         );
-        assert_eq!(Metadata::for_body(b"").checksum, [0, 0, 0, 0]);
     }
 
     #[test]
-    fn metadata_body_matches() {
-        let md = Metadata::for_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh");
-        assert!(md.matches_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh"));
+    fn archive_timestamps_in_time_zone() {
+        let timestamp: Timestamp = "2024-06-19T19:22:45Z".parse().unwrap();
+        let time_zone = TimeZone::fixed(jiff::tz::offset(2));
+
+        let name = format_archive_timestamp(timestamp, Some(&time_zone)).unwrap();
+        assert_eq!(name, "2024-06-19-21-22-45.000000+0200");
+        assert_eq!(parse_archive_timestamp(&name), Some(timestamp));
+
+        assert_eq!(display_timestamp(timestamp, None), "2024-06-19T19:22:45Z");
+        assert!(
+            display_timestamp(timestamp, Some(&time_zone)).starts_with("2024-06-19T21:22:45+02:00")
+        );
+    }
+
+    #[test]
+    fn archive_timestamps_sort_in_order() {
+        let timestamp: Timestamp = "2024-06-19T19:22:45Z".parse().unwrap();
+        assert_eq!(
+            format_archive_timestamp(timestamp, None).unwrap(),
+            "2024-06-19-19-22-45.000000"
+        );
+
+        let names = [0, 1, 500, 999_999, 1_000_000]
+            .map(|micros| timestamp + Span::new().microseconds(micros))
+            .map(|timestamp| format_archive_timestamp(timestamp, None).unwrap());
+        assert!(names.windows(2).all(|pair| pair[0] < pair[1]), "{names:?}");
+        assert_eq!(names[2], "2024-06-19-19-22-45.000500");
 
-        assert!(!md.matches_body(b"klasjdhfaklsdh asdk1fjhasldk aldkfjhaskdfjh"));
-        assert!(!md.matches_body(b""));
-        assert!(!md.matches_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh1"));
+        for name in names {
+            let parsed = parse_archive_timestamp(&name).unwrap();
+            assert_eq!(format_archive_timestamp(parsed, None).unwrap(), name);
+        }
     }
 
     #[test]
-    fn metadata_as_bytes() {
-        let md = Metadata::for_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh");
-
-        let md_bytes = md.as_bytes();
-        assert_eq!(md_bytes.len(), 16);
-        assert_eq!(&md_bytes[..8], b"WALL\xE2\x80\xA2A");
-        assert_eq!(&md_bytes[8..12], &[0, 0, 0, 1]);
-        assert_eq!(&md_bytes[12..16], &[191, 106, 231, 136]);
-
-        let md = Metadata::for_body(b"");
-
-        let md_bytes = md.as_bytes();
-        assert_eq!(md_bytes.len(), 16);
-        assert_eq!(&md_bytes[..8], b"WALL\xE2\x80\xA2A");
-        assert_eq!(&md_bytes[8..12], &[0, 0, 0, 1]);
-        assert_eq!(&md_bytes[12..16], &[0, 0, 0, 0]);
+    fn later_archive_timestamps() {
+        assert_eq!(
+            later_archive_timestamp("2024-06-19-19-22-45.999999", 1).as_deref(),
+            Some("2024-06-19-19-22-46.000000")
+        );
+        assert_eq!(
+            later_archive_timestamp("2024-06-19-21-22-45.000000+0200", 12).as_deref(),
+            Some("2024-06-19-21-22-45.000012+0200")
+        );
+        assert_eq!(
+            later_archive_timestamp("2024-06-19-15-22-45.500000-0430", 0).as_deref(),
+            Some("2024-06-19-15-22-45.500000-0430")
+        );
+        assert_eq!(later_archive_timestamp("000042", 1), None);
     }
 
     #[test]
-    fn metadata_from_bytes() {
-        let md = Metadata::read_from(b"WALL\xE2\x80\xA2A\x00\x00\x00\x01\x00\x00\x00\x00").unwrap();
-        assert_eq!(md.magic, MAGIC);
-        assert_eq!(md.version, VERSION);
-        assert_eq!(md.checksum, [0, 0, 0, 0]);
-        assert!(md.matches_body(b""));
-
-        let md = Metadata::read_from(b"WALL\xE2\x80\xA2A\x00\x00\x00\x01\xBF\x6A\xE7\x88").unwrap();
-        assert_eq!(md.magic, MAGIC);
-        assert_eq!(md.version, VERSION);
-        assert_eq!(md.checksum, [191, 106, 231, 136]);
-        assert!(md.matches_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh"));
+    fn archive_naming_from_str() {
+        assert_eq!(
+            "sequence".parse::<ArchiveNaming>().unwrap(),
+            ArchiveNaming::Sequence
+        );
+        assert_eq!(
+            "content".parse::<ArchiveNaming>().unwrap(),
+            ArchiveNaming::Content
+        );
+        let err = "random".parse::<ArchiveNaming>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'random' is an unknown option for naming archive files"
+        );
     }
 }
@@ -0,0 +1,538 @@
+//! This module contains the replacement of the oldest archives with archives
+//! of their merged value, and the journal which makes it safe against
+//! crashes.
+//!
+//! Before the compacted archives are moved into place, the `COMPACTION`
+//! journal records the filename, checksum, and size of each one, in the order
+//! they are read, along with the filenames of the archives they replace:
+//!
+//! ```text
+//! compacted  <crc32 hex>  <size>  <filename>
+//! replaced  <filename>
+//! ```
+//!
+//! Once archives with those filenames and checksums are all in place the
+//! compaction has happened, so the replaced archives are no longer listed,
+//! even before they are deleted and removed from the `CHECKSUMS`, `SOURCES`,
+//! and `ARCHIVE_INDEX` files. Until then the compacted archives which are
+//! already in place are not listed instead. A journal whose archives are not
+//! all in place yet belongs to a compaction which is still writing them or
+//! which crashed, see `store::recovery`.
+
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use super::{
+    archive_dir, archive_name, finalize_archive, index::ArchiveIndex, later_archive_timestamp,
+    read_archive_checksum, read_archive_metadata, write_new_archive_file,
+};
+use crate::{
+    atomic_file::write_atomically,
+    checksums::{record_compacted_archives, ChecksumEntry},
+    format::KeySizes,
+    sources::ArchiveSources,
+    value::Value,
+};
+
+/// Return the path of the compaction journal in the data directory.
+pub fn compaction_journal_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("COMPACTION")
+}
+
+/// Return the path the compacted archive with the given index is written to
+/// before it is moved into place. It is outside of the archive directory so
+/// that it is never finalized like a leftover temporary archive.
+fn compacted_temp_path(data_dir: &Path, index: usize) -> PathBuf {
+    match index {
+        0 => data_dir.join("COMPACTED_ARCHIVE.tmp"),
+        index => data_dir.join(format!("COMPACTED_ARCHIVE.{index}.tmp")),
+    }
+}
+
+/// The compaction recorded in the `COMPACTION` journal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompactionJournal {
+    /// The filename of each compacted archive with its checksum and size, in
+    /// the order they are read
+    pub compacted: Vec<(String, ChecksumEntry)>,
+    /// The filenames of the archives they replace
+    pub replaced: Vec<String>,
+}
+
+impl CompactionJournal {
+    /// Read the journal from the data directory, returning `Ok(None)` if no
+    /// compaction is in progress.
+    pub fn read(data_dir: &Path) -> anyhow::Result<Option<Self>> {
+        let contents = match fs::read_to_string(compaction_journal_path(data_dir)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("reading COMPACTION file"),
+        };
+
+        Self::parse(&contents).map(Some)
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut compacted = Vec::new();
+        let mut replaced = Vec::new();
+
+        for (line_index, line) in contents.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let mut parse_line = || -> anyhow::Result<()> {
+                match line.split_once("  ") {
+                    Some(("compacted", rest)) => {
+                        let mut parts = rest.splitn(3, "  ");
+                        let (Some(checksum), Some(len), Some(name)) =
+                            (parts.next(), parts.next(), parts.next())
+                        else {
+                            anyhow::bail!("expected 'compacted  <checksum>  <size>  <filename>'");
+                        };
+
+                        let checksum =
+                            u32::from_str_radix(checksum, 16).context("parsing checksum")?;
+                        let len = len.parse().context("parsing size")?;
+                        compacted.push((name.to_string(), ChecksumEntry { checksum, len }));
+                    }
+                    Some(("replaced", name)) => replaced.push(name.to_string()),
+                    _ => anyhow::bail!("expected 'compacted' or 'replaced' followed by two spaces"),
+                }
+
+                Ok(())
+            };
+
+            parse_line()
+                .with_context(|| format!("parsing line {} of COMPACTION file", line_index + 1))?;
+        }
+
+        if compacted.is_empty() {
+            anyhow::bail!("the COMPACTION file does not name any compacted archive");
+        }
+
+        Ok(Self {
+            compacted,
+            replaced,
+        })
+    }
+
+    /// Atomically write the journal to the data directory.
+    fn write(&self, data_dir: &Path) -> anyhow::Result<()> {
+        write_atomically(
+            &compaction_journal_path(data_dir),
+            self.to_string().as_bytes(),
+        )
+        .context("writing COMPACTION file")
+    }
+
+    /// Return true if every compacted archive is in place, which means the
+    /// replaced archives are no longer part of the data directory.
+    pub fn is_committed(&self, data_dir: &Path) -> bool {
+        self.compacted.iter().all(|(name, entry)| {
+            read_archive_checksum(&archive_dir(data_dir).join(name))
+                .is_ok_and(|checksum| checksum == entry.checksum)
+        })
+    }
+
+    /// Record the compacted archives, then delete the replaced archives along
+    /// with their entries and the journal. Each step can be repeated, so that
+    /// a compaction which crashed after its archives were in place can be
+    /// finished by the next command.
+    pub fn finish(&self, data_dir: &Path) -> anyhow::Result<()> {
+        record_compacted_archives(data_dir, &self.compacted, &self.replaced)
+            .context("recording compacted archives in CHECKSUMS")?;
+
+        let mut sources = ArchiveSources::read(data_dir)?;
+        let mut index = ArchiveIndex::read(data_dir)?;
+        let (mut sources_changed, mut index_changed) = (false, false);
+        for name in &self.replaced {
+            sources_changed |= sources.remove(name);
+            index_changed |= index.remove(name);
+        }
+        if sources_changed {
+            sources.write(data_dir)?;
+        }
+        if index_changed {
+            index.write(data_dir)?;
+        }
+
+        for name in &self.replaced {
+            remove_file_if_exists(&archive_dir(data_dir).join(name))
+                .context("deleting compacted archive")?;
+        }
+
+        self.remove(data_dir)
+    }
+
+    /// Delete the compacted archives which are already in place, then remove
+    /// the journal along with the temporary files of the compacted archives,
+    /// if any are left. This must only be called while the compaction is not
+    /// committed, see [`Self::is_committed`].
+    pub fn abandon(&self, data_dir: &Path) -> anyhow::Result<()> {
+        for (name, entry) in &self.compacted {
+            let path = archive_dir(data_dir).join(name);
+            if read_archive_checksum(&path).is_ok_and(|checksum| checksum == entry.checksum) {
+                remove_file_if_exists(&path).context("deleting incomplete compacted archive")?;
+            }
+        }
+
+        self.remove(data_dir)
+    }
+
+    fn remove(&self, data_dir: &Path) -> anyhow::Result<()> {
+        for index in 0..self.compacted.len() {
+            remove_file_if_exists(&compacted_temp_path(data_dir, index))?;
+        }
+        remove_file_if_exists(&compaction_journal_path(data_dir))
+    }
+
+    /// Return the filenames of the compacted archives, in the order they are
+    /// read.
+    pub fn compacted_names(&self) -> impl Iterator<Item = &str> {
+        self.compacted.iter().map(|(name, _)| name.as_str())
+    }
+}
+
+impl fmt::Display for CompactionJournal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, entry) in &self.compacted {
+            writeln!(
+                f,
+                "compacted  {:08x}  {}  {name}",
+                entry.checksum, entry.len
+            )?;
+        }
+        for name in &self.replaced {
+            writeln!(f, "replaced  {name}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Return the filenames of the archives which are not listed because of a
+/// compaction in progress: the archives it replaces once all of its compacted
+/// archives are in place, since they may not be deleted yet, or else the
+/// compacted archives which are already in place.
+pub fn hidden_archives(data_dir: &Path) -> anyhow::Result<HashSet<String>> {
+    Ok(match CompactionJournal::read(data_dir)? {
+        Some(journal) if journal.is_committed(data_dir) => journal.replaced.into_iter().collect(),
+        Some(journal) => journal.compacted_names().map(str::to_string).collect(),
+        None => HashSet::new(),
+    })
+}
+
+/// Replace the given archives, which are the oldest ones in the data
+/// directory in order, with a single archive holding their merged `value`,
+/// then return its path, see [`write_compacted_archive_values`].
+pub fn write_compacted_archive_value(
+    data_dir: &Path,
+    archives: &[PathBuf],
+    value: Value,
+    direct_io: bool,
+) -> anyhow::Result<PathBuf> {
+    let mut paths = write_compacted_archive_values(data_dir, archives, vec![value], direct_io)?;
+    Ok(paths.remove(0))
+}
+
+/// Replace the given archives, which are the oldest ones in the data
+/// directory in order, with an archive for each of the `values`, which
+/// together hold their merged value, then return their paths.
+///
+/// The new archives take the place of the last of the given archives in the
+/// order of archives, since they have the same sequence number and creation
+/// time, and are read in the order of the `values`. They are named after it,
+/// see [`compacted_archive_name`], or by the hash of their body if the
+/// archives are named by content, so an archive being read is never replaced
+/// with different contents.
+pub fn write_compacted_archive_values(
+    data_dir: &Path,
+    archives: &[PathBuf],
+    values: Vec<Value>,
+    direct_io: bool,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let Some(last_path) = archives.last() else {
+        anyhow::bail!("there are no archives to compact");
+    };
+    if values.is_empty() {
+        anyhow::bail!("there are no values to write the compacted archives with");
+    }
+    if compaction_journal_path(data_dir).exists() {
+        anyhow::bail!(
+            "another compaction is in progress, or one was interrupted and will be finished or \
+             abandoned by the next command in a minute"
+        );
+    }
+
+    let metadata = read_archive_metadata(last_path)
+        .with_context(|| format!("reading metadata of '{}'", last_path.display()))?;
+    let Some(sequence) = metadata.sequence() else {
+        anyhow::bail!(
+            "the archive '{}' has no sequence number, so an archive cannot take its place",
+            last_path.display()
+        );
+    };
+    let created = metadata
+        .created()
+        .unwrap_or_else(|| jiff::Timestamp::now().as_second());
+
+    let count = values.len();
+    let mut body_hashes = Vec::with_capacity(count);
+    let mut entries = Vec::with_capacity(count);
+    for (index, value) in values.into_iter().enumerate() {
+        let key_sizes = KeySizes::of(&value);
+        let body = minicbor::to_vec(value).context("encoding CBOR value")?;
+        body_hashes.push(blake3::hash(&body));
+
+        // A temporary file is only left behind by a compaction which failed
+        // before writing its journal
+        let temp_path = compacted_temp_path(data_dir, index);
+        remove_file_if_exists(&temp_path)?;
+        entries.push(write_new_archive_file(
+            &temp_path,
+            sequence,
+            created,
+            direct_io,
+            key_sizes,
+            |cbor_writer| {
+                minicbor::encode::Write::write_all(cbor_writer, &body).context("writing CBOR value")
+            },
+        )?);
+    }
+
+    let last_name = archive_name(last_path);
+    let mut index = ArchiveIndex::read(data_dir)?;
+    let index_timestamp = index.timestamp(&last_name).map(str::to_string);
+    let names = match &index_timestamp {
+        Some(_) => body_hashes
+            .iter()
+            .map(|hash| format!("{}.bin", hash.to_hex()))
+            .collect(),
+        None => {
+            let mut name = compacted_archive_name(&last_name)?;
+            let mut names = split_archive_names(&name, count);
+            while names
+                .iter()
+                .any(|name| archive_dir(data_dir).join(name).exists())
+            {
+                name = compacted_archive_name(&name)?;
+                names = split_archive_names(&name, count);
+            }
+            names
+        }
+    };
+    for name in &names {
+        let archive_path = archive_dir(data_dir).join(name);
+        if archive_path.exists() {
+            anyhow::bail!(
+                "an archive with the same content as a compacted archive already exists at '{}'",
+                archive_path.display()
+            );
+        }
+    }
+
+    if let Some(timestamp) = index_timestamp {
+        // The archives share a sequence number, so they are read in the
+        // order of their timestamps
+        for (offset, name) in names.iter().enumerate() {
+            let Some(timestamp) = later_archive_timestamp(&timestamp, offset as i64) else {
+                anyhow::bail!(
+                    "the timestamp '{timestamp}' of the archive '{last_name}' in the index \
+                     cannot be parsed"
+                );
+            };
+            index.insert(name.clone(), timestamp);
+        }
+        index.write(data_dir)?;
+    }
+    let mut sources = ArchiveSources::read(data_dir)?;
+    if let Some(label) = sources.label(&last_name).cloned() {
+        for name in &names {
+            sources.insert(name.clone(), label.clone());
+        }
+        sources.write(data_dir)?;
+    }
+
+    let journal = CompactionJournal {
+        compacted: names.iter().cloned().zip(entries).collect(),
+        replaced: archives.iter().map(|path| archive_name(path)).collect(),
+    };
+    journal.write(data_dir)?;
+
+    // Linking the last compacted archive into place is the moment the
+    // compaction happens, until then the ones in place are not listed
+    let archive_paths = names
+        .iter()
+        .map(|name| archive_dir(data_dir).join(name))
+        .collect::<Vec<_>>();
+    for (index, archive_path) in archive_paths.iter().enumerate() {
+        if let Err(err) = finalize_archive(&compacted_temp_path(data_dir, index), archive_path) {
+            if !journal.is_committed(data_dir) {
+                journal.abandon(data_dir)?;
+            }
+            return Err(err);
+        }
+    }
+
+    tracing::info!(
+        archive_files = ?names,
+        compacted = archives.len(),
+        "Compacted the oldest archives"
+    );
+    journal.finish(data_dir)?;
+
+    Ok(archive_paths)
+}
+
+/// Return the filename of the archive which replaces an archive with the
+/// given filename. For an archive named by a timestamp this adds a
+/// microsecond, otherwise it adds one to the number after its last `.`, or
+/// else appends `.1`, like `000042.bin` to `000042.1.bin`.
+///
+/// Returns an error if the number after the last `.` would need another
+/// digit, which would break the order of the filenames.
+fn compacted_archive_name(name: &str) -> anyhow::Result<String> {
+    let stem = name.strip_suffix(".bin").unwrap_or(name);
+    if let Some(timestamp) = later_archive_timestamp(stem, 1) {
+        return Ok(format!("{timestamp}.bin"));
+    }
+
+    let counter = stem.rsplit_once('.').and_then(|(base, counter)| {
+        if counter.is_empty() || !counter.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        Some((base, counter.parse::<u64>().ok()?, counter.len()))
+    });
+
+    match counter {
+        Some((base, counter, width)) => {
+            let counter = counter + 1;
+            if counter.to_string().len() > width {
+                anyhow::bail!(
+                    "cannot name the archive which replaces '{name}', since the number after its \
+                     last '.' cannot be increased without another digit"
+                );
+            }
+            Ok(format!("{base}.{counter:0width$}.bin"))
+        }
+        None => Ok(format!("{stem}.1.bin")),
+    }
+}
+
+/// Return the filenames of the given number of archives which replace an
+/// archive with the given filename, like [`compacted_archive_name`], numbered
+/// so that they sort in order, like `000042.1.0.bin` and `000042.1.1.bin`. A
+/// single archive keeps the given filename.
+fn split_archive_names(name: &str, count: usize) -> Vec<String> {
+    if count == 1 {
+        return vec![name.to_string()];
+    }
+
+    let stem = name.strip_suffix(".bin").unwrap_or(name);
+    let width = (count - 1).to_string().len();
+    (0..count)
+        .map(|index| format!("{stem}.{index:0width$}.bin"))
+        .collect()
+}
+
+fn remove_file_if_exists(path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err).with_context(|| format!("removing '{}'", path.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_round_trip() {
+        let journal = CompactionJournal {
+            compacted: vec![
+                (
+                    "000002.1.0.bin".into(),
+                    ChecksumEntry {
+                        checksum: 0xbf6ae788,
+                        len: 1024,
+                    },
+                ),
+                (
+                    "000002.1.1.bin".into(),
+                    ChecksumEntry {
+                        checksum: 0x1c,
+                        len: 512,
+                    },
+                ),
+            ],
+            replaced: vec![
+                "000000.bin".into(),
+                "000001.bin".into(),
+                "000002.bin".into(),
+            ],
+        };
+
+        let contents = journal.to_string();
+        assert_eq!(
+            contents,
+            "compacted  bf6ae788  1024  000002.1.0.bin\n\
+             compacted  0000001c  512  000002.1.1.bin\n\
+             replaced  000000.bin\n\
+             replaced  000001.bin\n\
+             replaced  000002.bin\n"
+        );
+        assert_eq!(CompactionJournal::parse(&contents).unwrap(), journal);
+        assert_eq!(
+            journal.compacted_names().collect::<Vec<_>>(),
+            ["000002.1.0.bin", "000002.1.1.bin"]
+        );
+
+        assert!(CompactionJournal::parse("replaced  000000.bin\n").is_err());
+        assert!(CompactionJournal::parse("compacted  000002.1.bin\n").is_err());
+    }
+
+    #[test]
+    fn compacted_archive_names() {
+        let name = |name| compacted_archive_name(name).unwrap();
+        assert_eq!(name("000042.bin"), "000042.1.bin");
+        assert_eq!(name("000042.1.bin"), "000042.2.bin");
+        assert_eq!(name("000042.09.bin"), "000042.10.bin");
+        assert_eq!(
+            name("2024-06-19-19-22-45.000999.bin"),
+            "2024-06-19-19-22-45.001000.bin"
+        );
+        assert_eq!(
+            name("2024-06-19-19-22-45.999999.bin"),
+            "2024-06-19-19-22-46.000000.bin"
+        );
+        assert_eq!(
+            name("2024-06-19-21-22-45.123456+0200.bin"),
+            "2024-06-19-21-22-45.123457+0200.bin"
+        );
+
+        let err = compacted_archive_name("000042.9.bin").unwrap_err();
+        assert!(err.to_string().contains("another digit"), "{err}");
+    }
+
+    #[test]
+    fn split_archive_names_sort_in_order() {
+        assert_eq!(split_archive_names("000042.1.bin", 1), ["000042.1.bin"]);
+        assert_eq!(
+            split_archive_names("000042.1.bin", 3),
+            ["000042.1.0.bin", "000042.1.1.bin", "000042.1.2.bin"]
+        );
+
+        let names = split_archive_names("2024-06-19-19-22-45.000001.bin", 12);
+        assert_eq!(names[0], "2024-06-19-19-22-45.000001.00.bin");
+        assert_eq!(names[11], "2024-06-19-19-22-45.000001.11.bin");
+        assert!(names.windows(2).all(|pair| pair[0] < pair[1]), "{names:?}");
+    }
+}
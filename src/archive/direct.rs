@@ -0,0 +1,92 @@
+//! This module contains the writing of archive files with direct IO, which
+//! bypasses the page cache so that archiving large values does not evict the
+//! cached pages of the files other processes are serving.
+//!
+//! Direct IO needs the buffer, the offset, and the length of every write to
+//! be aligned, so the contents are copied into aligned blocks and the file is
+//! cut back to its real length afterwards.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// The alignment of direct IO, which is the page size and a multiple of the
+/// block size of the common filesystems
+const ALIGN: usize = 4096;
+
+/// The number of aligned blocks written at a time
+const CHUNK_BLOCKS: usize = 256;
+
+#[derive(Clone, Copy, FromZeroes, FromBytes, AsBytes)]
+#[repr(C, align(4096))]
+struct AlignedBlock([u8; ALIGN]);
+
+/// Create the file at `path` with the given contents and sync it, writing
+/// with direct IO where the platform and filesystem support it and through
+/// the page cache otherwise.
+#[cfg(target_os = "linux")]
+pub fn write_new_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path);
+
+    // Filesystems without direct IO, like tmpfs, reject the flag or the
+    // alignment with EINVAL
+    let result = file.and_then(|file| write_aligned(&file, contents));
+    match result {
+        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+            tracing::debug!(
+                path = %path.display(),
+                "The filesystem does not support direct IO, writing through the page cache"
+            );
+            write_buffered(path, contents)
+        }
+        result => result,
+    }
+}
+
+/// Create the file at `path` with the given contents and sync it, writing
+/// through the page cache since direct IO is not supported on this platform.
+#[cfg(not(target_os = "linux"))]
+pub fn write_new_file(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_buffered(path, contents)
+}
+
+#[cfg(target_os = "linux")]
+fn write_aligned(file: &File, contents: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+
+    let mut blocks = vec![AlignedBlock([0; ALIGN]); CHUNK_BLOCKS];
+    let buffer = blocks.as_bytes_mut();
+
+    for (index, chunk) in contents.chunks(buffer.len()).enumerate() {
+        let padded_len = chunk.len().next_multiple_of(ALIGN);
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        buffer[chunk.len()..padded_len].fill(0);
+        file.write_all_at(&buffer[..padded_len], (index * buffer.len()) as u64)?;
+    }
+
+    file.set_len(contents.len() as u64)?;
+    file.sync_all()
+}
+
+/// Write the contents to the file at `path`, which may have been created by a
+/// failed attempt at direct IO.
+fn write_buffered(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    file.write_all(contents)?;
+
+    File::sync_all(&file)
+}
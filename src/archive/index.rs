@@ -0,0 +1,120 @@
+//! This module contains the ordering index for content-addressed archive
+//! files.
+//!
+//! Content-addressed archives are named by the hash of their body, so the
+//! filename no longer says when the archive was created. The index records
+//! the creation timestamp of each of these archives, one `<timestamp>  <filename>`
+//! pair per line, so that they can be read in order.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::atomic_file::write_atomically;
+
+fn index_file_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("ARCHIVE_INDEX")
+}
+
+/// The mapping from content-addressed archive filenames to the timestamp
+/// when each was created
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ArchiveIndex {
+    timestamps: BTreeMap<String, String>,
+}
+
+impl ArchiveIndex {
+    /// Read the index from the data directory, returning an empty index if it
+    /// does not exist.
+    pub fn read(data_dir: &Path) -> anyhow::Result<Self> {
+        let contents = match fs::read_to_string(index_file_path(data_dir)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).context("reading ARCHIVE_INDEX file"),
+        };
+
+        Self::parse(&contents)
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut timestamps = BTreeMap::new();
+
+        for (line_index, line) in contents.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((timestamp, name)) = line.split_once("  ") else {
+                anyhow::bail!(
+                    "parsing line {} of ARCHIVE_INDEX file, expected '<timestamp>  <filename>'",
+                    line_index + 1
+                );
+            };
+
+            timestamps.insert(name.to_string(), timestamp.to_string());
+        }
+
+        Ok(Self { timestamps })
+    }
+
+    /// Atomically write the index to the data directory.
+    pub fn write(&self, data_dir: &Path) -> anyhow::Result<()> {
+        write_atomically(&index_file_path(data_dir), self.to_string().as_bytes())
+            .context("writing ARCHIVE_INDEX file")
+    }
+
+    /// Record the creation timestamp for the archive with the given filename.
+    pub fn insert(&mut self, name: String, timestamp: String) {
+        self.timestamps.insert(name, timestamp);
+    }
+
+    /// Remove the entry of the archive with the given filename, returning
+    /// true if it had one.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.timestamps.remove(name).is_some()
+    }
+
+    /// Return the creation timestamp of the archive with the given filename.
+    pub fn timestamp(&self, name: &str) -> Option<&str> {
+        self.timestamps.get(name).map(String::as_str)
+    }
+}
+
+impl std::fmt::Display for ArchiveIndex {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut entries = self.timestamps.iter().collect::<Vec<_>>();
+        entries.sort_by(|(a_name, a_ts), (b_name, b_ts)| (a_ts, a_name).cmp(&(b_ts, b_name)));
+
+        for (name, timestamp) in entries {
+            writeln!(f, "{timestamp}  {name}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn index_round_trip() {
+        let mut index = ArchiveIndex::default();
+        index.insert("ffff.bin".into(), "2024-06-19-19-22-45".into());
+        index.insert("0000.bin".into(), "2024-06-20-08-00-00".into());
+
+        let contents = index.to_string();
+        assert_eq!(
+            contents,
+            "2024-06-19-19-22-45  ffff.bin\n2024-06-20-08-00-00  0000.bin\n"
+        );
+        assert_eq!(ArchiveIndex::parse(&contents).unwrap(), index);
+        assert_eq!(index.timestamp("ffff.bin"), Some("2024-06-19-19-22-45"));
+        assert_eq!(index.timestamp("aaaa.bin"), None);
+    }
+}
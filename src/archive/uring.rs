@@ -0,0 +1,225 @@
+//! This module contains the reading of whole archive files through io_uring,
+//! which opens and reads a batch of files with a few system calls instead of
+//! several for each file.
+
+use std::{
+    ffi::CString,
+    fs::File,
+    io,
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::ffi::OsStrExt,
+    },
+    path::PathBuf,
+};
+
+use io_uring::{opcode, types, IoUring};
+
+/// The largest number of bytes read by a single operation
+const MAX_READ_LEN: usize = 1 << 30;
+
+/// Reads batches of whole files through an io_uring instance
+pub struct UringReader {
+    ring: IoUring,
+    batch_len: usize,
+}
+
+impl std::fmt::Debug for UringReader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("UringReader")
+            .field("batch_len", &self.batch_len)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A file of the batch which is being read
+struct PendingRead {
+    file: File,
+    buffer: Vec<u8>,
+    filled: usize,
+    done: bool,
+}
+
+impl UringReader {
+    /// Set up an io_uring instance for reading up to `batch_len` files at a
+    /// time, which fails if io_uring is not supported or not allowed.
+    pub fn new(batch_len: usize) -> io::Result<Self> {
+        let entries = u32::try_from(batch_len).map_err(io::Error::other)?;
+
+        Ok(Self {
+            ring: IoUring::new(entries)?,
+            batch_len,
+        })
+    }
+
+    /// Return the number of files read by each call to [`Self::read_files`].
+    pub fn batch_len(&self) -> usize {
+        self.batch_len
+    }
+
+    /// Read the whole contents of each file, opening all of them with one
+    /// submission and then reading all of them with another.
+    ///
+    /// A file which cannot be opened or read gets an error in its place. The
+    /// outer error is returned if io_uring itself fails.
+    pub fn read_files(&mut self, paths: &[PathBuf]) -> io::Result<Vec<io::Result<Vec<u8>>>> {
+        assert!(paths.len() <= self.batch_len, "more paths than the batch");
+
+        let files = self.open_files(paths)?;
+
+        let mut results = Vec::with_capacity(files.len());
+        let mut pending = Vec::with_capacity(files.len());
+        for file in files {
+            let read = file.and_then(|file| {
+                let len = usize::try_from(file.metadata()?.len()).map_err(io::Error::other)?;
+                Ok(PendingRead {
+                    file,
+                    buffer: vec![0; len],
+                    filled: 0,
+                    done: len == 0,
+                })
+            });
+            match read {
+                Ok(read) => {
+                    results.push(Ok(pending.len()));
+                    pending.push(read);
+                }
+                Err(err) => results.push(Err(err)),
+            }
+        }
+
+        let mut errors = self.read_pending(&mut pending)?;
+
+        Ok(results
+            .into_iter()
+            .map(|result| {
+                let index = result?;
+                match errors[index].take() {
+                    Some(err) => Err(err),
+                    None => {
+                        let read = &mut pending[index];
+                        let mut buffer = std::mem::take(&mut read.buffer);
+                        buffer.truncate(read.filled);
+                        Ok(buffer)
+                    }
+                }
+            })
+            .collect())
+    }
+
+    fn open_files(&mut self, paths: &[PathBuf]) -> io::Result<Vec<io::Result<File>>> {
+        let c_paths = paths
+            .iter()
+            .map(|path| CString::new(path.as_os_str().as_bytes()))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (index, path) in c_paths.iter().enumerate() {
+            let entry = opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), path.as_ptr())
+                .flags(libc::O_RDONLY | libc::O_CLOEXEC)
+                .build()
+                .user_data(index as u64);
+            // SAFETY: the path outlives the operation, which completes before
+            // this function returns
+            unsafe { self.ring.submission().push(&entry) }
+                .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+        }
+
+        let mut files: Vec<Option<io::Result<File>>> = (0..paths.len()).map(|_| None).collect();
+        let mut remaining = paths.len();
+        while remaining > 0 {
+            if let Err(err) = self.submit_and_wait(remaining) {
+                // The paths may still be read by the kernel
+                std::mem::forget(c_paths);
+                return Err(err);
+            }
+            for entry in self.ring.completion() {
+                let result = entry.result();
+                files[entry.user_data() as usize] = Some(if result < 0 {
+                    Err(io::Error::from_raw_os_error(-result))
+                } else {
+                    // SAFETY: the kernel returned a new file descriptor which
+                    // nothing else owns
+                    Ok(unsafe { File::from_raw_fd(result) })
+                });
+                remaining -= 1;
+            }
+        }
+
+        Ok(files
+            .into_iter()
+            .map(|file| file.expect("every open completed"))
+            .collect())
+    }
+
+    /// Read the files until each one is full or ends, returning the error of
+    /// each file which failed.
+    fn read_pending(&mut self, pending: &mut [PendingRead]) -> io::Result<Vec<Option<io::Error>>> {
+        let mut errors: Vec<Option<io::Error>> = (0..pending.len()).map(|_| None).collect();
+
+        loop {
+            let mut submitted = 0;
+            for (index, read) in pending.iter_mut().enumerate() {
+                if read.done {
+                    continue;
+                }
+                let len = (read.buffer.len() - read.filled).min(MAX_READ_LEN);
+                let entry = opcode::Read::new(
+                    types::Fd(read.file.as_raw_fd()),
+                    read.buffer[read.filled..].as_mut_ptr(),
+                    len as u32,
+                )
+                .offset(read.filled as u64)
+                .build()
+                .user_data(index as u64);
+                // SAFETY: the buffer and file outlive the operation, which
+                // completes before the next one is submitted
+                unsafe { self.ring.submission().push(&entry) }
+                    .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+                submitted += 1;
+            }
+            if submitted == 0 {
+                return Ok(errors);
+            }
+
+            let mut remaining = submitted;
+            while remaining > 0 {
+                if let Err(err) = self.submit_and_wait(remaining) {
+                    // The buffers may still be written by the kernel, so they
+                    // must never be freed
+                    for read in pending.iter_mut() {
+                        std::mem::forget(std::mem::take(&mut read.buffer));
+                    }
+                    return Err(err);
+                }
+                for entry in self.ring.completion() {
+                    let index = entry.user_data() as usize;
+                    let read = &mut pending[index];
+                    match entry.result() {
+                        result if result == -libc::EINTR || result == -libc::EAGAIN => {}
+                        result if result < 0 => {
+                            errors[index] = Some(io::Error::from_raw_os_error(-result));
+                            read.done = true;
+                        }
+                        // The file ended early, keep what was read
+                        0 => read.done = true,
+                        result => {
+                            read.filled += result as usize;
+                            read.done = read.filled == read.buffer.len();
+                        }
+                    }
+                    remaining -= 1;
+                }
+            }
+        }
+    }
+
+    fn submit_and_wait(&mut self, want: usize) -> io::Result<()> {
+        loop {
+            match self.ring.submit_and_wait(want) {
+                Ok(_) => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! This module contains a helper for replacing small metadata files in the
+//! data directory without readers ever observing a partial write, and the
+//! lock which serializes the updates of those files that read them first.
+
+use std::{
+    collections::hash_map::RandomState,
+    fs::{self, File, OpenOptions},
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::Context;
+
+/// Replace the contents of the file at `path` with `contents`.
+///
+/// The contents are written and synced to a temporary file next to the
+/// destination first, with a name unique to this call so that concurrent
+/// writers never share it, then renamed over it, and the directory is synced
+/// so that the rename survives a crash.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let temp_file_path = unique_temp_path(path);
+
+    let mut temp_file = File::create(&temp_file_path)
+        .with_context(|| format!("creating temporary file '{}'", temp_file_path.display()))?;
+    temp_file
+        .write_all(contents)
+        .context("writing temporary file")?;
+    temp_file.sync_all().context("syncing temporary file")?;
+    drop(temp_file);
+
+    if let Err(err) = fs::rename(&temp_file_path, path) {
+        let _ = fs::remove_file(&temp_file_path);
+        return Err(err)
+            .with_context(|| format!("replacing '{}' with updated version", path.display()));
+    }
+    if let Some(dir) = path.parent() {
+        sync_dir(dir).with_context(|| format!("syncing directory '{}'", dir.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Return a path next to the given one for a temporary file, like
+/// `CHECKSUMS.1234.5eb63bbbe01eeed0.tmp`, with the process ID and a random
+/// suffix.
+fn unique_temp_path(path: &Path) -> PathBuf {
+    let suffix = RandomState::new().build_hasher().finish();
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(format!(".{}.{suffix:016x}.tmp", process::id()));
+    path.with_file_name(name)
+}
+
+/// Wait for the entries of the directory to reach the disk.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// An exclusive `flock` on the `LOCK` file in the data directory, which is
+/// released when dropped.
+///
+/// It is held while a metadata file is read, updated, and written back, so
+/// that commands running at the same time do not overwrite each other's
+/// updates. It must not be taken again while it is held.
+#[derive(Debug)]
+pub struct DataDirLock {
+    _file: File,
+}
+
+impl DataDirLock {
+    /// Wait to take the lock on the data directory.
+    pub fn acquire(data_dir: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(data_dir.join("LOCK"))
+            .context("opening LOCK file")?;
+        lock_exclusive(&file).context("locking data directory")?;
+
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(unix)]
+fn lock_exclusive(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn lock_exclusive(_file: &File) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//! This module contains the implementation of the `bench-gen` CLI command
+
+use std::{
+    io::{self, BufWriter, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::Instant,
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+use uom::si::{information::byte, u64::Information};
+
+use crate::{
+    append::default_staging_limit,
+    store::{RecordOutcome, Settings, State},
+    value::Value,
+};
+
+/// The `bench-gen` sub-command generates a synthetic workload of JSON
+/// records, one per line, for measuring how fast records are staged, merged,
+/// and archived.
+///
+/// The same options and seed always generate the same records. The records
+/// are written to stdout, or appended to the data directory with `--append`,
+/// which reports how long it took.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "bench-gen")]
+pub struct BenchGenCommand {
+    /// the number of records to generate.
+    #[argh(option, default = "10_000")]
+    records: u64,
+    /// the number of distinct keys that each object picks its fields from,
+    /// so that later records overwrite and merge with earlier ones.
+    #[argh(option, default = "8")]
+    fan_out: usize,
+    /// the number of levels of objects in each record, below which the
+    /// values are numbers, strings, and booleans.
+    #[argh(option, default = "3")]
+    depth: usize,
+    /// how the "events" array of each record is filled, either "none" (the
+    /// default) for no array, "append" for a single new element per record
+    /// which concatenated merges keep growing, or "replace" for a fixed set
+    /// of --array-len elements.
+    #[argh(option, default = "ArrayGrowth::None")]
+    array_growth: ArrayGrowth,
+    /// the number of elements of the "events" array with --array-growth
+    /// replace.
+    #[argh(option, default = "16")]
+    array_len: usize,
+    /// the seed of the random number generator.
+    #[argh(option, default = "0")]
+    seed: u64,
+    /// append the records to the data directory instead of writing them to
+    /// stdout, and report the time it took.
+    #[argh(switch)]
+    append: bool,
+    /// with --append, the maximum size that the staging file reach before it
+    /// is archived.
+    #[argh(option, default = "default_staging_limit()")]
+    staging_limit: Information,
+}
+
+/// This enum controls how the array of each generated record is filled
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ArrayGrowth {
+    /// Records have no array
+    None,
+    /// Each record has a single new element
+    Append,
+    /// Each record has the same number of elements
+    Replace,
+}
+
+impl FromStr for ArrayGrowth {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "none" => Self::None,
+            "append" => Self::Append,
+            "replace" => Self::Replace,
+            x => anyhow::bail!("'{x}' is an unknown option for the array growth"),
+        })
+    }
+}
+
+impl BenchGenCommand {
+    /// Return true if the records are appended to the data directory, which
+    /// must then be initialized.
+    pub fn uses_data_dir(&self) -> bool {
+        self.append
+    }
+
+    /// This function executes the bench-gen command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
+        if self.fan_out == 0 {
+            anyhow::bail!("--fan-out must be at least 1");
+        }
+
+        let mut generator = Generator {
+            rng: SplitMix64(self.seed),
+            fan_out: self.fan_out,
+            depth: self.depth,
+            array_growth: self.array_growth,
+            array_len: self.array_len,
+        };
+        let mut line = Vec::new();
+
+        if !self.append {
+            let stdout = io::stdout();
+            let mut handle = BufWriter::new(stdout.lock());
+            for index in 0..self.records {
+                serde_json::to_writer(&mut handle, &generator.record(index))
+                    .context("writing record to stdout")?;
+                writeln!(handle).context("writing record to stdout")?;
+            }
+            return handle.flush().context("flushing stdout");
+        }
+
+        let settings = Settings {
+            staging_limit_bytes: self.staging_limit.get::<byte>(),
+            ..Settings::default()
+        };
+        let mut state = State::new(data_dir, settings)?;
+        let mut total_bytes = 0;
+
+        let start = Instant::now();
+        for index in 0..self.records {
+            line.clear();
+            serde_json::to_writer(&mut line, &generator.record(index))
+                .context("converting record to JSON")?;
+            total_bytes += line.len() as u64;
+
+            if let RecordOutcome::Rejected(err) = state.stage_record(&line)? {
+                return Err(err.context(format!("appending generated record {index}")));
+            }
+        }
+        state.flush()?;
+        let elapsed = start.elapsed();
+
+        let seconds = elapsed.as_secs_f64();
+        println!(
+            "appended {} records ({total_bytes} bytes) in {seconds:.3}s, {:.0} records/s, {:.1} \
+             MB/s",
+            self.records,
+            self.records as f64 / seconds,
+            total_bytes as f64 / seconds / 1_000_000.0,
+        );
+
+        Ok(())
+    }
+}
+
+/// A small, fast random number generator, so that the same seed always
+/// generates the same workload, or the same sample with `read --sample`
+#[derive(Debug)]
+pub struct SplitMix64(pub u64);
+
+impl SplitMix64 {
+    pub fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Return a number less than `bound`, which must not be zero.
+    pub fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/// Generates the records of a workload
+#[derive(Debug)]
+struct Generator {
+    rng: SplitMix64,
+    fan_out: usize,
+    depth: usize,
+    array_growth: ArrayGrowth,
+    array_len: usize,
+}
+
+impl Generator {
+    /// Generate the record with the given index.
+    fn record(&mut self, index: u64) -> Value {
+        let Value::Object(mut fields) = self.object(self.depth.max(1)) else {
+            unreachable!("objects are generated at every level above zero");
+        };
+        fields.insert(0, ("seq".into(), Value::Number(index.to_string())));
+
+        match self.array_growth {
+            ArrayGrowth::None => {}
+            ArrayGrowth::Append => fields.push((
+                "events".into(),
+                Value::Array(vec![Value::Number(index.to_string())]),
+            )),
+            ArrayGrowth::Replace => {
+                let elements = (0..self.array_len).map(|_| self.leaf()).collect();
+                fields.push(("events".into(), Value::Array(elements)));
+            }
+        }
+
+        Value::Object(fields)
+    }
+
+    /// Generate an object with `levels` levels of objects, or a leaf value if
+    /// `levels` is zero.
+    fn object(&mut self, levels: usize) -> Value {
+        if levels == 0 {
+            return self.leaf();
+        }
+
+        let num_fields = 1 + self.rng.below(self.fan_out);
+        let mut fields: Vec<(String, Value)> = Vec::with_capacity(num_fields);
+        for _ in 0..num_fields {
+            let key = format!("k{}", self.rng.below(self.fan_out));
+            if fields.iter().all(|(existing, _)| *existing != key) {
+                let value = self.object(levels - 1);
+                fields.push((key, value));
+            }
+        }
+
+        Value::Object(fields)
+    }
+
+    fn leaf(&mut self) -> Value {
+        match self.rng.below(3) {
+            0 => Value::Number(self.rng.below(1_000_000).to_string()),
+            1 => Value::String(format!("value-{}", self.rng.below(1_000))),
+            _ => Value::Bool(self.rng.below(2) == 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(seed: u64, array_growth: ArrayGrowth) -> Generator {
+        Generator {
+            rng: SplitMix64(seed),
+            fan_out: 4,
+            depth: 2,
+            array_growth,
+            array_len: 3,
+        }
+    }
+
+    fn depth(value: &Value) -> usize {
+        match value {
+            Value::Object(fields) => 1 + fields.iter().map(|(_, v)| depth(v)).max().unwrap_or(0),
+            Value::Array(elements) => 1 + elements.iter().map(depth).max().unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    #[test]
+    fn generate_records() {
+        let records = |seed| {
+            let mut generator = generator(seed, ArrayGrowth::Append);
+            (0..20).map(|i| generator.record(i)).collect::<Vec<_>>()
+        };
+        assert_eq!(records(7), records(7));
+        assert_ne!(records(7), records(8));
+
+        for (index, record) in records(7).iter().enumerate() {
+            let Value::Object(fields) = record else {
+                panic!("expected an object");
+            };
+            assert_eq!(fields[0], ("seq".into(), Value::Number(index.to_string())));
+            assert_eq!(
+                fields.last().unwrap(),
+                &(
+                    "events".to_string(),
+                    Value::Array(vec![Value::Number(index.to_string())])
+                )
+            );
+            assert!(fields[1..fields.len() - 1]
+                .iter()
+                .all(|(key, value)| key.starts_with('k') && depth(value) == 1));
+        }
+
+        let record = generator(1, ArrayGrowth::Replace).record(0);
+        assert!(matches!(
+            record.pointer("/events"),
+            Some(Value::Array(elements)) if elements.len() == 3
+        ));
+    }
+}
@@ -0,0 +1,295 @@
+//! This module contains things relating to the `CHECKSUMS` manifest, which
+//! lists every archive file in the data directory along with its checksum and
+//! size.
+//!
+//! Each line of the manifest has the form `<crc32 hex>  <size>  <filename>`.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    archive::{archive_name, list_archive_files, read_archive_checksum},
+    atomic_file::{write_atomically, DataDirLock},
+};
+
+fn checksums_file_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("CHECKSUMS")
+}
+
+/// The checksum and size recorded for a single archive file
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ChecksumEntry {
+    /// The CRC32 checksum of the archive body
+    pub checksum: u32,
+    /// The length in bytes of the whole archive file
+    pub len: u64,
+}
+
+/// The list of all archive files that are expected in the data directory
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ChecksumManifest {
+    entries: BTreeMap<String, ChecksumEntry>,
+}
+
+impl ChecksumManifest {
+    /// Read the manifest from the data directory, returning `Ok(None)` if it
+    /// does not exist.
+    pub fn read(data_dir: &Path) -> anyhow::Result<Option<Self>> {
+        let contents = match fs::read_to_string(checksums_file_path(data_dir)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("reading CHECKSUMS file"),
+        };
+
+        Self::parse(&contents).map(Some)
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut entries = BTreeMap::new();
+
+        for (line_index, line) in contents.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let parse_line = || -> anyhow::Result<(String, ChecksumEntry)> {
+                let mut parts = line.splitn(3, "  ");
+                let (Some(checksum), Some(len), Some(name)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    anyhow::bail!("expected '<checksum>  <size>  <filename>'");
+                };
+
+                let checksum = u32::from_str_radix(checksum, 16).context("parsing checksum")?;
+                let len = len.parse().context("parsing size")?;
+
+                Ok((name.to_string(), ChecksumEntry { checksum, len }))
+            };
+
+            let (name, entry) = parse_line()
+                .with_context(|| format!("parsing line {} of CHECKSUMS file", line_index + 1))?;
+            entries.insert(name, entry);
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Build a manifest from the headers of all the archive files currently in
+    /// the data directory.
+    pub fn from_archive_files(data_dir: &Path) -> anyhow::Result<Self> {
+        let mut entries = BTreeMap::new();
+
+        for path in list_archive_files(data_dir)? {
+            let checksum = read_archive_checksum(&path)
+                .with_context(|| format!("reading checksum of '{}'", path.display()))?;
+            let len = fs::metadata(&path)
+                .with_context(|| format!("reading metadata of '{}'", path.display()))?
+                .len();
+
+            entries.insert(archive_name(&path), ChecksumEntry { checksum, len });
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Write the manifest to the data directory.
+    ///
+    /// The manifest is written to a temporary file first, then renamed over
+    /// the existing manifest so that readers never see a partial update.
+    pub fn write(&self, data_dir: &Path) -> anyhow::Result<()> {
+        write_atomically(&checksums_file_path(data_dir), self.to_string().as_bytes())
+            .context("writing CHECKSUMS file")
+    }
+
+    /// Add or replace the entry for the archive file with the given name.
+    pub fn insert(&mut self, name: String, entry: ChecksumEntry) {
+        self.entries.insert(name, entry);
+    }
+
+    /// Return the entry for the archive file with the given name.
+    pub fn get(&self, name: &str) -> Option<&ChecksumEntry> {
+        self.entries.get(name)
+    }
+
+    /// Iterate over the names and entries of all archive files, ordered by
+    /// name.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &ChecksumEntry)> {
+        self.entries
+            .iter()
+            .map(|(name, entry)| (name.as_str(), entry))
+    }
+}
+
+impl std::fmt::Display for ChecksumManifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, entry) in &self.entries {
+            writeln!(f, "{:08x}  {}  {name}", entry.checksum, entry.len)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Record a new archive file in the manifest, before it is moved to the
+/// given path, so that a crash never leaves an archive which is not listed.
+///
+/// Returns `false` if the archive was already listed with the same entry,
+/// like when a crashed run is recovered, and fails if it is listed with a
+/// different one. If the data directory does not have a manifest yet, then
+/// one is created which also lists all the archive files that already exist.
+pub fn record_archive(
+    data_dir: &Path,
+    archive_path: &Path,
+    entry: ChecksumEntry,
+) -> anyhow::Result<bool> {
+    let name = archive_name(archive_path);
+    update_manifest(data_dir, |manifest| match manifest.get(&name) {
+        Some(listed) if *listed == entry => Ok(false),
+        Some(_) => anyhow::bail!("the archive '{name}' is already listed with another checksum"),
+        None => {
+            manifest.insert(name.clone(), entry);
+            Ok(true)
+        }
+    })
+}
+
+/// Remove the entry recorded by [`record_archive`] for an archive which could
+/// not be moved into place after all.
+pub fn forget_archive(data_dir: &Path, archive_path: &Path) -> anyhow::Result<()> {
+    let name = archive_name(archive_path);
+    update_manifest(data_dir, |manifest| {
+        manifest.entries.remove(&name);
+        Ok(())
+    })
+}
+
+/// Record the archives with the given filenames, checksums, and sizes which
+/// replaced the compacted archives with the given filenames in the manifest,
+/// and remove their entries.
+pub fn record_compacted_archives(
+    data_dir: &Path,
+    archives: &[(String, ChecksumEntry)],
+    replaced: &[String],
+) -> anyhow::Result<()> {
+    update_manifest(data_dir, |manifest| {
+        for name in replaced {
+            manifest.entries.remove(name);
+        }
+        for (name, entry) in archives {
+            manifest.insert(name.clone(), *entry);
+        }
+        Ok(())
+    })
+}
+
+/// Read the manifest, or create it from the existing archives, change it, and
+/// write it back, all while holding the lock on the data directory.
+fn update_manifest<T>(
+    data_dir: &Path,
+    update: impl FnOnce(&mut ChecksumManifest) -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let _lock = DataDirLock::acquire(data_dir)?;
+    let mut manifest = match ChecksumManifest::read(data_dir)? {
+        Some(manifest) => manifest,
+        None => {
+            tracing::info!("No CHECKSUMS file present, creating one from existing archives");
+            ChecksumManifest::from_archive_files(data_dir)?
+        }
+    };
+
+    let result = update(&mut manifest)?;
+    manifest.write(data_dir)?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::test_dir::TempDir;
+
+    #[test]
+    fn manifest_round_trip() {
+        let mut manifest = ChecksumManifest::default();
+        manifest.insert(
+            "2024-06-19-19-22-45.bin".into(),
+            ChecksumEntry {
+                checksum: 0xbf6ae788,
+                len: 1024,
+            },
+        );
+        manifest.insert(
+            "2024-06-20-08-00-00.bin".into(),
+            ChecksumEntry {
+                checksum: 0x1c,
+                len: 16,
+            },
+        );
+
+        let contents = manifest.to_string();
+        assert_eq!(
+            contents,
+            "bf6ae788  1024  2024-06-19-19-22-45.bin\n\
+             0000001c  16  2024-06-20-08-00-00.bin\n"
+        );
+        assert_eq!(ChecksumManifest::parse(&contents).unwrap(), manifest);
+    }
+
+    #[test]
+    fn manifest_parse_errors() {
+        let err = ChecksumManifest::parse("bf6ae788  1024  a.bin\nnot a line\n").unwrap_err();
+        assert_eq!(err.to_string(), "parsing line 2 of CHECKSUMS file");
+
+        let err = ChecksumManifest::parse("zzzzzzzz  1024  a.bin\n").unwrap_err();
+        assert_eq!(
+            format!("{:#}", err.root_cause()),
+            "invalid digit found in string"
+        );
+    }
+
+    #[test]
+    fn record_archives_concurrently() {
+        let data_dir = TempDir::new();
+        fs::create_dir(data_dir.path().join("archived")).unwrap();
+        let entry = |index| ChecksumEntry {
+            checksum: index,
+            len: 16,
+        };
+        let archive_path = |index| data_dir.path().join(format!("archived/{index:06}.bin"));
+
+        thread::scope(|scope| {
+            for index in 0..8 {
+                let archive_path = archive_path(index);
+                let data_dir = data_dir.path();
+                scope.spawn(move || {
+                    assert!(record_archive(data_dir, &archive_path, entry(index)).unwrap());
+                });
+            }
+        });
+
+        let manifest = ChecksumManifest::read(data_dir.path()).unwrap().unwrap();
+        assert_eq!(manifest.iter().count(), 8);
+
+        // Recording an archive again is only allowed with the same entry
+        assert!(!record_archive(data_dir.path(), &archive_path(3), entry(3)).unwrap());
+        assert!(record_archive(data_dir.path(), &archive_path(3), entry(4)).is_err());
+        forget_archive(data_dir.path(), &archive_path(3)).unwrap();
+        let manifest = ChecksumManifest::read(data_dir.path()).unwrap().unwrap();
+        assert_eq!(manifest.get("000003.bin"), None);
+
+        // No temporary files are left behind
+        let mut names = fs::read_dir(data_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["CHECKSUMS", "LOCK", "archived"]);
+    }
+}
@@ -0,0 +1,132 @@
+//! This module contains the implementation of the `compact` CLI command
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use argh::FromArgs;
+use jiff::Timestamp;
+use uom::si::{information::byte, u64::Information};
+
+use crate::{
+    archive::archive_name,
+    merge_settings::read_merge_settings,
+    output::Output,
+    store::compaction::{compact_archives, ArchiveSelection},
+    value::DEFAULT_MAX_DEPTH,
+};
+
+/// The `compact` sub-command merges the archives into a single archive which
+/// takes their place, so that reads merge fewer archives.
+///
+/// With `--oldest` or `--before` only the oldest archives are merged, leaving
+/// the recent archives untouched, so that it can run incrementally, like from
+/// cron.
+///
+/// With `--target-size` the merged value is split by its top-level keys into
+/// as many archives of at most that size as it takes, like `"64 MiB"`, so
+/// that no single archive grows unwieldy. A key which does not fit in an
+/// archive of that size on its own still gets an archive of its own.
+///
+/// Reads which are running while the archives are replaced may fail, and can
+/// be retried.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "compact")]
+pub struct CompactCommand {
+    /// compact this many of the oldest archives.
+    #[argh(option)]
+    oldest: Option<usize>,
+    /// compact the oldest archives created before this time, like
+    /// `2024-06-19T19:22:45Z`, up to the first archive which was not.
+    #[argh(option)]
+    before: Option<Timestamp>,
+    /// merge the archives with the settings in this TOML file instead of the
+    /// ones in the manifest.
+    #[argh(option)]
+    merge_settings: Option<PathBuf>,
+    /// split the compacted archive by its top-level keys into archives of at
+    /// most this size.
+    #[argh(option)]
+    target_size: Option<Information>,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the stored values, deeper values are rejected as an error.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+    /// write the compacted archives with direct IO on Linux, bypassing the
+    /// page cache.
+    #[argh(switch)]
+    direct_io: bool,
+}
+
+impl CompactCommand {
+    /// This function executes the compact command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf, output: Output) -> anyhow::Result<()> {
+        let selection = match (self.oldest, self.before) {
+            (None, None) => ArchiveSelection::All,
+            (Some(oldest), None) => ArchiveSelection::Oldest(oldest),
+            (None, Some(before)) => ArchiveSelection::Before(before),
+            (Some(_), Some(_)) => anyhow::bail!("expected at most one of --oldest or --before"),
+        };
+
+        let merge_config = self
+            .merge_settings
+            .as_deref()
+            .map(read_merge_settings)
+            .transpose()?;
+        let outcome = compact_archives(
+            &data_dir,
+            selection,
+            merge_config.as_ref(),
+            self.target_size
+                .map(|target_size| target_size.get::<byte>()),
+            self.max_nesting_depth,
+            self.direct_io,
+        )
+        .context("compacting archives")?;
+
+        if output.is_json() {
+            return output.write_result(
+                "compact",
+                serde_json::json!({
+                    "archives": outcome
+                        .archives
+                        .iter()
+                        .map(|path| archive_name(path))
+                        .collect::<Vec<_>>(),
+                    "archive_bytes": outcome.archive_bytes,
+                    "compacted_archives": outcome
+                        .compacted
+                        .iter()
+                        .map(|path| archive_name(path))
+                        .collect::<Vec<_>>(),
+                    "compacted_bytes": outcome.compacted_bytes,
+                }),
+            );
+        }
+
+        match outcome.archives.as_slice() {
+            [] => println!("fewer than two archives selected, nothing to compact"),
+            [archive] => println!(
+                "compacted {} archives ({} bytes) into '{}' ({} bytes)",
+                outcome.compacted.len(),
+                outcome.compacted_bytes,
+                archive_name(archive),
+                outcome.archive_bytes,
+            ),
+            archives => println!(
+                "compacted {} archives ({} bytes) into {} archives ({} bytes): {}",
+                outcome.compacted.len(),
+                outcome.compacted_bytes,
+                archives.len(),
+                outcome.archive_bytes,
+                archives
+                    .iter()
+                    .map(|archive| format!("'{}'", archive_name(archive)))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+        }
+
+        Ok(())
+    }
+}
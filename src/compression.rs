@@ -0,0 +1,160 @@
+//! This module contains the compression formats which input can be read in
+//! and output can be written in.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    str::FromStr,
+};
+
+/// A compressed stream format
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "gzip" => Self::Gzip,
+            "zstd" => Self::Zstd,
+            x => anyhow::bail!("'{x}' is an unknown option for the compression format"),
+        })
+    }
+}
+
+impl Compression {
+    /// Return the format whose magic number starts the given bytes, if any.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+        const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+        if header.starts_with(GZIP_MAGIC) {
+            Some(Self::Gzip)
+        } else if header.starts_with(ZSTD_MAGIC) {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// Wrap the reader so that it reads the decompressed stream.
+    ///
+    /// Concatenated gzip members and zstd frames are all read.
+    pub fn decoder<'r>(self, reader: impl BufRead + 'r) -> io::Result<Box<dyn BufRead + 'r>> {
+        Ok(match self {
+            Self::Gzip => Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(reader))),
+            Self::Zstd => Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(
+                reader,
+            )?)),
+        })
+    }
+}
+
+/// A writer which compresses everything written to it
+pub enum Encoder<W: Write> {
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+}
+
+impl Compression {
+    /// Wrap the writer so that everything written is compressed, which must
+    /// be completed by calling [`Encoder::finish`].
+    pub fn encoder<W: Write>(self, writer: W) -> io::Result<Encoder<W>> {
+        Ok(match self {
+            Self::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+                writer,
+                flate2::Compression::default(),
+            )),
+            Self::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(writer, 0)?),
+        })
+    }
+}
+
+impl<W: Write> Encoder<W> {
+    /// Write the end of the compressed stream and flush the inner writer.
+    pub fn finish(self) -> io::Result<()> {
+        let mut writer = match self {
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        };
+
+        writer.flush()
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use super::*;
+
+    fn round_trip(compression: Compression, compressed: Vec<u8>) {
+        assert_eq!(Compression::detect(&compressed), Some(compression));
+
+        let mut decompressed = String::new();
+        compression
+            .decoder(compressed.as_slice())
+            .unwrap()
+            .read_to_string(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, "{\"a\":1}\n{\"b\":2}\n");
+    }
+
+    #[test]
+    fn decode_gzip() {
+        // Two concatenated gzip members
+        let mut compressed = Vec::new();
+        for line in ["{\"a\":1}\n", "{\"b\":2}\n"] {
+            let mut encoder =
+                flate2::write::GzEncoder::new(&mut compressed, flate2::Compression::default());
+            encoder.write_all(line.as_bytes()).unwrap();
+            encoder.finish().unwrap();
+        }
+
+        round_trip(Compression::Gzip, compressed);
+    }
+
+    #[test]
+    fn decode_zstd() {
+        let compressed = zstd::encode_all(&b"{\"a\":1}\n{\"b\":2}\n"[..], 0).unwrap();
+
+        round_trip(Compression::Zstd, compressed);
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let mut compressed = Vec::new();
+            let mut encoder = compression.encoder(&mut compressed).unwrap();
+            encoder.write_all(b"{\"a\":1}\n{\"b\":2}\n").unwrap();
+            encoder.finish().unwrap();
+
+            round_trip(compression, compressed);
+        }
+    }
+
+    #[test]
+    fn detect_plain_json() {
+        assert_eq!(Compression::detect(b"{\"a\":1}"), None);
+        assert_eq!(Compression::detect(b""), None);
+    }
+}
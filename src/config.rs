@@ -0,0 +1,89 @@
+//! This module contains the implementation of the `config` CLI command
+
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+
+use crate::manifest::Manifest;
+
+/// Settings which are kept up to date by `wall-a` itself, and so cannot be
+/// changed with `config set`
+const MANAGED_SETTINGS: &[&str] = &["format_version", "next_sequence"];
+
+/// The `config` sub-command shows and changes the settings in the `MANIFEST`
+/// file of the data directory, like `config set array_behavior union`.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "config")]
+pub struct ConfigCommand {
+    #[argh(subcommand)]
+    action: ConfigAction,
+}
+
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand)]
+enum ConfigAction {
+    Get(ConfigGetCommand),
+    Set(ConfigSetCommand),
+}
+
+/// Print the value of a setting, or every setting as `<setting>  <value>`
+/// lines if none is given. Nothing is printed for a setting which is not set.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "get")]
+struct ConfigGetCommand {
+    /// the name of the setting, like `merge_mode`.
+    #[argh(positional)]
+    setting: Option<String>,
+}
+
+/// Change the value of a setting, after checking that the value is allowed.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "set")]
+struct ConfigSetCommand {
+    /// the name of the setting, like `array_behavior`.
+    #[argh(positional)]
+    setting: String,
+    /// the new value of the setting, like `union`.
+    #[argh(positional)]
+    value: String,
+}
+
+impl ConfigCommand {
+    /// This function executes the config command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
+        match self.action {
+            ConfigAction::Get(ConfigGetCommand { setting }) => {
+                let manifest = Manifest::read(&data_dir)?;
+                let stdout = io::stdout();
+                let mut handle = stdout.lock();
+
+                match setting {
+                    Some(setting) => {
+                        if let Some(value) = manifest.get(&setting)? {
+                            writeln!(handle, "{value}").context("writing setting to stdout")?;
+                        }
+                    }
+                    None => write!(handle, "{manifest}").context("writing settings to stdout")?,
+                }
+            }
+            ConfigAction::Set(ConfigSetCommand { setting, value }) => {
+                if MANAGED_SETTINGS.contains(&setting.as_str()) {
+                    anyhow::bail!("'{setting}' is managed by wall-a and cannot be changed");
+                }
+
+                Manifest::update(&data_dir, |manifest| {
+                    manifest
+                        .set(&setting, &value)
+                        .with_context(|| format!("setting '{setting}'"))
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
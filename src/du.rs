@@ -0,0 +1,372 @@
+//! This module contains the implementation of the `du` CLI command
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+
+use crate::{
+    archive::{archive_len, archive_name, list_archive_files, read_archive_key_sizes},
+    human::{human_bytes, ColorChoice, Style},
+    output::Output,
+    staging::staging_file_paths,
+    store::collect_archived_values,
+    value::DEFAULT_MAX_DEPTH,
+};
+
+/// The `du` sub-command reports how much storage the staging files and the
+/// archive files in the data directory take up.
+///
+/// When stdout is a terminal the sizes are written in units like `MiB`.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "du")]
+pub struct DuCommand {
+    /// also estimate how many bytes would be reclaimed by compacting all the
+    /// archives into one, this requires reading every archive.
+    #[argh(switch)]
+    reclaimable: bool,
+    /// also report how many bytes of the archives each top-level key takes
+    /// up, from the key sizes recorded at the end of each archive.
+    #[argh(switch)]
+    by_key: bool,
+    /// output the report as a JSON object instead of a table.
+    #[argh(switch)]
+    json: bool,
+    /// whether to write the table for humans and in color, either "auto"
+    /// (the default) when stdout is a terminal, "always", or "never" for a
+    /// table without color.
+    #[argh(option, default = "ColorChoice::Auto")]
+    color: ColorChoice,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the stored values, deeper values are rejected as an error.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+}
+
+impl DuCommand {
+    /// This function executes the du command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf, output: Output) -> anyhow::Result<()> {
+        let mut usage = StorageUsage::collect(&data_dir, self.reclaimable, self.max_nesting_depth)?;
+        if self.by_key {
+            usage.collect_key_usage(&data_dir)?;
+        }
+
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+
+        if self.json || output.is_json() {
+            serde_json::to_writer(&mut handle, &usage.to_json())
+                .context("writing usage report to stdout")?;
+            writeln!(handle).context("writing usage report to stdout")?;
+        } else {
+            usage
+                .write_table(handle, self.color.stdout_style())
+                .context("writing usage report to stdout")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The storage used by the data directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageUsage {
+    staging_bytes: u64,
+    /// The filename and size of each archive, in read order
+    archives: Vec<(String, u64)>,
+    /// The estimated number of bytes saved by compacting all archives into one
+    reclaimable_bytes: Option<u64>,
+    /// The bytes taken up by each top-level key, only collected if asked for
+    key_usage: Option<KeyUsage>,
+}
+
+/// The bytes of the archive bodies taken up by each top-level key
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct KeyUsage {
+    /// Each key with the bytes it takes up across all archives, largest first
+    keys: Vec<(String, u64)>,
+    /// The bytes of the archives written before key sizes were recorded
+    unrecorded_bytes: u64,
+}
+
+impl StorageUsage {
+    /// Measure the storage used by the data directory, optionally reading
+    /// every archive to estimate the bytes reclaimable by compaction.
+    pub fn collect(data_dir: &Path, reclaimable: bool, max_depth: usize) -> anyhow::Result<Self> {
+        let mut staging_bytes = 0;
+        for staging_file in staging_file_paths(data_dir)? {
+            staging_bytes += file_len(&staging_file)
+                .context("reading staging file metadata")?
+                .unwrap_or(0);
+        }
+
+        let archives = list_archive_files(data_dir)?
+            .into_iter()
+            .map(|path| {
+                let len = file_len(&path)
+                    .with_context(|| format!("reading metadata of '{}'", path.display()))?
+                    .unwrap_or(0);
+                Ok((archive_name(&path), len))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let reclaimable_bytes = if reclaimable {
+            let mut scratch_buffer = Vec::new();
+            let compacted_len = collect_archived_values(&mut scratch_buffer, data_dir, max_depth)
+                .context("collecting and merging all archived values")?
+                .map_or(0, |value| archive_len(&value));
+            let archive_bytes = archives.iter().map(|(_, len)| len).sum::<u64>();
+
+            Some(archive_bytes.saturating_sub(compacted_len))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            staging_bytes,
+            archives,
+            reclaimable_bytes,
+            key_usage: None,
+        })
+    }
+
+    /// Add up the key sizes recorded in the footer of every archive, without
+    /// reading the archive bodies.
+    pub fn collect_key_usage(&mut self, data_dir: &Path) -> anyhow::Result<()> {
+        let mut keys = BTreeMap::<String, u64>::new();
+        let mut unrecorded_bytes = 0;
+        for path in list_archive_files(data_dir)? {
+            let key_sizes = read_archive_key_sizes(&path)
+                .with_context(|| format!("reading key sizes of '{}'", path.display()))?;
+            match key_sizes {
+                Some(key_sizes) => {
+                    for (key, len) in key_sizes.iter() {
+                        *keys.entry(key.to_string()).or_default() += len;
+                    }
+                }
+                None => {
+                    unrecorded_bytes += file_len(&path)
+                        .with_context(|| format!("reading metadata of '{}'", path.display()))?
+                        .unwrap_or(0);
+                }
+            }
+        }
+
+        let mut keys = keys.into_iter().collect::<Vec<_>>();
+        keys.sort_by(|(a_key, a_len), (b_key, b_len)| b_len.cmp(a_len).then(a_key.cmp(b_key)));
+        self.key_usage = Some(KeyUsage {
+            keys,
+            unrecorded_bytes,
+        });
+
+        Ok(())
+    }
+
+    fn archive_bytes(&self) -> u64 {
+        self.archives.iter().map(|(_, len)| len).sum()
+    }
+
+    /// Return the report as a JSON object.
+    pub fn to_json(&self) -> serde_json::Value {
+        let archives = self
+            .archives
+            .iter()
+            .map(|(name, len)| serde_json::json!({ "name": name, "bytes": len }))
+            .collect::<Vec<_>>();
+
+        let mut report = serde_json::json!({
+            "staging_bytes": self.staging_bytes,
+            "archive_bytes": self.archive_bytes(),
+            "total_bytes": self.staging_bytes + self.archive_bytes(),
+            "archives": archives,
+        });
+        if let Some(reclaimable_bytes) = self.reclaimable_bytes {
+            report["reclaimable_bytes"] = reclaimable_bytes.into();
+        }
+        if let Some(key_usage) = &self.key_usage {
+            report["keys"] = key_usage
+                .keys
+                .iter()
+                .map(|(key, len)| serde_json::json!({ "key": key, "bytes": len }))
+                .collect();
+            report["unrecorded_key_bytes"] = key_usage.unrecorded_bytes.into();
+        }
+
+        report
+    }
+
+    /// Write the report as a table, with the sizes in bytes, or in units like
+    /// `MiB` if it is written for humans.
+    fn write_table(&self, mut writer: impl Write, human: Option<Style>) -> io::Result<()> {
+        let archive_bytes = self.archive_bytes();
+        let archives_label = format!("archives ({} files)", self.archives.len());
+
+        let mut rows = vec![
+            ("staging".to_string(), self.staging_bytes),
+            (archives_label, archive_bytes),
+        ];
+        rows.extend(
+            self.archives
+                .iter()
+                .map(|(name, len)| (format!("  {name}"), *len)),
+        );
+        rows.push(("total".to_string(), self.staging_bytes + archive_bytes));
+        if let Some(reclaimable_bytes) = self.reclaimable_bytes {
+            rows.push(("reclaimable by compaction".to_string(), reclaimable_bytes));
+        }
+        if let Some(key_usage) = &self.key_usage {
+            rows.push((
+                format!("keys ({} keys)", key_usage.keys.len()),
+                key_usage.keys.iter().map(|(_, len)| len).sum(),
+            ));
+            rows.extend(
+                key_usage
+                    .keys
+                    .iter()
+                    .map(|(key, len)| (format!("  {key}"), *len)),
+            );
+            if key_usage.unrecorded_bytes > 0 {
+                rows.push((
+                    "archives without key sizes".to_string(),
+                    key_usage.unrecorded_bytes,
+                ));
+            }
+        }
+
+        let label_width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+        for (label, bytes) in rows {
+            let Some(style) = human else {
+                writeln!(writer, "{label:<label_width$}  {bytes:>12}")?;
+                continue;
+            };
+
+            let line = format!("{label:<label_width$}  {:>12}", human_bytes(bytes));
+            if label == "total" {
+                writeln!(writer, "{}", style.bold(&line))?;
+            } else {
+                writeln!(writer, "{line}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Return the length of the file at the given path, or `None` if it does not
+/// exist.
+fn file_len(path: &Path) -> io::Result<Option<u64>> {
+    match fs::metadata(path) {
+        Ok(metadata) => Ok(Some(metadata.len())),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage() -> StorageUsage {
+        StorageUsage {
+            staging_bytes: 120,
+            archives: vec![
+                ("2024-06-19-19-22-45.bin".into(), 1000),
+                ("2024-06-20-19-22-45.bin".into(), 2000),
+            ],
+            reclaimable_bytes: Some(900),
+            key_usage: None,
+        }
+    }
+
+    #[test]
+    fn usage_table() {
+        let mut output = Vec::new();
+        usage().write_table(&mut output, None).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "staging                             120\n\
+             archives (2 files)                 3000\n  \
+             2024-06-19-19-22-45.bin          1000\n  \
+             2024-06-20-19-22-45.bin          2000\n\
+             total                              3120\n\
+             reclaimable by compaction           900\n"
+        );
+    }
+
+    #[test]
+    fn human_usage_table() {
+        let mut output = Vec::new();
+        usage()
+            .write_table(&mut output, Some(Style { color: false }))
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "staging                           120 B\n\
+             archives (2 files)              2.9 KiB\n  \
+             2024-06-19-19-22-45.bin        1000 B\n  \
+             2024-06-20-19-22-45.bin       2.0 KiB\n\
+             total                           3.0 KiB\n\
+             reclaimable by compaction         900 B\n"
+        );
+    }
+
+    #[test]
+    fn usage_by_key() {
+        let mut usage = usage();
+        usage.key_usage = Some(KeyUsage {
+            keys: vec![("metrics".into(), 1800), ("name".into(), 40)],
+            unrecorded_bytes: 1000,
+        });
+
+        let mut output = Vec::new();
+        usage.write_table(&mut output, None).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "staging                              120\n\
+             archives (2 files)                  3000\n  \
+             2024-06-19-19-22-45.bin           1000\n  \
+             2024-06-20-19-22-45.bin           2000\n\
+             total                               3120\n\
+             reclaimable by compaction            900\n\
+             keys (2 keys)                       1840\n  \
+             metrics                           1800\n  \
+             name                                40\n\
+             archives without key sizes          1000\n"
+        );
+
+        let report = usage.to_json();
+        assert_eq!(
+            report["keys"],
+            serde_json::json!([
+                {"key": "metrics", "bytes": 1800},
+                {"key": "name", "bytes": 40},
+            ])
+        );
+        assert_eq!(report["unrecorded_key_bytes"], 1000);
+    }
+
+    #[test]
+    fn usage_json() {
+        assert_eq!(
+            usage().to_json(),
+            serde_json::json!({
+                "staging_bytes": 120,
+                "archive_bytes": 3000,
+                "total_bytes": 3120,
+                "archives": [
+                    {"name": "2024-06-19-19-22-45.bin", "bytes": 1000},
+                    {"name": "2024-06-20-19-22-45.bin", "bytes": 2000},
+                ],
+                "reclaimable_bytes": 900,
+            })
+        );
+    }
+}
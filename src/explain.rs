@@ -0,0 +1,232 @@
+//! This module contains the implementation of `read --explain`, which shows
+//! how the merged value at a path was produced, one record at a time.
+
+use std::{io::Write, path::Path};
+
+use anyhow::Context;
+
+use crate::{
+    archive::{archive_name, list_archive_files, read_archive_value_and_hash},
+    manifest::Manifest,
+    query,
+    staging::StagingFileReader,
+    store::RepeatedArchives,
+    value::{arrays::ArrayRules, merge::MergeSettings, Value},
+};
+
+/// Write each record of the data directory which contributed to the value at
+/// the path, with the merge rule which combined it with the earlier records
+/// and the value that `read` would have output at the path afterwards.
+///
+/// Records which have nothing at the path and do not change it are left out,
+/// and archives repeating the archive before them are listed as skipped.
+/// Like `read`, the records of the staging file are merged together and then
+/// on top of the archives.
+pub fn explain(
+    mut writer: impl Write,
+    data_dir: &Path,
+    path: &query::Path,
+    max_depth: usize,
+    verify_checksums: bool,
+) -> anyhow::Result<()> {
+    let manifest = Manifest::read(data_dir)?;
+    let mut explanation = Explanation::new(path, manifest.merge_settings(), manifest.array_rules);
+
+    let mut scratch_buffer = Vec::<u8>::new();
+    let mut repeated = RepeatedArchives::default();
+    for archive_path in list_archive_files(data_dir)? {
+        scratch_buffer.clear();
+        let (value, body_hash) = read_archive_value_and_hash(
+            &archive_path,
+            &mut scratch_buffer,
+            max_depth,
+            verify_checksums,
+        )
+        .with_context(|| format!("reading archive value from '{}'", archive_path.display()))?;
+
+        if repeated.is_repeat(&archive_path, body_hash) {
+            if path.lookup(&value).is_some() {
+                writeln!(
+                    writer,
+                    "archive {}: skipped, identical to the archive before it",
+                    archive_name(&archive_path)
+                )
+                .context("writing explanation to stdout")?;
+            }
+            continue;
+        }
+
+        if let Some(step) = explanation.push(value, false) {
+            let source = format!("archive {}", archive_name(&archive_path));
+            step.write(&mut writer, &source)?;
+        }
+    }
+
+    let mut record_index = 0;
+    StagingFileReader::for_each_value(data_dir, max_depth, |value| {
+        record_index += 1;
+        match explanation.push(value, true) {
+            Some(step) => step.write(&mut writer, &format!("staging record {record_index}")),
+            None => Ok(()),
+        }
+    })
+    .context("reading values from staging file")?;
+
+    let result = explanation.current();
+    writeln!(writer, "result: {}", to_json(result.as_ref())?)
+        .context("writing explanation to stdout")
+}
+
+/// The records merged so far, kept apart like `read` does
+#[derive(Debug)]
+struct Explanation<'p> {
+    path: &'p query::Path,
+    merge_settings: MergeSettings,
+    array_rules: ArrayRules,
+    /// The merged value of the archives so far
+    archived: Option<Value>,
+    /// The merged value of the staging file records so far
+    staged: Option<Value>,
+}
+
+/// How a single record changed the value at the path
+#[derive(Debug, PartialEq)]
+struct Step {
+    /// The value of the record at the path, if it had one
+    record: Option<Value>,
+    /// How the record was merged at the path
+    rule: String,
+    /// The value at the path after the record, if there was one
+    value: Option<Value>,
+}
+
+impl<'p> Explanation<'p> {
+    fn new(path: &'p query::Path, merge_settings: MergeSettings, array_rules: ArrayRules) -> Self {
+        Self {
+            path,
+            merge_settings,
+            array_rules,
+            archived: None,
+            staged: None,
+        }
+    }
+
+    /// Merge the next record, from the staging file if `staged` is true,
+    /// returning how it changed the value at the path if it contributed.
+    fn push(&mut self, record: Value, staged: bool) -> Option<Step> {
+        let before = self.merged();
+        let before = before.as_ref().and_then(|merged| self.path.lookup(merged));
+        let record_at = self.path.lookup(&record).cloned();
+        let rule = match (before, &record_at) {
+            (_, None) => "replaced a parent".to_string(),
+            (None, Some(_)) => "set".to_string(),
+            (Some(before), Some(record_at)) => {
+                self.merge_settings.rule(before, record_at).to_string()
+            }
+        };
+        let previous = self.current();
+
+        let merged = if staged {
+            &mut self.staged
+        } else {
+            &mut self.archived
+        };
+        *merged = self
+            .merge_settings
+            .merge_optional(merged.take(), Some(record));
+
+        let value = self.current();
+        if record_at.is_none() && value == previous {
+            return None;
+        }
+
+        Some(Step {
+            record: record_at,
+            rule,
+            value,
+        })
+    }
+
+    /// Return the merged value before it is resolved, with the staging file
+    /// on top of the archives.
+    fn merged(&self) -> Option<Value> {
+        self.merge_settings
+            .merge_optional(self.archived.clone(), self.staged.clone())
+    }
+
+    /// Return the value at the path that `read` would output.
+    fn current(&self) -> Option<Value> {
+        self.merged()
+            .and_then(|merged| self.merge_settings.resolve(merged))
+            .and_then(|mut merged| {
+                self.array_rules.apply(&mut merged);
+                self.path.lookup(&merged).cloned()
+            })
+    }
+}
+
+impl Step {
+    fn write(&self, mut writer: impl Write, source: &str) -> anyhow::Result<()> {
+        writeln!(
+            writer,
+            "{source}: {}\n  record: {}\n  value:  {}",
+            self.rule,
+            to_json(self.record.as_ref())?,
+            to_json(self.value.as_ref())?
+        )
+        .context("writing explanation to stdout")
+    }
+}
+
+/// Convert the value to JSON, or `-` if it is missing.
+fn to_json(value: Option<&Value>) -> anyhow::Result<String> {
+    match value {
+        Some(value) => serde_json::to_string(value).context("converting value to JSON"),
+        None => Ok("-".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    macro_rules! json {
+        ($input:tt) => {
+            Value::from(::serde_json::json!($input))
+        };
+    }
+
+    #[test]
+    fn explain_steps() {
+        let path: query::Path = "metrics.tags".parse().unwrap();
+        let mut explanation =
+            Explanation::new(&path, MergeSettings::default(), ArrayRules::default());
+
+        assert_eq!(
+            explanation.push(json!({"metrics": {"tags": ["a"]}}), false),
+            Some(Step {
+                record: Some(json!(["a"])),
+                rule: "set".into(),
+                value: Some(json!(["a"])),
+            })
+        );
+        assert_eq!(explanation.push(json!({"other": 1}), false), None);
+        assert_eq!(
+            explanation.push(json!({"metrics": {"tags": ["b"]}}), true),
+            Some(Step {
+                record: Some(json!(["b"])),
+                rule: "concatenated arrays".into(),
+                value: Some(json!(["a", "b"])),
+            })
+        );
+        assert_eq!(
+            explanation.push(json!({"metrics": 0}), true),
+            Some(Step {
+                record: None,
+                rule: "replaced a parent".into(),
+                value: None,
+            })
+        );
+        assert_eq!(explanation.current(), None);
+    }
+}
@@ -0,0 +1,105 @@
+//! This module contains the implementation of the `export` CLI command
+
+use std::{path::PathBuf, str::FromStr};
+
+use argh::FromArgs;
+
+use crate::{
+    query,
+    store::read_merged_value,
+    table::{rows_at, Table},
+    value::DEFAULT_MAX_DEPTH,
+};
+
+/// The `export` sub-command writes the merged value to a file in a columnar
+/// format, with one column for each leaf path of the rows.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "export")]
+pub struct ExportCommand {
+    /// the format of the output file, currently only "parquet", which
+    /// requires the `parquet` feature.
+    #[argh(option)]
+    format: ExportFormat,
+    /// the path of the file to write, which is replaced if it exists.
+    #[argh(option)]
+    output: PathBuf,
+    /// write one row for each element of the array at this path, instead of
+    /// a single row for the whole merged value.
+    #[argh(option)]
+    rows: Option<query::Path>,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the stored values, deeper values are rejected as an error.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+}
+
+/// This enum lists the file formats that `export` can write
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// An Apache Parquet file
+    Parquet,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "parquet" => Self::Parquet,
+            x => anyhow::bail!("'{x}' is an unknown option for the export format"),
+        })
+    }
+}
+
+impl ExportCommand {
+    /// This function executes the export command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
+        let Some(final_value) = read_merged_value(&data_dir, self.max_nesting_depth)? else {
+            tracing::warn!("No data is present in archive or staging");
+            return Ok(());
+        };
+
+        let rows = rows_at(&final_value, self.rows.as_ref())?;
+        let table = Table::from_rows(rows);
+        tracing::info!(
+            rows = table.num_rows,
+            columns = table.columns.len(),
+            "Exporting table"
+        );
+
+        match self.format {
+            ExportFormat::Parquet => write_parquet(&table, &self.output),
+        }
+    }
+}
+
+/// Write the table as a Parquet file at the given path.
+#[cfg(not(feature = "parquet"))]
+fn write_parquet(_table: &Table, _output: &std::path::Path) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "wall-a was built without the `parquet` feature, so --format parquet is not available"
+    )
+}
+
+/// Write the table as a Parquet file at the given path.
+#[cfg(feature = "parquet")]
+fn write_parquet(table: &Table, output: &std::path::Path) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use parquet::arrow::ArrowWriter;
+
+    use crate::table::to_record_batch;
+
+    let batch = to_record_batch(table)?;
+    let file = std::fs::File::create(output)
+        .with_context(|| format!("creating output file '{}'", output.display()))?;
+
+    let mut writer =
+        ArrowWriter::try_new(file, batch.schema(), None).context("starting Parquet file")?;
+    writer
+        .write(&batch)
+        .context("writing rows to Parquet file")?;
+    writer.close().context("finishing Parquet file")?;
+
+    Ok(())
+}
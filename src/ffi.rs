@@ -0,0 +1,220 @@
+//! A C API over [`Store`], which is only available with the `ffi` feature.
+//!
+//! The declarations are in `include/wall_a.h`. Every function which can fail
+//! records the error message, which can be read with [`walla_last_error`]
+//! from the same thread.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, CStr, CString},
+    ptr,
+};
+
+use anyhow::Context;
+
+use crate::store::{RecordOutcome, Settings, Store};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Record the error so that it is returned by [`walla_last_error`].
+fn set_last_error(err: anyhow::Error) {
+    // Interior NUL bytes cannot be represented, so they are dropped
+    let message = format!("{err:#}").replace('\0', "");
+    let message = CString::new(message).expect("NUL bytes were removed");
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Convert a NUL-terminated string from the caller into a `&str`.
+///
+/// # Safety
+///
+/// The pointer must be NULL or point to a NUL-terminated string which lives
+/// for `'a`.
+unsafe fn str_arg<'a>(name: &str, value: *const c_char) -> anyhow::Result<&'a str> {
+    if value.is_null() {
+        anyhow::bail!("{name} is NULL");
+    }
+
+    CStr::from_ptr(value)
+        .to_str()
+        .with_context(|| format!("{name} is not valid UTF-8"))
+}
+
+/// Open the store in the given data directory, creating the directory if it
+/// does not exist.
+///
+/// Returns NULL on error. The store must be closed with
+/// [`walla_store_close`].
+///
+/// # Safety
+///
+/// `data_dir` must be NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn walla_store_open(data_dir: *const c_char) -> *mut Store {
+    let result = str_arg("data_dir", data_dir).and_then(|data_dir| {
+        Store::open(data_dir, Settings::default())
+            .with_context(|| format!("opening store in '{data_dir}'"))
+    });
+
+    match result {
+        Ok(store) => Box::into_raw(Box::new(store)),
+        Err(err) => {
+            set_last_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Append a single JSON record to the store.
+///
+/// Returns 0 if the record was appended or was a duplicate, 1 if the record
+/// was rejected, and -1 on error.
+///
+/// # Safety
+///
+/// `store` must be NULL or returned by [`walla_store_open`] and not yet
+/// closed, and `json` must be NULL or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn walla_store_append(store: *mut Store, json: *const c_char) -> c_int {
+    let result = (|| {
+        let store = store.as_mut().context("store is NULL")?;
+        let json = str_arg("json", json)?;
+
+        store.append(json.as_bytes())
+    })();
+
+    match result {
+        Ok(
+            RecordOutcome::Appended | RecordOutcome::DuplicateRecord | RecordOutcome::DuplicateId,
+        ) => 0,
+        Ok(RecordOutcome::Rejected(err)) => {
+            set_last_error(err);
+            1
+        }
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// Read the merged value of the store as a JSON string, which is `null` if
+/// nothing has been appended.
+///
+/// Returns NULL on error. The string must be freed with
+/// [`walla_string_free`].
+///
+/// # Safety
+///
+/// `store` must be NULL or returned by [`walla_store_open`] and not yet
+/// closed.
+#[no_mangle]
+pub unsafe extern "C" fn walla_store_read(store: *mut Store) -> *mut c_char {
+    let result = (|| {
+        let store = store.as_mut().context("store is NULL")?;
+        let value = store.read()?;
+        let json = serde_json::to_string(&value).context("converting value to JSON")?;
+
+        // JSON strings escape NUL, so this cannot fail
+        Ok(CString::new(json).expect("JSON has no NUL bytes"))
+    })();
+
+    match result {
+        Ok(json) => json.into_raw(),
+        Err(err) => {
+            set_last_error(err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Free a string returned by [`walla_store_read`]. Does nothing if `value` is
+/// NULL.
+///
+/// # Safety
+///
+/// `value` must be NULL or returned by [`walla_store_read`] and not yet
+/// freed.
+#[no_mangle]
+pub unsafe extern "C" fn walla_string_free(value: *mut c_char) {
+    if !value.is_null() {
+        drop(CString::from_raw(value));
+    }
+}
+
+/// Flush any buffered records and close the store.
+///
+/// Returns 0 on success and -1 if the records could not be flushed, in which
+/// case the store is still closed. Does nothing if `store` is NULL.
+///
+/// # Safety
+///
+/// `store` must be NULL or returned by [`walla_store_open`] and not yet
+/// closed.
+#[no_mangle]
+pub unsafe extern "C" fn walla_store_close(store: *mut Store) -> c_int {
+    if store.is_null() {
+        return 0;
+    }
+
+    match Box::from_raw(store).flush() {
+        Ok(()) => 0,
+        Err(err) => {
+            set_last_error(err);
+            -1
+        }
+    }
+}
+
+/// Return the message of the last error on this thread, or NULL if there has
+/// been none.
+///
+/// The string is owned by the library and is valid until the next call into
+/// the library on this thread.
+#[no_mangle]
+pub extern "C" fn walla_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn last_error() -> String {
+        let message = walla_last_error();
+        assert!(!message.is_null());
+        unsafe { CStr::from_ptr(message) }
+            .to_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn null_arguments() {
+        unsafe {
+            assert!(walla_store_open(ptr::null()).is_null());
+            assert_eq!(last_error(), "data_dir is NULL");
+
+            assert_eq!(walla_store_append(ptr::null_mut(), c"{}".as_ptr()), -1);
+            assert_eq!(last_error(), "store is NULL");
+
+            assert!(walla_store_read(ptr::null_mut()).is_null());
+            assert_eq!(last_error(), "store is NULL");
+
+            walla_string_free(ptr::null_mut());
+            assert_eq!(walla_store_close(ptr::null_mut()), 0);
+        }
+    }
+
+    #[test]
+    fn error_messages_drop_nul_bytes() {
+        set_last_error(anyhow::anyhow!("bad\0 value"));
+        assert_eq!(last_error(), "bad value");
+    }
+}
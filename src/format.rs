@@ -0,0 +1,1133 @@
+//! This module contains the encoding of archive files, which start with a
+//! fixed size [`Metadata`] header followed by the CBOR encoded value.
+//!
+//! In version 2 archives, the body is followed by the CRC32 checksum of each
+//! block of the body, so that corruption can be located within the body.
+//!
+//! In version 3 archives, the block checksums are followed by a footer with
+//! the size of each top-level key within the body, see [`KeySizes`]. The
+//! footer ends with its own length, so it can be read from the end of the
+//! file without reading the body.
+//!
+//! Archive files are read and written by the `wall-a` tool, while this module
+//! only works with bytes in memory, so that it can be used anywhere the
+//! [`Value`] type can.
+
+use std::{io::BufRead, mem, ops::Range};
+
+use anyhow::Context;
+use crc32fast::Hasher;
+use zerocopy::{AsBytes, FromBytes, FromZeroes, Unaligned};
+
+use crate::value::{self, Value};
+
+/// The version of the archive format which is written
+pub const FORMAT_VERSION: u32 = 3;
+
+const VERSION: [u8; 4] = u32::to_be_bytes(FORMAT_VERSION);
+// WALL•A
+const MAGIC: [u8; 8] = *b"WALL\xE2\x80\xA2A";
+
+/// The length of the metadata of version 1 archives, which end after the
+/// checksum
+const V1_LEN: usize = 16;
+
+/// The length of the blocks of the archive body which each have their own
+/// checksum, the last block may be shorter
+const BLOCK_LEN: u32 = 1 << 20;
+
+/// The length of the end of the footer, the length of the footer and the
+/// CRC32 checksum of the key sizes before it
+pub const FOOTER_TRAILER_LEN: usize = 8;
+
+/// The version of the tool writing archives, recorded in their metadata
+const TOOL_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// This struct contains metadata used to protect the archive file integrity,
+/// to order archive files, and to record where they came from.
+///
+/// Archives written by older versions have version 1 metadata, which is only
+/// the first 16 bytes and ends after the checksum. Version 2 metadata ends
+/// with a CRC32 checksum of the fields before it, so that corruption of the
+/// metadata itself is reported as such.
+#[derive(Debug, FromZeroes, FromBytes, Unaligned, AsBytes, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub struct Metadata {
+    magic: [u8; 8],
+    version: [u8; 4],
+    checksum: [u8; 4],
+    sequence: [u8; 8],
+    /// Seconds since the Unix epoch
+    created: [u8; 8],
+    body_len: [u8; 8],
+    /// ASCII padded with zeros
+    tool_version: [u8; 16],
+    block_len: [u8; 4],
+    header_checksum: [u8; 4],
+}
+
+impl Metadata {
+    /// Read the metadata from the start of an archive, in either version.
+    ///
+    /// The header checksum of version 2 metadata is verified before any other
+    /// field is used, so a corrupted version is not mistaken for an unknown
+    /// one.
+    pub fn from_reader(mut reader: impl BufRead) -> anyhow::Result<Self> {
+        let mut buf = Metadata::default();
+        reader
+            .read_exact(&mut buf.as_bytes_mut()[..V1_LEN])
+            .context("trying to read metadata")?;
+        // Check the magic before any other field, so that a file which is not
+        // an archive, like one which is still being written, is reported as
+        // such instead of as an unsupported version or a corrupted body
+        if buf.magic != MAGIC {
+            anyhow::bail!("not a wall-a archive, the file does not start with the archive magic");
+        }
+
+        let version = buf.version();
+        if version == 1 {
+            return Ok(buf);
+        }
+
+        let supported = (2..=FORMAT_VERSION).contains(&version);
+        if let Err(err) = reader.read_exact(&mut buf.as_bytes_mut()[V1_LEN..]) {
+            if supported {
+                return Err(err).context(format!("trying to read version {version} metadata"));
+            }
+            anyhow::bail!("archive version {version} is not supported");
+        }
+
+        buf.assert_header_checksum()?;
+        if !supported {
+            anyhow::bail!("archive version {version} is not supported");
+        }
+
+        Ok(buf)
+    }
+
+    /// Create a new metadata with the checksum and length of an archive body,
+    /// the sequence number of the archive, and the time it was created in
+    /// seconds since the Unix epoch.
+    pub fn new(checksum: u32, body_len: u64, sequence: u64, created: i64) -> Self {
+        let mut metadata = Self {
+            checksum: checksum.to_be_bytes(),
+            sequence: sequence.to_be_bytes(),
+            created: created.to_be_bytes(),
+            body_len: body_len.to_be_bytes(),
+            ..Self::default()
+        };
+        metadata.header_checksum = metadata.compute_header_checksum();
+
+        metadata
+    }
+
+    /// Return the CRC32 checksum of every field before the header checksum.
+    fn compute_header_checksum(&self) -> [u8; 4] {
+        let len = mem::size_of::<Self>() - self.header_checksum.len();
+        crc32fast::hash(&self.as_bytes()[..len]).to_be_bytes()
+    }
+
+    /// Returns `Ok(())` if the header checksum matches the other fields of
+    /// version 2 metadata, otherwise an error saying the metadata is
+    /// corrupted.
+    fn assert_header_checksum(&self) -> anyhow::Result<()> {
+        let header_checksum = self.compute_header_checksum();
+
+        if self.header_checksum != header_checksum {
+            anyhow::bail!(
+                "Archive metadata is corrupted, header checksum [{:08x}] did not match checksum \
+                 from the file metadata [{:08x}]",
+                u32::from_be_bytes(header_checksum),
+                u32::from_be_bytes(self.header_checksum),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Return the version of the archive format.
+    pub fn version(&self) -> u32 {
+        u32::from_be_bytes(self.version)
+    }
+
+    /// Return the number of bytes the metadata takes up at the start of the
+    /// archive, which depends on the version.
+    pub fn encoded_len(&self) -> usize {
+        if self.version() == 1 {
+            V1_LEN
+        } else {
+            mem::size_of::<Self>()
+        }
+    }
+
+    /// Return the bytes of the metadata as written at the start of the
+    /// archive.
+    pub fn to_bytes(&self) -> &[u8] {
+        &self.as_bytes()[..self.encoded_len()]
+    }
+
+    /// Return the CRC32 checksum of the archive body recorded in this
+    /// metadata.
+    pub fn checksum(&self) -> u32 {
+        u32::from_be_bytes(self.checksum)
+    }
+
+    /// Return the sequence number of the archive, which increases with every
+    /// archive written to a data directory.
+    ///
+    /// Returns `None` for version 1 archives, which were written before
+    /// sequence numbers.
+    pub fn sequence(&self) -> Option<u64> {
+        (self.version() != 1).then(|| u64::from_be_bytes(self.sequence))
+    }
+
+    /// Return the time the archive was created, in seconds since the Unix
+    /// epoch.
+    ///
+    /// Returns `None` for version 1 archives.
+    pub fn created(&self) -> Option<i64> {
+        (self.version() != 1).then(|| i64::from_be_bytes(self.created))
+    }
+
+    /// Return the length in bytes of the archive body, after the metadata.
+    ///
+    /// Returns `None` for version 1 archives.
+    pub fn body_len(&self) -> Option<u64> {
+        (self.version() != 1).then(|| u64::from_be_bytes(self.body_len))
+    }
+
+    /// Return the length of the blocks of the archive body which have their
+    /// own checksum.
+    ///
+    /// Returns `None` for version 1 archives, which have no block checksums.
+    pub fn block_len(&self) -> Option<u32> {
+        (self.version() != 1).then(|| u32::from_be_bytes(self.block_len))
+    }
+
+    /// Return the length in bytes of the body followed by the block checksums,
+    /// which is everything after the metadata except for the footer.
+    ///
+    /// Returns `None` for version 1 archives.
+    pub fn content_len(&self) -> Option<u64> {
+        let body_len = self.body_len()?;
+        Some(body_len + 4 * self.block_ranges(body_len).len() as u64)
+    }
+
+    /// Return the range of the body covered by each block checksum.
+    fn block_ranges(&self, body_len: u64) -> Vec<Range<u64>> {
+        let block_len = u64::from(self.block_len().unwrap_or_default().max(1));
+        (0..body_len.div_ceil(block_len))
+            .map(|index| index * block_len..((index + 1) * block_len).min(body_len))
+            .collect()
+    }
+
+    /// Return true if the block checksums are followed by a footer, which is
+    /// the case for version 3 archives.
+    pub fn has_footer(&self) -> bool {
+        self.version() >= 3
+    }
+
+    /// Return the version of the tool which wrote the archive, like `0.1.2`.
+    ///
+    /// Returns `None` for version 1 archives.
+    pub fn tool_version(&self) -> Option<String> {
+        (self.version() != 1).then(|| {
+            let len = self
+                .tool_version
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(self.tool_version.len());
+            String::from_utf8_lossy(&self.tool_version[..len]).into_owned()
+        })
+    }
+
+    /// Create a new metadata based on the content of the given archive body.
+    #[cfg(test)]
+    fn for_body(body: &[u8]) -> Self {
+        Self::new(crc32fast::hash(body), body.len() as u64, 0, 0)
+    }
+
+    /// Check everything after the metadata of an archive, the body, the block
+    /// checksums, and the footer, returning the body if it matches this
+    /// metadata.
+    ///
+    /// If the body is corrupted, the error lists the byte ranges of the body
+    /// whose blocks do not match their checksums.
+    pub fn verify_content<'c>(&self, content: &'c [u8]) -> anyhow::Result<&'c [u8]> {
+        let Some(body_len) = self.body_len() else {
+            self.assert_checksum(content)?;
+            return Ok(content);
+        };
+
+        let body = self.body(content)?;
+        self.key_sizes(content)?;
+        let (checksum, corrupt) = self.check_blocks(content, body_len);
+        if self.checksum != checksum {
+            let corrupt = if corrupt.is_empty() {
+                "unknown".to_string()
+            } else {
+                corrupt
+                    .iter()
+                    .map(|range| format!("{}..{}", range.start, range.end))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            anyhow::bail!(
+                "Checksum for given body [{:08x}] did not match checksum from the file metadata \
+                 [{:08x}], corrupt bytes of the body: {corrupt}",
+                u32::from_be_bytes(checksum),
+                u32::from_be_bytes(self.checksum),
+            );
+        } else if !corrupt.is_empty() {
+            anyhow::bail!("Block checksums did not match the body, which matches its checksum");
+        }
+
+        Ok(body)
+    }
+
+    /// Check each block of the body against its checksum, returning the
+    /// checksum of the whole body along with the byte ranges of the body
+    /// whose blocks do not match, where adjacent ranges are joined.
+    ///
+    /// The content must have the length checked by [`Self::body`].
+    fn check_blocks(&self, content: &[u8], body_len: u64) -> ([u8; 4], Vec<Range<u64>>) {
+        let content_len = self.content_len().unwrap_or_default() as usize;
+        let (body, block_checksums) = content[..content_len].split_at(body_len as usize);
+        let mut hasher = Hasher::new();
+        let mut corrupt = Vec::<Range<u64>>::new();
+        for (range, expected) in self
+            .block_ranges(body_len)
+            .into_iter()
+            .zip(block_checksums.chunks_exact(4))
+        {
+            let mut block_hasher = Hasher::new();
+            block_hasher.update(&body[range.start as usize..range.end as usize]);
+            if block_hasher.clone().finalize().to_be_bytes() != expected {
+                match corrupt.last_mut() {
+                    Some(last) if last.end == range.start => last.end = range.end,
+                    _ => corrupt.push(range),
+                }
+            }
+            hasher.combine(&block_hasher);
+        }
+
+        (hasher.finalize().to_be_bytes(), corrupt)
+    }
+
+    /// Return the body from everything after the metadata of an archive,
+    /// checking only its length and not its checksums.
+    pub fn body<'c>(&self, content: &'c [u8]) -> anyhow::Result<&'c [u8]> {
+        let (Some(body_len), Some(mut content_len)) = (self.body_len(), self.content_len()) else {
+            return Ok(content);
+        };
+
+        if self.has_footer() {
+            let trailer_start = content.len().saturating_sub(FOOTER_TRAILER_LEN);
+            content_len += footer_len(&content[trailer_start..])?;
+        }
+        if content.len() as u64 != content_len {
+            anyhow::bail!(
+                "Length of given archive content [{}] did not match length from the file \
+                 metadata [{content_len}]",
+                content.len()
+            );
+        }
+
+        Ok(&content[..body_len as usize])
+    }
+
+    /// Return the key sizes from the footer at the end of everything after the
+    /// metadata of an archive, checking the checksum of the footer.
+    ///
+    /// Returns `None` for archives before version 3, which have no footer.
+    pub fn key_sizes(&self, content: &[u8]) -> anyhow::Result<Option<KeySizes>> {
+        let (Some(content_len), true) = (self.content_len(), self.has_footer()) else {
+            return Ok(None);
+        };
+
+        let footer = content
+            .get(content_len as usize..)
+            .context("archive content ends before the footer")?;
+        KeySizes::decode(footer).map(Some)
+    }
+
+    /// Returns `Ok(())` if the given archive body matches the length and
+    /// checksum in this metadata.
+    ///
+    /// Otherwise it returns an error with a custom message about the mismatch.
+    pub fn assert_checksum(&self, body: &[u8]) -> anyhow::Result<()> {
+        if let Some(body_len) = self.body_len() {
+            if body.len() as u64 != body_len {
+                anyhow::bail!(
+                    "Length of given body [{}] did not match length from the file metadata [{body_len}]",
+                    body.len()
+                );
+            }
+        }
+
+        let checksum = crc32fast::hash(body).to_be_bytes();
+
+        if self.checksum != checksum {
+            Err(anyhow::anyhow!(
+                "Checksum for given body [{:08x}] did not match checksum from the file metadata [{:08x}]",
+                u32::from_be_bytes(checksum),
+                u32::from_be_bytes(self.checksum),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Return true if the given archive body matches the checksum in this metadata.
+    #[cfg(test)]
+    fn matches_body(&self, body: &[u8]) -> bool {
+        let checksum = crc32fast::hash(body).to_be_bytes();
+        self.checksum == checksum
+    }
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        let mut tool_version = [0; 16];
+        let len = TOOL_VERSION.len().min(tool_version.len());
+        tool_version[..len].copy_from_slice(&TOOL_VERSION.as_bytes()[..len]);
+
+        Self {
+            magic: MAGIC,
+            version: VERSION,
+            checksum: [0; 4],
+            sequence: [0; 8],
+            created: [0; 8],
+            body_len: [0; 8],
+            tool_version,
+            block_len: BLOCK_LEN.to_be_bytes(),
+            header_checksum: [0; 4],
+        }
+    }
+}
+
+/// The number of bytes each top-level key of an archive value takes up in
+/// the body, which is recorded in the footer of version 3 archives so that
+/// storage can be attributed to keys without decoding the body.
+///
+/// The size of a key is the encoded size of its entry in the object, the key
+/// along with its value. A value which is not an object has no keys.
+#[derive(Debug, Default, Clone, PartialEq, Eq, minicbor::Encode, minicbor::Decode)]
+#[cbor(transparent)]
+pub struct KeySizes(#[n(0)] Vec<(String, u64)>);
+
+impl KeySizes {
+    /// Measure the size of each top-level key of the value.
+    pub fn of(value: &Value) -> Self {
+        let Value::Object(entries) = value else {
+            return Self::default();
+        };
+
+        Self(
+            entries
+                .iter()
+                .map(|entry| (entry.0.clone(), minicbor::len(entry) as u64))
+                .collect(),
+        )
+    }
+
+    /// Return each key with its size in bytes, in the order of the object.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.0.iter().map(|(key, len)| (key.as_str(), *len))
+    }
+
+    /// Return the number of bytes the footer takes up, see [`Self::encode`].
+    pub fn encoded_len(&self) -> usize {
+        minicbor::len(&self.0) + FOOTER_TRAILER_LEN
+    }
+
+    /// Encode the footer of an archive, the CBOR key sizes followed by the
+    /// length of the whole footer and the CRC32 checksum of the key sizes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut footer = minicbor::to_vec(self).expect("writing to a vector never fails");
+        let checksum = crc32fast::hash(&footer);
+        let len = (footer.len() + FOOTER_TRAILER_LEN) as u32;
+        footer.extend_from_slice(&len.to_be_bytes());
+        footer.extend_from_slice(&checksum.to_be_bytes());
+
+        footer
+    }
+
+    /// Decode the footer of an archive, which must be exactly the bytes
+    /// written by [`Self::encode`].
+    pub fn decode(footer: &[u8]) -> anyhow::Result<Self> {
+        let len = footer_len(footer)?;
+        if footer.len() as u64 != len {
+            anyhow::bail!(
+                "Length of given footer [{}] did not match length from the footer [{len}]",
+                footer.len()
+            );
+        }
+
+        let (key_sizes, trailer) = footer.split_at(footer.len() - FOOTER_TRAILER_LEN);
+        let checksum = crc32fast::hash(key_sizes).to_be_bytes();
+        if checksum != trailer[4..] {
+            anyhow::bail!(
+                "Checksum for given footer [{:08x}] did not match checksum from the footer \
+                 [{:08x}]",
+                u32::from_be_bytes(checksum),
+                u32::from_be_bytes(trailer[4..].try_into().expect("4 bytes")),
+            );
+        }
+
+        minicbor::decode(key_sizes).context("decoding key sizes from footer")
+    }
+}
+
+/// The size in bytes of the archive file of an object, which is updated as
+/// entries are added to the object, so that the entries of an object can be
+/// split into archives of a bounded size without encoding each candidate.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ObjectArchiveLen {
+    entries: u64,
+    entries_len: u64,
+    key_sizes_len: u64,
+}
+
+impl ObjectArchiveLen {
+    /// Return the size with an entry added to the object, whose key is `key`
+    /// and whose encoded size is `len`, like in [`KeySizes`].
+    pub fn with_entry(self, key: &str, len: u64) -> Self {
+        Self {
+            entries: self.entries + 1,
+            entries_len: self.entries_len + len,
+            key_sizes_len: self.key_sizes_len + minicbor::len((key, len)) as u64,
+        }
+    }
+
+    /// Return the size in bytes of the archive file.
+    pub fn get(self) -> u64 {
+        // The body of an empty object ends with the number of its entries,
+        // zero, which takes up a single byte
+        let body_len = minicbor::len(Value::Object(Vec::new())) as u64 - 1
+            + minicbor::len(self.entries) as u64
+            + self.entries_len;
+        let block_checksums_len = 4 * body_len.div_ceil(u64::from(BLOCK_LEN));
+        let footer_len =
+            minicbor::len(self.entries) as u64 + self.key_sizes_len + FOOTER_TRAILER_LEN as u64;
+
+        mem::size_of::<Metadata>() as u64 + body_len + block_checksums_len + footer_len
+    }
+}
+
+/// Return the length of the whole footer of a version 3 archive, from the
+/// bytes at the end of the archive, which must include the trailer.
+pub fn footer_len(end: &[u8]) -> anyhow::Result<u64> {
+    let Some(trailer) = end
+        .len()
+        .checked_sub(FOOTER_TRAILER_LEN)
+        .map(|start| &end[start..])
+    else {
+        anyhow::bail!("archive is too short to contain a footer");
+    };
+
+    let len = u32::from_be_bytes(trailer[..4].try_into().expect("4 bytes"));
+    if (len as usize) < FOOTER_TRAILER_LEN {
+        anyhow::bail!("Length from the footer [{len}] is shorter than the end of the footer");
+    }
+
+    Ok(u64::from(len))
+}
+
+/// Computes the checksums of an archive body as it is written.
+#[derive(Debug, Default, Clone)]
+pub struct BodyHasher {
+    hasher: Hasher,
+    block_hasher: Hasher,
+    body_len: u64,
+    block_checksums: Vec<u8>,
+}
+
+impl BodyHasher {
+    /// Add the next bytes of the body.
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.hasher.update(bytes);
+
+        while !bytes.is_empty() {
+            let block_remaining = u64::from(BLOCK_LEN) - self.body_len % u64::from(BLOCK_LEN);
+            let (block, rest) = bytes.split_at(bytes.len().min(block_remaining as usize));
+            self.block_hasher.update(block);
+            self.body_len += block.len() as u64;
+            bytes = rest;
+
+            if self.body_len % u64::from(BLOCK_LEN) == 0 {
+                self.finish_block();
+            }
+        }
+    }
+
+    fn finish_block(&mut self) {
+        let checksum = mem::take(&mut self.block_hasher).finalize();
+        self.block_checksums
+            .extend_from_slice(&checksum.to_be_bytes());
+    }
+
+    /// Finish the body, returning the metadata of the archive with the given
+    /// sequence number and creation time, and the block checksums which are
+    /// written after the body.
+    pub fn finish(mut self, sequence: u64, created: i64) -> (Metadata, Vec<u8>) {
+        if self.body_len % u64::from(BLOCK_LEN) != 0 {
+            self.finish_block();
+        }
+
+        let metadata = Metadata::new(self.hasher.finalize(), self.body_len, sequence, created);
+        (metadata, self.block_checksums)
+    }
+}
+
+/// Encode the value as the bytes of an archive file with the given sequence
+/// number and creation time in seconds since the Unix epoch, the metadata
+/// followed by the CBOR body, the block checksums, and the footer.
+pub fn encode_archive(value: &Value, sequence: u64, created: i64) -> anyhow::Result<Vec<u8>> {
+    let body = minicbor::to_vec(value).context("encoding CBOR value")?;
+
+    let mut hasher = BodyHasher::default();
+    hasher.update(&body);
+    let (metadata, block_checksums) = hasher.finish(sequence, created);
+
+    let mut bytes = metadata.to_bytes().to_vec();
+    bytes.extend_from_slice(&body);
+    bytes.extend_from_slice(&block_checksums);
+    bytes.extend_from_slice(&KeySizes::of(value).encode());
+
+    Ok(bytes)
+}
+
+/// Decode the bytes of an archive file, verifying the checksums of the body.
+///
+/// The `max_depth` limits how deeply arrays and objects may be nested in the
+/// decoded value.
+pub fn decode_archive(bytes: &[u8], max_depth: usize) -> anyhow::Result<Value> {
+    let metadata =
+        Metadata::from_reader(bytes).context("archive is too short to contain metadata")?;
+    let body = metadata.verify_content(&bytes[metadata.encoded_len()..])?;
+
+    value::cbor::from_cbor_slice(body, max_depth)
+}
+
+/// The parts of the value of a corrupted archive which could be salvaged,
+/// see [`salvage_archive`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Salvage {
+    /// An object with the top-level keys whose entries were intact
+    pub value: Value,
+    /// The top-level keys whose entries overlap a corrupted block, in the
+    /// order of the object
+    pub lost_keys: Vec<String>,
+}
+
+/// Salvage the top-level keys of a corrupted archive whose entries lie
+/// entirely in blocks of the body which match their checksums.
+///
+/// Only version 3 archives can be salvaged, since the footer records where
+/// each entry is in the body, so that the intact entries can be decoded on
+/// their own. The metadata and the footer must be intact. The `max_depth`
+/// limits how deeply arrays and objects may be nested in the entries.
+pub fn salvage_archive(bytes: &[u8], max_depth: usize) -> anyhow::Result<Salvage> {
+    let metadata =
+        Metadata::from_reader(bytes).context("archive is too short to contain metadata")?;
+    let content = &bytes[metadata.encoded_len()..];
+    let body = metadata.body(content)?;
+    let Some(key_sizes) = metadata.key_sizes(content)? else {
+        anyhow::bail!(
+            "archives before version 3 do not record where each key is in the body, so no part \
+             of it can be salvaged"
+        );
+    };
+    if key_sizes.0.is_empty() {
+        anyhow::bail!("the archive value has no top-level keys, so no part of it can be salvaged");
+    }
+
+    let (_, corrupt) = metadata.check_blocks(content, body.len() as u64);
+    let entries_len = key_sizes.iter().map(|(_, len)| len).sum::<u64>();
+    // The entries are at the end of the body, after the start of the object
+    let Some(mut start) = (body.len() as u64).checked_sub(entries_len) else {
+        anyhow::bail!("the key sizes in the footer add up to more than the body");
+    };
+
+    let mut fields = Vec::new();
+    let mut lost_keys = Vec::new();
+    for (key, len) in key_sizes.iter() {
+        let range = start..start + len;
+        start = range.end;
+
+        let intact = !corrupt
+            .iter()
+            .any(|corrupt| corrupt.start < range.end && range.start < corrupt.end);
+        let field = intact
+            .then(|| {
+                value::cbor::field_from_cbor_slice(
+                    &body[range.start as usize..range.end as usize],
+                    max_depth,
+                )
+            })
+            .and_then(Result::ok)
+            .filter(|(field_key, _)| field_key == key);
+        match field {
+            Some(field) => fields.push(field),
+            None => lost_keys.push(key.to_string()),
+        }
+    }
+
+    Ok(Salvage {
+        value: Value::Object(fields),
+        lost_keys,
+    })
+}
+
+/// Decode the bytes of an archive file like [`decode_archive`], then check
+/// that the value encodes back to a body of the same length, or with
+/// `round_trip` to exactly the same bytes.
+///
+/// This catches bodies which pass their checksums but were written wrongly,
+/// like by a bug in the encoder. Returns the metadata of the archive.
+pub fn check_archive(bytes: &[u8], max_depth: usize, round_trip: bool) -> anyhow::Result<Metadata> {
+    let metadata =
+        Metadata::from_reader(bytes).context("archive is too short to contain metadata")?;
+    let body = metadata.verify_content(&bytes[metadata.encoded_len()..])?;
+    let value = value::cbor::from_cbor_slice(body, max_depth)?;
+
+    let encoded_len = minicbor::len(&value);
+    if encoded_len != body.len() {
+        anyhow::bail!(
+            "Value decoded from the body encodes to [{encoded_len}] bytes, but the body has [{}] \
+             bytes",
+            body.len()
+        );
+    }
+
+    if let Some(key_sizes) = metadata.key_sizes(&bytes[metadata.encoded_len()..])? {
+        if key_sizes != KeySizes::of(&value) {
+            anyhow::bail!("Key sizes in the footer did not match the value decoded from the body");
+        }
+    }
+
+    if round_trip {
+        let encoded = minicbor::to_vec(&value).context("encoding CBOR value")?;
+        if let Some(offset) = encoded.iter().zip(body).position(|(a, b)| a != b) {
+            anyhow::bail!(
+                "Value decoded from the body encodes to different bytes, starting at byte \
+                 [{offset}] of the body"
+            );
+        }
+    }
+
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::DEFAULT_MAX_DEPTH;
+
+    #[test]
+    fn create_metadata() {
+        let md = Metadata::for_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh");
+        assert_eq!(md.checksum, [191, 106, 231, 136]);
+        assert_eq!(md.magic, MAGIC);
+        assert_eq!(md.version, VERSION);
+
+        assert_eq!(
+            Metadata::for_body(b"hello sun goodbye moon").checksum,
+            [204, 119, 81, 28]
+        );
+        assert_eq!(
+            Metadata::for_body(b"hello moon goodbye sun").checksum,
+            [4, 104, 210, 191]
+        );
+        assert_eq!(
+            Metadata::for_body(b"hello mo0n goodbye sun").checksum,
+            [117, 247, 173, 212]
+        );
+        assert_eq!(Metadata::for_body(b"").checksum, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn metadata_body_matches() {
+        let md = Metadata::for_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh");
+        assert!(md.matches_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh"));
+
+        assert!(!md.matches_body(b"klasjdhfaklsdh asdk1fjhasldk aldkfjhaskdfjh"));
+        assert!(!md.matches_body(b""));
+        assert!(!md.matches_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh1"));
+    }
+
+    #[test]
+    fn metadata_as_bytes() {
+        let body = b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh";
+        let md = Metadata::new(crc32fast::hash(body), body.len() as u64, 7, 1_718_824_965);
+
+        let md_bytes = md.to_bytes();
+        assert_eq!(md_bytes.len(), 64);
+        assert_eq!(&md_bytes[..8], b"WALL\xE2\x80\xA2A");
+        assert_eq!(&md_bytes[8..12], &[0, 0, 0, 3]);
+        assert_eq!(&md_bytes[12..16], &[191, 106, 231, 136]);
+        assert_eq!(&md_bytes[16..24], &[0, 0, 0, 0, 0, 0, 0, 7]);
+        assert_eq!(&md_bytes[24..32], &1_718_824_965_i64.to_be_bytes());
+        assert_eq!(&md_bytes[32..40], &[0, 0, 0, 0, 0, 0, 0, 43]);
+        assert_eq!(
+            &md_bytes[40..40 + TOOL_VERSION.len()],
+            TOOL_VERSION.as_bytes()
+        );
+
+        assert_eq!(md.sequence(), Some(7));
+        assert_eq!(md.created(), Some(1_718_824_965));
+        assert_eq!(md.body_len(), Some(43));
+        assert_eq!(md.tool_version().as_deref(), Some(TOOL_VERSION));
+        assert_eq!(md.block_len(), Some(BLOCK_LEN));
+        assert_eq!(&md_bytes[56..60], &BLOCK_LEN.to_be_bytes());
+        assert_eq!(
+            &md_bytes[60..],
+            &crc32fast::hash(&md_bytes[..60]).to_be_bytes()
+        );
+
+        let md = Metadata::for_body(b"");
+
+        let md_bytes = md.to_bytes();
+        assert_eq!(md_bytes.len(), 64);
+        assert_eq!(&md_bytes[..8], b"WALL\xE2\x80\xA2A");
+        assert_eq!(&md_bytes[8..12], &[0, 0, 0, 3]);
+        assert_eq!(&md_bytes[12..16], &[0, 0, 0, 0]);
+        assert_eq!(&md_bytes[16..40], &[0; 24]);
+    }
+
+    #[test]
+    fn archive_round_trip() {
+        let value = Value::from(serde_json::json!({"a": [1, "two", null], "b": {"c": true}}));
+
+        let bytes = encode_archive(&value, 3, 0).unwrap();
+        assert_eq!(&bytes[..8], b"WALL\xE2\x80\xA2A");
+        assert_eq!(decode_archive(&bytes, 2).unwrap(), value);
+        assert!(decode_archive(&bytes, 0).is_err());
+        assert_eq!(
+            Metadata::from_reader(&bytes[..]).unwrap().sequence(),
+            Some(3)
+        );
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(decode_archive(&corrupted, 2).is_err());
+        assert!(decode_archive(&bytes[..4], 2).is_err());
+        assert!(decode_archive(&bytes[..20], 2).is_err());
+
+        let mut truncated = bytes.clone();
+        truncated.pop();
+        let err = decode_archive(&truncated, 2).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("Length of given archive content"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn metadata_from_bytes() {
+        let md = Metadata::from_reader(&b"WALL\xE2\x80\xA2A\x00\x00\x00\x01\x00\x00\x00\x00"[..])
+            .unwrap();
+        assert_eq!(md.magic, MAGIC);
+        assert_eq!(md.version(), 1);
+        assert_eq!(md.checksum, [0, 0, 0, 0]);
+        assert_eq!(md.sequence(), None);
+        assert_eq!(md.created(), None);
+        assert_eq!(md.body_len(), None);
+        assert_eq!(md.tool_version(), None);
+        assert_eq!(md.encoded_len(), 16);
+        assert!(md.matches_body(b""));
+
+        let md = Metadata::from_reader(&b"WALL\xE2\x80\xA2A\x00\x00\x00\x01\xBF\x6A\xE7\x88"[..])
+            .unwrap();
+        assert_eq!(md.magic, MAGIC);
+        assert_eq!(md.version(), 1);
+        assert_eq!(md.checksum, [191, 106, 231, 136]);
+        assert!(md.matches_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh"));
+
+        let mut bytes = b"WALL\xE2\x80\xA2A\x00\x00\x00\x02\xBF\x6A\xE7\x88".to_vec();
+        bytes.extend_from_slice(&256_u64.to_be_bytes());
+        bytes.extend_from_slice(&60_i64.to_be_bytes());
+        bytes.extend_from_slice(&43_u64.to_be_bytes());
+        bytes.extend_from_slice(b"0.9.0\0\0\0\0\0\0\0\0\0\0\0");
+        bytes.extend_from_slice(&16_u32.to_be_bytes());
+        bytes.extend_from_slice(&crc32fast::hash(&bytes).to_be_bytes());
+        let md = Metadata::from_reader(&bytes[..]).unwrap();
+        assert_eq!(md.version(), 2);
+        assert!(!md.has_footer());
+        assert_eq!(md.sequence(), Some(256));
+        assert_eq!(md.created(), Some(60));
+        assert_eq!(md.body_len(), Some(43));
+        assert_eq!(md.tool_version().as_deref(), Some("0.9.0"));
+        assert_eq!(md.block_len(), Some(16));
+        assert_eq!(md.content_len(), Some(43 + 3 * 4));
+        assert_eq!(md.encoded_len(), 64);
+        assert!(md.matches_body(b"klasjdhfaklsdh asdklfjhasldk aldkfjhaskdfjh"));
+        assert!(Metadata::from_reader(&bytes[..40]).is_err());
+
+        let err = Metadata::from_reader(&b"WALL\xE2\x80\xA2A\x00\x00\x00\x09\x00\x00\x00\x00"[..])
+            .unwrap_err();
+        assert_eq!(err.to_string(), "archive version 9 is not supported");
+
+        // A file which is not an archive, like a zero-filled file which is
+        // still being written, is rejected before its version is read
+        let err = Metadata::from_reader(&[0; 64][..]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "not a wall-a archive, the file does not start with the archive magic"
+        );
+    }
+
+    #[test]
+    fn corrupted_metadata() {
+        let bytes = Metadata::new(0, 0, 1, 60).to_bytes().to_vec();
+        assert!(Metadata::from_reader(&bytes[..]).is_ok());
+
+        // A corrupted version is reported as corruption, not as an unknown
+        // version
+        let mut corrupted = bytes.clone();
+        corrupted[11] = 6;
+        let err = Metadata::from_reader(&corrupted[..]).unwrap_err();
+        assert!(
+            err.to_string().starts_with("Archive metadata is corrupted"),
+            "{err}"
+        );
+
+        for index in [12, 20, 30, 45, 58, 62] {
+            let mut corrupted = bytes.clone();
+            corrupted[index] ^= 1;
+            assert!(Metadata::from_reader(&corrupted[..]).is_err(), "{index}");
+        }
+    }
+
+    #[test]
+    fn block_checksums() {
+        let body = vec![7; 2 * BLOCK_LEN as usize + 5];
+        let mut hasher = BodyHasher::default();
+        for chunk in body.chunks(1000) {
+            hasher.update(chunk);
+        }
+        let (md, block_checksums) = hasher.finish(0, 0);
+
+        assert_eq!(md.checksum(), crc32fast::hash(&body));
+        assert_eq!(md.content_len(), Some(body.len() as u64 + 12));
+        assert_eq!(
+            &block_checksums[8..],
+            &crc32fast::hash(&body[2 * BLOCK_LEN as usize..]).to_be_bytes()
+        );
+
+        let footer = KeySizes::default().encode();
+        let mut content = [body.as_slice(), &block_checksums, &footer].concat();
+        assert_eq!(md.verify_content(&content).unwrap(), body);
+
+        content[BLOCK_LEN as usize + 10] ^= 1;
+        let err = md.verify_content(&content).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("corrupt bytes of the body: 1048576..2097152"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn corrupt_blocks_are_located() {
+        let body = b"0123456789";
+        let mut md = Metadata::new(crc32fast::hash(body), body.len() as u64, 0, 0);
+        md.block_len = 4_u32.to_be_bytes();
+        md.header_checksum = md.compute_header_checksum();
+
+        let mut content = body.to_vec();
+        for block in [&body[..4], &body[4..8], &body[8..]] {
+            content.extend_from_slice(&crc32fast::hash(block).to_be_bytes());
+        }
+        let footer = KeySizes::default().encode();
+        content.extend_from_slice(&footer);
+        assert_eq!(md.verify_content(&content).unwrap(), body);
+
+        let mut corrupted = content.clone();
+        corrupted[1] ^= 1;
+        corrupted[5] ^= 1;
+        corrupted[9] ^= 1;
+        let err = md.verify_content(&corrupted).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("corrupt bytes of the body: 0..10"),
+            "{err}"
+        );
+
+        let mut corrupted = content.clone();
+        corrupted[9] ^= 1;
+        let err = md.verify_content(&corrupted).unwrap_err();
+        assert!(err.to_string().ends_with(": 8..10"), "{err}");
+
+        // The body is intact, but a block checksum is not
+        let mut corrupted = content.clone();
+        let footer_start = corrupted.len() - footer.len();
+        corrupted[footer_start - 1] ^= 1;
+        assert!(md.verify_content(&corrupted).is_err());
+        assert!(md.verify_content(&content[..12]).is_err());
+
+        // The body and block checksums are intact, but the footer is not
+        let mut corrupted = content.clone();
+        corrupted[footer_start] ^= 1;
+        assert!(md.verify_content(&corrupted).is_err());
+        assert!(md.verify_content(&content[..footer_start]).is_err());
+    }
+
+    #[test]
+    fn salvage_intact_keys() {
+        let value = Value::Object(
+            ["a", "b", "c", "d"]
+                .into_iter()
+                .map(|key| (key.to_string(), Value::String("x".repeat(600_000))))
+                .chain([("e".to_string(), Value::Bool(true))])
+                .collect(),
+        );
+        let mut bytes = encode_archive(&value, 0, 0).unwrap();
+        let body_start = Metadata::from_reader(bytes.as_slice())
+            .unwrap()
+            .encoded_len();
+
+        // The second block holds the end of "b", "c", and the start of "d"
+        bytes[body_start + BLOCK_LEN as usize + 10] ^= 1;
+        let salvage = salvage_archive(&bytes, DEFAULT_MAX_DEPTH).unwrap();
+        let Value::Object(fields) = &salvage.value else {
+            panic!("salvaged value is an object");
+        };
+        let keys = fields
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["a", "e"]);
+        assert_eq!(fields[0].1, Value::String("x".repeat(600_000)));
+        assert_eq!(salvage.lost_keys, ["b", "c", "d"]);
+
+        // The last block holds the end of "d" and all of "e"
+        let mut bytes = encode_archive(&value, 0, 0).unwrap();
+        bytes[body_start + 2 * BLOCK_LEN as usize + 10] ^= 1;
+        let salvage = salvage_archive(&bytes, DEFAULT_MAX_DEPTH).unwrap();
+        let Value::Object(fields) = &salvage.value else {
+            panic!("salvaged value is an object");
+        };
+        let keys = fields
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["a", "b", "c"]);
+        assert_eq!(salvage.lost_keys, ["d", "e"]);
+
+        // An archive which is not an object has no keys to salvage
+        let bytes = encode_archive(&Value::Bool(true), 0, 0).unwrap();
+        assert!(salvage_archive(&bytes, DEFAULT_MAX_DEPTH).is_err());
+    }
+
+    #[test]
+    fn salvage_needs_intact_footer() {
+        let value = Value::from(serde_json::json!({"a": "x".repeat(100), "b": true}));
+        let bytes = encode_archive(&value, 0, 0).unwrap();
+        let salvage = salvage_archive(&bytes, DEFAULT_MAX_DEPTH).unwrap();
+        assert_eq!(salvage.value, value);
+        assert!(salvage.lost_keys.is_empty());
+
+        // Without the key sizes there is no telling where each entry starts
+        let mut corrupted = bytes.clone();
+        let footer_byte = corrupted.len() - FOOTER_TRAILER_LEN - 1;
+        corrupted[footer_byte] ^= 1;
+        let error = salvage_archive(&corrupted, DEFAULT_MAX_DEPTH).unwrap_err();
+        assert!(format!("{error:#}").contains("footer"), "{error:#}");
+
+        let mut corrupted = bytes;
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        assert!(salvage_archive(&corrupted, DEFAULT_MAX_DEPTH).is_err());
+    }
+
+    #[test]
+    fn key_sizes_footer() {
+        let value = Value::from(serde_json::json!({"a": [1, "two", null], "b": {"c": true}}));
+        let key_sizes = KeySizes::of(&value);
+        let Value::Object(entries) = &value else {
+            unreachable!()
+        };
+        assert_eq!(
+            key_sizes.iter().collect::<Vec<_>>(),
+            [
+                ("a", minicbor::len(&entries[0]) as u64),
+                ("b", minicbor::len(&entries[1]) as u64)
+            ]
+        );
+
+        let footer = key_sizes.encode();
+        assert_eq!(footer.len(), key_sizes.encoded_len());
+        assert_eq!(footer_len(&footer).unwrap(), footer.len() as u64);
+        assert_eq!(KeySizes::decode(&footer).unwrap(), key_sizes);
+
+        let mut corrupted = footer.clone();
+        corrupted[2] ^= 1;
+        assert!(KeySizes::decode(&corrupted).is_err());
+        assert!(KeySizes::decode(&footer[1..]).is_err());
+        assert!(footer_len(&footer[..4]).is_err());
+
+        assert_eq!(KeySizes::of(&Value::Null), KeySizes::default());
+        let bytes = encode_archive(&value, 0, 0).unwrap();
+        let md = Metadata::from_reader(&bytes[..]).unwrap();
+        assert_eq!(
+            md.key_sizes(&bytes[md.encoded_len()..]).unwrap(),
+            Some(key_sizes)
+        );
+    }
+
+    #[test]
+    fn object_archive_len() {
+        // Enough entries that their number takes up more than one byte, and
+        // enough bytes for more than one block
+        let mut entries = Vec::new();
+        let mut len = ObjectArchiveLen::default();
+        for index in 0..300 {
+            let value = Value::String("x".repeat(index * 40));
+            let entry = (format!("key{index}"), value);
+            len = len.with_entry(&entry.0, minicbor::len(&entry) as u64);
+            entries.push(entry);
+
+            let bytes = encode_archive(&Value::Object(entries.clone()), 0, 0).unwrap();
+            assert_eq!(len.get(), bytes.len() as u64, "{} entries", entries.len());
+        }
+
+        let bytes = encode_archive(&Value::Object(Vec::new()), 0, 0).unwrap();
+        assert_eq!(ObjectArchiveLen::default().get(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn archive_structure() {
+        let value = Value::from(serde_json::json!({"a": [1, "two", null], "b": {"c": true}}));
+        let bytes = encode_archive(&value, 0, 0).unwrap();
+
+        assert_eq!(
+            check_archive(&bytes, 2, true).unwrap(),
+            Metadata::from_reader(&bytes[..]).unwrap()
+        );
+        assert!(check_archive(&bytes, 1, false).is_err());
+
+        // A body which passes its checksums, but holds a string with its
+        // length in an extra byte, which is never written
+        let mut body = vec![0x82, 0x03, 0x81, 0x78, 0x01, b'b'];
+        let value = value::cbor::from_cbor_slice(&body, 2).unwrap();
+        assert_eq!(value, Value::String("b".into()));
+
+        let mut hasher = BodyHasher::default();
+        hasher.update(&body);
+        let (metadata, block_checksums) = hasher.finish(0, 0);
+        let mut bytes = metadata.to_bytes().to_vec();
+        bytes.append(&mut body);
+        bytes.extend_from_slice(&block_checksums);
+        bytes.extend_from_slice(&KeySizes::default().encode());
+
+        assert_eq!(decode_archive(&bytes, 2).unwrap(), value);
+        let err = check_archive(&bytes, 2, false).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Value decoded from the body encodes to [5] bytes, but the body has [6] bytes"
+        );
+    }
+}
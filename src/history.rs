@@ -0,0 +1,202 @@
+//! This module contains the implementation of the `history` CLI command
+
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+use jiff::Timestamp;
+
+use crate::{
+    archive::{
+        display_timestamp, list_archive_files_with_timestamps, modified_timestamp,
+        read_archive_value,
+    },
+    manifest::Manifest,
+    query,
+    staging::{staging_file_paths, StagingFileReader},
+    value::{arrays::ArrayRules, merge::MergeSettings, Value, DEFAULT_MAX_DEPTH},
+};
+
+/// The `history` sub-command prints each successive value held by a path,
+/// like `history metrics.cpu`, by merging the archives and the staging file
+/// one record at a time.
+///
+/// Each line is the time of the record which changed the value and the new
+/// value as JSON, separated by a tab. The value is left empty if the path was
+/// removed. Each archive counts as a single record at the time it was
+/// created, and each record in the staging file counts as written when the
+/// staging file was last modified. Times are shown in the `timezone` from
+/// the MANIFEST, or in UTC if it is not set.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "history")]
+pub struct HistoryCommand {
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the stored values, deeper values are rejected as an error.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+    /// the path to follow, like `metrics.cpu`.
+    #[argh(positional)]
+    path: query::Path,
+}
+
+impl HistoryCommand {
+    /// This function executes the history command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
+        let manifest = Manifest::read(&data_dir)?;
+        let merge_settings = manifest.merge_settings();
+        let time_zone = manifest.time_zone()?;
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+
+        let mut timeline = Timeline::new(&self.path, merge_settings, manifest.array_rules);
+        let mut write_change = |timestamp: Timestamp, value: Option<&Value>| {
+            let value = value
+                .map(serde_json::to_string)
+                .transpose()
+                .context("converting value to JSON")?
+                .unwrap_or_default();
+            let timestamp = display_timestamp(timestamp, time_zone.as_ref());
+            writeln!(handle, "{timestamp}\t{value}").context("writing history to stdout")
+        };
+
+        let mut scratch_buffer = Vec::<u8>::new();
+        for (timestamp, path) in list_archive_files_with_timestamps(&data_dir)? {
+            scratch_buffer.clear();
+            let value = read_archive_value(&path, &mut scratch_buffer, self.max_nesting_depth)
+                .context("reading archive value")?;
+
+            if let Some(change) = timeline.push_archived(value) {
+                write_change(timestamp, change.as_ref())?;
+            }
+        }
+
+        for staging_file in staging_file_paths(&data_dir)? {
+            if !staging_file.exists() {
+                continue;
+            }
+            let timestamp = modified_timestamp(&staging_file)?;
+            StagingFileReader::for_each_value_in(
+                &[staging_file],
+                self.max_nesting_depth,
+                |value| match timeline.push_staged(value) {
+                    Some(change) => write_change(timestamp, change.as_ref()),
+                    None => Ok(()),
+                },
+            )
+            .context("reading values from staging file")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The values held by a path as records are merged one at a time
+#[derive(Debug)]
+struct Timeline<'p> {
+    path: &'p query::Path,
+    merge_settings: MergeSettings,
+    array_rules: ArrayRules,
+    /// The merged value of the archives so far
+    archived: Option<Value>,
+    /// The merged value of the staging file records so far
+    staged: Option<Value>,
+    /// The value at the path after the last record, `None` if it was missing
+    current: Option<Value>,
+}
+
+impl<'p> Timeline<'p> {
+    fn new(path: &'p query::Path, merge_settings: MergeSettings, array_rules: ArrayRules) -> Self {
+        Self {
+            path,
+            merge_settings,
+            array_rules,
+            archived: None,
+            staged: None,
+            current: None,
+        }
+    }
+
+    /// Merge the value of the next archive, returning the new value at the
+    /// path if it changed.
+    fn push_archived(&mut self, value: Value) -> Option<Option<Value>> {
+        self.archived = self
+            .merge_settings
+            .merge_optional(self.archived.take(), Some(value));
+
+        self.update()
+    }
+
+    /// Merge the next record of the staging file, returning the new value at
+    /// the path if it changed.
+    fn push_staged(&mut self, value: Value) -> Option<Option<Value>> {
+        self.staged = self
+            .merge_settings
+            .merge_optional(self.staged.take(), Some(value));
+
+        self.update()
+    }
+
+    fn update(&mut self) -> Option<Option<Value>> {
+        // The staging file is merged on top of the archives as a whole, like
+        // the `read` command does
+        let merged = self
+            .merge_settings
+            .merge_optional(self.archived.clone(), self.staged.clone());
+        let value = merged
+            .and_then(|merged| self.merge_settings.resolve(merged))
+            .and_then(|mut merged| {
+                self.array_rules.apply(&mut merged);
+                self.path.lookup(&merged).cloned()
+            });
+
+        if value == self.current {
+            return None;
+        }
+        self.current = value.clone();
+
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(value: serde_json::Value) -> Value {
+        Value::from(value)
+    }
+
+    #[test]
+    fn changes_at_path() {
+        let path = "metrics.cpu".parse().unwrap();
+        let mut timeline = Timeline::new(&path, MergeSettings::default(), ArrayRules::default());
+
+        assert_eq!(
+            timeline.push_archived(json(serde_json::json!({"metrics": {"mem": 1}}))),
+            None
+        );
+        assert_eq!(
+            timeline.push_archived(json(serde_json::json!({"metrics": {"cpu": 5}}))),
+            Some(Some(json(serde_json::json!(5))))
+        );
+        assert_eq!(
+            timeline.push_staged(json(serde_json::json!({"metrics": {"cpu": 5, "mem": 2}}))),
+            None
+        );
+        assert_eq!(
+            timeline.push_staged(json(serde_json::json!({"metrics": {"cpu": 7}}))),
+            Some(Some(json(serde_json::json!(7))))
+        );
+        assert_eq!(
+            timeline.push_staged(Value::Object(vec![(
+                "metrics".into(),
+                Value::Object(vec![("cpu".into(), Value::tombstone())]),
+            )])),
+            Some(None)
+        );
+    }
+}
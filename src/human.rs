@@ -0,0 +1,183 @@
+//! This module contains the human-friendly output of the reports of `list`,
+//! `verify`, and `du`, which are written with sizes like `1.5 MiB`, times
+//! like `3 hours ago`, and colors when stdout is a terminal.
+
+use std::{
+    io::{self, IsTerminal},
+    str::FromStr,
+};
+
+use jiff::Timestamp;
+
+/// This enum controls whether a report is written for humans, and in color
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Write for humans if stdout is a terminal, in color unless the
+    /// `NO_COLOR` environment variable is set
+    #[default]
+    Auto,
+    /// Always write for humans, in color
+    Always,
+    /// Write for humans if stdout is a terminal, without color
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => Self::Auto,
+            "always" => Self::Always,
+            "never" => Self::Never,
+            x => anyhow::bail!(
+                "'{x}' is an unknown option for the color, expected auto, always, or never"
+            ),
+        })
+    }
+}
+
+impl ColorChoice {
+    /// Return the style of the report on stdout, or `None` if it should be
+    /// written as a plain table for scripts.
+    pub fn stdout_style(self) -> Option<Style> {
+        let terminal = io::stdout().is_terminal();
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+
+        match self {
+            Self::Auto => terminal.then_some(Style { color: !no_color }),
+            Self::Always => Some(Style { color: true }),
+            Self::Never => terminal.then_some(Style { color: false }),
+        }
+    }
+}
+
+/// The colors of a human-friendly report, which are left out unless `color`
+/// is set
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Style {
+    pub color: bool,
+}
+
+impl Style {
+    /// Return the text in green, for parts which are intact.
+    pub fn green(self, text: &str) -> String {
+        self.paint("32", text)
+    }
+
+    /// Return the text in red, for problems.
+    pub fn red(self, text: &str) -> String {
+        self.paint("31", text)
+    }
+
+    /// Return the text in bold, for totals.
+    pub fn bold(self, text: &str) -> String {
+        self.paint("1", text)
+    }
+
+    /// Return the text dimmed, for missing values.
+    pub fn dim(self, text: &str) -> String {
+        self.paint("2", text)
+    }
+
+    fn paint(self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// Format a number of bytes with a binary unit, like `512 B` or `1.5 MiB`.
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["KiB", "MiB", "GiB", "TiB", "PiB"];
+
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = UNITS[0];
+    for next_unit in &UNITS[1..] {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = next_unit;
+    }
+
+    format!("{size:.1} {unit}")
+}
+
+/// Format how long before `now` the timestamp was, like `3 hours ago`.
+pub fn relative_time(timestamp: Timestamp, now: Timestamp) -> String {
+    const UNITS: [(i64, &str); 5] = [
+        (365 * 24 * 60 * 60, "year"),
+        (30 * 24 * 60 * 60, "month"),
+        (24 * 60 * 60, "day"),
+        (60 * 60, "hour"),
+        (60, "minute"),
+    ];
+
+    let seconds = now.as_second() - timestamp.as_second();
+    if seconds < 0 {
+        return "in the future".to_string();
+    }
+
+    let (count, unit) = UNITS
+        .iter()
+        .find(|(unit_seconds, _)| seconds >= *unit_seconds)
+        .map_or((seconds, "second"), |(unit_seconds, unit)| {
+            (seconds / unit_seconds, *unit)
+        });
+    if count == 1 {
+        format!("1 {unit} ago")
+    } else {
+        format!("{count} {unit}s ago")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!(human_bytes(0), "0 B");
+        assert_eq!(human_bytes(1023), "1023 B");
+        assert_eq!(human_bytes(1024), "1.0 KiB");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+
+    #[test]
+    fn relative_times() {
+        let now: Timestamp = "2024-06-20T19:22:45Z".parse().unwrap();
+        let ago = |seconds: i64| {
+            relative_time(
+                Timestamp::from_second(now.as_second() - seconds).unwrap(),
+                now,
+            )
+        };
+
+        assert_eq!(ago(0), "0 seconds ago");
+        assert_eq!(ago(1), "1 second ago");
+        assert_eq!(ago(90), "1 minute ago");
+        assert_eq!(ago(3 * 60 * 60), "3 hours ago");
+        assert_eq!(ago(2 * 24 * 60 * 60 + 5), "2 days ago");
+        assert_eq!(ago(400 * 24 * 60 * 60), "1 year ago");
+        assert_eq!(ago(-5), "in the future");
+    }
+
+    #[test]
+    fn styles() {
+        assert_eq!(Style { color: false }.red("corrupt"), "corrupt");
+        assert_eq!(
+            Style { color: true }.red("corrupt"),
+            "\x1b[31mcorrupt\x1b[0m"
+        );
+        assert_eq!("never".parse::<ColorChoice>().unwrap(), ColorChoice::Never);
+        assert!("sometimes".parse::<ColorChoice>().is_err());
+    }
+}
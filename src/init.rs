@@ -0,0 +1,121 @@
+//! This module contains the implementation of the `init` CLI command
+
+use std::{
+    fs::DirBuilder,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+
+use crate::{
+    archive::archive_dir, format::FORMAT_VERSION, manifest::Manifest, output::Output,
+    store::ttl::TtlRules, value::merge::MergeMode,
+};
+
+/// The `init` sub-command creates a data directory with its `archived`
+/// directory and a `MANIFEST` file holding its settings, readable only by
+/// the current user.
+///
+/// The other commands refuse to use a data directory which was not created
+/// by `init` unless `--force` is given. Passing `--force` to `init` adopts a
+/// directory which is not empty, like one written by an older version,
+/// keeping any settings that are not given.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "init")]
+pub struct InitCommand {
+    /// how records are merged, either "ordered" (the default) or "crdt" so
+    /// that the result is the same in any order.
+    #[argh(option)]
+    merge_mode: Option<MergeMode>,
+    /// the time zone of archive filenames and reported times, like
+    /// `Europe/Paris`, UTC if not given.
+    #[argh(option)]
+    timezone: Option<String>,
+    /// a time-to-live rule for the `TTL` file, like `"sessions.*  12h"`, can
+    /// be repeated.
+    #[argh(option)]
+    ttl: Vec<String>,
+}
+
+impl InitCommand {
+    /// This function executes the init command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf, force: bool, output: Output) -> anyhow::Result<()> {
+        let mut manifest = if force {
+            Manifest::read(&data_dir)?
+        } else {
+            if Manifest::exists(&data_dir) {
+                anyhow::bail!("'{}' is already initialized", data_dir.display());
+            }
+            if !is_empty_dir(&data_dir)? {
+                anyhow::bail!(
+                    "'{}' is not empty, pass --force to initialize it anyway",
+                    data_dir.display()
+                );
+            }
+            Manifest::default()
+        };
+
+        manifest.format_version = Some(FORMAT_VERSION);
+        if let Some(merge_mode) = self.merge_mode {
+            manifest.merge_mode = merge_mode;
+        }
+        if let Some(timezone) = self.timezone {
+            manifest.timezone = Some(timezone);
+        }
+        manifest.time_zone()?;
+
+        create_private_dir(&data_dir).context("creating data directory")?;
+        create_private_dir(&archive_dir(&data_dir)).context("creating archived directory")?;
+
+        if !self.ttl.is_empty() {
+            TtlRules::write(&data_dir, &self.ttl)?;
+        }
+        manifest.write(&data_dir)?;
+
+        tracing::info!(data_dir = %data_dir.display(), "Initialized data directory");
+
+        output.write_result(
+            "init",
+            serde_json::json!({
+                "data_dir": data_dir.display().to_string(),
+                "format_version": FORMAT_VERSION,
+                "adopted": force,
+            }),
+        )
+    }
+}
+
+fn is_empty_dir(dir: &Path) -> anyhow::Result<bool> {
+    match dir.read_dir() {
+        Ok(mut entries) => Ok(entries.next().is_none()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(true),
+        Err(err) => Err(err).with_context(|| format!("reading entries of '{}'", dir.display())),
+    }
+}
+
+/// Create the directory and any missing parents, and make it accessible only
+/// to the current user.
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true);
+
+    #[cfg(unix)]
+    {
+        use std::{
+            fs,
+            os::unix::fs::{DirBuilderExt, PermissionsExt},
+        };
+
+        builder.mode(0o700).create(dir)?;
+        // An existing directory keeps its mode when created again
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+    }
+
+    #[cfg(not(unix))]
+    {
+        builder.create(dir)
+    }
+}
@@ -0,0 +1,35 @@
+//! WALL•A is a tool for incrementally storing JSON data and then compacting
+//! it once it reaches a certain size.
+//!
+//! The core of this library does not touch the filesystem: the
+//! [`value::Value`] type with the merge function, and the encoding of archive
+//! files. It builds without any features, including for
+//! `wasm32-unknown-unknown`, so the same merge semantics can be used anywhere.
+//!
+//! The `store` feature adds the storage engine which manages a data
+//! directory, see [`store::Store`]. The `ffi` feature exposes that engine
+//! through a C API, see [`ffi`], and the `python` feature through a Python
+//! module named `wall_a`.
+
+#[cfg(feature = "store")]
+pub mod archive;
+#[cfg(feature = "store")]
+mod atomic_file;
+#[cfg(feature = "store")]
+pub mod checksums;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod format;
+#[cfg(feature = "store")]
+pub mod manifest;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "store")]
+pub mod sources;
+#[cfg(feature = "store")]
+pub mod staging;
+#[cfg(feature = "store")]
+pub mod store;
+#[cfg(all(test, feature = "store"))]
+mod test_dir;
+pub mod value;
@@ -0,0 +1,281 @@
+//! This module contains the implementation of the `list` CLI command
+
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+use jiff::{tz::TimeZone, Timestamp};
+
+use crate::{
+    archive::{archive_name, display_timestamp, list_archive_files, read_archive_metadata},
+    human::{human_bytes, relative_time, ColorChoice, Style},
+    manifest::Manifest,
+    output::Output,
+};
+
+/// The `list` sub-command lists the archive files in the data directory in
+/// the order they are read, along with the provenance recorded in their
+/// metadata.
+///
+/// Archives written by older versions do not record anything but their
+/// checksum, so the other columns are left as `-`.
+///
+/// When stdout is a terminal the table shows how long ago each archive was
+/// created and the size of its body in units like `KiB`.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "list")]
+pub struct ListCommand {
+    /// output the list as a JSON array instead of a table.
+    #[argh(switch)]
+    json: bool,
+    /// whether to write the table for humans and in color, either "auto"
+    /// (the default) when stdout is a terminal, "always", or "never" for a
+    /// table without color.
+    #[argh(option, default = "ColorChoice::Auto")]
+    color: ColorChoice,
+}
+
+impl ListCommand {
+    /// This function executes the list command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf, output: Output) -> anyhow::Result<()> {
+        let time_zone = Manifest::read(&data_dir)?.time_zone()?;
+        let archives = list_archive_files(&data_dir)?
+            .into_iter()
+            .map(|path| {
+                let metadata = read_archive_metadata(&path)
+                    .with_context(|| format!("reading metadata of '{}'", path.display()))?;
+
+                Ok(ArchiveInfo {
+                    name: archive_name(&path),
+                    version: metadata.version(),
+                    sequence: metadata.sequence(),
+                    created: metadata
+                        .created()
+                        .and_then(|created| Timestamp::from_second(created).ok()),
+                    tool_version: metadata.tool_version(),
+                    body_len: metadata.body_len(),
+                })
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+
+        if self.json || output.is_json() {
+            let archives = archives
+                .iter()
+                .map(|archive| archive.to_json(time_zone.as_ref()))
+                .collect();
+            serde_json::to_writer(&mut handle, &serde_json::Value::Array(archives))
+                .context("writing archive list to stdout")?;
+            writeln!(handle).context("writing archive list to stdout")?;
+        } else {
+            let format = TableFormat {
+                time_zone: time_zone.as_ref(),
+                human: self
+                    .color
+                    .stdout_style()
+                    .map(|style| (style, Timestamp::now())),
+            };
+            write_table(handle, &archives, &format).context("writing archive list to stdout")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The metadata of a single archive file
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ArchiveInfo {
+    name: String,
+    version: u32,
+    sequence: Option<u64>,
+    created: Option<Timestamp>,
+    tool_version: Option<String>,
+    body_len: Option<u64>,
+}
+
+impl ArchiveInfo {
+    /// Return the metadata as JSON, with the creation time formatted in the
+    /// time zone from the MANIFEST.
+    fn to_json(&self, time_zone: Option<&TimeZone>) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "version": self.version,
+            "sequence": self.sequence,
+            "created": self
+                .created
+                .map(|created| display_timestamp(created, time_zone)),
+            "tool_version": self.tool_version,
+            "body_bytes": self.body_len,
+        })
+    }
+}
+
+/// How the cells of the archive table are formatted
+#[derive(Debug, Default)]
+struct TableFormat<'a> {
+    /// The time zone from the MANIFEST, which creation times are formatted in
+    time_zone: Option<&'a TimeZone>,
+    /// For a table written for humans, its style and the time which creation
+    /// times are shown relative to
+    human: Option<(Style, Timestamp)>,
+}
+
+fn write_table(
+    mut writer: impl Write,
+    archives: &[ArchiveInfo],
+    format: &TableFormat,
+) -> io::Result<()> {
+    fn or_dash(value: Option<impl ToString>) -> String {
+        value.map_or_else(|| "-".to_string(), |value| value.to_string())
+    }
+
+    let rows = archives
+        .iter()
+        .map(|archive| {
+            let created = archive.created.map(|created| match format.human {
+                Some((_, now)) => relative_time(created, now),
+                None => display_timestamp(created, format.time_zone),
+            });
+            let body_len = match format.human {
+                Some(_) => archive.body_len.map(human_bytes),
+                None => archive.body_len.map(|len| len.to_string()),
+            };
+            [
+                archive.name.clone(),
+                or_dash(archive.sequence),
+                or_dash(created),
+                or_dash(archive.tool_version.as_ref()),
+                or_dash(body_len),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let body_header = if format.human.is_some() {
+        "body size"
+    } else {
+        "body bytes"
+    };
+    let header = ["name", "sequence", "created", "tool version", body_header].map(String::from);
+    let mut widths = header.clone().map(|column| column.len());
+    for row in &rows {
+        for (width, column) in widths.iter_mut().zip(row) {
+            *width = (*width).max(column.len());
+        }
+    }
+
+    let style = format.human.map(|(style, _)| style).unwrap_or_default();
+    let [name_width, sequence_width, created_width, tool_version_width, body_len_width] = widths;
+    for (index, row) in std::iter::once(&header).chain(&rows).enumerate() {
+        // The cells are padded before they are colored, since the escape
+        // codes take up no room on the terminal
+        let cells = [
+            format!("{:<name_width$}", row[0]),
+            format!("{:>sequence_width$}", row[1]),
+            format!("{:<created_width$}", row[2]),
+            format!("{:<tool_version_width$}", row[3]),
+            format!("{:>body_len_width$}", row[4]),
+        ]
+        .map(|cell| {
+            if index == 0 {
+                style.bold(&cell)
+            } else if cell.trim() == "-" {
+                style.dim(&cell)
+            } else {
+                cell
+            }
+        });
+        writeln!(writer, "{}", cells.join("  "))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archives() -> Vec<ArchiveInfo> {
+        vec![
+            ArchiveInfo {
+                name: "2024-06-19-19-22-45.bin".into(),
+                version: 1,
+                sequence: None,
+                created: None,
+                tool_version: None,
+                body_len: None,
+            },
+            ArchiveInfo {
+                name: "2024-06-20-19-22-45.bin".into(),
+                version: 2,
+                sequence: Some(0),
+                created: Some("2024-06-20T19:22:45Z".parse().unwrap()),
+                tool_version: Some("0.1.2".into()),
+                body_len: Some(1024),
+            },
+        ]
+    }
+
+    #[test]
+    fn archive_table() {
+        let mut output = Vec::new();
+        write_table(&mut output, &archives(), &TableFormat::default()).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name                     sequence  created               tool version  body bytes\n\
+             2024-06-19-19-22-45.bin         -  -                     -                      -\n\
+             2024-06-20-19-22-45.bin         0  2024-06-20T19:22:45Z  0.1.2               1024\n"
+        );
+    }
+
+    #[test]
+    fn human_archive_table() {
+        let format = TableFormat {
+            time_zone: None,
+            human: Some((
+                Style { color: false },
+                "2024-06-20T22:22:45Z".parse().unwrap(),
+            )),
+        };
+        let mut output = Vec::new();
+        write_table(&mut output, &archives(), &format).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name                     sequence  created      tool version  body size\n\
+             2024-06-19-19-22-45.bin         -  -            -                     -\n\
+             2024-06-20-19-22-45.bin         0  3 hours ago  0.1.2           1.0 KiB\n"
+        );
+
+        let format = TableFormat {
+            human: Some((Style { color: true }, Timestamp::UNIX_EPOCH)),
+            ..format
+        };
+        let mut output = Vec::new();
+        write_table(&mut output, &archives()[..1], &format).unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("\x1b[2m       -\x1b[0m"));
+    }
+
+    #[test]
+    fn archive_json() {
+        assert_eq!(
+            archives()[1].to_json(None),
+            serde_json::json!({
+                "name": "2024-06-20-19-22-45.bin",
+                "version": 2,
+                "sequence": 0,
+                "created": "2024-06-20T19:22:45Z",
+                "tool_version": "0.1.2",
+                "body_bytes": 1024,
+            })
+        );
+    }
+}
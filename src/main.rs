@@ -1,15 +1,72 @@
 use std::path::PathBuf;
 
 use argh::FromArgs;
-use tracing_subscriber::{filter::EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    filter::EnvFilter,
+    fmt::{self, format::FmtSpan},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
+use wall_a::{archive, checksums, format, manifest, sources, staging, store, value};
 
-use crate::{append::AppendCommand, read::ReadCommand};
+use crate::{
+    append::AppendCommand,
+    bench_gen::BenchGenCommand,
+    compact::CompactCommand,
+    config::ConfigCommand,
+    du::DuCommand,
+    export::ExportCommand,
+    history::HistoryCommand,
+    init::InitCommand,
+    list::ListCommand,
+    output::{Output, OutputMode},
+    preview::PreviewCommand,
+    read::ReadCommand,
+    repair::RepairCommand,
+    rpc::RpcCommand,
+    serve::ServeCommand,
+    set::{MoveCommand, SetCommand, UnsetCommand},
+    shell::ShellCommand,
+    snapshot::SnapshotCommand,
+    stats::StatsCommand,
+    verify::VerifyCommand,
+    watch::WatchCommand,
+};
 
 mod append;
-mod archive;
+mod bench_gen;
+mod compact;
+mod compression;
+mod config;
+mod du;
+mod explain;
+mod export;
+mod history;
+mod human;
+mod init;
+mod list;
+mod merge_settings;
+mod output;
+mod preview;
+mod profile;
+mod query;
 mod read;
-mod staging;
-mod value;
+mod repair;
+mod rpc;
+mod serve;
+mod set;
+mod shell;
+mod snapshot;
+mod stats;
+mod table;
+mod template;
+mod toml_output;
+mod verify;
+mod watch;
+
+#[cfg(feature = "count-allocations")]
+#[global_allocator]
+static ALLOCATOR: profile::counting::CountingAllocator = profile::counting::CountingAllocator;
 
 /// WALL•A is a tool for incrementally storing JSON data and then
 /// compacting it once it reaches a certain size.
@@ -19,35 +76,99 @@ struct Command {
     #[argh(option)]
     data_dir: PathBuf,
 
+    /// use the data directory even if it was not created by `init`, or let
+    /// `init` adopt a directory which is not empty.
+    #[argh(switch)]
+    force: bool,
+
+    /// how commands report what they did, either "text" (the default) or
+    /// "json" to write a JSON object with the files created and removed,
+    /// the bytes written, and the duration of commands which change the data
+    /// directory, and the JSON reports of `list`, `du`, and `verify`.
+    #[argh(option, default = "OutputMode::Text")]
+    output: OutputMode,
+
     #[argh(subcommand)]
     subcommand: Subcommand,
 }
 
 impl Command {
     fn execute(self) -> anyhow::Result<()> {
-        self.subcommand.execute(self.data_dir)
+        let uses_data_dir = match &self.subcommand {
+            Subcommand::Init(_) => false,
+            Subcommand::BenchGen(sub) => sub.uses_data_dir(),
+            _ => true,
+        };
+        if !self.force && uses_data_dir {
+            manifest::check_initialized(&self.data_dir)?;
+        }
+
+        self.subcommand
+            .execute(self.data_dir, self.force, Output::new(self.output))
     }
 }
 
 #[derive(Debug, PartialEq, FromArgs)]
 #[argh(subcommand)]
 enum Subcommand {
+    Init(InitCommand),
     Read(ReadCommand),
+    Preview(PreviewCommand),
     Append(AppendCommand),
+    Stats(StatsCommand),
+    Du(DuCommand),
+    Verify(VerifyCommand),
+    Serve(ServeCommand),
+    Watch(WatchCommand),
+    Rpc(RpcCommand),
+    Export(ExportCommand),
+    Set(SetCommand),
+    Unset(UnsetCommand),
+    Move(MoveCommand),
+    History(HistoryCommand),
+    List(ListCommand),
+    Config(ConfigCommand),
+    BenchGen(BenchGenCommand),
+    Shell(ShellCommand),
+    Snapshot(SnapshotCommand),
+    Compact(CompactCommand),
+    Repair(RepairCommand),
 }
 
 impl Subcommand {
-    fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
+    fn execute(self, data_dir: PathBuf, force: bool, output: Output) -> anyhow::Result<()> {
         match self {
+            Self::Init(sub) => sub.execute(data_dir, force, output),
             Self::Read(sub) => sub.execute(data_dir),
-            Self::Append(sub) => sub.execute(data_dir),
+            Self::Preview(sub) => sub.execute(data_dir),
+            Self::Append(sub) => sub.execute(data_dir, output),
+            Self::Stats(sub) => sub.execute(data_dir),
+            Self::Du(sub) => sub.execute(data_dir, output),
+            Self::Verify(sub) => sub.execute(data_dir, output),
+            Self::Serve(sub) => sub.execute(data_dir),
+            Self::Watch(sub) => sub.execute(data_dir),
+            Self::Rpc(sub) => sub.execute(data_dir),
+            Self::Export(sub) => sub.execute(data_dir),
+            Self::Set(sub) => sub.execute(data_dir, output),
+            Self::Unset(sub) => sub.execute(data_dir, output),
+            Self::Move(sub) => sub.execute(data_dir, output),
+            Self::History(sub) => sub.execute(data_dir),
+            Self::List(sub) => sub.execute(data_dir, output),
+            Self::Config(sub) => sub.execute(data_dir),
+            Self::BenchGen(sub) => sub.execute(data_dir),
+            Self::Shell(sub) => sub.execute(data_dir),
+            Self::Snapshot(sub) => sub.execute(data_dir, output),
+            Self::Compact(sub) => sub.execute(data_dir, output),
+            Self::Repair(sub) => sub.execute(data_dir, output),
         }
     }
 }
 
 fn main() -> anyhow::Result<()> {
     tracing_subscriber::registry()
-        .with(fmt::layer())
+        // Log the time spent in each span when it closes, like reading an
+        // archive with WALLA_LOG=debug
+        .with(fmt::layer().with_span_events(FmtSpan::CLOSE))
         .with(EnvFilter::from_env("WALLA_LOG"))
         .init();
 
@@ -0,0 +1,501 @@
+//! This module contains things relating to the `MANIFEST` file, which holds
+//! the settings that every command must agree on for a data directory.
+//!
+//! Each line of the file has the form `<setting>  <value>`, like
+//! `merge_mode  crdt`. Lines which are empty or start with `#` are ignored,
+//! and a missing file or setting means the default. The file is rewritten
+//! whenever an archive is created, which drops any comments.
+//!
+//! The `init` command creates the `MANIFEST` file, and the other commands
+//! refuse to use a data directory without one, see [`check_initialized`].
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use jiff::tz::TimeZone;
+
+use crate::{
+    archive::next_archive_sequence,
+    atomic_file::{write_atomically, DataDirLock},
+    format::FORMAT_VERSION,
+    store::keys::KeyNormalization,
+    value::{
+        arrays::ArrayRules,
+        merge::{ArrayBehavior, KeyCase, MergeMode, MergeSettings, NullBehavior, UnionKeep},
+        rollup::NumberRollups,
+    },
+};
+
+/// The names of all the settings
+pub const SETTINGS: &[&str] = &[
+    "format_version",
+    "merge_mode",
+    "array_behavior",
+    "null_behavior",
+    "union_keep",
+    "key_case",
+    "normalize_keys",
+    "rename_key",
+    "max_array_len",
+    "array_keep",
+    "sort_array",
+    "rollup_numbers",
+    "next_sequence",
+    "timezone",
+];
+
+fn manifest_file_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("MANIFEST")
+}
+
+/// The settings of a data directory
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// The version of the archive format the data directory was initialized
+    /// with, see [`FORMAT_VERSION`]
+    pub format_version: Option<u32>,
+    /// Whether records are merged in order, see [`MergeMode`]
+    pub merge_mode: MergeMode,
+    /// How arrays are merged, see [`ArrayBehavior`]
+    pub array_behavior: ArrayBehavior,
+    /// How `null` values are merged, see [`NullBehavior`]
+    pub null_behavior: NullBehavior,
+    /// Which duplicate is kept when arrays are unioned, see [`UnionKeep`]
+    pub union_keep: UnionKeep,
+    /// Whether object keys are matched by case, see [`KeyCase`]
+    pub key_case: KeyCase,
+    /// How the keys of records are normalized as they are staged, from the
+    /// `normalize_keys` and `rename_key` settings, which may be given more
+    /// than once
+    pub key_normalization: KeyNormalization,
+    /// The rules applied to arrays after merging, from the `max_array_len`
+    /// settings, which may be given once for every array and once for each
+    /// pattern, the `array_keep` setting and the `sort_array` settings, which
+    /// may be given once for each pattern
+    pub array_rules: ArrayRules,
+    /// The patterns of keys leading to the numbers which are rolled up as
+    /// they are merged, from the `rollup_numbers` settings, which may be
+    /// given once for each pattern
+    pub number_rollups: NumberRollups,
+    /// The sequence number of the next archive, see
+    /// [`Metadata::sequence`](crate::format::Metadata::sequence)
+    pub next_sequence: Option<u64>,
+    /// The IANA name of the time zone of archive filenames and reported
+    /// times, like `Europe/Paris`. Without one, times are in UTC and archive
+    /// filenames are not labeled with an offset.
+    pub timezone: Option<String>,
+}
+
+impl Manifest {
+    /// Read the manifest from the data directory, returning the default
+    /// settings if the `MANIFEST` file does not exist.
+    pub fn read(data_dir: &Path) -> anyhow::Result<Self> {
+        let contents = match fs::read_to_string(manifest_file_path(data_dir)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).context("reading MANIFEST file"),
+        };
+
+        Self::parse(&contents)
+    }
+
+    /// Return true if the data directory has a `MANIFEST` file.
+    pub fn exists(data_dir: &Path) -> bool {
+        manifest_file_path(data_dir).exists()
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut manifest = Self::default();
+
+        for (line_index, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            manifest
+                .parse_setting(line)
+                .with_context(|| format!("parsing line {} of MANIFEST file", line_index + 1))?;
+        }
+
+        Ok(manifest)
+    }
+
+    fn parse_setting(&mut self, line: &str) -> anyhow::Result<()> {
+        let Some((setting, value)) = line.split_once("  ") else {
+            anyhow::bail!("expected '<setting>  <value>'");
+        };
+
+        self.set(setting, value)
+    }
+
+    /// Change a setting, like `set("array_behavior", "union")`, after
+    /// checking that the value is allowed.
+    pub fn set(&mut self, setting: &str, value: &str) -> anyhow::Result<()> {
+        match setting {
+            "format_version" => {
+                let version: u32 = value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("'{value}' is not a format version"))?;
+                if version > FORMAT_VERSION {
+                    anyhow::bail!(
+                        "format version {version} is newer than the supported version \
+                         {FORMAT_VERSION}"
+                    );
+                }
+                self.format_version = Some(version);
+            }
+            "merge_mode" => self.merge_mode = value.trim().parse()?,
+            "array_behavior" => self.array_behavior = value.trim().parse()?,
+            "null_behavior" => self.null_behavior = value.trim().parse()?,
+            "union_keep" => self.union_keep = value.trim().parse()?,
+            "key_case" => self.key_case = value.trim().parse()?,
+            "normalize_keys" => self.key_normalization.insert_step(value.trim().parse()?),
+            "rename_key" => self.key_normalization.insert_rename(value.trim().parse()?),
+            "max_array_len" => self.array_rules.insert_limit(value.trim().parse()?),
+            "array_keep" => self.array_rules.keep = value.trim().parse()?,
+            "sort_array" => self.array_rules.insert_sort(value.trim().parse()?),
+            "rollup_numbers" => self.number_rollups.insert(value.trim().parse()?),
+            "next_sequence" => {
+                self.next_sequence = Some(
+                    value
+                        .trim()
+                        .parse()
+                        .with_context(|| format!("'{value}' is not a sequence number"))?,
+                )
+            }
+            "timezone" => {
+                let name = value.trim();
+                TimeZone::get(name)
+                    .with_context(|| format!("'{name}' is not a known time zone"))?;
+                self.timezone = Some(name.to_string());
+            }
+            x => anyhow::bail!("'{x}' is an unknown setting"),
+        }
+
+        Ok(())
+    }
+
+    /// Return the value of a setting, or `None` if it is not set. A setting
+    /// which is given more than once has its values on separate lines.
+    pub fn get(&self, setting: &str) -> anyhow::Result<Option<String>> {
+        if !SETTINGS.contains(&setting) {
+            anyhow::bail!("'{setting}' is an unknown setting");
+        }
+
+        let values = self
+            .settings()
+            .into_iter()
+            .filter_map(|(name, value)| (name == setting).then_some(value))
+            .collect::<Vec<_>>();
+
+        Ok((!values.is_empty()).then(|| values.join("\n")))
+    }
+
+    /// Return the settings which are set, in the order they are written.
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        let merge_mode = match self.merge_mode {
+            MergeMode::Ordered => "ordered",
+            MergeMode::Crdt => "crdt",
+        };
+        let array_behavior = match self.array_behavior {
+            ArrayBehavior::Concat => "concat",
+            ArrayBehavior::Merge => "merge",
+            ArrayBehavior::Union => "union",
+            ArrayBehavior::Replace => "replace",
+        };
+        let null_behavior = match self.null_behavior {
+            NullBehavior::Merge => "merge",
+            NullBehavior::Ignore => "ignore",
+        };
+        let union_keep = match self.union_keep {
+            UnionKeep::First => "first",
+            UnionKeep::Last => "last",
+        };
+        let key_case = match self.key_case {
+            KeyCase::Sensitive => "sensitive",
+            KeyCase::Insensitive => "insensitive",
+        };
+
+        let mut settings = Vec::with_capacity(SETTINGS.len());
+        if let Some(format_version) = self.format_version {
+            settings.push(("format_version", format_version.to_string()));
+        }
+        settings.push(("merge_mode", merge_mode.to_string()));
+        settings.push(("array_behavior", array_behavior.to_string()));
+        settings.push(("null_behavior", null_behavior.to_string()));
+        settings.push(("union_keep", union_keep.to_string()));
+        settings.push(("key_case", key_case.to_string()));
+        for step in &self.key_normalization.steps {
+            settings.push(("normalize_keys", step.to_string()));
+        }
+        for rename in &self.key_normalization.renames {
+            settings.push(("rename_key", rename.to_string()));
+        }
+        for limit in &self.array_rules.limits {
+            settings.push(("max_array_len", limit.to_string()));
+        }
+        settings.push(("array_keep", self.array_rules.keep.to_string()));
+        for sort in &self.array_rules.sorts {
+            settings.push(("sort_array", sort.to_string()));
+        }
+        for pattern in &self.number_rollups.patterns {
+            settings.push(("rollup_numbers", pattern.to_string()));
+        }
+        if let Some(next_sequence) = self.next_sequence {
+            settings.push(("next_sequence", next_sequence.to_string()));
+        }
+        if let Some(timezone) = &self.timezone {
+            settings.push(("timezone", timezone.clone()));
+        }
+
+        settings
+    }
+
+    /// Write the manifest to the data directory.
+    ///
+    /// The manifest is written to a temporary file first, then renamed over
+    /// the existing manifest so that readers never see a partial update.
+    pub fn write(&self, data_dir: &Path) -> anyhow::Result<()> {
+        write_atomically(&manifest_file_path(data_dir), self.to_string().as_bytes())
+            .context("writing MANIFEST file")
+    }
+
+    /// Read the manifest from the data directory, change it, and write it
+    /// back, all while holding the lock on the data directory, so that
+    /// commands running at the same time do not overwrite each other's
+    /// changes.
+    pub fn update<T>(
+        data_dir: &Path,
+        update: impl FnOnce(&mut Self) -> anyhow::Result<T>,
+    ) -> anyhow::Result<T> {
+        let _lock = DataDirLock::acquire(data_dir)?;
+        let mut manifest = Self::read(data_dir)?;
+        let result = update(&mut manifest)?;
+        manifest.write(data_dir)?;
+
+        Ok(result)
+    }
+
+    /// Take the sequence number for a new archive, recording the next one in
+    /// the manifest before the archive is written.
+    ///
+    /// Recording it first means a crash can only skip sequence numbers, never
+    /// reuse one, and it is taken under the lock on the data directory, so no
+    /// two archives get the same one. Without a recorded sequence number, the
+    /// archives are read to continue after the greatest one.
+    pub fn reserve_sequence(data_dir: &Path) -> anyhow::Result<u64> {
+        Self::update(data_dir, |manifest| {
+            let sequence = match manifest.next_sequence {
+                Some(sequence) => sequence,
+                None => next_archive_sequence(data_dir)?,
+            };
+            manifest.next_sequence = Some(sequence + 1);
+
+            Ok(sequence)
+        })
+    }
+
+    /// Return the time zone of archive filenames and reported times, or `None`
+    /// for unlabeled UTC.
+    pub fn time_zone(&self) -> anyhow::Result<Option<TimeZone>> {
+        self.timezone
+            .as_deref()
+            .map(|name| {
+                TimeZone::get(name).with_context(|| format!("'{name}' is not a known time zone"))
+            })
+            .transpose()
+    }
+
+    /// Return the settings used to merge the values in the data directory.
+    pub fn merge_settings(&self) -> MergeSettings {
+        MergeSettings {
+            mode: self.merge_mode,
+            array_behavior: self.array_behavior,
+            null_behavior: self.null_behavior,
+            union_keep: self.union_keep,
+            key_case: self.key_case,
+        }
+    }
+}
+
+/// Return an error unless the data directory was initialized, which means it
+/// has a `MANIFEST` file that this version can read.
+///
+/// A directory without one either does not exist yet, or belongs to
+/// something other than `wall-a`, or was created by a version from before the
+/// `init` command.
+pub fn check_initialized(data_dir: &Path) -> anyhow::Result<()> {
+    if Manifest::exists(data_dir) {
+        return Manifest::read(data_dir).map(|_| ());
+    }
+
+    let is_empty = match data_dir.read_dir() {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(err) if err.kind() == ErrorKind::NotFound => true,
+        Err(err) => {
+            return Err(err).with_context(|| format!("reading entries of '{}'", data_dir.display()))
+        }
+    };
+
+    if is_empty {
+        anyhow::bail!(
+            "'{}' is not an initialized data directory, create it with `wall-a init` or pass \
+             --force",
+            data_dir.display()
+        );
+    } else {
+        anyhow::bail!(
+            "'{}' has no MANIFEST file, so it may not be a wall-a data directory; adopt it with \
+             `--force init` or pass --force",
+            data_dir.display()
+        );
+    }
+}
+
+impl std::fmt::Display for Manifest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (setting, value) in self.settings() {
+            writeln!(f, "{setting}  {value}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::test_dir::TempDir;
+
+    #[test]
+    fn parse_manifest() {
+        assert_eq!(Manifest::parse("").unwrap(), Manifest::default());
+        assert_eq!(
+            Manifest::parse("# written by hand\nmerge_mode  crdt\n").unwrap(),
+            Manifest {
+                merge_mode: MergeMode::Crdt,
+                ..Manifest::default()
+            }
+        );
+
+        let manifest = Manifest {
+            format_version: Some(2),
+            merge_mode: MergeMode::Crdt,
+            array_behavior: ArrayBehavior::Union,
+            null_behavior: NullBehavior::Ignore,
+            union_keep: UnionKeep::Last,
+            key_case: KeyCase::Insensitive,
+            key_normalization: KeyNormalization::default(),
+            array_rules: ArrayRules::default(),
+            number_rollups: NumberRollups::default(),
+            next_sequence: Some(12),
+            timezone: Some("Europe/Paris".into()),
+        };
+        assert_eq!(
+            manifest.to_string(),
+            "format_version  2\nmerge_mode  crdt\narray_behavior  union\nnull_behavior  ignore\n\
+             union_keep  last\nkey_case  insensitive\n\
+             array_keep  newest\nnext_sequence  12\ntimezone  Europe/Paris\n"
+        );
+        assert_eq!(Manifest::parse(&manifest.to_string()).unwrap(), manifest);
+
+        let err = Manifest::parse("merge_mode  ordered\ncolour  blue").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "parsing line 2 of MANIFEST file: 'colour' is an unknown setting"
+        );
+        assert!(Manifest::parse("merge_mode  random").is_err());
+        assert!(Manifest::parse("merge_mode crdt").is_err());
+        assert!(Manifest::parse("next_sequence  -1").is_err());
+        assert!(Manifest::parse("timezone  Mars/Olympus_Mons").is_err());
+        assert!(Manifest::parse("format_version  4").is_err());
+        assert!(Manifest::parse("array_behavior  shuffle").is_err());
+        assert!(Manifest::parse("union_keep  middle").is_err());
+        assert!(Manifest::parse("key_case  upper").is_err());
+    }
+
+    #[test]
+    fn get_and_set_settings() {
+        let mut manifest = Manifest::default();
+        assert_eq!(
+            manifest.get("array_behavior").unwrap().as_deref(),
+            Some("concat")
+        );
+        assert_eq!(manifest.get("timezone").unwrap(), None);
+        assert!(manifest.get("colour").is_err());
+
+        manifest.set("array_behavior", "union").unwrap();
+        assert_eq!(manifest.array_behavior, ArrayBehavior::Union);
+        assert_eq!(
+            manifest.merge_settings().array_behavior,
+            ArrayBehavior::Union
+        );
+        assert!(manifest.set("array_behavior", "shuffle").is_err());
+        assert_eq!(manifest.array_behavior, ArrayBehavior::Union);
+
+        manifest.set("max_array_len", "1000").unwrap();
+        manifest.set("max_array_len", "events.*  10").unwrap();
+        assert_eq!(
+            manifest.get("max_array_len").unwrap().as_deref(),
+            Some("1000\nevents.*  10")
+        );
+        manifest.set("normalize_keys", "trim").unwrap();
+        manifest.set("normalize_keys", "nfc").unwrap();
+        manifest.set("rename_key", "hostname  host").unwrap();
+        assert_eq!(
+            manifest.get("normalize_keys").unwrap().as_deref(),
+            Some("trim\nnfc")
+        );
+        assert!(manifest.set("rename_key", "hostname").is_err());
+        manifest.set("sort_array", "items  id").unwrap();
+        manifest.set("sort_array", "tags").unwrap();
+        manifest.set("sort_array", "items  name").unwrap();
+        assert_eq!(
+            manifest.get("sort_array").unwrap().as_deref(),
+            Some("items  name\ntags")
+        );
+        assert!(manifest.set("sort_array", "items..id").is_err());
+        manifest.set("rollup_numbers", "latency.*").unwrap();
+        manifest.set("rollup_numbers", "latency.*").unwrap();
+        assert_eq!(
+            manifest.get("rollup_numbers").unwrap().as_deref(),
+            Some("latency.*")
+        );
+        assert_eq!(Manifest::parse(&manifest.to_string()).unwrap(), manifest);
+
+        for &setting in SETTINGS {
+            manifest.get(setting).unwrap();
+        }
+    }
+
+    #[test]
+    fn reserve_sequences_concurrently() {
+        let data_dir = TempDir::new();
+        Manifest::default().write(data_dir.path()).unwrap();
+
+        let mut sequences = thread::scope(|scope| {
+            let threads = (0..8)
+                .map(|_| {
+                    scope.spawn(|| {
+                        (0..10)
+                            .map(|_| Manifest::reserve_sequence(data_dir.path()).unwrap())
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .flat_map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        sequences.sort_unstable();
+        assert_eq!(sequences, (0..80).collect::<Vec<_>>());
+    }
+}
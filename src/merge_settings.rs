@@ -0,0 +1,83 @@
+//! This module contains the `--merge-settings` option of `read`, `append`,
+//! and `compact`, which loads the merge settings from a TOML file instead of
+//! the `MANIFEST`, like:
+//!
+//! ```toml
+//! array_behavior = "union"
+//! null_behavior = "ignore"
+//!
+//! [[array_behaviors]]
+//! pattern = "events"
+//! behavior = "concat"
+//! ```
+
+use std::{fs, path::Path};
+
+use anyhow::Context;
+
+use crate::value::merge::MergeConfig;
+
+/// Read the merge settings, along with the array behaviors which override
+/// them at some paths, from the TOML file at the given path.
+pub fn read_merge_settings(path: &Path) -> anyhow::Result<MergeConfig> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("reading merge settings file '{}'", path.display()))?;
+
+    parse_merge_settings(&contents)
+        .with_context(|| format!("parsing merge settings file '{}'", path.display()))
+}
+
+fn parse_merge_settings(contents: &str) -> anyhow::Result<MergeConfig> {
+    let table: toml::Table = contents.parse()?;
+    // The settings are flattened next to the overrides, which hides unknown
+    // settings from the check for them
+    let known = toml::Table::try_from(MergeConfig::default())?;
+    if let Some(key) = table
+        .keys()
+        .find(|key| *key != "array_behaviors" && !known.contains_key(*key))
+    {
+        anyhow::bail!("'{key}' is an unknown merge setting");
+    }
+
+    Ok(table.try_into()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::merge::{ArrayBehavior, ArrayBehaviorAt, MergeSettings, NullBehavior};
+
+    #[test]
+    fn parse_settings_file() {
+        let config = parse_merge_settings(
+            "array_behavior = \"union\"\nnull_behavior = \"ignore\"\n\n[[array_behaviors]]\n\
+             pattern = \"hosts.*.events\"\nbehavior = \"concat\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            MergeConfig {
+                settings: MergeSettings {
+                    array_behavior: ArrayBehavior::Union,
+                    null_behavior: NullBehavior::Ignore,
+                    ..MergeSettings::default()
+                },
+                array_behaviors: vec!["hosts.*.events=concat".parse::<ArrayBehaviorAt>().unwrap()],
+                ..MergeConfig::default()
+            }
+        );
+
+        assert_eq!(parse_merge_settings("").unwrap(), MergeConfig::default());
+        assert_eq!(
+            parse_merge_settings("array_behaviour = \"union\"")
+                .unwrap_err()
+                .to_string(),
+            "'array_behaviour' is an unknown merge setting"
+        );
+        assert!(parse_merge_settings("array_behavior = \"shuffle\"").is_err());
+        assert!(parse_merge_settings(
+            "[[array_behaviors]]\npattern = \"a..b\"\nbehavior = \"union\""
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! This module contains the global `--output json` mode, in which commands
+//! write a JSON object describing what they did to stdout, so that they can
+//! be orchestrated without parsing log lines.
+
+use std::{
+    io::{self, Write},
+    str::FromStr,
+    time::Instant,
+};
+
+use anyhow::Context;
+
+/// This enum controls how commands report their result
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum OutputMode {
+    /// Write results for people, or only log them
+    #[default]
+    Text,
+    /// Write a single JSON object with the result of the command
+    Json,
+}
+
+impl FromStr for OutputMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "text" => Self::Text,
+            "json" => Self::Json,
+            x => anyhow::bail!("'{x}' is an unknown option for the output, expected text or json"),
+        })
+    }
+}
+
+/// The output of a command, which measures how long the command took
+#[derive(Debug, Copy, Clone)]
+pub struct Output {
+    mode: OutputMode,
+    started: Instant,
+}
+
+impl Output {
+    /// Start timing a command.
+    pub fn new(mode: OutputMode) -> Self {
+        Self {
+            mode,
+            started: Instant::now(),
+        }
+    }
+
+    /// Return true if the result is written as JSON.
+    pub fn is_json(self) -> bool {
+        self.mode == OutputMode::Json
+    }
+
+    /// With `--output json`, write the result to stdout as a line of JSON,
+    /// along with the name of the command and how long it took in
+    /// milliseconds. Otherwise nothing is written.
+    pub fn write_result(self, command: &str, result: serde_json::Value) -> anyhow::Result<()> {
+        if !self.is_json() {
+            return Ok(());
+        }
+
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        write_result(
+            &mut handle,
+            command,
+            result,
+            self.started.elapsed().as_millis(),
+        )
+        .context("writing result to stdout")
+    }
+}
+
+fn write_result(
+    mut writer: impl Write,
+    command: &str,
+    result: serde_json::Value,
+    duration_ms: u128,
+) -> io::Result<()> {
+    let mut object = serde_json::Map::new();
+    object.insert("command".into(), command.into());
+    match result {
+        serde_json::Value::Object(fields) => object.extend(fields),
+        result => {
+            object.insert("result".into(), result);
+        }
+    }
+    object.insert("duration_ms".into(), (duration_ms as u64).into());
+
+    serde_json::to_writer(&mut writer, &object)?;
+    writeln!(writer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_results() {
+        let mut output = Vec::new();
+        write_result(
+            &mut output,
+            "set",
+            serde_json::json!({"appended_records": 1, "archives_created": []}),
+            12,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"command\":\"set\",\"appended_records\":1,\"archives_created\":[],\
+             \"duration_ms\":12}\n"
+        );
+
+        assert_eq!("json".parse::<OutputMode>().unwrap(), OutputMode::Json);
+        assert_eq!(
+            "yaml".parse::<OutputMode>().unwrap_err().to_string(),
+            "'yaml' is an unknown option for the output, expected text or json"
+        );
+    }
+}
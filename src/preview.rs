@@ -0,0 +1,109 @@
+//! This module contains the implementation of the `preview` CLI command
+
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+
+use crate::{
+    archive::{archive_name, list_archive_files, read_archive_value},
+    manifest::Manifest,
+    staging::StagingFileReader,
+    value::{preview::Preview, Value, DEFAULT_MAX_DEPTH},
+};
+
+/// The `preview` sub-command gives a quick look at what is in the data
+/// directory, by merging only the newest archive and the staging file and
+/// writing a shortened copy of the result as JSON.
+///
+/// Parts of the value which were only written before the newest archive are
+/// missing, use `read` for the full merged value.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "preview")]
+pub struct PreviewCommand {
+    /// the number of levels of arrays and objects to output, deeper arrays
+    /// and objects are replaced by a string of "..." inside their brackets.
+    #[argh(option, default = "2")]
+    max_depth: usize,
+    /// the number of characters to output of each string, longer strings are
+    /// cut short and end with "…".
+    #[argh(option, default = "80")]
+    truncate_strings: usize,
+    /// replace each truncated part with an object holding a
+    /// "$wall-a:truncated" key, like `read --truncation-markers`.
+    #[argh(switch)]
+    truncation_markers: bool,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the stored values, deeper values are rejected as an error.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+}
+
+impl PreviewCommand {
+    /// This function executes the preview command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
+        let (value, archives) = read_recent_value(&data_dir, self.max_nesting_depth)?;
+
+        let Some(value) = value else {
+            tracing::warn!("No data is present in archive or staging");
+            return Ok(());
+        };
+        let preview = Preview {
+            max_depth: Some(self.max_depth),
+            max_string_len: Some(self.truncate_strings),
+            markers: self.truncation_markers,
+        };
+
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        serde_json::to_writer(&mut handle, &preview.apply(value))
+            .context("writing preview to stdout")?;
+        writeln!(handle).context("writing preview to stdout")?;
+
+        if archives > 1 {
+            writeln!(
+                io::stderr(),
+                "preview of the newest of {archives} archives and the staging file, use `read` \
+                 for the full value"
+            )
+            .context("writing note to stderr")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Merge the newest archive and the staging file like `read` does, returning
+/// the value and the number of archives in the data directory.
+fn read_recent_value(data_dir: &Path, max_depth: usize) -> anyhow::Result<(Option<Value>, usize)> {
+    let manifest = Manifest::read(data_dir)?;
+    let merge_settings = manifest.merge_settings();
+
+    let archives = list_archive_files(data_dir)?;
+    let archived_value = match archives.last() {
+        Some(archive_path) => {
+            tracing::debug!(archive = %archive_name(archive_path), "Reading newest archive");
+            let value = read_archive_value(archive_path, &mut Vec::new(), max_depth).with_context(
+                || format!("reading archive value from '{}'", archive_path.display()),
+            )?;
+            Some(value)
+        }
+        None => None,
+    };
+    let staging_value = StagingFileReader::read_merged_value(data_dir, max_depth)
+        .context("reading values from staging file")?;
+
+    let value = merge_settings
+        .merge_optional(archived_value, staging_value)
+        .and_then(|value| merge_settings.resolve(value))
+        .map(|mut value| {
+            manifest.array_rules.apply(&mut value);
+            value
+        });
+
+    Ok((value, archives.len()))
+}
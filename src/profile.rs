@@ -0,0 +1,267 @@
+//! This module contains the resource usage report of `read --profile`, and
+//! the allocator which counts allocations for it when the
+//! `count-allocations` feature is enabled.
+
+use std::{io, time::Duration};
+
+use wall_a::{archive::archive_name, store::profile::ReadProfile};
+
+/// The resources used by the whole process while reading
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceUsage {
+    /// The largest resident set size of the process so far, or `None` if it
+    /// is not known on this platform
+    pub peak_rss_bytes: Option<u64>,
+    /// The allocations made since the read started, or `None` if they were
+    /// not counted
+    pub allocations: Option<AllocationCounts>,
+}
+
+/// The number of allocations made and the number of bytes they requested
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationCounts {
+    pub count: u64,
+    pub bytes: u64,
+}
+
+impl AllocationCounts {
+    /// Return the allocations made since the process started, or `None` if
+    /// the counting allocator is not installed.
+    pub fn now() -> Option<Self> {
+        #[cfg(feature = "count-allocations")]
+        {
+            Some(counting::counts())
+        }
+        #[cfg(not(feature = "count-allocations"))]
+        {
+            None
+        }
+    }
+
+    /// Return the allocations made between `earlier` and these counts.
+    pub fn since(self, earlier: Self) -> Self {
+        Self {
+            count: self.count - earlier.count,
+            bytes: self.bytes - earlier.bytes,
+        }
+    }
+}
+
+/// Return the largest resident set size of the process so far.
+#[cfg(unix)]
+pub fn peak_rss_bytes() -> Option<u64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: the pointer is valid for writing a `rusage`
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    // SAFETY: `getrusage` succeeded, so it filled in the struct
+    let max_rss = u64::try_from(unsafe { usage.assume_init() }.ru_maxrss).ok()?;
+
+    // macOS reports bytes, and the other platforms kibibytes
+    if cfg!(target_os = "macos") {
+        Some(max_rss)
+    } else {
+        Some(max_rss * 1024)
+    }
+}
+
+/// Return the largest resident set size of the process so far, which is not
+/// known on this platform.
+#[cfg(not(unix))]
+pub fn peak_rss_bytes() -> Option<u64> {
+    None
+}
+
+/// Write the bytes read and the time spent on each archive and the staging
+/// files, followed by the resources used by the process.
+pub fn write_profile(
+    mut writer: impl io::Write,
+    profile: &ReadProfile,
+    usage: ResourceUsage,
+) -> io::Result<()> {
+    let mut rows = vec![(
+        "archive".to_string(),
+        "bytes".to_string(),
+        "decode".to_string(),
+        "merge".to_string(),
+    )];
+    rows.extend(profile.archives.iter().map(|archive| {
+        (
+            archive_name(&archive.path),
+            archive.bytes.to_string(),
+            millis(archive.decode),
+            millis(archive.merge),
+        )
+    }));
+    rows.push((
+        "staging".to_string(),
+        profile.staging_bytes.to_string(),
+        millis(profile.staging_time),
+        "-".to_string(),
+    ));
+    rows.push((
+        "total".to_string(),
+        profile.total_bytes().to_string(),
+        millis(
+            profile
+                .archives
+                .iter()
+                .map(|archive| archive.decode)
+                .sum::<Duration>()
+                + profile.staging_time,
+        ),
+        millis(profile.archives.iter().map(|archive| archive.merge).sum()),
+    ));
+
+    let label_width = rows
+        .iter()
+        .map(|(label, ..)| label.len())
+        .max()
+        .unwrap_or(0);
+    for (label, bytes, decode, merge) in rows {
+        writeln!(
+            writer,
+            "{label:<label_width$}  {bytes:>12}  {decode:>12}  {merge:>12}"
+        )?;
+    }
+
+    match usage.peak_rss_bytes {
+        Some(bytes) => writeln!(writer, "peak RSS: {bytes} bytes")?,
+        None => writeln!(writer, "peak RSS: not known on this platform")?,
+    }
+    match usage.allocations {
+        Some(allocations) => writeln!(
+            writer,
+            "allocations: {} ({} bytes)",
+            allocations.count, allocations.bytes
+        )?,
+        None => writeln!(
+            writer,
+            "allocations: not counted, build with the `count-allocations` feature"
+        )?,
+    }
+
+    Ok(())
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.3}ms", duration.as_secs_f64() * 1000.0)
+}
+
+/// The global allocator which counts allocations, see [`AllocationCounts`]
+#[cfg(feature = "count-allocations")]
+pub mod counting {
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    use super::AllocationCounts;
+
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    static BYTES: AtomicU64 = AtomicU64::new(0);
+
+    /// Allocates with the system allocator, counting each allocation and
+    /// reallocation and the bytes they request
+    #[derive(Debug)]
+    pub struct CountingAllocator;
+
+    fn record(size: usize) {
+        COUNT.fetch_add(1, Ordering::Relaxed);
+        BYTES.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub(super) fn counts() -> AllocationCounts {
+        AllocationCounts {
+            count: COUNT.load(Ordering::Relaxed),
+            bytes: BYTES.load(Ordering::Relaxed),
+        }
+    }
+
+    // SAFETY: every call is passed on to the system allocator unchanged
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            record(layout.size());
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            record(layout.size());
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            record(new_size);
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use wall_a::store::profile::ArchiveProfile;
+
+    use super::*;
+
+    #[test]
+    fn profile_report() {
+        let profile = ReadProfile {
+            archives: vec![
+                ArchiveProfile {
+                    path: PathBuf::from("archived/2024-06-19-19-22-45.bin"),
+                    bytes: 1000,
+                    decode: Duration::from_micros(1250),
+                    merge: Duration::from_micros(300),
+                },
+                ArchiveProfile {
+                    path: PathBuf::from("archived/2024-06-20-19-22-45.bin"),
+                    bytes: 2000,
+                    decode: Duration::from_micros(2500),
+                    merge: Duration::ZERO,
+                },
+            ],
+            staging_bytes: 120,
+            staging_time: Duration::from_micros(50),
+        };
+        let usage = ResourceUsage {
+            peak_rss_bytes: Some(8_392_704),
+            allocations: Some(AllocationCounts {
+                count: 1234,
+                bytes: 567_890,
+            }),
+        };
+
+        let mut output = Vec::new();
+        write_profile(&mut output, &profile, usage).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+archive                         bytes        decode         merge
+2024-06-19-19-22-45.bin          1000       1.250ms       0.300ms
+2024-06-20-19-22-45.bin          2000       2.500ms       0.000ms
+staging                           120       0.050ms             -
+total                            3120       3.800ms       0.300ms
+peak RSS: 8392704 bytes
+allocations: 1234 (567890 bytes)
+"
+        );
+
+        let mut output = Vec::new();
+        write_profile(
+            &mut output,
+            &ReadProfile::default(),
+            ResourceUsage::default(),
+        )
+        .unwrap();
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .ends_with("allocations: not counted, build with the `count-allocations` feature\n"));
+    }
+}
@@ -0,0 +1,191 @@
+//! A Python module named `wall_a` over [`store::Store`] and the merge
+//! function, which is only available with the `python` feature.
+//!
+//! Python values are converted to and from [`Value`] through the `json`
+//! module, so anything `json.dumps` accepts can be appended or merged.
+
+use std::str::FromStr;
+
+use pyo3::{
+    exceptions::{PyRuntimeError, PyValueError},
+    prelude::*,
+    types::PyDict,
+};
+
+use crate::{
+    store::{self, RecordOutcome, Settings},
+    value::{
+        self,
+        merge::{ArrayBehavior, KeyCase, MergeMode, MergeSettings, NullBehavior, UnionKeep},
+        Value, DEFAULT_MAX_DEPTH,
+    },
+};
+
+/// Convert an error from reading or writing the data directory into a Python
+/// exception.
+fn runtime_error(err: anyhow::Error) -> PyErr {
+    PyRuntimeError::new_err(format!("{err:#}"))
+}
+
+/// Convert a Python object into a value, by way of its JSON text.
+fn to_value(object: &Bound<'_, PyAny>) -> PyResult<Value> {
+    let json: String = object
+        .py()
+        .import("json")?
+        .call_method1("dumps", (object,))?
+        .extract()?;
+
+    value::from_json_slice(json.as_bytes(), DEFAULT_MAX_DEPTH)
+        .map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+/// Convert a value into a Python object, by way of its JSON text.
+fn to_object<'py>(py: Python<'py>, value: &Value) -> PyResult<Bound<'py, PyAny>> {
+    let json = serde_json::to_string(value).map_err(|err| runtime_error(err.into()))?;
+
+    py.import("json")?.call_method1("loads", (json,))
+}
+
+/// Parse the merge settings from the names of the behaviors and mode, using
+/// the default for any which are not given.
+fn merge_settings(
+    array_behavior: Option<&str>,
+    null_behavior: Option<&str>,
+    union_keep: Option<&str>,
+    key_case: Option<&str>,
+    mode: Option<&str>,
+) -> anyhow::Result<MergeSettings> {
+    let defaults = MergeSettings::default();
+
+    Ok(MergeSettings {
+        array_behavior: array_behavior
+            .map(ArrayBehavior::from_str)
+            .transpose()?
+            .unwrap_or(defaults.array_behavior),
+        null_behavior: null_behavior
+            .map(NullBehavior::from_str)
+            .transpose()?
+            .unwrap_or(defaults.null_behavior),
+        union_keep: union_keep
+            .map(UnionKeep::from_str)
+            .transpose()?
+            .unwrap_or(defaults.union_keep),
+        key_case: key_case
+            .map(KeyCase::from_str)
+            .transpose()?
+            .unwrap_or(defaults.key_case),
+        mode: mode
+            .map(MergeMode::from_str)
+            .transpose()?
+            .unwrap_or(defaults.mode),
+    })
+}
+
+/// A data directory which records can be appended to and the merged value
+/// read from
+#[pyclass(name = "Store", unsendable)]
+struct Store(store::Store);
+
+#[pymethods]
+impl Store {
+    /// Open the store in the given data directory, creating the directory if
+    /// it does not exist.
+    #[new]
+    fn new(data_dir: std::path::PathBuf) -> PyResult<Self> {
+        store::Store::open(data_dir, Settings::default())
+            .map(Self)
+            .map_err(runtime_error)
+    }
+
+    /// Append a single record, returning "appended" or "duplicate".
+    ///
+    /// Raises `ValueError` if the record is rejected.
+    fn append(&mut self, record: &Bound<'_, PyAny>) -> PyResult<&'static str> {
+        let json =
+            serde_json::to_vec(&to_value(record)?).map_err(|err| runtime_error(err.into()))?;
+
+        match self.0.append(&json).map_err(runtime_error)? {
+            RecordOutcome::Rejected(err) => Err(PyValueError::new_err(format!("{err:#}"))),
+            outcome => Ok(outcome.status()),
+        }
+    }
+
+    /// Flush any buffered records to the staging file.
+    fn flush(&mut self) -> PyResult<()> {
+        self.0.flush().map_err(runtime_error)
+    }
+
+    /// Read the merged value, or `None` if nothing has been appended.
+    fn read<'py>(&mut self, py: Python<'py>) -> PyResult<Option<Bound<'py, PyAny>>> {
+        self.0
+            .read()
+            .map_err(runtime_error)?
+            .map(|value| to_object(py, &value))
+            .transpose()
+    }
+}
+
+/// Merge two values like records are merged by a store, favouring `b` as
+/// the more recent.
+///
+/// `settings` may be a dict with "array_behavior", "null_behavior",
+/// "union_keep", "key_case", and "mode" keys, which take the same names as the command line options and
+/// the manifest.
+#[pyfunction]
+#[pyo3(signature = (a, b, settings = None))]
+fn merge<'py>(
+    a: &Bound<'py, PyAny>,
+    b: &Bound<'py, PyAny>,
+    settings: Option<&Bound<'py, PyDict>>,
+) -> PyResult<Bound<'py, PyAny>> {
+    let behavior = |key: &str| -> PyResult<Option<String>> {
+        settings
+            .map(|settings| settings.get_item(key))
+            .transpose()?
+            .flatten()
+            .map(|value| value.extract())
+            .transpose()
+    };
+    let settings = merge_settings(
+        behavior("array_behavior")?.as_deref(),
+        behavior("null_behavior")?.as_deref(),
+        behavior("union_keep")?.as_deref(),
+        behavior("key_case")?.as_deref(),
+        behavior("mode")?.as_deref(),
+    )
+    .map_err(|err| PyValueError::new_err(err.to_string()))?;
+
+    to_object(a.py(), &settings.merge(to_value(a)?, to_value(b)?))
+}
+
+#[pymodule]
+fn wall_a(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<Store>()?;
+    module.add_function(wrap_pyfunction!(merge, module)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merge_settings_from_names() {
+        let settings =
+            merge_settings(Some("union"), None, Some("last"), None, Some("crdt")).unwrap();
+        assert_eq!(settings.mode, MergeMode::Crdt);
+        assert_eq!(settings.array_behavior, ArrayBehavior::Union);
+        assert_eq!(settings.union_keep, UnionKeep::Last);
+        assert_eq!(
+            settings.null_behavior,
+            MergeSettings::default().null_behavior
+        );
+
+        let err = merge_settings(None, Some("drop"), None, None, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'drop' is an unknown option for merging null values"
+        );
+    }
+}
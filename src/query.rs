@@ -0,0 +1,98 @@
+//! This module contains ways of selecting parts of a [`Value`]: paths which
+//! address a single part, slices and random samples of arrays, and predicates
+//! which test values.
+
+mod expr;
+mod path;
+mod sample;
+mod slice;
+
+pub use self::{
+    expr::Predicate,
+    path::{Path, PathList, Segment},
+    sample::Sample,
+    slice::Slice,
+};
+use crate::value::Value;
+
+/// Remove the elements of the array at the given path which do not match the
+/// predicate.
+pub fn retain_matching(
+    value: &mut Value,
+    path: &Path,
+    predicate: &Predicate,
+) -> anyhow::Result<()> {
+    match path.lookup_mut(value) {
+        Some(Value::Array(elements)) => {
+            elements.retain(|element| predicate.matches(element));
+            Ok(())
+        }
+        Some(other) => anyhow::bail!(
+            "'{path}' is {} and not an array, its elements cannot be filtered",
+            other.type_name()
+        ),
+        None => anyhow::bail!("'{path}' is not present in the merged value"),
+    }
+}
+
+/// Return a value containing only the parts of the given value at each of
+/// the paths, in the same positions. Paths which are not present are left
+/// out.
+pub fn project(value: &Value, paths: &[Path]) -> Value {
+    let mut projected = Value::Object(Vec::new());
+    for path in paths {
+        if let Some(part) = path.lookup(value) {
+            path.insert(&mut projected, part.clone());
+        }
+    }
+
+    projected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retain_matching_elements() {
+        let mut value = Value::from(serde_json::json!({
+            "events": [{"level": "error"}, {"level": "info"}, {"level": "error", "n": 2}],
+        }));
+        let predicate: Predicate = r#"level == "error""#.parse().unwrap();
+
+        retain_matching(&mut value, &"events".parse().unwrap(), &predicate).unwrap();
+        assert_eq!(
+            value,
+            Value::from(serde_json::json!({
+                "events": [{"level": "error"}, {"level": "error", "n": 2}],
+            }))
+        );
+
+        let err =
+            retain_matching(&mut value, &"events[0]".parse().unwrap(), &predicate).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'events[0]' is object and not an array, its elements cannot be filtered"
+        );
+    }
+
+    #[test]
+    fn project_paths() {
+        let value = Value::from(serde_json::json!({
+            "name": "wall-a",
+            "metrics": {"errors": 3, "warnings": 7},
+            "runs": ["a", "b"],
+        }));
+        let paths =
+            ["metrics.errors", "name", "missing.key", "runs[1]"].map(|path| path.parse().unwrap());
+
+        assert_eq!(
+            project(&value, &paths),
+            Value::from(serde_json::json!({
+                "metrics": {"errors": 3},
+                "name": "wall-a",
+                "runs": [null, "b"],
+            }))
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! Predicates over a [`Value`], written like `metrics.errors > 0 && !muted`.
+//!
+//! The grammar, from lowest to highest precedence, is:
+//!  - `a || b` and `a && b`
+//!  - `!a`
+//!  - comparisons `a == b`, `a != b`, `a < b`, `a <= b`, `a > b`, `a >= b`
+//!  - paths like `runs[0].status` (see [`Path`]), JSON literals like `"ok"`,
+//!    `1.5`, `true`, or `null`, and parenthesized predicates
+//!
+//! A path on its own is true if it exists and is not `null` or `false`.
+//! Paths which do not exist compare as `null`.
+
+use std::{cmp::Ordering, str::FromStr};
+
+use super::path::{split_string_literal, Path};
+use crate::value::Value;
+
+/// A boolean expression which can be tested against a value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Predicate {
+    /// True if either predicate is true
+    Or(Box<Predicate>, Box<Predicate>),
+    /// True if both predicates are true
+    And(Box<Predicate>, Box<Predicate>),
+    /// True if the predicate is false
+    Not(Box<Predicate>),
+    /// True if the comparison between the operands holds
+    Compare(Operand, Comparison, Operand),
+    /// True if the operand is present and not `null` or `false`
+    Truthy(Operand),
+}
+
+/// A value used in a [`Predicate`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Operand {
+    /// The part of the tested value at this path
+    Path(Path),
+    /// A constant value
+    Literal(Value),
+}
+
+/// The comparison operators
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Predicate {
+    /// Return true if the value satisfies this predicate.
+    pub fn matches(&self, value: &Value) -> bool {
+        match self {
+            Predicate::Or(left, right) => left.matches(value) || right.matches(value),
+            Predicate::And(left, right) => left.matches(value) && right.matches(value),
+            Predicate::Not(inner) => !inner.matches(value),
+            Predicate::Compare(left, comparison, right) => {
+                let left = left.resolve(value).unwrap_or(&Value::Null);
+                let right = right.resolve(value).unwrap_or(&Value::Null);
+
+                comparison.holds(left, right)
+            }
+            Predicate::Truthy(operand) => !matches!(
+                operand.resolve(value),
+                None | Some(Value::Null) | Some(Value::Bool(false))
+            ),
+        }
+    }
+}
+
+impl Operand {
+    fn resolve<'v>(&'v self, value: &'v Value) -> Option<&'v Value> {
+        match self {
+            Operand::Path(path) => path.lookup(value),
+            Operand::Literal(literal) => Some(literal),
+        }
+    }
+}
+
+impl Comparison {
+    fn holds(self, left: &Value, right: &Value) -> bool {
+        match self {
+            Comparison::Eq => values_equal(left, right),
+            Comparison::Ne => !values_equal(left, right),
+            Comparison::Lt => compare_values(left, right) == Some(Ordering::Less),
+            Comparison::Le => matches!(
+                compare_values(left, right),
+                Some(Ordering::Less | Ordering::Equal)
+            ),
+            Comparison::Gt => compare_values(left, right) == Some(Ordering::Greater),
+            Comparison::Ge => matches!(
+                compare_values(left, right),
+                Some(Ordering::Greater | Ordering::Equal)
+            ),
+        }
+    }
+}
+
+/// Numbers are equal if they have the same numeric value, everything else is
+/// compared structurally.
+fn values_equal(left: &Value, right: &Value) -> bool {
+    match (left, right) {
+        (Value::Number(_), Value::Number(_)) => {
+            compare_values(left, right) == Some(Ordering::Equal)
+        }
+        _ => left == right,
+    }
+}
+
+/// Numbers are ordered numerically and strings lexicographically, other
+/// values have no order.
+fn compare_values(left: &Value, right: &Value) -> Option<Ordering> {
+    match (left, right) {
+        (Value::Number(left), Value::Number(right)) => {
+            let left: f64 = left.parse().ok()?;
+            let right: f64 = right.parse().ok()?;
+            left.partial_cmp(&right)
+        }
+        (Value::String(left), Value::String(right)) => Some(left.cmp(right)),
+        _ => None,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Not,
+    Compare(Comparison),
+    Operand(Operand),
+}
+
+fn tokenize(mut input: &str) -> anyhow::Result<Vec<Token>> {
+    const SYMBOLS: &[(&str, Token)] = &[
+        ("&&", Token::And),
+        ("||", Token::Or),
+        ("==", Token::Compare(Comparison::Eq)),
+        ("!=", Token::Compare(Comparison::Ne)),
+        ("<=", Token::Compare(Comparison::Le)),
+        (">=", Token::Compare(Comparison::Ge)),
+        ("<", Token::Compare(Comparison::Lt)),
+        (">", Token::Compare(Comparison::Gt)),
+        ("!", Token::Not),
+        ("(", Token::LeftParen),
+        (")", Token::RightParen),
+    ];
+
+    let mut tokens = Vec::new();
+
+    'next: loop {
+        input = input.trim_start();
+        let Some(first) = input.chars().next() else {
+            return Ok(tokens);
+        };
+
+        for (symbol, token) in SYMBOLS {
+            if let Some(rest) = input.strip_prefix(symbol) {
+                tokens.push(token.clone());
+                input = rest;
+                continue 'next;
+            }
+        }
+
+        if first == '"' {
+            let (string, rest) = split_string_literal(input)?;
+            tokens.push(Token::Operand(Operand::Literal(Value::String(string))));
+            input = rest;
+        } else if first == '-' || first.is_ascii_digit() {
+            let end = input
+                .find(|c: char| !(c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')))
+                .unwrap_or(input.len());
+            let (number, rest) = input.split_at(end);
+            let number: serde_json::Number = number
+                .parse()
+                .map_err(|_| anyhow::anyhow!("'{number}' is not a valid number"))?;
+            tokens.push(Token::Operand(Operand::Literal(Value::Number(
+                number.to_string(),
+            ))));
+            input = rest;
+        } else {
+            let keyword = [
+                ("true", Value::Bool(true)),
+                ("false", Value::Bool(false)),
+                ("null", Value::Null),
+            ]
+            .into_iter()
+            .find(|(keyword, _)| {
+                input.strip_prefix(keyword).is_some_and(|rest| {
+                    !rest.starts_with(|c: char| c.is_ascii_alphanumeric() || "_-.[".contains(c))
+                })
+            });
+
+            if let Some((keyword, literal)) = keyword {
+                tokens.push(Token::Operand(Operand::Literal(literal)));
+                input = &input[keyword.len()..];
+            } else {
+                let (path, rest) = Path::parse_prefix(input)?;
+                tokens.push(Token::Operand(Operand::Path(path)));
+                input = rest;
+            }
+        }
+    }
+}
+
+/// A recursive descent parser over the tokens of a predicate
+struct Parser {
+    tokens: std::iter::Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    fn parse_or(&mut self) -> anyhow::Result<Predicate> {
+        let mut left = self.parse_and()?;
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            left = Predicate::Or(Box::new(left), Box::new(self.parse_and()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_and(&mut self) -> anyhow::Result<Predicate> {
+        let mut left = self.parse_not()?;
+        while self.tokens.next_if_eq(&Token::And).is_some() {
+            left = Predicate::And(Box::new(left), Box::new(self.parse_not()?));
+        }
+        Ok(left)
+    }
+
+    fn parse_not(&mut self) -> anyhow::Result<Predicate> {
+        if self.tokens.next_if_eq(&Token::Not).is_some() {
+            return Ok(Predicate::Not(Box::new(self.parse_not()?)));
+        }
+        self.parse_comparison()
+    }
+
+    fn parse_comparison(&mut self) -> anyhow::Result<Predicate> {
+        let left = match self.tokens.next() {
+            Some(Token::LeftParen) => {
+                let inner = self.parse_or()?;
+                if self.tokens.next() != Some(Token::RightParen) {
+                    anyhow::bail!("expected ')'");
+                }
+                return Ok(inner);
+            }
+            Some(Token::Operand(operand)) => operand,
+            Some(token) => anyhow::bail!("unexpected {token:?}, expected a path or literal"),
+            None => anyhow::bail!("unexpected end of predicate"),
+        };
+
+        let Some(Token::Compare(comparison)) = self.tokens.peek().cloned() else {
+            return Ok(Predicate::Truthy(left));
+        };
+        self.tokens.next();
+
+        match self.tokens.next() {
+            Some(Token::Operand(right)) => Ok(Predicate::Compare(left, comparison, right)),
+            _ => anyhow::bail!("expected a path or literal after comparison"),
+        }
+    }
+}
+
+impl FromStr for Predicate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let mut parser = Parser {
+                tokens: tokenize(s)?.into_iter().peekable(),
+            };
+            let predicate = parser.parse_or()?;
+            if let Some(token) = parser.tokens.next() {
+                anyhow::bail!("unexpected {token:?} after the end of the predicate");
+            }
+            Ok(predicate)
+        };
+
+        parse().map_err(|err: anyhow::Error| anyhow::anyhow!("invalid predicate '{s}': {err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(predicate: &str, value: serde_json::Value) -> bool {
+        predicate
+            .parse::<Predicate>()
+            .unwrap()
+            .matches(&Value::from(value))
+    }
+
+    #[test]
+    fn comparisons() {
+        let value = serde_json::json!({
+            "metrics": {"errors": 3, "rate": 0.5},
+            "status": "ok",
+            "muted": false,
+        });
+
+        assert!(matches("metrics.errors > 0", value.clone()));
+        assert!(matches("metrics.errors == 3.0", value.clone()));
+        assert!(!matches("metrics.errors < -1e2", value.clone()));
+        assert!(matches("metrics.rate <= 0.5", value.clone()));
+        assert!(matches(r#"status == "ok""#, value.clone()));
+        assert!(matches(r#"status < "zzz""#, value.clone()));
+        assert!(!matches(r#"status > 1"#, value.clone()));
+        assert!(matches("missing == null", value.clone()));
+        assert!(matches("muted == false", value));
+    }
+
+    #[test]
+    fn boolean_logic() {
+        let value = serde_json::json!({"a": true, "b": false, "c": null, "n": 0});
+
+        assert!(matches("a", value.clone()));
+        assert!(!matches("b", value.clone()));
+        assert!(!matches("c", value.clone()));
+        assert!(matches("n", value.clone()));
+        assert!(!matches("missing", value.clone()));
+        assert!(matches("!b && a", value.clone()));
+        assert!(matches("b || c || a", value.clone()));
+        assert!(!matches("!(a || b)", value.clone()));
+        assert!(matches("a && (b || n == 0)", value));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let err = "a >".parse::<Predicate>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid predicate 'a >': expected a path or literal after comparison"
+        );
+
+        assert!("(a".parse::<Predicate>().is_err());
+        assert!("a b".parse::<Predicate>().is_err());
+        assert!("a == 1.2.3".parse::<Predicate>().is_err());
+        assert!(r#"a == "open"#.parse::<Predicate>().is_err());
+        assert!("".parse::<Predicate>().is_err());
+    }
+}
@@ -0,0 +1,405 @@
+//! Paths which address a part of a [`Value`], written like `metrics.errors`,
+//! `runs[1].reason`, or `["key with spaces"]`.
+
+use std::{fmt, str::FromStr};
+
+use crate::value::Value;
+
+/// A single step of a [`Path`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Segment {
+    /// Select the field with this key of an object
+    Key(String),
+    /// Select the element at this index of an array
+    Index(usize),
+}
+
+/// A sequence of keys and indices leading from the root of a value to one of
+/// its parts.
+///
+/// The path with no segments is written `@` and refers to the whole value.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Path {
+    segments: Vec<Segment>,
+}
+
+impl Path {
+    /// Return the keys and indices of this path, from the root.
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Return the path to the given part of the value at this path.
+    pub fn join(&self, segment: Segment) -> Path {
+        let mut path = self.clone();
+        path.segments.push(segment);
+        path
+    }
+
+    /// Return the part of the value at this path, or `None` if there is
+    /// nothing there.
+    pub fn lookup<'v>(&self, value: &'v Value) -> Option<&'v Value> {
+        self.segments
+            .iter()
+            .try_fold(value, |target, segment| match (target, segment) {
+                (Value::Object(fields), Segment::Key(key)) => fields
+                    .iter()
+                    .find(|(field, _)| field == key)
+                    .map(|(_, value)| value),
+                (Value::Array(elements), Segment::Index(index)) => elements.get(*index),
+                _ => None,
+            })
+    }
+
+    /// Return the part of the value at this path for modification, or `None`
+    /// if there is nothing there.
+    pub fn lookup_mut<'v>(&self, value: &'v mut Value) -> Option<&'v mut Value> {
+        self.segments
+            .iter()
+            .try_fold(value, |target, segment| match (target, segment) {
+                (Value::Object(fields), Segment::Key(key)) => fields
+                    .iter_mut()
+                    .find(|(field, _)| field == key)
+                    .map(|(_, value)| value),
+                (Value::Array(elements), Segment::Index(index)) => elements.get_mut(*index),
+                _ => None,
+            })
+    }
+
+    /// Store the value at this path inside the target, creating any objects
+    /// and arrays along the way. Arrays are padded with `null` up to the
+    /// index, and parts of the target which are in the way are replaced.
+    pub fn insert(&self, target: &mut Value, value: Value) {
+        let target = self
+            .segments
+            .iter()
+            .fold(target, |target, segment| match segment {
+                Segment::Key(key) => {
+                    if !matches!(target, Value::Object(_)) {
+                        *target = Value::Object(Vec::new());
+                    }
+                    let Value::Object(fields) = target else {
+                        unreachable!("target was just made an object")
+                    };
+                    let position = match fields.iter().position(|(field, _)| field == key) {
+                        Some(position) => position,
+                        None => {
+                            fields.push((key.clone(), Value::Null));
+                            fields.len() - 1
+                        }
+                    };
+                    &mut fields[position].1
+                }
+                Segment::Index(index) => {
+                    if !matches!(target, Value::Array(_)) {
+                        *target = Value::Array(Vec::new());
+                    }
+                    let Value::Array(elements) = target else {
+                        unreachable!("target was just made an array")
+                    };
+                    if elements.len() <= *index {
+                        elements.resize(*index + 1, Value::Null);
+                    }
+                    &mut elements[*index]
+                }
+            });
+
+        *target = value;
+    }
+
+    /// Return the path to each leaf of the value along with the leaf, in
+    /// order. Leaves are scalars and empty arrays and objects.
+    pub fn leaves(value: &Value) -> Vec<(Path, &Value)> {
+        fn visit<'v>(path: &mut Path, value: &'v Value, leaves: &mut Vec<(Path, &'v Value)>) {
+            match value {
+                Value::Array(elements) if !elements.is_empty() => {
+                    for (index, element) in elements.iter().enumerate() {
+                        path.segments.push(Segment::Index(index));
+                        visit(path, element, leaves);
+                        path.segments.pop();
+                    }
+                }
+                Value::Object(fields) if !fields.is_empty() => {
+                    for (key, field) in fields {
+                        path.segments.push(Segment::Key(key.clone()));
+                        visit(path, field, leaves);
+                        path.segments.pop();
+                    }
+                }
+                _ => leaves.push((path.clone(), value)),
+            }
+        }
+
+        let mut leaves = Vec::new();
+        visit(&mut Path::default(), value, &mut leaves);
+        leaves
+    }
+
+    /// Parse a path from the start of the input, returning the path and the
+    /// rest of the input after it.
+    pub(super) fn parse_prefix(input: &str) -> anyhow::Result<(Self, &str)> {
+        let mut segments = Vec::new();
+
+        let mut rest = if let Some(rest) = input.strip_prefix('@') {
+            rest
+        } else {
+            let (key, rest) = split_identifier(input);
+            if key.is_empty() && !rest.starts_with('[') {
+                anyhow::bail!("expected a path at '{input}'");
+            }
+            if !key.is_empty() {
+                segments.push(Segment::Key(key.to_string()));
+            }
+            rest
+        };
+
+        loop {
+            if let Some(after_dot) = rest.strip_prefix('.') {
+                let (key, after_key) = split_identifier(after_dot);
+                if key.is_empty() {
+                    anyhow::bail!("expected a key after '.' at '{rest}'");
+                }
+                segments.push(Segment::Key(key.to_string()));
+                rest = after_key;
+            } else if let Some(after_bracket) = rest.strip_prefix('[') {
+                let (segment, after_segment) = parse_bracket(after_bracket)
+                    .map_err(|err| anyhow::anyhow!("{err} at '{rest}'"))?;
+                segments.push(segment);
+                rest = after_segment;
+            } else {
+                return Ok((Self { segments }, rest));
+            }
+        }
+    }
+}
+
+/// Split off the longest prefix of the input which is a bare key, made of
+/// ASCII letters, digits, `_`, and `-`, not starting with a digit or `-`.
+fn split_identifier(input: &str) -> (&str, &str) {
+    let starts_key = input
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    if !starts_key {
+        return ("", input);
+    }
+
+    let end = input
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+        .unwrap_or(input.len());
+    input.split_at(end)
+}
+
+/// Parse the inside of a `[...]` segment, either an array index or a quoted
+/// key, and return it with the rest of the input after the closing bracket.
+fn parse_bracket(input: &str) -> anyhow::Result<(Segment, &str)> {
+    let (segment, rest) = if input.starts_with('"') {
+        let (key, rest) = split_string_literal(input)?;
+        (Segment::Key(key), rest)
+    } else {
+        let end = input
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(input.len());
+        let (digits, rest) = input.split_at(end);
+        let index = digits
+            .parse()
+            .map_err(|_| anyhow::anyhow!("expected an array index or quoted key"))?;
+        (Segment::Index(index), rest)
+    };
+
+    let Some(rest) = rest.strip_prefix(']') else {
+        anyhow::bail!("expected ']'");
+    };
+
+    Ok((segment, rest))
+}
+
+/// Parse a JSON string literal from the start of the input, returning the
+/// decoded string and the rest of the input after the closing quote.
+pub(super) fn split_string_literal(input: &str) -> anyhow::Result<(String, &str)> {
+    let mut escaped = false;
+    let end = input
+        .char_indices()
+        .skip(1)
+        .find(|&(_, c)| {
+            let is_end = c == '"' && !escaped;
+            escaped = c == '\\' && !escaped;
+            is_end
+        })
+        .map(|(index, _)| index + 1)
+        .ok_or_else(|| anyhow::anyhow!("unterminated string"))?;
+
+    let (literal, rest) = input.split_at(end);
+    let string =
+        serde_json::from_str(literal).map_err(|err| anyhow::anyhow!("invalid string: {err}"))?;
+
+    Ok((string, rest))
+}
+
+impl FromStr for Path {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, rest) = Self::parse_prefix(s)?;
+        if !rest.is_empty() {
+            anyhow::bail!("unexpected '{rest}' after path '{path}'");
+        }
+
+        Ok(path)
+    }
+}
+
+/// A comma separated list of paths, like `name,metrics.errors`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PathList(pub Vec<Path>);
+
+impl FromStr for PathList {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut paths = Vec::new();
+        let mut rest = s;
+        loop {
+            let (path, after_path) = Path::parse_prefix(rest.trim_start())?;
+            paths.push(path);
+
+            rest = after_path.trim_start();
+            if rest.is_empty() {
+                return Ok(Self(paths));
+            }
+            let Some(after_comma) = rest.strip_prefix(',') else {
+                anyhow::bail!("expected ',' between paths at '{rest}'");
+            };
+            rest = after_comma;
+        }
+    }
+}
+
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.segments.is_empty() {
+            return f.write_str("@");
+        }
+
+        for (position, segment) in self.segments.iter().enumerate() {
+            match segment {
+                Segment::Key(key) if split_identifier(key) == (key.as_str(), "") => {
+                    if position > 0 {
+                        f.write_str(".")?;
+                    }
+                    f.write_str(key)?;
+                }
+                Segment::Key(key) => write!(
+                    f,
+                    "[{}]",
+                    serde_json::to_string(key).expect("serializing a string cannot fail")
+                )?,
+                Segment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display() {
+        let path: Path = r#"runs[1].reason["odd key"].x-y"#.parse().unwrap();
+        assert_eq!(
+            path.segments,
+            vec![
+                Segment::Key("runs".into()),
+                Segment::Index(1),
+                Segment::Key("reason".into()),
+                Segment::Key("odd key".into()),
+                Segment::Key("x-y".into()),
+            ]
+        );
+        assert_eq!(path.to_string(), r#"runs[1].reason["odd key"].x-y"#);
+
+        assert_eq!("@".parse::<Path>().unwrap(), Path::default());
+        assert_eq!(Path::default().to_string(), "@");
+        assert_eq!("@[0]".parse::<Path>().unwrap().to_string(), "[0]");
+
+        assert!("".parse::<Path>().is_err());
+        assert!("a.".parse::<Path>().is_err());
+        assert!("a[x]".parse::<Path>().is_err());
+        assert!("a b".parse::<Path>().is_err());
+    }
+
+    #[test]
+    fn parse_list() {
+        let PathList(paths) = r#"a.b, ["c,d"],e[0]"#.parse().unwrap();
+        let paths: Vec<_> = paths.iter().map(ToString::to_string).collect();
+        assert_eq!(paths, vec!["a.b", r#"["c,d"]"#, "e[0]"]);
+
+        assert!("a,".parse::<PathList>().is_err());
+        assert!("a b".parse::<PathList>().is_err());
+    }
+
+    #[test]
+    fn lookup() {
+        let mut value = Value::from(serde_json::json!({
+            "metrics": {"errors": "3"},
+            "runs": [{"ok": true}, {"ok": false}],
+        }));
+
+        let path: Path = "runs[1].ok".parse().unwrap();
+        assert_eq!(path.lookup(&value), Some(&Value::Bool(false)));
+        *path.lookup_mut(&mut value).unwrap() = Value::Null;
+        assert_eq!(path.lookup(&value), Some(&Value::Null));
+
+        let path: Path = "metrics.errors[0]".parse().unwrap();
+        assert_eq!(path.lookup(&value), None);
+    }
+
+    #[test]
+    fn insert() {
+        let mut value = Value::from(serde_json::json!({"a": 1}));
+
+        let path: Path = "b.c[2]".parse().unwrap();
+        path.insert(&mut value, Value::Bool(true));
+        let path: Path = "a.d".parse().unwrap();
+        path.insert(&mut value, Value::Null);
+        assert_eq!(
+            value,
+            Value::from(serde_json::json!({"a": {"d": null}, "b": {"c": [null, null, true]}}))
+        );
+
+        Path::default().insert(&mut value, Value::Bool(false));
+        assert_eq!(value, Value::Bool(false));
+    }
+
+    #[test]
+    fn leaves() {
+        let value = Value::from(serde_json::json!({
+            "a": {"b": 1, "c": []},
+            "d": [true, {}],
+            "odd key": null,
+        }));
+
+        let leaves: Vec<_> = Path::leaves(&value)
+            .into_iter()
+            .map(|(path, leaf)| (path.to_string(), leaf.clone()))
+            .collect();
+        assert_eq!(
+            leaves,
+            vec![
+                ("a.b".to_string(), Value::Number("1".into())),
+                ("a.c".to_string(), Value::Array(Vec::new())),
+                ("d[0]".to_string(), Value::Bool(true)),
+                ("d[1]".to_string(), Value::Object(Vec::new())),
+                (r#"["odd key"]"#.to_string(), Value::Null),
+            ]
+        );
+
+        assert_eq!(
+            Path::leaves(&Value::Null),
+            vec![(Path::default(), &Value::Null)]
+        );
+    }
+}
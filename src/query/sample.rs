@@ -0,0 +1,154 @@
+//! Samples which replace a large array with a random selection of its
+//! elements, written like `events=100`.
+
+use std::{fmt, str::FromStr};
+
+use super::path::Path;
+use crate::{bench_gen::SplitMix64, value::Value};
+
+/// The only key of the object which replaces a sampled array, written like
+/// `{"$wall-a:sampled": {"len": 120000, "elements": [...]}}`
+pub const SAMPLED_KEY: &str = "$wall-a:sampled";
+
+/// A number of elements to keep of the array at a path
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sample {
+    path: Path,
+    count: usize,
+}
+
+impl Sample {
+    /// Replace the array at the path with a uniform random sample of its
+    /// elements, kept in their original order, inside a marker object which
+    /// records the length of the array.
+    ///
+    /// Arrays with no more elements than the sample are left as they are.
+    pub fn apply(&self, value: &mut Value, rng: &mut SplitMix64) -> anyhow::Result<()> {
+        let Some(target) = self.path.lookup_mut(value) else {
+            anyhow::bail!("'{}' is not present in the merged value", self.path);
+        };
+        let Value::Array(elements) = &mut *target else {
+            anyhow::bail!(
+                "'{}' is {} and not an array, it cannot be sampled",
+                self.path,
+                target.type_name()
+            );
+        };
+        let len = elements.len();
+        if len <= self.count {
+            return Ok(());
+        }
+
+        // Selection sampling keeps each element with the probability which
+        // gives every subset of `count` elements the same chance
+        let mut needed = self.count;
+        let mut sampled = Vec::with_capacity(self.count);
+        for (index, element) in std::mem::take(elements).into_iter().enumerate() {
+            if rng.below(len - index) < needed {
+                sampled.push(element);
+                needed -= 1;
+            }
+        }
+
+        *target = Value::Object(vec![(
+            SAMPLED_KEY.to_string(),
+            Value::Object(vec![
+                ("len".to_string(), Value::Number(len.to_string())),
+                ("elements".to_string(), Value::Array(sampled)),
+            ]),
+        )]);
+
+        Ok(())
+    }
+}
+
+impl FromStr for Sample {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let Some((path, count)) = s.rsplit_once('=') else {
+                anyhow::bail!("expected a path followed by '=<count>'");
+            };
+
+            // A sample of the whole value may leave out the path
+            let path = if path.is_empty() {
+                Path::default()
+            } else {
+                path.parse()?
+            };
+            let count = count
+                .parse()
+                .map_err(|_| anyhow::anyhow!("'{count}' is not a valid number of elements"))?;
+
+            Ok(Self { path, count })
+        };
+
+        parse().map_err(|err: anyhow::Error| anyhow::anyhow!("invalid sample '{s}': {err}"))
+    }
+}
+
+impl fmt::Display for Sample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.path, self.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_samples() {
+        let mut value = Value::from(serde_json::json!({"events": (0..100).collect::<Vec<_>>()}));
+        let sample = "events=10".parse::<Sample>().unwrap();
+        sample.apply(&mut value, &mut SplitMix64(7)).unwrap();
+
+        let marker = value.pointer("/events/$wall-a:sampled").unwrap();
+        assert_eq!(marker.pointer("/len"), Some(&Value::Number("100".into())));
+        let Some(Value::Array(elements)) = marker.pointer("/elements") else {
+            panic!("the sample has no elements: {marker:?}");
+        };
+        let elements = elements
+            .iter()
+            .map(|element| match element {
+                Value::Number(n) => n.parse::<u32>().unwrap(),
+                other => panic!("unexpected element {other:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(elements.len(), 10);
+        assert!(elements.windows(2).all(|pair| pair[0] < pair[1]));
+
+        // The same seed gives the same sample
+        let mut again = Value::from(serde_json::json!({"events": (0..100).collect::<Vec<_>>()}));
+        sample.apply(&mut again, &mut SplitMix64(7)).unwrap();
+        assert_eq!(again, value);
+
+        let mut small = Value::from(serde_json::json!({"events": [1, 2]}));
+        sample.apply(&mut small, &mut SplitMix64(7)).unwrap();
+        assert_eq!(small, Value::from(serde_json::json!({"events": [1, 2]})));
+
+        let mut object = Value::from(serde_json::json!({"events": {"a": 1}}));
+        let err = sample.apply(&mut object, &mut SplitMix64(7)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'events' is object and not an array, it cannot be sampled"
+        );
+    }
+
+    #[test]
+    fn parse_samples() {
+        assert_eq!(
+            "runs[2].events=100".parse::<Sample>().unwrap().to_string(),
+            "runs[2].events=100"
+        );
+        assert_eq!("=5".parse::<Sample>().unwrap().to_string(), "@=5");
+
+        let err = "events".parse::<Sample>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid sample 'events': expected a path followed by '=<count>'"
+        );
+        assert!("events=-1".parse::<Sample>().is_err());
+    }
+}
@@ -0,0 +1,156 @@
+//! Slices which select a range of elements from an array, written like
+//! `events[100:200]`.
+
+use std::{fmt, str::FromStr};
+
+use super::path::Path;
+use crate::value::Value;
+
+/// A range of elements of the array at a path, where either end of the range
+/// may be left open
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slice {
+    path: Path,
+    /// The index of the first element to keep
+    start: Option<usize>,
+    /// The index after the last element to keep
+    end: Option<usize>,
+}
+
+impl Slice {
+    /// Replace the array at the path with only the elements in the range.
+    ///
+    /// Bounds past the end of the array are clamped to its length.
+    pub fn apply(&self, value: &mut Value) -> anyhow::Result<()> {
+        let elements = match self.path.lookup_mut(value) {
+            Some(Value::Array(elements)) => elements,
+            Some(other) => anyhow::bail!(
+                "'{}' is {} and not an array, it cannot be sliced",
+                self.path,
+                other.type_name()
+            ),
+            None => anyhow::bail!("'{}' is not present in the merged value", self.path),
+        };
+
+        let end = self.end.unwrap_or(usize::MAX).min(elements.len());
+        let start = self.start.unwrap_or(0).min(end);
+        elements.truncate(end);
+        elements.drain(..start);
+
+        Ok(())
+    }
+}
+
+impl FromStr for Slice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let Some((path, range)) = s
+                .strip_suffix(']')
+                .and_then(|s| s.rsplit_once('['))
+                .filter(|(_, range)| range.contains(':'))
+            else {
+                anyhow::bail!("expected a path followed by '[start:end]'");
+            };
+
+            // A slice of the whole value may leave out the path
+            let path = if path.is_empty() {
+                Path::default()
+            } else {
+                path.parse()?
+            };
+
+            let (start, end) = range.split_once(':').expect("range contains ':'");
+            let parse_bound = |bound: &str| -> anyhow::Result<Option<usize>> {
+                if bound.is_empty() {
+                    return Ok(None);
+                }
+                bound
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| anyhow::anyhow!("'{bound}' is not a valid array index"))
+            };
+
+            Ok(Self {
+                path,
+                start: parse_bound(start)?,
+                end: parse_bound(end)?,
+            })
+        };
+
+        parse().map_err(|err: anyhow::Error| anyhow::anyhow!("invalid slice '{s}': {err}"))
+    }
+}
+
+impl fmt::Display for Slice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[", self.path)?;
+        if let Some(start) = self.start {
+            write!(f, "{start}")?;
+        }
+        f.write_str(":")?;
+        if let Some(end) = self.end {
+            write!(f, "{end}")?;
+        }
+        f.write_str("]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sliced(slice: &str) -> Value {
+        let mut value = Value::from(serde_json::json!({"events": [0, 1, 2, 3, 4]}));
+        slice.parse::<Slice>().unwrap().apply(&mut value).unwrap();
+        value
+    }
+
+    #[test]
+    fn apply_slices() {
+        assert_eq!(
+            sliced("events[1:3]"),
+            Value::from(serde_json::json!({"events": [1, 2]}))
+        );
+        assert_eq!(
+            sliced("events[3:]"),
+            Value::from(serde_json::json!({"events": [3, 4]}))
+        );
+        assert_eq!(
+            sliced("events[:2]"),
+            Value::from(serde_json::json!({"events": [0, 1]}))
+        );
+        assert_eq!(
+            sliced("events[4:100]"),
+            Value::from(serde_json::json!({"events": [4]}))
+        );
+        assert_eq!(
+            sliced("events[3:1]"),
+            Value::from(serde_json::json!({"events": []}))
+        );
+
+        let mut value = Value::from(serde_json::json!([0, 1, 2]));
+        "[1:]".parse::<Slice>().unwrap().apply(&mut value).unwrap();
+        assert_eq!(value, Value::from(serde_json::json!([1, 2])));
+    }
+
+    #[test]
+    fn parse_slices() {
+        assert_eq!(
+            "runs[2].events[10:20]"
+                .parse::<Slice>()
+                .unwrap()
+                .to_string(),
+            "runs[2].events[10:20]"
+        );
+        assert_eq!("[:]".parse::<Slice>().unwrap().to_string(), "@[:]");
+
+        let err = "events[2]".parse::<Slice>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid slice 'events[2]': expected a path followed by '[start:end]'"
+        );
+        assert!("events[-1:]".parse::<Slice>().is_err());
+    }
+}
@@ -1,100 +1,657 @@
 //! This module contains the implementation of the `read` CLI command
 
 use std::{
-    collections::BTreeMap,
-    io::{self, ErrorKind},
+    io::{self, Write},
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
 use anyhow::Context;
 use argh::FromArgs;
 
 use crate::{
-    archive::read_archive_value,
-    staging::StagingFileReader,
-    value::{merge::MergeSettings, Value},
+    archive::archive_name,
+    bench_gen::SplitMix64,
+    compression::Compression,
+    explain::explain,
+    merge_settings::read_merge_settings,
+    profile::{peak_rss_bytes, write_profile, AllocationCounts, ResourceUsage},
+    query::{self, project, retain_matching, PathList, Predicate, Sample, Slice},
+    sources::SourceLabel,
+    store::{
+        profile::ReadProfile,
+        read_merged_value_profiled,
+        recovery::recover_temp_files,
+        updated::{annotate, read_last_updated, read_provenance, restrict},
+        ReadOptions, SkippedArchive,
+    },
+    table::{rows_at, value_text},
+    template::Template,
+    toml_output::write_toml,
+    value::{merge::ArrayBehaviorAt, preview::Preview, Value, DEFAULT_MAX_DEPTH},
 };
 
 /// The `read` sub-command reads and merges all the archived JSON data
 /// into a single object and outputs it to stdout.
 #[derive(Debug, PartialEq, FromArgs)]
 #[argh(subcommand, name = "read")]
-pub struct ReadCommand {}
+pub struct ReadCommand {
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the stored values, deeper values are rejected as an error.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+    /// instead of the full merged value, print each top-level key along with
+    /// the type and serialized size of its value. The whole value is still
+    /// merged from every archive and staging file, so this is no faster than
+    /// reading it, but the output is much smaller.
+    #[argh(switch)]
+    keys: bool,
+    /// how to write the merged value, either "json" (the default), "flat",
+    /// which writes one line for each leaf value with its path and the value
+    /// as JSON separated by a tab, "csv" and "tsv", which write the --columns
+    /// as a table with a header, "toml", which writes a TOML document that
+    /// leaves out null fields, or "arrow", which writes an Arrow IPC stream
+    /// with a column for each leaf path and requires the `arrow` feature.
+    #[argh(option, default = "OutputFormat::Json")]
+    format: OutputFormat,
+    /// instead of writing the merged value in a format, render this template
+    /// file with it, like a markdown or HTML report. Templates use the Jinja
+    /// syntax, with the merged value as "value" and each of its top-level
+    /// fields as a variable of the same name.
+    #[argh(option)]
+    template: Option<PathBuf>,
+    /// the comma separated paths to write as columns with --format csv or
+    /// tsv, like 'name,metrics.errors'.
+    #[argh(option)]
+    columns: Option<PathList>,
+    /// write one csv, tsv, or arrow row for each element of the array at this
+    /// path, instead of a single row for the whole merged value.
+    #[argh(option)]
+    rows: Option<query::Path>,
+    /// compress the output with either "gzip" or "zstd".
+    #[argh(option)]
+    compress: Option<Compression>,
+    /// only output the merged value if it matches the given predicate, like
+    /// 'metrics.errors > 0 && status != "ok"'.
+    #[argh(option, long = "where")]
+    predicate: Option<Predicate>,
+    /// instead of testing the whole merged value with --where, keep only the
+    /// elements of the array at the given path which match it.
+    #[argh(option)]
+    where_at: Option<query::Path>,
+    /// only output a range of the elements of an array in the merged value,
+    /// given like 'events[100:200]'. Either end of the range may be left
+    /// out, and this option may be repeated.
+    #[argh(option)]
+    slice: Vec<Slice>,
+    /// replace a large array in the merged value with a random sample of
+    /// its elements, given like 'events=100', inside an object which also
+    /// records the length of the array. This option may be repeated.
+    #[argh(option)]
+    sample: Vec<Sample>,
+    /// output an object with the merged value under "value" and the time
+    /// that each part of it was last updated under "updated", which has the
+    /// same objects as the value with each other part replaced by a time.
+    #[argh(switch)]
+    with_timestamps: bool,
+    /// output the merged value with every part except objects wrapped in
+    /// an object with the part under "value", the archive or "staging" for
+    /// the staging file which last set it under "source", and the time it
+    /// was set under "at".
+    #[argh(switch)]
+    with_provenance: bool,
+    /// leave out archives which fail their checksums or cannot be decoded,
+    /// instead of failing. The skipped archives are listed on stderr after
+    /// the output.
+    #[argh(switch)]
+    skip_corrupt: bool,
+    /// do not verify the checksums of the archive bodies, which is faster
+    /// for large archives. Unsafe unless the archives are trusted and stored
+    /// on a filesystem which checksums its data, since a corrupted archive
+    /// may then be read as the wrong value.
+    #[argh(switch)]
+    no_verify: bool,
+    /// only read the records appended with `append --source` and this
+    /// label, instead of the records of every source.
+    #[argh(option)]
+    source: Option<SourceLabel>,
+    /// merge the arrays matched by a pattern with another behavior than the
+    /// one in the manifest, given like 'events=concat' or
+    /// 'hosts.*.tags=union'. Only the merges made while reading use it, so
+    /// records archived together stay merged as they were. This option may
+    /// be repeated, and the first pattern which matches an array is used.
+    #[argh(option)]
+    array_behavior_at: Vec<ArrayBehaviorAt>,
+    /// merge with the settings in this TOML file instead of the ones in the
+    /// manifest, along with the array behaviors it gives for some paths,
+    /// which apply after the ones from --array-behavior-at.
+    #[argh(option)]
+    merge_settings: Option<PathBuf>,
+    /// instead of the merged value, show how the value at the given path was
+    /// produced: each archive and staging record which contributed to it,
+    /// the merge rule which combined it with the earlier records, and the
+    /// value at the path afterwards.
+    #[argh(option)]
+    explain: Option<query::Path>,
+    /// the number of levels of arrays and objects to output, for a preview of
+    /// a large value. Deeper arrays and objects are replaced by a string of
+    /// "..." inside their brackets, like "[...]".
+    #[argh(option)]
+    max_depth: Option<usize>,
+    /// the number of characters to output of each string, for a preview of a
+    /// large value. Longer strings are cut short and end with "…".
+    #[argh(option)]
+    truncate_strings: Option<usize>,
+    /// with --max-depth or --truncate-strings, replace each truncated part
+    /// with an object holding a "$wall-a:truncated" key, which describes its
+    /// type and length, and the start of a string, so that truncated output
+    /// can be told apart from real data.
+    #[argh(switch)]
+    truncation_markers: bool,
+    /// after the output, write a report to stderr of the bytes read and the
+    /// time spent decoding and merging each archive, the peak resident set
+    /// size, and the number of allocations, which are only counted when
+    /// built with the `count-allocations` feature.
+    #[argh(switch)]
+    profile: bool,
+    /// paths like 'name' or 'metrics.errors' to output, instead of the whole
+    /// merged value. The output is an object containing only these parts.
+    #[argh(positional)]
+    paths: Vec<query::Path>,
+}
+
+/// This enum controls how the `read` command writes the merged value
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Write the value as compact JSON
+    #[default]
+    Json,
+    /// Write each leaf of the value on its own line, after its path
+    Flat,
+    /// Write selected paths as comma separated values
+    Csv,
+    /// Write selected paths as tab separated values
+    Tsv,
+    /// Write the value as a TOML document
+    Toml,
+    /// Write every leaf path as a column of an Arrow IPC stream
+    Arrow,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "json" => Self::Json,
+            "flat" => Self::Flat,
+            "csv" => Self::Csv,
+            "tsv" => Self::Tsv,
+            "toml" => Self::Toml,
+            "arrow" => Self::Arrow,
+            x => anyhow::bail!("'{x}' is an unknown option for the output format"),
+        })
+    }
+}
 
 impl ReadCommand {
     /// This function executes the read command.
     #[tracing::instrument]
     pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
-        let mut scratch_buffer = Vec::<u8>::new();
+        let table = matches!(self.format, OutputFormat::Csv | OutputFormat::Tsv);
+        if table != self.columns.is_some() {
+            anyhow::bail!("--columns must be given exactly when --format is csv or tsv");
+        }
+        if self.rows.is_some() && !(table || self.format == OutputFormat::Arrow) {
+            anyhow::bail!("--rows requires --format csv, tsv, or arrow");
+        }
+        if self.with_timestamps
+            && (self.keys || !matches!(self.format, OutputFormat::Json | OutputFormat::Flat))
+        {
+            anyhow::bail!("--with-timestamps requires --format json or flat, without --keys");
+        }
+        if self.template.is_some() && (self.keys || self.format != OutputFormat::Json) {
+            anyhow::bail!("--template cannot be combined with --keys or --format");
+        }
+        if self.with_timestamps && self.skip_corrupt {
+            anyhow::bail!("only one of --with-timestamps and --skip-corrupt may be given");
+        }
+        if self.with_provenance
+            && (self.keys || !matches!(self.format, OutputFormat::Json | OutputFormat::Flat))
+        {
+            anyhow::bail!("--with-provenance requires --format json or flat, without --keys");
+        }
+        if self.with_provenance && (self.with_timestamps || self.skip_corrupt) {
+            anyhow::bail!(
+                "--with-provenance cannot be combined with --with-timestamps or --skip-corrupt"
+            );
+        }
+
+        if self.truncation_markers && self.preview().is_empty() {
+            anyhow::bail!("--truncation-markers requires --max-depth or --truncate-strings");
+        }
+        if !self.preview().is_empty()
+            && (self.keys || !matches!(self.format, OutputFormat::Json | OutputFormat::Flat))
+        {
+            anyhow::bail!(
+                "--max-depth and --truncate-strings require --format json or flat, without --keys"
+            );
+        }
+
+        if self.explain.is_some()
+            && (self.keys
+                || !self.preview().is_empty()
+                || self.with_timestamps
+                || self.with_provenance
+                || self.skip_corrupt
+                || self.predicate.is_some()
+                || !self.slice.is_empty()
+                || !self.sample.is_empty()
+                || !self.paths.is_empty()
+                || self.compress.is_some()
+                || self.format != OutputFormat::Json
+                || self.template.is_some()
+                || !self.array_behavior_at.is_empty()
+                || self.merge_settings.is_some()
+                || self.profile)
+        {
+            anyhow::bail!("--explain cannot be combined with options which change the output");
+        }
+
+        if self.source.is_some()
+            && (self.explain.is_some() || self.with_timestamps || self.with_provenance)
+        {
+            anyhow::bail!(
+                "--source cannot be combined with --explain, --with-timestamps, or \
+                 --with-provenance"
+            );
+        }
+
+        recover_temp_files(&data_dir).context("recovering leftover temporary files")?;
+        if let Some(path) = &self.explain {
+            return explain(
+                io::stdout().lock(),
+                &data_dir,
+                path,
+                self.max_nesting_depth,
+                !self.no_verify,
+            );
+        }
 
-        let archived_value = collect_archived_values(&mut scratch_buffer, &data_dir)
-            .context("collecting and merging all archived values")?;
+        let template = self.template.as_deref().map(Template::load).transpose()?;
+        let merge_config = self
+            .merge_settings
+            .as_deref()
+            .map(read_merge_settings)
+            .transpose()?;
+        let mut array_behaviors = self.array_behavior_at.clone();
+        if let Some(merge_config) = &merge_config {
+            array_behaviors.extend(merge_config.array_behaviors.iter().cloned());
+        }
 
-        let staging_value = StagingFileReader::read_merged_value(&data_dir)
-            .context("opening staging file for archiving")?;
+        let options = ReadOptions {
+            skip_corrupt: self.skip_corrupt,
+            verify_checksums: !self.no_verify,
+            source: self.source.as_ref(),
+            array_behaviors: &array_behaviors,
+            merge_settings: merge_config.map(|merge_config| merge_config.settings),
+            custom_merges: &[],
+        };
+        let allocations_before = AllocationCounts::now();
+        let mut profile = ReadProfile::default();
+        let (final_value, skipped) =
+            read_merged_value_profiled(&data_dir, self.max_nesting_depth, options, &mut profile)?;
 
-        let final_value = match (archived_value, staging_value) {
-            (None, None) => {
+        let result = match final_value {
+            Some(final_value) => self.output(&data_dir, final_value, template.as_ref()),
+            None => {
                 tracing::warn!("No data is present in archive or staging");
-                return Ok(());
+                Ok(())
             }
-            (None, Some(value)) | (Some(value), None) => value,
-            (Some(accum), Some(value)) => {
-                let merge_settings = MergeSettings::default();
+        };
+
+        // The output comes first, even when stdout and stderr are the same
+        io::stdout().flush().context("flushing stdout")?;
+        report_skipped(io::stderr().lock(), &skipped).context("writing skipped archives")?;
+        if self.profile {
+            let usage = ResourceUsage {
+                peak_rss_bytes: peak_rss_bytes(),
+                allocations: AllocationCounts::now()
+                    .zip(allocations_before)
+                    .map(|(after, before)| after.since(before)),
+            };
+            write_profile(io::stderr().lock(), &profile, usage)
+                .context("writing profile report")?;
+        }
+        result
+    }
 
-                merge_settings.merge(accum, value)
+    /// Filter the merged value with the query options and write it to stdout.
+    fn output(
+        &self,
+        data_dir: &Path,
+        mut final_value: Value,
+        template: Option<&Template>,
+    ) -> anyhow::Result<()> {
+        match (&self.predicate, &self.where_at) {
+            (Some(predicate), Some(path)) => {
+                retain_matching(&mut final_value, path, predicate)?;
             }
-        };
+            (Some(predicate), None) => {
+                if !predicate.matches(&final_value) {
+                    tracing::info!("Merged value does not match the --where predicate");
+                    return Ok(());
+                }
+            }
+            (None, Some(_)) => anyhow::bail!("--where-at requires a --where predicate"),
+            (None, None) => {}
+        }
+
+        for slice in &self.slice {
+            slice.apply(&mut final_value)?;
+        }
+
+        let mut rng = SplitMix64(jiff::Timestamp::now().as_nanosecond() as u64);
+        for sample in &self.sample {
+            sample.apply(&mut final_value, &mut rng)?;
+        }
+
+        if !self.paths.is_empty() {
+            final_value = project(&final_value, &self.paths);
+        }
+
+        if self.with_timestamps {
+            let updated = read_last_updated(data_dir, self.max_nesting_depth)?
+                .map(|tree| restrict(&tree, &final_value))
+                .unwrap_or(Value::Null);
+            final_value = Value::Object(vec![
+                ("value".into(), final_value),
+                ("updated".into(), updated),
+            ]);
+        }
+
+        if self.with_provenance {
+            let provenance =
+                read_provenance(data_dir, self.max_nesting_depth)?.unwrap_or(Value::Null);
+            final_value = annotate(&provenance, &final_value);
+        }
+
+        let final_value = self.preview().apply(final_value);
 
         let stdout = io::stdout();
         let handle = stdout.lock();
 
-        serde_json::to_writer(handle, &final_value).context("writing final value to stdout")?;
+        match self.compress {
+            Some(compression) => {
+                let mut encoder = compression
+                    .encoder(handle)
+                    .context("starting compressed output")?;
+                self.write_output(&mut encoder, &final_value, template)?;
+                encoder.finish().context("finishing compressed output")?;
+            }
+            None => self.write_output(handle, &final_value, template)?,
+        }
 
         Ok(())
     }
-}
 
-fn collect_archived_values(
-    scratch_buffer: &mut Vec<u8>,
-    data_dir: &Path,
-) -> anyhow::Result<Option<Value>> {
-    let archive_dir_entries = match data_dir.join("archived").read_dir() {
-        Ok(entries) => entries,
-        Err(err) => {
-            if matches!(err.kind(), ErrorKind::NotFound) {
-                // archived directory does not exist
-                return Ok(None);
-            } else {
-                return Err(err).context("reading archived directory entries");
+    /// Return the limits of the preview given by --max-depth and
+    /// --truncate-strings.
+    fn preview(&self) -> Preview {
+        Preview {
+            max_depth: self.max_depth,
+            max_string_len: self.truncate_strings,
+            markers: self.truncation_markers,
+        }
+    }
+
+    /// Write the final value to the writer in the chosen format, or render
+    /// the template with it.
+    fn write_output(
+        &self,
+        mut writer: impl Write,
+        final_value: &Value,
+        template: Option<&Template>,
+    ) -> anyhow::Result<()> {
+        if self.keys {
+            return write_keys(writer, final_value).context("writing keys to stdout");
+        }
+        if let Some(template) = template {
+            return template.render(writer, final_value);
+        }
+
+        match self.format {
+            OutputFormat::Json => {
+                serde_json::to_writer(writer, final_value).context("writing final value to stdout")
             }
+            OutputFormat::Flat => {
+                write_flat(writer, final_value).context("writing leaves to stdout")
+            }
+            OutputFormat::Csv | OutputFormat::Tsv => {
+                let delimiter = if self.format == OutputFormat::Csv {
+                    b','
+                } else {
+                    b'\t'
+                };
+                let columns = self
+                    .columns
+                    .as_ref()
+                    .map(|PathList(columns)| columns.as_slice())
+                    .unwrap_or_default();
+                write_table(
+                    &mut writer,
+                    final_value,
+                    self.rows.as_ref(),
+                    columns,
+                    delimiter,
+                )
+                .context("writing table to stdout")
+            }
+            OutputFormat::Toml => write_toml(writer, final_value),
+            OutputFormat::Arrow => write_arrow(writer, final_value, self.rows.as_ref())
+                .context("writing Arrow stream to stdout"),
         }
-    };
+    }
+}
 
-    // Iterate through all dir entries ordered by filename (the timestamp part of the filename specifically)
-    let mut all_entries = archive_dir_entries
-        .map(|res| res.map(|entry| (entry.file_name(), entry)))
-        .collect::<Result<BTreeMap<_, _>, _>>()
-        .context("reading all dir entries into set")?;
+/// Write one line for each archive which was left out of the merged value by
+/// --skip-corrupt, with the reason it could not be read.
+fn report_skipped(mut writer: impl Write, skipped: &[SkippedArchive]) -> io::Result<()> {
+    for archive in skipped {
+        writeln!(
+            writer,
+            "skipped corrupt archive {}: {:#}",
+            archive_name(&archive.path),
+            archive.error
+        )?;
+    }
 
-    let Some((_, first_entry)) = all_entries.pop_first() else {
-        // The directory was empty
-        return Ok(None);
+    Ok(())
+}
+
+/// Write one line for each top-level key of the given value, with the type
+/// and the serialized JSON size in bytes of the associated value.
+fn write_keys(mut writer: impl Write, value: &Value) -> anyhow::Result<()> {
+    let Value::Object(fields) = value else {
+        anyhow::bail!(
+            "merged value is {} and not an object, it has no keys",
+            value.type_name()
+        );
     };
 
-    let mut accum = read_archive_value(&first_entry.path(), scratch_buffer)
-        .context("reading first archive value")?;
+    for (key, value) in fields {
+        writeln!(writer, "{key}\t{}\t{}", value.type_name(), value.json_len())?;
+    }
 
-    let merge_settings = MergeSettings::default();
+    Ok(())
+}
 
-    for (_, entry) in all_entries {
-        scratch_buffer.clear();
+/// Write one line for each leaf of the given value, with the path to the leaf
+/// and the leaf as compact JSON.
+fn write_flat(mut writer: impl Write, value: &Value) -> anyhow::Result<()> {
+    for (path, leaf) in query::Path::leaves(value) {
+        writeln!(writer, "{path}\t{}", serde_json::to_string(leaf)?)?;
+    }
 
-        let value =
-            read_archive_value(&entry.path(), scratch_buffer).context("reading archive value")?;
+    Ok(())
+}
 
-        accum = merge_settings.merge(accum, value);
+/// Write the values at the column paths as a table with a header row, with a
+/// single row for the whole value or one row for each element of the array
+/// at the rows path.
+///
+/// Strings are written without quotes, missing values and `null` as empty
+/// fields, and arrays and objects as compact JSON.
+fn write_table(
+    writer: impl Write,
+    value: &Value,
+    rows: Option<&query::Path>,
+    columns: &[query::Path],
+    delimiter: u8,
+) -> anyhow::Result<()> {
+    let rows = rows_at(value, rows)?;
+
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(writer);
+    writer.write_record(columns.iter().map(ToString::to_string))?;
+
+    for row in rows {
+        let fields = columns
+            .iter()
+            .map(|column| match column.lookup(row) {
+                None | Some(Value::Null) => String::new(),
+                Some(value) => value_text(value),
+            })
+            .collect::<Vec<_>>();
+        writer.write_record(fields)?;
     }
+    writer.flush()?;
 
-    Ok(Some(accum))
+    Ok(())
+}
+
+/// Write the leaf paths of the rows as the columns of a single record batch
+/// in an Arrow IPC stream.
+#[cfg(not(feature = "arrow"))]
+fn write_arrow(
+    _writer: impl Write,
+    _value: &Value,
+    _rows: Option<&query::Path>,
+) -> anyhow::Result<()> {
+    anyhow::bail!(
+        "wall-a was built without the `arrow` feature, so --format arrow is not available"
+    )
+}
+
+/// Write the leaf paths of the rows as the columns of a single record batch
+/// in an Arrow IPC stream.
+#[cfg(feature = "arrow")]
+fn write_arrow(
+    writer: impl Write,
+    value: &Value,
+    rows: Option<&query::Path>,
+) -> anyhow::Result<()> {
+    use crate::table::{to_record_batch, Table};
+
+    let batch = to_record_batch(&Table::from_rows(rows_at(value, rows)?))?;
+
+    let mut writer = arrow_ipc::writer::StreamWriter::try_new(writer, &batch.schema())?;
+    writer.write(&batch)?;
+    writer.finish()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_of_object() {
+        let value = Value::from(serde_json::json!({
+            "name": "wall-a",
+            "runs": [true, false],
+            "nested": {"a": null},
+        }));
+
+        let mut output = Vec::new();
+        write_keys(&mut output, &value).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name\tstring\t8\nruns\tarray\t12\nnested\tobject\t10\n"
+        );
+    }
+
+    #[test]
+    fn flat_leaves() {
+        let value = Value::from(serde_json::json!({
+            "name": "wall-a",
+            "runs": [{"ok": true}, {"ok": false}],
+        }));
+
+        let mut output = Vec::new();
+        write_flat(&mut output, &value).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "name\t\"wall-a\"\nruns[0].ok\ttrue\nruns[1].ok\tfalse\n"
+        );
+    }
+
+    #[test]
+    fn table_of_rows() {
+        let value = Value::from(serde_json::json!({
+            "events": [
+                {"id": 1, "msg": "hello, world", "tags": ["a"]},
+                {"id": 2, "ok": true},
+            ],
+        }));
+        let PathList(columns) = "id,msg,ok,tags".parse().unwrap();
+
+        let mut output = Vec::new();
+        write_table(
+            &mut output,
+            &value,
+            Some(&"events".parse().unwrap()),
+            &columns,
+            b',',
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "id,msg,ok,tags\n1,\"hello, world\",,\"[\"\"a\"\"]\"\n2,,true,\n"
+        );
+
+        let mut output = Vec::new();
+        write_table(&mut output, &value, None, &columns[..1], b'\t').unwrap();
+        // A row with a single empty field is quoted so it is not a blank line
+        assert_eq!(String::from_utf8(output).unwrap(), "id\n\"\"\n");
+    }
+
+    #[test]
+    fn keys_of_non_object() {
+        let value = Value::from(serde_json::json!([1, 2, 3]));
+
+        let err = write_keys(Vec::new(), &value).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "merged value is array and not an object, it has no keys"
+        );
+    }
+
+    #[test]
+    fn skipped_archives() {
+        let skipped = [SkippedArchive {
+            path: PathBuf::from("data/archived/2024-06-19-19-22-45.bin"),
+            error: anyhow::anyhow!("checksum mismatch").context("reading archive value"),
+        }];
+
+        let mut output = Vec::new();
+        report_skipped(&mut output, &skipped).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "skipped corrupt archive 2024-06-19-19-22-45.bin: reading archive value: checksum \
+             mismatch\n"
+        );
+    }
 }
@@ -0,0 +1,253 @@
+//! This module contains the implementation of the `repair` CLI command
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+
+use crate::{
+    archive::archive_name,
+    output::Output,
+    store::repair::{repair_archives, Repair},
+    value::DEFAULT_MAX_DEPTH,
+};
+
+/// The `repair` sub-command replaces each corrupted archive with the
+/// top-level keys that lie entirely in blocks of its body which match their
+/// checksums, so that reads no longer fail on it. The values of the lost
+/// keys fall back to the older archives.
+///
+/// The corrupted archive is deleted once its replacement is in place, so
+/// copy it first to keep it.
+///
+/// With `--output json` or `--report`, the repairs are written as a JSON
+/// object for automation, with the problem, action taken, and salvaged and
+/// lost keys of each corrupted archive.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "repair")]
+pub struct RepairCommand {
+    /// only report which keys would be salvaged and lost, without replacing
+    /// any archive.
+    #[argh(switch)]
+    dry_run: bool,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the archived values.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+    /// write the replacement archives with direct IO on Linux, bypassing the
+    /// page cache.
+    #[argh(switch)]
+    direct_io: bool,
+    /// also write the JSON report to this file, which is written even when
+    /// an archive cannot be salvaged.
+    #[argh(option)]
+    report: Option<PathBuf>,
+}
+
+impl RepairCommand {
+    /// This function executes the repair command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf, output: Output) -> anyhow::Result<()> {
+        let repairs = repair_archives(
+            &data_dir,
+            self.max_nesting_depth,
+            self.dry_run,
+            self.direct_io,
+        )
+        .context("repairing archives")?;
+
+        if let Some(report_path) = &self.report {
+            let mut report = serde_json::to_vec(&json_report(&data_dir, &repairs, self.dry_run))
+                .context("converting repair report to JSON")?;
+            report.push(b'\n');
+            fs::write(report_path, report)
+                .with_context(|| format!("writing repair report to '{}'", report_path.display()))?;
+        }
+
+        if output.is_json() {
+            output.write_result("repair", json_report(&data_dir, &repairs, self.dry_run))?;
+        } else if repairs.is_empty() {
+            println!("no corrupted archives found");
+        } else {
+            for repair in &repairs {
+                println!("{}", describe_repair(repair, self.dry_run));
+            }
+        }
+
+        let unsalvageable = repairs
+            .iter()
+            .filter(|repair| matches!(repair, Repair::Unsalvageable { .. }))
+            .count();
+        if unsalvageable > 0 {
+            anyhow::bail!("could not salvage {unsalvageable} corrupted archive(s)");
+        }
+
+        Ok(())
+    }
+}
+
+/// Return the report of the repairs in the data directory as a JSON object,
+/// with the number of salvaged and unsalvageable archives.
+fn json_report(data_dir: &Path, repairs: &[Repair], dry_run: bool) -> serde_json::Value {
+    let unsalvageable = repairs
+        .iter()
+        .filter(|repair| matches!(repair, Repair::Unsalvageable { .. }))
+        .count();
+
+    serde_json::json!({
+        "data_dir": data_dir.display().to_string(),
+        "dry_run": dry_run,
+        "salvaged": repairs.len() - unsalvageable,
+        "unsalvageable": unsalvageable,
+        "archives": repairs.iter().map(json_repair).collect::<Vec<_>>(),
+    })
+}
+
+/// Return the repair of a single archive as a JSON object, where the action
+/// is `replaced` if a replacement was written, or `none` otherwise.
+fn json_repair(repair: &Repair) -> serde_json::Value {
+    match repair {
+        Repair::Salvaged {
+            archive,
+            problem,
+            replacement,
+            salvaged_keys,
+            lost_keys,
+        } => serde_json::json!({
+            "name": archive_name(archive),
+            "status": "salvaged",
+            "problem": problem,
+            "action": if replacement.is_some() { "replaced" } else { "none" },
+            "replacement": replacement.as_deref().map(archive_name),
+            "salvaged_keys": salvaged_keys,
+            "lost_keys": lost_keys,
+        }),
+        Repair::Unsalvageable {
+            archive,
+            problem,
+            error,
+        } => serde_json::json!({
+            "name": archive_name(archive),
+            "status": "unsalvageable",
+            "problem": problem,
+            "action": "none",
+            "error": format!("{error:#}"),
+        }),
+    }
+}
+
+fn describe_repair(repair: &Repair, dry_run: bool) -> String {
+    match repair {
+        Repair::Salvaged {
+            archive,
+            replacement,
+            salvaged_keys,
+            lost_keys,
+            ..
+        } => {
+            let verb = if dry_run { "would salvage" } else { "salvaged" };
+            let mut line = format!(
+                "{verb} {} of {} keys from '{}'",
+                salvaged_keys.len(),
+                salvaged_keys.len() + lost_keys.len(),
+                archive_name(archive)
+            );
+            if let Some(replacement) = replacement {
+                line += &format!(" into '{}'", archive_name(replacement));
+            }
+            if !lost_keys.is_empty() {
+                line += &format!(", lost: {}", lost_keys.join(", "));
+            }
+            line
+        }
+        Repair::Unsalvageable { archive, error, .. } => {
+            format!("cannot salvage '{}': {error:#}", archive_name(archive))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn repairs() -> Vec<Repair> {
+        vec![
+            Repair::Salvaged {
+                archive: PathBuf::from("data/archived/2024-06-19-19-22-45.bin"),
+                problem: "block 1 does not match its checksum".into(),
+                replacement: Some(PathBuf::from("data/archived/2024-06-19-19-22-45.1.bin")),
+                salvaged_keys: vec!["a".into(), "b".into()],
+                lost_keys: vec!["c".into()],
+            },
+            Repair::Unsalvageable {
+                archive: PathBuf::from("data/archived/2024-06-20-19-22-45.bin"),
+                problem: "footer does not match its checksum".into(),
+                error: anyhow::anyhow!("cannot read the key sizes"),
+            },
+        ]
+    }
+
+    #[test]
+    fn json_repairs() {
+        let report = json_report(Path::new("data"), &repairs(), false);
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "data_dir": "data",
+                "dry_run": false,
+                "salvaged": 1,
+                "unsalvageable": 1,
+                "archives": [
+                    {
+                        "name": "2024-06-19-19-22-45.bin",
+                        "status": "salvaged",
+                        "problem": "block 1 does not match its checksum",
+                        "action": "replaced",
+                        "replacement": "2024-06-19-19-22-45.1.bin",
+                        "salvaged_keys": ["a", "b"],
+                        "lost_keys": ["c"],
+                    },
+                    {
+                        "name": "2024-06-20-19-22-45.bin",
+                        "status": "unsalvageable",
+                        "problem": "footer does not match its checksum",
+                        "action": "none",
+                        "error": "cannot read the key sizes",
+                    },
+                ],
+            })
+        );
+
+        let mut repairs = repairs();
+        repairs.truncate(1);
+        if let Repair::Salvaged { replacement, .. } = &mut repairs[0] {
+            *replacement = None;
+        }
+        let report = json_report(Path::new("data"), &repairs, true);
+        assert_eq!(report["dry_run"], true);
+        assert_eq!(report["archives"][0]["action"], "none");
+        assert_eq!(
+            report["archives"][0]["replacement"],
+            serde_json::Value::Null
+        );
+    }
+
+    #[test]
+    fn plain_repairs() {
+        let lines = repairs()
+            .iter()
+            .map(|repair| describe_repair(repair, false))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "salvaged 2 of 3 keys from '2024-06-19-19-22-45.bin' into \
+                 '2024-06-19-19-22-45.1.bin', lost: c",
+                "cannot salvage '2024-06-20-19-22-45.bin': cannot read the key sizes",
+            ]
+        );
+    }
+}
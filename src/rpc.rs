@@ -0,0 +1,341 @@
+//! This module contains the implementation of the `rpc` CLI command
+
+use std::{
+    io::{self, BufRead, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+use serde_json::json;
+use uom::si::{information::byte, u64::Information};
+
+use crate::{
+    append::default_staging_limit,
+    archive::ArchiveNaming,
+    du::StorageUsage,
+    store::{
+        read_merged_value,
+        updated::{read_last_updated, restrict},
+        RecordOutcome, Settings, State,
+    },
+    value::DEFAULT_MAX_DEPTH,
+};
+
+/// The JSON-RPC error code for a request which is not valid JSON
+const PARSE_ERROR: i64 = -32700;
+/// The JSON-RPC error code for a request which is not a valid request object
+const INVALID_REQUEST: i64 = -32600;
+/// The JSON-RPC error code for an unknown method
+const METHOD_NOT_FOUND: i64 = -32601;
+/// The JSON-RPC error code for invalid method parameters
+const INVALID_PARAMS: i64 = -32602;
+/// The JSON-RPC error code for a failure while executing the method
+const INTERNAL_ERROR: i64 = -32603;
+
+/// The `rpc` sub-command reads JSON-RPC 2.0 requests from stdin, one per
+/// line, and writes one response line to stdout for each, until stdin is
+/// closed.
+///
+/// The methods are:
+///  - `append` with a `records` array parameter, which stages each record
+///    like the `append` command and returns the outcome of each
+///  - `read`, which returns the merged value, or `null` if there is no data
+///  - `query` with a `pointer` parameter, which returns the part of the
+///    merged value at that JSON Pointer, or `null` if there is none
+///  - `updated` with an optional `pointer` parameter, which returns when
+///    each part of the merged value (or the part at that JSON Pointer) was
+///    last updated, like `read --with-timestamps`
+///  - `status`, which returns the storage used like `du --json`
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "rpc")]
+pub struct RpcCommand {
+    /// this option gives the maximum size that the staging file reach
+    /// before it is archived and a new staging file is created.
+    #[argh(option, default = "default_staging_limit()")]
+    staging_limit: Information,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// records and stored values, deeper values are rejected.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+    /// this option controls how new archive files are named, either
+    /// "timestamp" (the default), "content", or "sequence".
+    #[argh(option, default = "ArchiveNaming::Timestamp")]
+    archive_naming: ArchiveNaming,
+}
+
+impl RpcCommand {
+    /// This function executes the rpc command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
+        let settings = Settings {
+            staging_limit_bytes: self.staging_limit.get::<byte>(),
+            max_nesting_depth: self.max_nesting_depth,
+            archive_naming: self.archive_naming,
+            ..Settings::default()
+        };
+        let mut server = RpcServer {
+            state: State::new(data_dir.clone(), settings)?,
+            data_dir,
+            max_depth: self.max_nesting_depth,
+        };
+
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+
+        let result = (|| {
+            for line in stdin.lock().lines() {
+                let line = line.context("reading request from stdin")?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let (response, result) = server.handle_line(&line);
+                if let Some(response) = response {
+                    serde_json::to_writer(&mut handle, &response)
+                        .context("writing response to stdout")?;
+                    writeln!(handle).context("writing response to stdout")?;
+                    handle.flush().context("flushing stdout")?;
+                }
+                result?;
+            }
+
+            Ok(())
+        })();
+        server.state.flush()?;
+
+        result
+    }
+}
+
+/// An error to report back to the client instead of a result
+#[derive(Debug)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RpcServer {
+    state: State,
+    data_dir: PathBuf,
+    max_depth: usize,
+}
+
+impl RpcServer {
+    /// Handle a single line of input, returning the response to write or
+    /// `None` if the request was a notification.
+    ///
+    /// Also returns an error if the data directory could not be read or
+    /// written, in which case the response is written but no more requests are
+    /// handled.
+    fn handle_line(&mut self, line: &str) -> (Option<serde_json::Value>, anyhow::Result<()>) {
+        let request: serde_json::Value = match serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(err) => {
+                let err = RpcError::new(PARSE_ERROR, format!("parse error: {err}"));
+                return (Some(error_response(serde_json::Value::Null, err)), Ok(()));
+            }
+        };
+
+        let Some(object) = request.as_object() else {
+            let err = RpcError::new(INVALID_REQUEST, "request must be a single JSON object");
+            return (Some(error_response(serde_json::Value::Null, err)), Ok(()));
+        };
+        // Requests without an id are notifications, which get no response
+        let id = object.get("id").cloned();
+        let method = object.get("method").and_then(serde_json::Value::as_str);
+        let params = object.get("params").cloned().unwrap_or_default();
+
+        let result = match (object.get("jsonrpc"), method) {
+            (Some(version), Some(method)) if version == "2.0" => self.call(method, params),
+            _ => Ok(Err(RpcError::new(
+                INVALID_REQUEST,
+                "request must have \"jsonrpc\": \"2.0\" and a string \"method\"",
+            ))),
+        };
+
+        let (response, result) = match result {
+            Ok(Ok(result)) => (
+                json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                Ok(()),
+            ),
+            Ok(Err(err)) => (error_response(id.clone().unwrap_or_default(), err), Ok(())),
+            Err(err) => (
+                error_response(
+                    id.clone().unwrap_or_default(),
+                    RpcError::new(INTERNAL_ERROR, format!("{err:#}")),
+                ),
+                Err(err),
+            ),
+        };
+
+        (id.is_some().then_some(response), result)
+    }
+
+    /// Call a method, returning `Ok(Err(_))` for errors which only affect this
+    /// request.
+    fn call(
+        &mut self,
+        method: &str,
+        params: serde_json::Value,
+    ) -> anyhow::Result<Result<serde_json::Value, RpcError>> {
+        match method {
+            "append" => {
+                let Some(records) = params.get("records").and_then(|r| r.as_array()) else {
+                    return Ok(Err(RpcError::new(
+                        INVALID_PARAMS,
+                        "expected params {\"records\": [...]}",
+                    )));
+                };
+
+                let mut results = Vec::with_capacity(records.len());
+                for record in records {
+                    let record =
+                        serde_json::to_vec(record).context("converting record to bytes")?;
+                    let outcome = self.state.stage_record(&record)?;
+
+                    let mut result = json!({ "status": outcome.status() });
+                    if let RecordOutcome::Rejected(err) = outcome {
+                        result["error"] = format!("{err:#}").into();
+                    }
+                    results.push(result);
+                }
+                self.state.flush()?;
+
+                Ok(Ok(json!({ "results": results })))
+            }
+            "read" => {
+                self.state.flush()?;
+                let value = read_merged_value(&self.data_dir, self.max_depth)?;
+
+                Ok(Ok(serde_json::to_value(value)?))
+            }
+            "query" => {
+                let Some(pointer) = params.get("pointer").and_then(|p| p.as_str()) else {
+                    return Ok(Err(RpcError::new(
+                        INVALID_PARAMS,
+                        "expected params {\"pointer\": \"/path/to/value\"}",
+                    )));
+                };
+
+                self.state.flush()?;
+                let value = read_merged_value(&self.data_dir, self.max_depth)?;
+                let found = value.as_ref().and_then(|value| value.pointer(pointer));
+
+                Ok(Ok(serde_json::to_value(found)?))
+            }
+            "updated" => {
+                let pointer = match params.get("pointer") {
+                    None => "",
+                    Some(serde_json::Value::String(pointer)) => pointer.as_str(),
+                    Some(_) => {
+                        return Ok(Err(RpcError::new(
+                            INVALID_PARAMS,
+                            "expected params {\"pointer\": \"/path/to/value\"} or none",
+                        )))
+                    }
+                };
+
+                self.state.flush()?;
+                let updated = match (
+                    read_merged_value(&self.data_dir, self.max_depth)?,
+                    read_last_updated(&self.data_dir, self.max_depth)?,
+                ) {
+                    (Some(value), Some(tree)) => Some(restrict(&tree, &value)),
+                    _ => None,
+                };
+                let found = updated.as_ref().and_then(|tree| tree.pointer(pointer));
+
+                Ok(Ok(serde_json::to_value(found)?))
+            }
+            "status" => {
+                self.state.flush()?;
+                let usage = StorageUsage::collect(&self.data_dir, false, self.max_depth)?;
+
+                Ok(Ok(usage.to_json()))
+            }
+            x => Ok(Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("'{x}' is an unknown method"),
+            ))),
+        }
+    }
+}
+
+fn error_response(id: serde_json::Value, err: RpcError) -> serde_json::Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": err.code, "message": err.message },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> RpcServer {
+        // These requests never touch the data directory
+        let data_dir = PathBuf::from("/nonexistent");
+        RpcServer {
+            state: State::new(data_dir.clone(), Settings::default()).unwrap(),
+            data_dir,
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+
+    #[test]
+    fn request_errors() {
+        let mut server = server();
+
+        assert_eq!(
+            server.handle_line("{").0.unwrap()["error"]["code"],
+            PARSE_ERROR
+        );
+        assert_eq!(
+            server.handle_line("[]").0.unwrap()["error"]["code"],
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            server
+                .handle_line(r#"{"jsonrpc": "2.0", "id": 3, "method": "delete"}"#)
+                .0
+                .unwrap(),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 3,
+                "error": { "code": -32601, "message": "'delete' is an unknown method" },
+            })
+        );
+        assert_eq!(
+            server
+                .handle_line(r#"{"jsonrpc": "2.0", "id": "q", "method": "query"}"#)
+                .0
+                .unwrap()["error"]["code"],
+            INVALID_PARAMS
+        );
+    }
+
+    #[test]
+    fn notifications_have_no_response() {
+        let mut server = server();
+
+        assert_eq!(
+            server
+                .handle_line(r#"{"jsonrpc": "2.0", "method": "delete"}"#)
+                .0,
+            None
+        );
+    }
+}
@@ -0,0 +1,339 @@
+//! This module contains the implementation of the `serve` CLI command
+
+use std::{io::Read, path::PathBuf, time::Duration};
+
+use anyhow::Context;
+use argh::FromArgs;
+use tiny_http::{Header, Method, Request, Response, Server};
+use uom::si::{
+    information::{byte, megabyte},
+    u64::Information,
+};
+
+use crate::{
+    append::default_staging_limit,
+    archive::ArchiveNaming,
+    store::{RecordOutcome, Settings, State},
+    value::DEFAULT_MAX_DEPTH,
+};
+
+mod grpc;
+
+fn default_listen() -> String {
+    "127.0.0.1:8080".into()
+}
+
+fn default_max_request_size() -> Information {
+    Information::new::<megabyte>(8)
+}
+
+/// The `serve` sub-command runs an HTTP server which appends records to the
+/// data directory.
+///
+/// `POST /append` accepts a body of newline-delimited JSON records, stages
+/// each one the same way as the `append` command, and responds with the
+/// outcome of every line. Requests are handled one at a time, so clients are
+/// held back while the previous batch is written. With `--background-archive`
+/// the staging file is archived in the background while appends go to a new
+/// staging file, and with `--backpressure-wait-ms` appends get a 429 Too Many
+/// Requests response with a `Retry-After` header once that one is full too.
+///
+/// With `--grpc`, the `WallA` service from `proto/wall_a.proto` is served
+/// instead.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "serve")]
+pub struct ServeCommand {
+    /// the address to listen on, defaults to "127.0.0.1:8080".
+    #[argh(option, default = "default_listen()")]
+    listen: String,
+    /// the maximum size of a request body, larger requests are refused
+    /// without staging any of their records.
+    #[argh(option, default = "default_max_request_size()")]
+    max_request_size: Information,
+    /// this option gives the maximum size that the staging file reach
+    /// before it is archived and a new staging file is created.
+    #[argh(option, default = "default_staging_limit()")]
+    staging_limit: Information,
+    /// this option gives the maximum number of bytes a single record (line)
+    /// may contain, longer records are rejected.
+    #[argh(option)]
+    max_record_bytes: Option<u64>,
+    /// the maximum number of bytes a record may take once it is parsed and
+    /// normalized, like `--max-record-bytes` but after keys are normalized
+    /// and records are unflattened or wrapped.
+    #[argh(option)]
+    max_normalized_bytes: Option<u64>,
+    /// the maximum number of elements in any array of a record, records with
+    /// a longer array are rejected.
+    #[argh(option)]
+    max_array_elements: Option<usize>,
+    /// the maximum number of keys in any object of a record, records with a
+    /// larger object are rejected.
+    #[argh(option)]
+    max_object_keys: Option<usize>,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// a record, deeper records are rejected.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+    /// this option controls how new archive files are named, either
+    /// "timestamp" (the default), "content", or "sequence".
+    #[argh(option, default = "ArchiveNaming::Timestamp")]
+    archive_naming: ArchiveNaming,
+    /// write archive files with direct IO on Linux, bypassing the page cache
+    /// so that archiving does not evict the cached pages of other files.
+    #[argh(switch)]
+    direct_io: bool,
+    /// archive the staging file on a background thread, renaming it aside
+    /// and appending to a new staging file meanwhile, so that appends never
+    /// wait for archiving.
+    #[argh(switch)]
+    background_archive: bool,
+    /// archive the staging file in the background like --background-archive,
+    /// and if the new staging file fills up while the last one is still being
+    /// archived for longer than this many milliseconds, respond to appends
+    /// with 429 Too Many Requests until it finishes instead of waiting.
+    #[argh(option)]
+    backpressure_wait_ms: Option<u64>,
+    /// serve the gRPC interface defined in `proto/wall_a.proto` instead of
+    /// HTTP. Requires the `grpc` feature.
+    #[argh(switch)]
+    grpc: bool,
+}
+
+impl ServeCommand {
+    /// This function executes the serve command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
+        let settings = Settings {
+            staging_limit_bytes: self.staging_limit.get::<byte>(),
+            max_record_bytes: self.max_record_bytes,
+            max_normalized_bytes: self.max_normalized_bytes,
+            max_array_elements: self.max_array_elements,
+            max_object_keys: self.max_object_keys,
+            max_nesting_depth: self.max_nesting_depth,
+            archive_naming: self.archive_naming,
+            direct_io: self.direct_io,
+            background_archive: self.background_archive,
+            backpressure_wait: self.backpressure_wait_ms.map(Duration::from_millis),
+            ..Settings::default()
+        };
+        let max_request_bytes = self.max_request_size.get::<byte>();
+        let mut state = State::new(data_dir.clone(), settings)?;
+
+        if self.grpc {
+            return grpc::serve(state, &self.listen, data_dir, self.max_nesting_depth);
+        }
+
+        let server = Server::http(&self.listen)
+            .map_err(|err| anyhow::anyhow!(err))
+            .with_context(|| format!("listening on '{}'", self.listen))?;
+        tracing::info!(listen = %self.listen, "Serving HTTP requests");
+
+        for request in server.incoming_requests() {
+            handle_request(&mut state, request, max_request_bytes)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Respond to a single HTTP request.
+///
+/// Returns an error only if the data directory could not be written, in which
+/// case the server stops.
+fn handle_request(
+    state: &mut State,
+    mut request: Request,
+    max_request_bytes: u64,
+) -> anyhow::Result<()> {
+    tracing::debug!(method = %request.method(), url = %request.url(), "Received request");
+
+    let (status, body, result) = match (request.method(), request.url()) {
+        (Method::Post, "/append") => match state.wait_for_archive() {
+            Ok(true) => append_request(state, &mut request, max_request_bytes),
+            Ok(false) => (
+                429,
+                serde_json::json!({ "error": "the staging file is being archived, retry later" }),
+                Ok(()),
+            ),
+            Err(err) => (
+                500,
+                serde_json::json!({ "error": format!("{err:#}") }),
+                Err(err),
+            ),
+        },
+        (_, "/append") => (
+            405,
+            serde_json::json!({ "error": "only POST is supported" }),
+            Ok(()),
+        ),
+        _ => (404, serde_json::json!({ "error": "not found" }), Ok(())),
+    };
+
+    let content_type = Header::from_bytes("Content-Type", "application/json")
+        .expect("header name and value are valid");
+    let mut response = Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(content_type);
+    if status == 429 {
+        response.add_header(
+            Header::from_bytes("Retry-After", "1").expect("header name and value are valid"),
+        );
+    }
+    if let Err(err) = request.respond(response) {
+        tracing::warn!("Failed to send response: {err}");
+    }
+
+    result
+}
+
+/// Stage the records in the body of a `POST /append` request, returning the
+/// status code and body of the response.
+fn append_request(
+    state: &mut State,
+    request: &mut Request,
+    max_request_bytes: u64,
+) -> (u16, serde_json::Value, anyhow::Result<()>) {
+    match read_body(request, max_request_bytes) {
+        Ok(Some(body)) => match append_lines(state, &body) {
+            Ok(report) => (200, report, Ok(())),
+            Err(err) => (
+                500,
+                serde_json::json!({ "error": format!("{err:#}") }),
+                Err(err),
+            ),
+        },
+        Ok(None) => (
+            413,
+            serde_json::json!({
+                "error": format!(
+                    "request body exceeded the maximum size of {max_request_bytes} bytes"
+                )
+            }),
+            Ok(()),
+        ),
+        Err(err) => (
+            400,
+            serde_json::json!({ "error": format!("{err:#}") }),
+            Ok(()),
+        ),
+    }
+}
+
+/// Read the body of the request, returning `Ok(None)` if it is longer than
+/// the limit.
+fn read_body(request: &mut Request, max_request_bytes: u64) -> anyhow::Result<Option<Vec<u8>>> {
+    if request
+        .body_length()
+        .is_some_and(|len| len as u64 > max_request_bytes)
+    {
+        return Ok(None);
+    }
+
+    let mut body = Vec::new();
+    request
+        .as_reader()
+        .take(max_request_bytes.saturating_add(1))
+        .read_to_end(&mut body)
+        .context("reading request body")?;
+
+    Ok((body.len() as u64 <= max_request_bytes).then_some(body))
+}
+
+/// Stage every non-empty line of the body as a record, then flush the staging
+/// file and return a report of the outcome of each line.
+fn append_lines(state: &mut State, body: &[u8]) -> anyhow::Result<serde_json::Value> {
+    let mut report = LinesReport::default();
+
+    for (line_number, line) in record_lines(body) {
+        let outcome = state.stage_record(line)?;
+        report.push(line_number, &outcome);
+    }
+    state.flush()?;
+
+    Ok(report.to_json())
+}
+
+/// Split a request body into its non-blank lines, paired with their line
+/// numbers starting from 1.
+fn record_lines(body: &[u8]) -> impl Iterator<Item = (usize, &[u8])> {
+    body.split(|b| *b == b'\n')
+        .enumerate()
+        .map(|(index, line)| (index + 1, line))
+        .filter(|(_, line)| !line.iter().all(u8::is_ascii_whitespace))
+}
+
+/// The outcome of every line of a request
+#[derive(Debug, Default)]
+struct LinesReport {
+    appended: u64,
+    duplicates: u64,
+    rejected: u64,
+    lines: Vec<serde_json::Value>,
+}
+
+impl LinesReport {
+    fn push(&mut self, line_number: usize, outcome: &RecordOutcome) {
+        let mut line = serde_json::json!({ "line": line_number, "status": outcome.status() });
+        match outcome {
+            RecordOutcome::Appended => self.appended += 1,
+            RecordOutcome::DuplicateRecord | RecordOutcome::DuplicateId => self.duplicates += 1,
+            RecordOutcome::Rejected(err) => {
+                self.rejected += 1;
+                line["error"] = format!("{err:#}").into();
+            }
+        }
+        self.lines.push(line);
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "appended": self.appended,
+            "duplicates": self.duplicates,
+            "rejected": self.rejected,
+            "lines": self.lines,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_record_lines() {
+        let body = b"{\"a\":1}\n\n  \n{\"b\":2}\r\n{\"c\":3}";
+        let lines = record_lines(body).collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                (1, &b"{\"a\":1}"[..]),
+                (4, &b"{\"b\":2}\r"[..]),
+                (5, &b"{\"c\":3}"[..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn lines_report() {
+        let mut report = LinesReport::default();
+        report.push(1, &RecordOutcome::Appended);
+        report.push(2, &RecordOutcome::Rejected(anyhow::anyhow!("bad record")));
+        report.push(3, &RecordOutcome::DuplicateId);
+
+        assert_eq!(
+            report.to_json(),
+            serde_json::json!({
+                "appended": 1,
+                "duplicates": 1,
+                "rejected": 1,
+                "lines": [
+                    {"line": 1, "status": "appended"},
+                    {"line": 2, "status": "rejected", "error": "bad record"},
+                    {"line": 3, "status": "duplicate"},
+                ],
+            })
+        );
+    }
+}
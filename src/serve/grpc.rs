@@ -0,0 +1,192 @@
+//! This module contains the gRPC interface for the `serve` command, which is
+//! only available with the `grpc` feature.
+//!
+//! The service and messages are generated from `proto/wall_a.proto` by the
+//! build script.
+
+use std::path::PathBuf;
+
+use crate::store::State;
+
+/// Serve the gRPC interface on the given address until the server fails.
+#[cfg(not(feature = "grpc"))]
+pub fn serve(
+    _state: State,
+    _listen: &str,
+    _data_dir: PathBuf,
+    _max_depth: usize,
+) -> anyhow::Result<()> {
+    anyhow::bail!("wall-a was built without the `grpc` feature, so --grpc is not available")
+}
+
+/// Serve the gRPC interface on the given address until the server fails.
+#[cfg(feature = "grpc")]
+pub fn serve(
+    state: State,
+    listen: &str,
+    data_dir: PathBuf,
+    max_depth: usize,
+) -> anyhow::Result<()> {
+    use std::{net::SocketAddr, sync::Mutex};
+
+    use anyhow::Context;
+
+    use self::service::{proto::wall_a_server::WallAServer, Service};
+
+    let addr: SocketAddr = listen
+        .parse()
+        .with_context(|| format!("parsing '{listen}' as a socket address"))?;
+    let service = Service {
+        state: Mutex::new(state),
+        data_dir,
+        max_depth,
+    };
+
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("starting async runtime")?;
+    tracing::info!(%addr, "Serving gRPC requests");
+
+    runtime
+        .block_on(
+            tonic::transport::Server::builder()
+                .add_service(WallAServer::new(service))
+                .serve(addr),
+        )
+        .context("serving gRPC requests")
+}
+
+// The service trait returns `tonic::Status` as the error, which is large
+#[cfg(feature = "grpc")]
+#[allow(clippy::result_large_err)]
+mod service {
+    use std::{
+        path::PathBuf,
+        sync::{Mutex, MutexGuard},
+    };
+
+    use tonic::{Request, Response, Status, Streaming};
+
+    use self::proto::{
+        record_result, wall_a_server::WallA, AppendRequest, AppendResponse, ReadRequest,
+        ReadResponse, RecordResult, VerifyRequest, VerifyResponse,
+    };
+    use crate::{
+        store::{read_merged_value, RecordOutcome, State},
+        verify::verify_data_dir,
+    };
+
+    #[allow(clippy::all, missing_docs)]
+    pub mod proto {
+        tonic::include_proto!("wall_a");
+    }
+
+    /// The implementation of the `WallA` service
+    #[derive(Debug)]
+    pub struct Service {
+        /// The appending state, shared between all requests so that records
+        /// are staged one at a time
+        pub state: Mutex<State>,
+        pub data_dir: PathBuf,
+        pub max_depth: usize,
+    }
+
+    impl Service {
+        fn lock_state(&self) -> Result<MutexGuard<'_, State>, Status> {
+            self.state
+                .lock()
+                .map_err(|_| Status::internal("a previous request failed while appending"))
+        }
+    }
+
+    /// Convert an error from reading or writing the data directory into an
+    /// RPC status.
+    fn internal(err: anyhow::Error) -> Status {
+        Status::internal(format!("{err:#}"))
+    }
+
+    #[tonic::async_trait]
+    impl WallA for Service {
+        async fn append(
+            &self,
+            request: Request<Streaming<AppendRequest>>,
+        ) -> Result<Response<AppendResponse>, Status> {
+            if !self.lock_state()?.wait_for_archive().map_err(internal)? {
+                return Err(Status::resource_exhausted(
+                    "the staging file is being archived, retry later",
+                ));
+            }
+
+            let mut records = request.into_inner();
+            let mut response = AppendResponse::default();
+
+            while let Some(AppendRequest { record }) = records.message().await? {
+                let outcome = self.lock_state()?.stage_record(&record).map_err(internal)?;
+
+                let result = match outcome {
+                    RecordOutcome::Appended => {
+                        response.appended += 1;
+                        RecordResult {
+                            status: record_result::Status::Appended.into(),
+                            error: String::new(),
+                        }
+                    }
+                    RecordOutcome::DuplicateRecord | RecordOutcome::DuplicateId => {
+                        response.duplicates += 1;
+                        RecordResult {
+                            status: record_result::Status::Duplicate.into(),
+                            error: String::new(),
+                        }
+                    }
+                    RecordOutcome::Rejected(err) => {
+                        response.rejected += 1;
+                        RecordResult {
+                            status: record_result::Status::Rejected.into(),
+                            error: format!("{err:#}"),
+                        }
+                    }
+                };
+                response.results.push(result);
+            }
+
+            self.lock_state()?.flush().map_err(internal)?;
+
+            Ok(Response::new(response))
+        }
+
+        async fn read(
+            &self,
+            _request: Request<ReadRequest>,
+        ) -> Result<Response<ReadResponse>, Status> {
+            // Hold the appending state so that the staging file is not
+            // written or archived while it is being read
+            let mut state = self.lock_state()?;
+            state.finish_archive().map_err(internal)?;
+            let value = read_merged_value(&self.data_dir, self.max_depth).map_err(internal)?;
+            drop(state);
+
+            let value = value
+                .map(|value| serde_json::to_string(&value))
+                .transpose()
+                .map_err(|err| internal(err.into()))?;
+
+            Ok(Response::new(ReadResponse { value }))
+        }
+
+        async fn verify(
+            &self,
+            _request: Request<VerifyRequest>,
+        ) -> Result<Response<VerifyResponse>, Status> {
+            let findings = verify_data_dir(&self.data_dir, None).map_err(internal)?;
+
+            Ok(Response::new(VerifyResponse {
+                problems: findings
+                    .iter()
+                    .filter(|finding| finding.is_problem())
+                    .count() as u64,
+                findings: findings.iter().map(ToString::to_string).collect(),
+            }))
+        }
+    }
+}
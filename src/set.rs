@@ -0,0 +1,383 @@
+//! This module contains the implementation of the `set`, `unset`, and
+//! `move` CLI commands
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use argh::FromArgs;
+use serde_json::error::Category;
+use uom::si::{information::byte, u64::Information};
+
+use crate::{
+    append::default_staging_limit,
+    archive::ArchiveNaming,
+    output::Output,
+    query::{Path, Segment},
+    store::{read_merged_value, RecordOutcome, Settings, State},
+    value::{self, Value, DEFAULT_MAX_DEPTH},
+};
+
+/// The `set` sub-command appends a record which sets the value at a path,
+/// like `set metrics.threshold 5`, so that it is merged like any other
+/// record.
+///
+/// The value is parsed as JSON if it is valid JSON, otherwise it is used as a
+/// string.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "set")]
+pub struct SetCommand {
+    /// the path of the field to set, like `metrics.threshold`.
+    #[argh(positional)]
+    path: Path,
+    /// the value to set the field to.
+    #[argh(positional)]
+    value: String,
+    /// use the value as a string, even if it is valid JSON.
+    #[argh(switch)]
+    string: bool,
+    /// this option gives the maximum size that the staging file reach
+    /// before it is archived and a new staging file is created.
+    #[argh(option, default = "default_staging_limit()")]
+    staging_limit: Information,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the record, deeper records are rejected.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+    /// this option controls how new archive files are named, either
+    /// "timestamp" (the default), "content", or "sequence".
+    #[argh(option, default = "ArchiveNaming::Timestamp")]
+    archive_naming: ArchiveNaming,
+}
+
+/// The `unset` sub-command appends a record which deletes the field at a
+/// path, like `unset secrets.old_token`, so that it is missing from later
+/// reads.
+///
+/// The deleted field is dropped when the staging file is archived if there
+/// are no older archives, otherwise a small marker is kept in the archive to
+/// hide the field in the older ones.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "unset")]
+pub struct UnsetCommand {
+    /// the path of the field to delete, like `secrets.old_token`.
+    #[argh(positional)]
+    path: Path,
+    /// this option gives the maximum size that the staging file reach
+    /// before it is archived and a new staging file is created.
+    #[argh(option, default = "default_staging_limit()")]
+    staging_limit: Information,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the record, deeper records are rejected.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+    /// this option controls how new archive files are named, either
+    /// "timestamp" (the default), "content", or "sequence".
+    #[argh(option, default = "ArchiveNaming::Timestamp")]
+    archive_naming: ArchiveNaming,
+}
+
+/// The `move` sub-command relocates the value at a path to another path,
+/// like `move metrics.errs metrics.errors`, to correct the layout of keys
+/// written earlier.
+///
+/// It appends a single record which holds the current value at the new path
+/// and a tombstone like `unset` at the old path, so the old path is dropped
+/// when the staging file is archived. Records appended to the old path later
+/// are not moved.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "move")]
+pub struct MoveCommand {
+    /// the path of the field to move, like `metrics.errs`.
+    #[argh(positional)]
+    from: Path,
+    /// the path to move the field to, like `metrics.errors`.
+    #[argh(positional)]
+    to: Path,
+    /// merge the moved value into the value already at the new path,
+    /// instead of failing if there is one.
+    #[argh(switch)]
+    merge: bool,
+    /// this option gives the maximum size that the staging file reach
+    /// before it is archived and a new staging file is created.
+    #[argh(option, default = "default_staging_limit()")]
+    staging_limit: Information,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the record, deeper records are rejected.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+    /// this option controls how new archive files are named, either
+    /// "timestamp" (the default), "content", or "sequence".
+    #[argh(option, default = "ArchiveNaming::Timestamp")]
+    archive_naming: ArchiveNaming,
+}
+
+impl SetCommand {
+    /// This function executes the set command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf, output: Output) -> anyhow::Result<()> {
+        let record = field_record(&self.path, self.value()?)?;
+        let settings = Settings {
+            staging_limit_bytes: self.staging_limit.get::<byte>(),
+            max_nesting_depth: self.max_nesting_depth,
+            archive_naming: self.archive_naming,
+            ..Settings::default()
+        };
+
+        let mut result = append_record(data_dir, settings, &record)
+            .with_context(|| format!("setting '{}'", self.path))?;
+        result["path"] = self.path.to_string().into();
+        output.write_result("set", result)
+    }
+
+    /// Parse the value to set.
+    fn value(&self) -> anyhow::Result<Value> {
+        Ok(if self.string {
+            Value::String(self.value.clone())
+        } else {
+            match value::from_json_slice(self.value.as_bytes(), self.max_nesting_depth) {
+                Ok(value) => value,
+                // Only text which is not JSON at all is used as a string
+                Err(err) if matches!(err.inner().classify(), Category::Syntax | Category::Eof) => {
+                    Value::String(self.value.clone())
+                }
+                Err(err) => return Err(err).context("parsing value as JSON"),
+            }
+        })
+    }
+}
+
+impl UnsetCommand {
+    /// This function executes the unset command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf, output: Output) -> anyhow::Result<()> {
+        let record = field_record(&self.path, Value::tombstone())?;
+        let settings = Settings {
+            staging_limit_bytes: self.staging_limit.get::<byte>(),
+            max_nesting_depth: self.max_nesting_depth,
+            archive_naming: self.archive_naming,
+            allow_tombstones: true,
+            ..Settings::default()
+        };
+
+        let mut result = append_record(data_dir, settings, &record)
+            .with_context(|| format!("unsetting '{}'", self.path))?;
+        result["path"] = self.path.to_string().into();
+        output.write_result("unset", result)
+    }
+}
+
+impl MoveCommand {
+    /// This function executes the move command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf, output: Output) -> anyhow::Result<()> {
+        let merged = read_merged_value(&data_dir, self.max_nesting_depth)?.unwrap_or_default();
+        let record = move_record(&merged, &self.from, &self.to, self.merge)?;
+        let settings = Settings {
+            staging_limit_bytes: self.staging_limit.get::<byte>(),
+            max_nesting_depth: self.max_nesting_depth,
+            archive_naming: self.archive_naming,
+            allow_tombstones: true,
+            ..Settings::default()
+        };
+
+        let mut result = append_record(data_dir, settings, &record)
+            .with_context(|| format!("moving '{}' to '{}'", self.from, self.to))?;
+        result["from"] = self.from.to_string().into();
+        result["to"] = self.to.to_string().into();
+        output.write_result("move", result)
+    }
+}
+
+/// Build the record which moves the value at `from` in the merged value to
+/// `to`, by setting it at `to` and deleting it at `from`.
+fn move_record(merged: &Value, from: &Path, to: &Path, merge: bool) -> anyhow::Result<Value> {
+    check_field_path(from)?;
+    check_field_path(to)?;
+    let from_segments = from.segments();
+    let to_segments = to.segments();
+    if from_segments.starts_with(to_segments) || to_segments.starts_with(from_segments) {
+        anyhow::bail!("'{from}' cannot be moved to '{to}', since one contains the other");
+    }
+
+    let Some(value) = from.lookup(merged) else {
+        anyhow::bail!("'{from}' is not present in the merged value");
+    };
+    // The parents of the new path must be objects already, or be missing so
+    // that the record creates them
+    let mut parent = Path::default();
+    for segment in &to_segments[..to_segments.len() - 1] {
+        parent = parent.join(segment.clone());
+        match parent.lookup(merged) {
+            Some(Value::Object(_)) | None => {}
+            Some(existing) => anyhow::bail!(
+                "'{parent}' is {} and not an object, so '{to}' cannot be created in it",
+                existing.type_name()
+            ),
+        }
+    }
+    if to.lookup(merged).is_some() && !merge {
+        anyhow::bail!("'{to}' already has a value, pass --merge to merge the moved value into it");
+    }
+
+    // Neither path contains the other, so both fit in one record
+    let mut record = Value::Null;
+    from.insert(&mut record, Value::tombstone());
+    to.insert(&mut record, value.clone());
+
+    Ok(record)
+}
+
+/// Build the record which contains only the value at the path.
+fn field_record(path: &Path, value: Value) -> anyhow::Result<Value> {
+    check_field_path(path)?;
+
+    let mut record = Value::Null;
+    path.insert(&mut record, value);
+
+    Ok(record)
+}
+
+/// Check that the path leads to a field of an object, which a record can
+/// change.
+fn check_field_path(path: &Path) -> anyhow::Result<()> {
+    if path.segments().is_empty() {
+        anyhow::bail!("the path must lead to a field, not the whole value");
+    }
+    // An array in a record is merged with the stored array rather than
+    // replacing one of its elements
+    if let Some(Segment::Index(index)) = path
+        .segments()
+        .iter()
+        .find(|segment| matches!(segment, Segment::Index(_)))
+    {
+        anyhow::bail!("'{path}' contains the array index [{index}], but only fields of objects can be changed");
+    }
+
+    Ok(())
+}
+
+/// Stage the record like `append`, then flush it to the staging file,
+/// returning the summary of what was appended and archived.
+fn append_record(
+    data_dir: PathBuf,
+    settings: Settings,
+    record: &Value,
+) -> anyhow::Result<serde_json::Value> {
+    let record = serde_json::to_vec(record).context("converting record to bytes")?;
+    let mut state = State::new(data_dir, settings)?;
+
+    if let RecordOutcome::Rejected(err) = state.stage_record(&record)? {
+        return Err(err);
+    }
+    state.flush()?;
+    state.finish_archive()?;
+
+    Ok(state.summary_json())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_record(args: &[&str]) -> anyhow::Result<serde_json::Value> {
+        let command = SetCommand::from_args(&["wall-a", "set"], args).unwrap();
+        let record = field_record(&command.path, command.value()?)?;
+
+        Ok(serde_json::to_value(record).unwrap())
+    }
+
+    #[test]
+    fn set_record_has_value_at_path() {
+        assert_eq!(
+            set_record(&["metrics.threshold", "5"]).unwrap(),
+            serde_json::json!({"metrics": {"threshold": 5}})
+        );
+        assert_eq!(
+            set_record(&["tags", r#"["a", "b"]"#]).unwrap(),
+            serde_json::json!({"tags": ["a", "b"]})
+        );
+        assert_eq!(
+            set_record(&["name", "not json"]).unwrap(),
+            serde_json::json!({"name": "not json"})
+        );
+        assert_eq!(
+            set_record(&["version", "5", "--string"]).unwrap(),
+            serde_json::json!({"version": "5"})
+        );
+        assert!(set_record(&["a", "[[1]]", "--max-nesting-depth", "1"]).is_err());
+    }
+
+    #[test]
+    fn unset_record_has_tombstone_at_path() {
+        let record = field_record(&"secrets.old_token".parse().unwrap(), Value::tombstone());
+
+        assert_eq!(
+            serde_json::to_value(record.unwrap()).unwrap(),
+            serde_json::json!({"secrets": {"old_token": {"$wall-a:unset": true}}})
+        );
+    }
+
+    #[test]
+    fn move_record_relocates_value() {
+        let merged = Value::from(serde_json::json!({
+            "metrics": {"errs": {"total": 3}, "count": 1},
+            "old": [1, 2],
+        }));
+        let moved = |from: &str, to: &str, merge: bool| {
+            move_record(&merged, &from.parse().unwrap(), &to.parse().unwrap(), merge)
+                .map(|record| serde_json::to_value(record).unwrap())
+                .map_err(|err| err.to_string())
+        };
+
+        assert_eq!(
+            moved("metrics.errs", "metrics.errors", false),
+            Ok(serde_json::json!({
+                "metrics": {"errs": {"$wall-a:unset": true}, "errors": {"total": 3}}
+            }))
+        );
+        assert_eq!(
+            moved("old", "archive.old", false),
+            Ok(serde_json::json!({"old": {"$wall-a:unset": true}, "archive": {"old": [1, 2]}}))
+        );
+
+        assert_eq!(
+            moved("old", "metrics.count", false),
+            Err(
+                "'metrics.count' already has a value, pass --merge to merge the moved value \
+                 into it"
+                    .into()
+            )
+        );
+        assert!(moved("old", "metrics.count", true).is_ok());
+        assert_eq!(
+            moved("old", "metrics.count.old", false),
+            Err(
+                "'metrics.count' is number and not an object, so 'metrics.count.old' cannot be \
+                 created in it"
+                    .into()
+            )
+        );
+        assert_eq!(
+            moved("metrics", "metrics.all", false),
+            Err("'metrics' cannot be moved to 'metrics.all', since one contains the other".into())
+        );
+        assert_eq!(
+            moved("missing", "found", false),
+            Err("'missing' is not present in the merged value".into())
+        );
+    }
+
+    #[test]
+    fn record_needs_object_fields() {
+        assert_eq!(
+            set_record(&["@", "5"]).unwrap_err().to_string(),
+            "the path must lead to a field, not the whole value"
+        );
+        assert_eq!(
+            set_record(&["runs[1].status", "\"ok\""])
+                .unwrap_err()
+                .to_string(),
+            "'runs[1].status' contains the array index [1], but only fields of objects can be changed"
+        );
+    }
+}
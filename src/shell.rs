@@ -0,0 +1,304 @@
+//! This module contains the implementation of the `shell` CLI command
+
+use std::{
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+use uom::si::{information::byte, u64::Information};
+
+use crate::{
+    append::default_staging_limit,
+    archive::{archive_name, list_archive_files},
+    manifest::Manifest,
+    query::{self, project},
+    staging::StagingFileReader,
+    store::{
+        collect_archived_values, read_merged_value, ttl::TtlRules, RecordOutcome, Settings, State,
+    },
+    value::{Value, DEFAULT_MAX_DEPTH},
+};
+
+const HELP: &str = "\
+append <json>     stage a record
+read [path...]    print the merged value, or only the given paths
+get <path>        print the merged value at a path
+archives          list the archive files, oldest first
+flush             write buffered records to the staging file
+help              show this message
+exit              leave the shell
+";
+
+/// The `shell` sub-command reads commands from stdin, one per line, to
+/// append records to the data directory and read the merged value back, for
+/// exploring a data directory without starting `wall-a` for every question.
+///
+/// The merged value of the archives is kept between commands, so only the
+/// staging file is read again unless an archive was added. Type `help` for
+/// the list of commands.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "shell")]
+pub struct ShellCommand {
+    /// the maximum size that the staging file reach before it is archived.
+    #[argh(option, default = "default_staging_limit()")]
+    staging_limit: Information,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// records and the stored values, deeper values are rejected.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+}
+
+impl ShellCommand {
+    /// This function executes the shell command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
+        let settings = Settings {
+            staging_limit_bytes: self.staging_limit.get::<byte>(),
+            max_nesting_depth: self.max_nesting_depth,
+            ..Settings::default()
+        };
+        let mut shell = Shell {
+            state: State::new(data_dir.clone(), settings)?,
+            data_dir,
+            max_depth: self.max_nesting_depth,
+            archives: None,
+            archived_value: None,
+        };
+
+        let stdin = io::stdin();
+        let interactive = stdin.is_terminal();
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+        let mut line = String::new();
+
+        loop {
+            if interactive {
+                write!(handle, "wall-a> ").context("writing prompt to stdout")?;
+                handle.flush().context("flushing stdout")?;
+            }
+
+            line.clear();
+            if stdin
+                .lock()
+                .read_line(&mut line)
+                .context("reading command")?
+                == 0
+            {
+                break;
+            }
+
+            let command = match line.parse::<ShellInput>() {
+                Ok(ShellInput::Empty) => continue,
+                Ok(ShellInput::Command(ShellAction::Exit)) => break,
+                Ok(ShellInput::Command(command)) => command,
+                Err(err) => {
+                    writeln!(handle, "error: {err:#}").context("writing error to stdout")?;
+                    continue;
+                }
+            };
+
+            if let Err(err) = shell.run(command, &mut handle) {
+                writeln!(handle, "error: {err:#}").context("writing error to stdout")?;
+            }
+        }
+
+        shell.state.flush()?;
+        shell.state.log_summary();
+
+        Ok(())
+    }
+}
+
+/// A line of input to the shell
+#[derive(Debug, PartialEq)]
+enum ShellInput {
+    /// A blank line, which does nothing
+    Empty,
+    Command(ShellAction),
+}
+
+/// A command of the shell, see [`HELP`]
+#[derive(Debug, PartialEq)]
+enum ShellAction {
+    Append(String),
+    Read(Vec<query::Path>),
+    Get(query::Path),
+    Archives,
+    Flush,
+    Help,
+    Exit,
+}
+
+impl FromStr for ShellInput {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        let rest = rest.trim();
+        let no_arguments = |action| {
+            if rest.is_empty() {
+                Ok(action)
+            } else {
+                Err(anyhow::anyhow!("`{name}` takes no arguments"))
+            }
+        };
+
+        let action = match name {
+            "" => return Ok(Self::Empty),
+            "append" if rest.is_empty() => anyhow::bail!("`append` needs a JSON record"),
+            "append" => ShellAction::Append(rest.to_string()),
+            "read" => ShellAction::Read(
+                rest.split_whitespace()
+                    .map(str::parse)
+                    .collect::<anyhow::Result<_>>()?,
+            ),
+            "get" if rest.is_empty() => anyhow::bail!("`get` needs a path"),
+            "get" => ShellAction::Get(rest.parse()?),
+            "archives" => no_arguments(ShellAction::Archives)?,
+            "flush" => no_arguments(ShellAction::Flush)?,
+            "help" => no_arguments(ShellAction::Help)?,
+            "exit" | "quit" => no_arguments(ShellAction::Exit)?,
+            x => anyhow::bail!("'{x}' is an unknown command, type `help` for the list"),
+        };
+
+        Ok(Self::Command(action))
+    }
+}
+
+/// The open data directory of the shell
+#[derive(Debug)]
+struct Shell {
+    data_dir: PathBuf,
+    max_depth: usize,
+    state: State,
+    /// The archive files merged into `archived_value`, or `None` before the
+    /// archives are first read
+    archives: Option<Vec<PathBuf>>,
+    archived_value: Option<Value>,
+}
+
+impl Shell {
+    fn run(&mut self, action: ShellAction, mut writer: impl Write) -> anyhow::Result<()> {
+        match action {
+            ShellAction::Append(record) => {
+                let outcome = self.state.stage_record(record.as_bytes())?;
+                self.state.flush()?;
+                match &outcome {
+                    RecordOutcome::Rejected(err) => {
+                        writeln!(writer, "{}: {err:#}", outcome.status())?
+                    }
+                    _ => writeln!(writer, "{}", outcome.status())?,
+                }
+            }
+            ShellAction::Read(paths) => {
+                let value = self.merged_value()?.unwrap_or(Value::Null);
+                let value = if paths.is_empty() {
+                    value
+                } else {
+                    project(&value, &paths)
+                };
+                writeln!(writer, "{}", serde_json::to_string(&value)?)?;
+            }
+            ShellAction::Get(path) => {
+                let value = self.merged_value()?;
+                match value.as_ref().and_then(|value| path.lookup(value)) {
+                    Some(value) => writeln!(writer, "{}", serde_json::to_string(value)?)?,
+                    None => writeln!(writer, "'{path}' is not in the merged value")?,
+                }
+            }
+            ShellAction::Archives => {
+                for path in list_archive_files(&self.data_dir)? {
+                    let len = fs::metadata(&path)
+                        .with_context(|| format!("reading metadata of '{}'", path.display()))?
+                        .len();
+                    writeln!(writer, "{}\t{len}", archive_name(&path))?;
+                }
+            }
+            ShellAction::Flush => self.state.flush()?,
+            ShellAction::Help => write!(writer, "{HELP}")?,
+            ShellAction::Exit => {}
+        }
+
+        Ok(())
+    }
+
+    /// Return the merged value like `read`, reading the archives again only
+    /// if they changed since the last time.
+    fn merged_value(&mut self) -> anyhow::Result<Option<Value>> {
+        self.state.flush()?;
+
+        // Expiring fields needs the time each archive updated them, so the
+        // merged value of the archives cannot be reused
+        if !TtlRules::read(&self.data_dir)?.is_empty() {
+            return read_merged_value(&self.data_dir, self.max_depth);
+        }
+
+        let archives = list_archive_files(&self.data_dir)?;
+        if self.archives.as_ref() != Some(&archives) {
+            tracing::debug!(
+                archives = archives.len(),
+                "Archive files changed, reading them"
+            );
+            self.archived_value =
+                collect_archived_values(&mut Vec::new(), &self.data_dir, self.max_depth)
+                    .context("collecting and merging all archived values")?;
+            self.archives = Some(archives);
+        }
+
+        let staging_value = StagingFileReader::read_merged_value(&self.data_dir, self.max_depth)
+            .context("reading merged value from staging file")?;
+
+        let manifest = Manifest::read(&self.data_dir)?;
+        let merge_settings = manifest.merge_settings();
+        Ok(merge_settings
+            .merge_optional(self.archived_value.clone(), staging_value)
+            .and_then(|value| merge_settings.resolve(value))
+            .map(|mut value| {
+                manifest.array_rules.apply(&mut value);
+                value
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_shell_input() {
+        assert_eq!("  \n".parse::<ShellInput>().unwrap(), ShellInput::Empty);
+        assert_eq!(
+            "append {\"a\": [1, 2]}\n".parse::<ShellInput>().unwrap(),
+            ShellInput::Command(ShellAction::Append("{\"a\": [1, 2]}".into()))
+        );
+        assert_eq!(
+            "read name  metrics.errors".parse::<ShellInput>().unwrap(),
+            ShellInput::Command(ShellAction::Read(vec![
+                "name".parse().unwrap(),
+                "metrics.errors".parse().unwrap(),
+            ]))
+        );
+        assert_eq!(
+            "read".parse::<ShellInput>().unwrap(),
+            ShellInput::Command(ShellAction::Read(Vec::new()))
+        );
+        assert_eq!(
+            "quit".parse::<ShellInput>().unwrap(),
+            ShellInput::Command(ShellAction::Exit)
+        );
+
+        assert!("append".parse::<ShellInput>().is_err());
+        assert!("get".parse::<ShellInput>().is_err());
+        assert!("flush now".parse::<ShellInput>().is_err());
+        let err = "compact".parse::<ShellInput>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'compact' is an unknown command, type `help` for the list"
+        );
+    }
+}
@@ -0,0 +1,193 @@
+//! This module contains the implementation of the `snapshot` CLI command
+
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+
+use crate::{archive::archive_dir, output::Output};
+
+/// The `snapshot` sub-command copies the data directory into a new directory,
+/// as a checkpoint which can be used with `--data-dir` or backed up.
+///
+/// Archive files never change once they are written, so on filesystems which
+/// support reflinks, like XFS, btrfs, and APFS, they are cloned instead of
+/// copied, which takes almost no time or space. Elsewhere, or if the snapshot
+/// is on a different filesystem, they are copied like the other files. The
+/// files are copied one after the other, so take snapshots while nothing is
+/// appending to the data directory.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "snapshot")]
+pub struct SnapshotCommand {
+    /// the path of the directory to create for the snapshot, which must not
+    /// exist yet.
+    #[argh(option)]
+    output: PathBuf,
+}
+
+impl SnapshotCommand {
+    /// This function executes the snapshot command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf, output: Output) -> anyhow::Result<()> {
+        fs::create_dir(&self.output)
+            .with_context(|| format!("creating snapshot directory '{}'", self.output.display()))?;
+
+        let snapshot_dir =
+            fs::canonicalize(&self.output).context("resolving snapshot directory")?;
+        if snapshot_dir
+            .starts_with(fs::canonicalize(&data_dir).context("resolving data directory")?)
+        {
+            let _ = fs::remove_dir(&self.output);
+            anyhow::bail!(
+                "the snapshot directory '{}' is inside the data directory",
+                self.output.display()
+            );
+        }
+
+        let mut summary = SnapshotSummary::default();
+        summary
+            .snapshot_dir(&data_dir, &self.output, &archive_dir(&data_dir))
+            .with_context(|| format!("writing snapshot to '{}'", self.output.display()))?;
+
+        if output.is_json() {
+            return output.write_result(
+                "snapshot",
+                serde_json::json!({
+                    "snapshot_dir": self.output.display().to_string(),
+                    "cloned_archives": summary.cloned,
+                    "copied_files": summary.copied,
+                    "copied_bytes": summary.copied_bytes,
+                }),
+            );
+        }
+
+        println!(
+            "snapshot of {} files in '{}', cloned {} archives and copied {} files ({} bytes)",
+            summary.cloned + summary.copied,
+            self.output.display(),
+            summary.cloned,
+            summary.copied,
+            summary.copied_bytes,
+        );
+
+        Ok(())
+    }
+}
+
+/// The number of files written to a snapshot
+#[derive(Debug, Default)]
+struct SnapshotSummary {
+    cloned: usize,
+    copied: usize,
+    copied_bytes: u64,
+}
+
+impl SnapshotSummary {
+    /// Copy the files in `from` to the directory `to`, which already exists,
+    /// cloning the archive files in `archive_dir` if possible.
+    fn snapshot_dir(&mut self, from: &Path, to: &Path, archive_dir: &Path) -> anyhow::Result<()> {
+        let entries = fs::read_dir(from)
+            .with_context(|| format!("reading directory entries of '{}'", from.display()))?;
+
+        for entry in entries {
+            let entry = entry.context("reading directory entry")?;
+            let source = entry.path();
+            let target = to.join(entry.file_name());
+
+            if entry.file_type().context("reading file type")?.is_dir() {
+                fs::create_dir(&target)
+                    .with_context(|| format!("creating directory '{}'", target.display()))?;
+                self.snapshot_dir(&source, &target, archive_dir)?;
+                continue;
+            }
+
+            let extension = source.extension().and_then(|ext| ext.to_str());
+            // Skip files which are still being written, or were left behind by
+            // a crash, see `store::recovery`
+            if extension == Some("tmp") {
+                continue;
+            }
+
+            if from == archive_dir && extension == Some("bin") {
+                match clone_file(&source, &target) {
+                    Ok(()) => {
+                        self.cloned += 1;
+                        continue;
+                    }
+                    Err(err) => tracing::debug!(
+                        %err,
+                        archive = %source.display(),
+                        "Cloning archive failed, copying it instead"
+                    ),
+                }
+            }
+
+            match fs::copy(&source, &target) {
+                Ok(len) => {
+                    self.copied += 1;
+                    self.copied_bytes += len;
+                }
+                // The staging file is removed when it is archived
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(err).with_context(|| format!("copying '{}'", source.display()))
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Create the file `to` as a clone of `from`, sharing the blocks on disk
+/// instead of copying them, which fails if the filesystem does not support it.
+#[cfg(target_os = "linux")]
+fn clone_file(from: &Path, to: &Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let source = fs::File::open(from)?;
+    let target = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+
+    // SAFETY: both file descriptors stay open for the duration of the call
+    if unsafe { libc::ioctl(target.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == -1 {
+        let err = io::Error::last_os_error();
+        drop(target);
+        let _ = fs::remove_file(to);
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+/// Create the file `to` as a clone of `from`, sharing the blocks on disk
+/// instead of copying them, which fails if the filesystem does not support it.
+#[cfg(target_os = "macos")]
+fn clone_file(from: &Path, to: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let from = CString::new(from.as_os_str().as_bytes())?;
+    let to = CString::new(to.as_os_str().as_bytes())?;
+
+    // SAFETY: both paths are NUL-terminated strings which outlive the call
+    if unsafe { libc::clonefile(from.as_ptr(), to.as_ptr(), 0) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Cloning files is not supported on this platform, so archives are copied.
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn clone_file(_from: &Path, _to: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "cloning files is not supported on this platform",
+    ))
+}
@@ -0,0 +1,186 @@
+//! This module contains the source labels of records, which say which
+//! producer appended them to a shared data directory.
+//!
+//! Records appended with a source label are staged in a staging file of
+//! their own, so each archive holds the records of a single source. The label
+//! of each archive is recorded in the `SOURCES` file, one
+//! `<label>  <filename>` pair per line, so that the contributions of a single
+//! source can be read on their own.
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+
+use crate::atomic_file::write_atomically;
+
+/// The maximum length of a source label
+const MAX_LABEL_LEN: usize = 64;
+
+fn sources_file_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("SOURCES")
+}
+
+/// The label of the producer which appended a record, like `edge-42`.
+///
+/// Labels are made of ASCII letters, digits, `-`, and `_`, so that they can be
+/// part of a filename.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SourceLabel(String);
+
+impl SourceLabel {
+    /// Return the label as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for SourceLabel {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            anyhow::bail!("a source label must not be empty");
+        }
+        if s.len() > MAX_LABEL_LEN {
+            anyhow::bail!("the source label '{s}' is longer than {MAX_LABEL_LEN} characters");
+        }
+        if let Some(c) = s
+            .chars()
+            .find(|c| !(c.is_ascii_alphanumeric() || matches!(c, '-' | '_')))
+        {
+            anyhow::bail!(
+                "the source label '{s}' contains '{c}', only ASCII letters, digits, '-', and '_' \
+                 are allowed"
+            );
+        }
+
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl fmt::Display for SourceLabel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The mapping from archive filenames to the label of the source whose
+/// records they hold. Archives without a label hold unlabeled records.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ArchiveSources {
+    labels: BTreeMap<String, SourceLabel>,
+}
+
+impl ArchiveSources {
+    /// Read the source labels from the data directory, returning an empty
+    /// mapping if the file does not exist.
+    pub fn read(data_dir: &Path) -> anyhow::Result<Self> {
+        let contents = match fs::read_to_string(sources_file_path(data_dir)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).context("reading SOURCES file"),
+        };
+
+        Self::parse(&contents)
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut labels = BTreeMap::new();
+
+        for (line_index, line) in contents.lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some((label, name)) = line.split_once("  ") else {
+                anyhow::bail!(
+                    "parsing line {} of SOURCES file, expected '<label>  <filename>'",
+                    line_index + 1
+                );
+            };
+            let label = label
+                .parse()
+                .with_context(|| format!("parsing line {} of SOURCES file", line_index + 1))?;
+
+            labels.insert(name.to_string(), label);
+        }
+
+        Ok(Self { labels })
+    }
+
+    /// Atomically write the source labels to the data directory.
+    pub fn write(&self, data_dir: &Path) -> anyhow::Result<()> {
+        write_atomically(&sources_file_path(data_dir), self.to_string().as_bytes())
+            .context("writing SOURCES file")
+    }
+
+    /// Record the source label of the archive with the given filename.
+    pub fn insert(&mut self, name: String, label: SourceLabel) {
+        self.labels.insert(name, label);
+    }
+
+    /// Remove the label of the archive with the given filename, returning
+    /// true if it had one.
+    pub fn remove(&mut self, name: &str) -> bool {
+        self.labels.remove(name).is_some()
+    }
+
+    /// Return the source label of the archive with the given filename, or
+    /// `None` if it holds unlabeled records.
+    pub fn label(&self, name: &str) -> Option<&SourceLabel> {
+        self.labels.get(name)
+    }
+}
+
+impl fmt::Display for ArchiveSources {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, label) in &self.labels {
+            writeln!(f, "{label}  {name}")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_source_labels() {
+        assert_eq!(
+            "edge-42".parse::<SourceLabel>().unwrap().as_str(),
+            "edge-42"
+        );
+        assert!("edge_42".parse::<SourceLabel>().is_ok());
+
+        assert!("".parse::<SourceLabel>().is_err());
+        assert!("edge.42".parse::<SourceLabel>().is_err());
+        assert!("edge/42".parse::<SourceLabel>().is_err());
+        assert!("a".repeat(65).parse::<SourceLabel>().is_err());
+    }
+
+    #[test]
+    fn sources_round_trip() {
+        let mut sources = ArchiveSources::default();
+        sources.insert("000002.bin".into(), "edge-42".parse().unwrap());
+        sources.insert("000001.bin".into(), "edge-7".parse().unwrap());
+
+        let contents = sources.to_string();
+        assert_eq!(contents, "edge-7  000001.bin\nedge-42  000002.bin\n");
+        assert_eq!(ArchiveSources::parse(&contents).unwrap(), sources);
+        assert_eq!(
+            sources.label("000002.bin").map(SourceLabel::as_str),
+            Some("edge-42")
+        );
+        assert_eq!(sources.label("000003.bin"), None);
+
+        assert!(ArchiveSources::parse("edge.7  000001.bin\n").is_err());
+    }
+}
@@ -1,31 +1,417 @@
 //! This module contains things relating to reading and writing from the staging file
 
 use std::{
+    collections::HashSet,
     fs::{self, File, Metadata, OpenOptions},
-    io::{BufRead, BufReader, BufWriter, Write},
+    io::{self, BufRead, BufReader, ErrorKind, IoSlice, Write},
     path::{Path, PathBuf},
 };
 
-use crate::value::Value;
+use crate::{
+    atomic_file::write_atomically,
+    sources::SourceLabel,
+    value::{self, merge::PathMergeSettings, Value},
+};
 use anyhow::Context;
 
-use super::value::merge::MergeSettings;
+use super::manifest::Manifest;
 
-fn staging_file_path(data_dir: &Path) -> PathBuf {
+/// Return the path of the staging file in the data directory.
+pub fn staging_file_path(data_dir: &Path) -> PathBuf {
     data_dir.join("staging.jsonl")
 }
 
-/// Delete the staging file
-pub fn delete_staging_file(data_dir: &Path) -> anyhow::Result<()> {
-    let staging_file_path = staging_file_path(data_dir);
+/// Return the path of the staging file for records from the given source,
+/// which is the staging file for unlabeled records.
+pub fn source_staging_file_path(data_dir: &Path, source: Option<&SourceLabel>) -> PathBuf {
+    match source {
+        Some(source) => data_dir.join(format!("staging.{source}.jsonl")),
+        None => staging_file_path(data_dir),
+    }
+}
+
+/// Return the path that a staging file is renamed to before it is archived,
+/// so that the file being archived never changes and records can be appended
+/// to a new staging file meanwhile.
+///
+/// Each rotation takes the next sequence number, so that the rotated staging
+/// files left behind by a crash are read in the order they were written.
+fn rotated_staging_file_path(
+    data_dir: &Path,
+    source: Option<&SourceLabel>,
+    sequence: u64,
+) -> PathBuf {
+    match source {
+        Some(source) => data_dir.join(format!("staging.{source}.{sequence}.rotating")),
+        None => data_dir.join(format!("staging.{sequence}.rotating")),
+    }
+}
+
+/// The parts of the name of a staging file
+#[derive(Debug, PartialEq, Eq)]
+struct StagingFileName {
+    /// The source of the records, or `None` for unlabeled records
+    source: Option<SourceLabel>,
+    /// The rest of the name of a shard of unlabeled records, like `-3` for
+    /// `staging-3.jsonl`, or `None` for any other staging file
+    shard: Option<String>,
+    /// The sequence number of a rotated staging file, or `None` for a staging
+    /// file which is appended to
+    rotation: Option<u64>,
+}
+
+impl StagingFileName {
+    /// Parse the name of a staging file, returning `None` if it is not one.
+    ///
+    /// Any `staging*.jsonl` file which is not the staging file of a source is
+    /// a shard of unlabeled records, written by another writer.
+    fn parse(file_name: &str) -> Option<Self> {
+        let rest = file_name.strip_prefix("staging")?;
+
+        if let Some(rest) = rest.strip_suffix(".rotating") {
+            let rest = rest.strip_prefix('.')?;
+            let (source, sequence) = match rest.rsplit_once('.') {
+                Some((source, sequence)) => (Some(source.parse().ok()?), sequence),
+                None => (None, rest),
+            };
+            return Some(Self {
+                source,
+                shard: None,
+                rotation: Some(sequence.parse().ok()?),
+            });
+        }
+
+        let rest = rest.strip_suffix(".jsonl")?;
+        let source = rest
+            .strip_prefix('.')
+            .and_then(|label| label.parse::<SourceLabel>().ok());
+        let shard = (source.is_none() && !rest.is_empty()).then(|| rest.to_string());
+        Some(Self {
+            source,
+            shard,
+            rotation: None,
+        })
+    }
+}
+
+/// Return the source of the records in a staging file, from its name.
+pub fn staging_file_source(path: &Path) -> Option<SourceLabel> {
+    StagingFileName::parse(path.file_name()?.to_str()?)?.source
+}
+
+/// Return the names and paths of all the staging files in the data directory.
+fn list_staging_file_names(data_dir: &Path) -> anyhow::Result<Vec<(StagingFileName, PathBuf)>> {
+    let entries = match data_dir.read_dir() {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).context("reading entries of data directory"),
+    };
+
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry.context("reading directory entry")?;
+        if let Some(name) = entry.file_name().to_str().and_then(StagingFileName::parse) {
+            names.push((name, entry.path()));
+        }
+    }
+
+    Ok(names)
+}
+
+/// Return the paths of the rotated staging files in the data directory, which
+/// have not been archived yet, oldest first.
+pub fn list_rotated_staging_files(data_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut rotated = list_staging_file_names(data_dir)?
+        .into_iter()
+        .filter_map(|(name, path)| Some((name.rotation?, path)))
+        .collect::<Vec<_>>();
+    rotated.sort_unstable();
 
-    Ok(fs::remove_file(&staging_file_path)?)
+    Ok(rotated.into_iter().map(|(_, path)| path).collect())
+}
+
+/// Return the paths of the staging files which may hold records, oldest
+/// first: the rotated staging files, then the staging file, which may not
+/// exist, then the shards of unlabeled records, ordered by their names, then
+/// the staging files of each source, ordered by their labels.
+pub fn staging_file_paths(data_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let names = list_staging_file_names(data_dir)?;
+
+    let mut rotated = Vec::new();
+    let mut shards = Vec::new();
+    let mut sources = Vec::new();
+    for (name, path) in names {
+        match name {
+            StagingFileName {
+                rotation: Some(sequence),
+                ..
+            } => rotated.push((sequence, path)),
+            StagingFileName {
+                shard: Some(shard), ..
+            } => shards.push((shard, path)),
+            StagingFileName {
+                source: Some(source),
+                ..
+            } => sources.push((source, path)),
+            _ => {}
+        }
+    }
+    rotated.sort_unstable();
+    shards.sort_unstable();
+    sources.sort_unstable();
+
+    if !rotated.is_empty() {
+        tracing::debug!(
+            rotated_staging_files = rotated.len(),
+            "Found rotated staging files which are not archived yet"
+        );
+    }
+
+    Ok(rotated
+        .into_iter()
+        .map(|(_, path)| path)
+        .chain([staging_file_path(data_dir)])
+        .chain(shards.into_iter().map(|(_, path)| path))
+        .chain(sources.into_iter().map(|(_, path)| path))
+        .collect())
+}
+
+/// Rename the staging file for records from the given source to new rotated
+/// staging files and return their paths in order, see
+/// [`rotated_staging_file_path`].
+///
+/// Without a source, the shards of unlabeled records are rotated along with
+/// the staging file, in the order they are read, so that they are archived
+/// together. Each staging file is renamed while holding an exclusive `flock`
+/// on it, and its writers must follow the same protocol as
+/// [`StagingFileWriter`]: take a shared lock before each write, then check
+/// that the path still names the file they have open, and if not reopen the
+/// path, since the file was rotated. Otherwise records written after the
+/// rotation would be lost once it is archived.
+///
+/// A rotated staging file never replaces another one, so when writers
+/// rotate at the same time each takes the next free sequence number.
+pub fn rotate_staging_files(
+    data_dir: &Path,
+    source: Option<&SourceLabel>,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let names = list_staging_file_names(data_dir)?;
+    let mut sequence = names
+        .iter()
+        .filter_map(|(name, _)| name.rotation)
+        .max()
+        .map_or(0, |sequence| sequence + 1);
+
+    let mut shards = Vec::new();
+    if source.is_none() {
+        shards = names
+            .into_iter()
+            .filter_map(|(name, path)| Some((name.shard?, path)))
+            .collect();
+        shards.sort_unstable();
+    }
+
+    let mut rotated = Vec::with_capacity(1 + shards.len());
+    let staging_path = source_staging_file_path(data_dir, source);
+    for (index, path) in [staging_path]
+        .into_iter()
+        .chain(shards.into_iter().map(|(_, path)| path))
+        .enumerate()
+    {
+        let rotated_path = match rotate_staging_file(data_dir, source, &path, &mut sequence) {
+            Ok(rotated_path) => rotated_path,
+            // A shard may have been archived by another writer meanwhile
+            Err(err) if index > 0 && err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("renaming staging file '{}'", path.display()))
+            }
+        };
+        tracing::debug!(
+            staging_file = %path.display(),
+            rotated_staging_file = %rotated_path.display(),
+            "Rotated staging file"
+        );
+        rotated.push(rotated_path);
+        sequence += 1;
+    }
+
+    Ok(rotated)
+}
+
+/// Rename a staging file to the rotated staging file with the first free
+/// sequence number from the given one, which is updated to it, holding an
+/// exclusive lock on the staging file.
+fn rotate_staging_file(
+    data_dir: &Path,
+    source: Option<&SourceLabel>,
+    path: &Path,
+    sequence: &mut u64,
+) -> io::Result<PathBuf> {
+    let _lock = lock_staging_file_exclusive(path, false)?;
+    loop {
+        let rotated_path = rotated_staging_file_path(data_dir, source, *sequence);
+        match rename_no_replace(path, &rotated_path) {
+            Ok(()) => return Ok(rotated_path),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => *sequence += 1,
+            Err(err) => return Err(err),
+        }
+    }
 }
 
-/// This struct controls appending to the staging file
+/// Open a staging file and take an exclusive lock on it, which is released
+/// when the returned file is closed, so that its writers wait to write until
+/// it has been rotated or replaced. With `create` the staging file is
+/// created if it does not exist.
+pub fn lock_staging_file_exclusive(path: &Path, create: bool) -> io::Result<File> {
+    let mut file = OpenOptions::new().append(true).create(create).open(path)?;
+    lock_current_file(&mut file, path, create, FileLock::Exclusive)?;
+    Ok(file)
+}
+
+/// The kinds of `flock` taken on staging files
+#[derive(Debug, Clone, Copy)]
+enum FileLock {
+    /// Held by writers while they write
+    Shared,
+    /// Held while a staging file is rotated or replaced
+    Exclusive,
+}
+
+/// Lock the open staging file, then check that the path still names it,
+/// and if not reopen the path, creating the file with `create`, and lock
+/// that instead, since the file was rotated or replaced while waiting for
+/// the lock.
+#[cfg(unix)]
+fn lock_current_file(file: &mut File, path: &Path, create: bool, lock: FileLock) -> io::Result<()> {
+    use std::os::{fd::AsRawFd, unix::fs::MetadataExt};
+
+    let operation = match lock {
+        FileLock::Shared => libc::LOCK_SH,
+        FileLock::Exclusive => libc::LOCK_EX,
+    };
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let locked = file.metadata()?;
+        match fs::metadata(path) {
+            Ok(current) if (locked.dev(), locked.ino()) == (current.dev(), current.ino()) => {
+                return Ok(())
+            }
+            Ok(_) => {}
+            Err(err) if create && err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        // Closing the old file releases its lock
+        *file = OpenOptions::new().append(true).create(create).open(path)?;
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_current_file(
+    _file: &mut File,
+    _path: &Path,
+    _create: bool,
+    _lock: FileLock,
+) -> io::Result<()> {
+    Ok(())
+}
+
+/// Release the lock taken by [`lock_current_file`] without closing the file.
+#[cfg(unix)]
+fn unlock_file(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_UN) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn unlock_file(_file: &File) -> io::Result<()> {
+    Ok(())
+}
+
+/// Rename a file, failing with [`ErrorKind::AlreadyExists`] instead of
+/// replacing a file which is already at the new path.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let from_c = CString::new(from.as_os_str().as_bytes())?;
+    let to_c = CString::new(to.as_os_str().as_bytes())?;
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            from_c.as_ptr(),
+            libc::AT_FDCWD,
+            to_c.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if result == -1 {
+        let err = io::Error::last_os_error();
+        // Some filesystems do not support the flag
+        if err.raw_os_error() == Some(libc::EINVAL) {
+            return link_and_unlink(from, to);
+        }
+        return Err(err);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    link_and_unlink(from, to)
+}
+
+/// Rename a file by linking it at the new path, which fails if a file is
+/// already there, then removing the old path.
+///
+/// A crash in between leaves the records at both paths, so they would be
+/// archived twice.
+fn link_and_unlink(from: &Path, to: &Path) -> io::Result<()> {
+    fs::hard_link(from, to)?;
+    fs::remove_file(from)
+}
+
+/// Delete a staging file, once it has been archived
+pub fn delete_staging_file(path: &Path) -> anyhow::Result<()> {
+    Ok(fs::remove_file(path)?)
+}
+
+/// Atomically replace the contents of a staging file with a single line
+/// containing the given value, returning the length of the line.
+///
+/// Records appended to the staging file meanwhile are lost, so callers hold
+/// the lock from [`lock_staging_file_exclusive`] while they read the staging
+/// file and replace it.
+pub fn rewrite_staging_file(staging_file_path: &Path, value: &Value) -> anyhow::Result<u64> {
+    let mut line = serde_json::to_vec(value).context("converting JSON value to bytes")?;
+    line.push(b'\n');
+
+    write_atomically(staging_file_path, &line).context("rewriting staging file")?;
+    Ok(line.len() as u64)
+}
+
+/// The number of bytes of lines which [`StagingFileWriter`] buffers before
+/// writing them, like [`BufWriter`](io::BufWriter)
+const WRITE_BUFFER_LEN: usize = 8 * 1024;
+
+/// This struct controls appending to the staging file.
+///
+/// The lines are buffered and written whole while holding a shared `flock` on
+/// the staging file, and if it was rotated or replaced meanwhile they are
+/// written to the new file at its path instead, see [`rotate_staging_files`].
 #[derive(Debug)]
 pub struct StagingFileWriter {
-    inner: BufWriter<File>,
+    file: File,
+    path: PathBuf,
+    buffer: Vec<u8>,
     metadata: Metadata,
 }
 
@@ -34,42 +420,88 @@ impl StagingFileWriter {
     /// the staging file.
     pub fn flush_if_present(file: &mut Option<Self>) -> anyhow::Result<()> {
         if let Some(ref mut file) = file {
-            file.writer().flush().context("flushing staging file")?;
+            file.flush().context("flushing staging file")?;
         }
 
         Ok(())
     }
 
-    /// If the given file is not `None`, open the staging file for appending
-    /// data.
+    /// If the given file is not `None`, then flush the buffered writes to
+    /// the staging file and wait for them to reach the disk.
+    #[cfg(feature = "kafka")]
+    pub fn sync_if_present(file: &mut Option<Self>) -> anyhow::Result<()> {
+        if let Some(ref mut file) = file {
+            file.flush().context("flushing staging file")?;
+            file.file.sync_data().context("syncing staging file")?;
+        }
+
+        Ok(())
+    }
+
+    /// If the given file is `None`, open the staging file at the path for
+    /// appending data.
     pub fn get_mut_or_open<'f>(
         file: &'f mut Option<Self>,
-        data_dir: &Path,
+        staging_file_path: &Path,
     ) -> anyhow::Result<&'f mut Self> {
         if file.is_none() {
-            *file = Some(Self::open(data_dir)?);
+            *file = Some(Self::open(staging_file_path)?);
         }
 
         Ok(file.as_mut().unwrap())
     }
 
-    fn open(data_dir: &Path) -> anyhow::Result<Self> {
-        let staging_file_path = staging_file_path(data_dir);
-
-        let inner = OpenOptions::new()
+    fn open(staging_file_path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
             .append(true)
             .create(true)
             .open(staging_file_path)
             .context("opening staging file for writing")?;
-        let metadata = inner.metadata().context("reading staging file metadata")?;
-        let inner = BufWriter::new(inner);
+        let metadata = file.metadata().context("reading staging file metadata")?;
 
-        Ok(Self { inner, metadata })
+        Ok(Self {
+            file,
+            path: staging_file_path.to_path_buf(),
+            buffer: Vec::new(),
+            metadata,
+        })
     }
 
-    /// Access the underlying [`Writer`] implementation for the staging file.
-    pub fn writer(&mut self) -> &mut impl Write {
-        &mut self.inner
+    /// Buffer a line, including its trailing newline, writing the buffered
+    /// lines once there are enough of them.
+    pub fn write_line(&mut self, line: &[u8]) -> anyhow::Result<()> {
+        self.buffer.extend_from_slice(line);
+        if self.buffer.len() >= WRITE_BUFFER_LEN {
+            self.flush()
+                .context("writing buffered lines to staging file")?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the lines to the staging file with as few `write_vectored`
+    /// calls as the operating system allows, after flushing any buffered
+    /// writes so that the lines stay in order.
+    pub fn write_lines(&mut self, lines: &[Vec<u8>]) -> anyhow::Result<()> {
+        self.flush().context("flushing staging file")?;
+        write_with_shared_lock(&mut self.file, &self.path, |file| {
+            write_all_vectored(file, lines)
+        })
+        .context("writing batch of lines to staging file")
+    }
+
+    /// Write the buffered lines to the staging file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+
+        write_with_shared_lock(&mut self.file, &self.path, |file| {
+            file.write_all(&self.buffer)
+        })?;
+        self.buffer.clear();
+
+        Ok(())
     }
 
     /// Return the length in bytes of the staging file when it was first opened.
@@ -78,6 +510,50 @@ impl StagingFileWriter {
     }
 }
 
+/// Run the write while holding a shared lock on the staging file at the path,
+/// reopening it first if the open file was rotated or replaced.
+fn write_with_shared_lock(
+    file: &mut File,
+    path: &Path,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    lock_current_file(file, path, true, FileLock::Shared)?;
+    let result = write(file);
+    unlock_file(file)?;
+    result
+}
+
+/// Write every byte of the buffers, continuing after partial writes, which
+/// happen when there are more buffers than one call can take.
+fn write_all_vectored(writer: &mut impl Write, mut buffers: &[Vec<u8>]) -> io::Result<()> {
+    // The number of bytes of the first buffer which are already written
+    let mut written = 0;
+    while let Some((first, rest)) = buffers.split_first() {
+        let slices = std::iter::once(&first[written..])
+            .chain(rest.iter().map(Vec::as_slice))
+            .map(IoSlice::new)
+            .collect::<Vec<_>>();
+        let mut num_bytes = match writer.write_vectored(&slices) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(num_bytes) => num_bytes + written,
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+
+        written = 0;
+        while let Some((first, rest)) = buffers.split_first() {
+            if num_bytes < first.len() {
+                written = num_bytes;
+                break;
+            }
+            num_bytes -= first.len();
+            buffers = rest;
+        }
+    }
+
+    Ok(())
+}
+
 /// This struct controls reading the contents of the staging file
 #[derive(Debug)]
 pub struct StagingFileReader {
@@ -85,35 +561,113 @@ pub struct StagingFileReader {
 }
 
 impl StagingFileReader {
-    fn open(data_dir: &Path) -> anyhow::Result<Self> {
-        let staging_file_path = staging_file_path(data_dir);
-
+    /// Open a staging file for reading, returning `Ok(None)` if it does not
+    /// exist.
+    fn open(staging_file_path: &Path) -> anyhow::Result<Option<Self>> {
         tracing::debug!(
             staging_file = %staging_file_path.display(),
             "Opening staging file for reading"
         );
-        let inner = OpenOptions::new()
-            .read(true)
-            .open(staging_file_path)
-            .context("opening staging file for reading")?;
+        let inner = match OpenOptions::new().read(true).open(staging_file_path) {
+            Ok(inner) => inner,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).context("opening staging file for reading"),
+        };
         let inner = BufReader::new(inner);
 
-        Ok(Self { inner })
+        Ok(Some(Self { inner }))
     }
 
-    /// Open the staging file, read all the lines, and merge those JSON values together.
+    /// Open a staging file and return the bytes of its last line, including
+    /// the trailing newline.
     ///
-    /// Returns `Ok(None)` if the staging file is empty.
-    pub fn read_merged_value(data_dir: &Path) -> anyhow::Result<Option<Value>> {
-        let reader = Self::open(data_dir)?;
-        let merge_settings = MergeSettings::default();
+    /// Returns `Ok(None)` if the staging file is empty or does not exist.
+    pub fn read_last_line(staging_file_path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+        let Some(mut reader) = Self::open(staging_file_path)? else {
+            return Ok(None);
+        };
 
-        let mut accum = None;
-        for line in reader.inner.lines() {
-            let line = line.context("reading line from staging file")?;
-            let value: Value =
-                serde_json::from_str(&line).context("parsing JSON value from staging line")?;
+        let mut last_line = None;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let num_bytes = reader
+                .inner
+                .read_until(b'\n', &mut line)
+                .context("reading line from staging file")?;
+            if num_bytes == 0 {
+                break;
+            }
+            last_line = Some(line.clone());
+        }
 
+        Ok(last_line)
+    }
+
+    /// Open the staging files, read all the lines, and merge those JSON values together, with the
+    /// merge settings from the manifest.
+    ///
+    /// Returns `Ok(None)` if the staging files are empty or do not exist. The `max_depth` limits
+    /// how deeply arrays and objects may be nested in each line.
+    pub fn read_merged_value(data_dir: &Path, max_depth: usize) -> anyhow::Result<Option<Value>> {
+        let merge_settings = Manifest::read(data_dir)?.merge_settings();
+
+        Self::read_merged_files(&staging_file_paths(data_dir)?, merge_settings, max_depth)
+    }
+
+    /// Like [`Self::read_merged_value`], but only read the given staging file.
+    pub fn read_merged_file<'a>(
+        staging_file_path: &Path,
+        merge_settings: impl Into<PathMergeSettings<'a>>,
+        max_depth: usize,
+    ) -> anyhow::Result<Option<Value>> {
+        Self::read_merged_files(&[staging_file_path], merge_settings, max_depth)
+    }
+
+    /// Like [`Self::read_merged_value`], but only read the given staging
+    /// files, in order.
+    pub fn read_merged_files<'a>(
+        paths: &[impl AsRef<Path>],
+        merge_settings: impl Into<PathMergeSettings<'a>>,
+        max_depth: usize,
+    ) -> anyhow::Result<Option<Value>> {
+        Self::merge_lines_in(paths, merge_settings.into(), max_depth, |_| true)
+    }
+
+    /// Like [`Self::read_merged_files`], but skip every line which is
+    /// byte-identical to an earlier line of the files, like the records sent
+    /// again by a producer retrying.
+    ///
+    /// Returns the merged value along with the number of lines skipped.
+    pub fn read_merged_unique_files<'a>(
+        paths: &[impl AsRef<Path>],
+        merge_settings: impl Into<PathMergeSettings<'a>>,
+        max_depth: usize,
+    ) -> anyhow::Result<(Option<Value>, u64)> {
+        // The lines are hashed so that only 32 bytes are kept for each one
+        let mut seen = HashSet::new();
+        let mut duplicates = 0;
+        let accum = Self::merge_lines_in(paths, merge_settings.into(), max_depth, |line| {
+            let unique = seen.insert(blake3::hash(line));
+            if !unique {
+                duplicates += 1;
+            }
+            unique
+        })?;
+
+        Ok((accum, duplicates))
+    }
+
+    /// Merge the lines of the given staging files which `include` returns
+    /// true for, in order, without parsing the other lines.
+    fn merge_lines_in(
+        paths: &[impl AsRef<Path>],
+        merge_settings: PathMergeSettings,
+        max_depth: usize,
+        include: impl FnMut(&[u8]) -> bool,
+    ) -> anyhow::Result<Option<Value>> {
+        let mut accum = None;
+        Self::for_each_included_value_in(paths, max_depth, include, |value| {
             if let Some(inner_accum) = accum.take() {
                 let merged = merge_settings.merge(inner_accum, value);
 
@@ -121,9 +675,269 @@ impl StagingFileReader {
             } else {
                 accum = Some(value);
             }
-        }
+
+            Ok(())
+        })?;
         tracing::trace!(?accum, "Collected merge JSON value from staging file");
 
         Ok(accum)
     }
+
+    /// Open the staging files, then parse each line as a JSON value and pass
+    /// it to the given function, in order.
+    ///
+    /// Does nothing if the staging files do not exist. The `max_depth` limits
+    /// how deeply arrays and objects may be nested in each line.
+    pub fn for_each_value(
+        data_dir: &Path,
+        max_depth: usize,
+        f: impl FnMut(Value) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        Self::for_each_value_in(&staging_file_paths(data_dir)?, max_depth, f)
+    }
+
+    /// Like [`Self::for_each_value`], but read the given staging files, in
+    /// order.
+    pub fn for_each_value_in(
+        paths: &[impl AsRef<Path>],
+        max_depth: usize,
+        f: impl FnMut(Value) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        Self::for_each_included_value_in(paths, max_depth, |_| true, f)
+    }
+
+    fn for_each_included_value_in(
+        paths: &[impl AsRef<Path>],
+        max_depth: usize,
+        mut include: impl FnMut(&[u8]) -> bool,
+        mut f: impl FnMut(Value) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        for path in paths {
+            let path = path.as_ref();
+            let Some(reader) = Self::open(path)? else {
+                continue;
+            };
+
+            // Closing the span logs the time spent on the file
+            let span = tracing::debug_span!(
+                "read_staging",
+                file = %path.display(),
+                bytes = tracing::field::Empty,
+                records = tracing::field::Empty,
+            );
+            let _entered = span.enter();
+            let mut records = 0;
+            let mut line_offset = 0;
+            for (line_index, line) in reader.inner.split(b'\n').enumerate() {
+                let line = line.context("reading line from staging file")?;
+                let line_start = line_offset;
+                line_offset += line.len() + 1;
+                if !include(&line) {
+                    continue;
+                }
+
+                let value = value::from_json_slice(&line, max_depth).with_context(|| {
+                    format!(
+                        "parsing JSON value from line {} of '{}' starting at byte {line_start}",
+                        line_index + 1,
+                        path.display()
+                    )
+                })?;
+
+                f(value)?;
+                records += 1;
+            }
+            span.record("bytes", line_offset);
+            span.record("records", records);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroU64, sync::Barrier, thread};
+
+    use super::*;
+    use crate::{
+        store::{Settings, Store},
+        test_dir::TempDir,
+        value::DEFAULT_MAX_DEPTH,
+    };
+
+    #[test]
+    fn staging_file_names() {
+        let name = |file_name| StagingFileName::parse(file_name);
+        let source = |label: &str| Some(label.parse::<SourceLabel>().unwrap());
+
+        let path = rotated_staging_file_path(Path::new("data"), None, 42);
+        assert_eq!(path, Path::new("data/staging.42.rotating"));
+        assert_eq!(
+            name("staging.42.rotating"),
+            Some(StagingFileName {
+                source: None,
+                shard: None,
+                rotation: Some(42)
+            })
+        );
+
+        let edge = source("edge-7");
+        let path = rotated_staging_file_path(Path::new("data"), edge.as_ref(), 3);
+        assert_eq!(path, Path::new("data/staging.edge-7.3.rotating"));
+        assert_eq!(staging_file_source(&path), edge);
+        assert_eq!(
+            name("staging.edge-7.3.rotating"),
+            Some(StagingFileName {
+                source: source("edge-7"),
+                shard: None,
+                rotation: Some(3)
+            })
+        );
+
+        assert_eq!(
+            name("staging.jsonl"),
+            Some(StagingFileName {
+                source: None,
+                shard: None,
+                rotation: None
+            })
+        );
+        let path = source_staging_file_path(Path::new("data"), edge.as_ref());
+        assert_eq!(path, Path::new("data/staging.edge-7.jsonl"));
+        assert_eq!(staging_file_source(&path), edge);
+
+        assert_eq!(name("staging.rotating"), None);
+        assert_eq!(name("staging.x.rotating"), None);
+        assert_eq!(name("staging.7.rotating.tmp"), None);
+        assert_eq!(name("staging.jsonl.tmp"), None);
+
+        for (file_name, shard) in [("staging-3.jsonl", "-3"), ("staging.a.b.jsonl", ".a.b")] {
+            assert_eq!(
+                name(file_name),
+                Some(StagingFileName {
+                    source: None,
+                    shard: Some(shard.into()),
+                    rotation: None
+                })
+            );
+            assert_eq!(staging_file_source(Path::new(file_name)), None);
+        }
+        assert_eq!(name("stagingx.rotating"), None);
+    }
+
+    /// A writer which takes at most a few bytes from at most two buffers at
+    /// a time, recording every call
+    struct Trickle {
+        written: Vec<u8>,
+        calls: usize,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.calls += 1;
+            let mut num_bytes = 0;
+            for buf in bufs.iter().take(2) {
+                let take = buf.len().min(5 - num_bytes);
+                self.written.extend_from_slice(&buf[..take]);
+                num_bytes += take;
+            }
+            Ok(num_bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_partial_vectored_writes() {
+        let lines =
+            ["{\"a\":1}\n", "\n", "{\"b\":[1,2,3]}\n", "{}\n"].map(|line| line.as_bytes().to_vec());
+        let mut writer = Trickle {
+            written: Vec::new(),
+            calls: 0,
+        };
+
+        write_all_vectored(&mut writer, &lines).unwrap();
+        assert_eq!(writer.written, lines.concat());
+        assert_eq!(writer.calls, 6);
+
+        write_all_vectored(&mut writer, &[]).unwrap();
+        assert_eq!(writer.calls, 6);
+    }
+
+    #[test]
+    fn append_while_another_writer_rotates() {
+        let data_dir = TempDir::new();
+        let records = 500;
+        let barrier = Barrier::new(2);
+        let append = |prefix: &str, staging_limit_bytes| {
+            let settings = Settings {
+                staging_limit_bytes,
+                ..Settings::default()
+            };
+            let mut store = Store::open(data_dir.path(), settings).unwrap();
+            for index in 0..records {
+                barrier.wait();
+                let record = format!(r#"{{"{prefix}{index}": {index}}}"#);
+                store.append(record.as_bytes()).unwrap();
+                store.flush().unwrap();
+            }
+        };
+
+        // One writer archives the shared staging file every few records,
+        // while the other only appends to it, in step so that they overlap
+        thread::scope(|scope| {
+            scope.spawn(|| append("a", 100));
+            scope.spawn(|| append("b", u64::MAX));
+        });
+
+        let Some(Value::Object(fields)) =
+            crate::store::read_merged_value(data_dir.path(), DEFAULT_MAX_DEPTH).unwrap()
+        else {
+            panic!("merged value is an object");
+        };
+        assert_eq!(fields.len(), 2 * records);
+    }
+
+    #[test]
+    fn pre_merge_while_another_writer_appends() {
+        let data_dir = TempDir::new();
+        let records = 200;
+        let barrier = Barrier::new(2);
+        let append = |prefix: &str, pre_merge_every| {
+            let settings = Settings {
+                staging_limit_bytes: u64::MAX,
+                pre_merge_every: NonZeroU64::new(pre_merge_every),
+                ..Settings::default()
+            };
+            let mut store = Store::open(data_dir.path(), settings).unwrap();
+            for index in 0..records {
+                barrier.wait();
+                let record = format!(r#"{{"{prefix}{index}": {index}}}"#);
+                store.append(record.as_bytes()).unwrap();
+                store.flush().unwrap();
+            }
+        };
+
+        // Both writers rewrite the shared staging file with what they merged
+        thread::scope(|scope| {
+            scope.spawn(|| append("a", 3));
+            scope.spawn(|| append("b", 5));
+        });
+
+        let Some(Value::Object(fields)) =
+            crate::store::read_merged_value(data_dir.path(), DEFAULT_MAX_DEPTH).unwrap()
+        else {
+            panic!("merged value is an object");
+        };
+        assert_eq!(fields.len(), 2 * records);
+        let lines = fs::read_to_string(staging_file_path(data_dir.path())).unwrap();
+        assert!(lines.lines().count() < 2 * records);
+    }
 }
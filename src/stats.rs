@@ -0,0 +1,233 @@
+//! This module contains the implementation of the `stats` CLI command
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+
+use crate::{
+    store::read_merged_value,
+    value::{Value, DEFAULT_MAX_DEPTH},
+};
+
+/// The `stats` sub-command reads and merges all the data, then reports
+/// statistics about the shape of the merged value.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "stats")]
+pub struct StatsCommand {
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the stored values, deeper values are rejected as an error.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+}
+
+impl StatsCommand {
+    /// This function executes the stats command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
+        let Some(final_value) = read_merged_value(&data_dir, self.max_nesting_depth)? else {
+            tracing::warn!("No data is present in archive or staging");
+            return Ok(());
+        };
+
+        let stats = ValueStats::collect(&final_value);
+
+        let stdout = io::stdout();
+        let handle = stdout.lock();
+
+        stats
+            .write_report(handle)
+            .context("writing stats report to stdout")?;
+
+        Ok(())
+    }
+}
+
+/// The largest array or string found in a value, and where it was found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Largest {
+    path: String,
+    len: usize,
+}
+
+impl Largest {
+    fn update(largest: &mut Option<Self>, path: &str, len: usize) {
+        if largest.as_ref().map_or(true, |largest| len > largest.len) {
+            *largest = Some(Self {
+                path: path.to_string(),
+                len,
+            });
+        }
+    }
+}
+
+/// Statistics about the shape of a value.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct ValueStats {
+    /// The number of values of each JSON type
+    type_counts: BTreeMap<&'static str, u64>,
+    /// The number of object keys found at each level of nesting
+    keys_per_level: Vec<u64>,
+    /// The deepest level at which any value was found
+    max_depth: usize,
+    /// The array with the most elements
+    largest_array: Option<Largest>,
+    /// The string with the most bytes
+    largest_string: Option<Largest>,
+    /// The serialized JSON size of each top-level key's value
+    top_level_sizes: Vec<(String, u64)>,
+}
+
+impl ValueStats {
+    fn collect(value: &Value) -> Self {
+        let mut stats = Self::default();
+        let mut path = String::new();
+        stats.visit(value, 0, &mut path);
+
+        if let Value::Object(fields) = value {
+            stats.top_level_sizes = fields
+                .iter()
+                .map(|(key, value)| (key.clone(), value.json_len()))
+                .collect();
+            stats.top_level_sizes.sort_by(|(_, a), (_, b)| b.cmp(a));
+        }
+
+        stats
+    }
+
+    fn visit(&mut self, value: &Value, depth: usize, path: &mut String) {
+        *self.type_counts.entry(value.type_name()).or_default() += 1;
+        self.max_depth = self.max_depth.max(depth);
+
+        let path_len = path.len();
+        match value {
+            Value::Null | Value::Bool(_) | Value::Number(_) => {}
+            Value::String(inner) => Largest::update(&mut self.largest_string, path, inner.len()),
+            Value::Array(inner) => {
+                Largest::update(&mut self.largest_array, path, inner.len());
+
+                for (index, item) in inner.iter().enumerate() {
+                    path.push_str(&format!("[{index}]"));
+                    self.visit(item, depth + 1, path);
+                    path.truncate(path_len);
+                }
+            }
+            Value::Object(fields) => {
+                if self.keys_per_level.len() <= depth {
+                    self.keys_per_level.resize(depth + 1, 0);
+                }
+                self.keys_per_level[depth] += fields.len() as u64;
+
+                for (key, item) in fields {
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+                    path.push_str(key);
+                    self.visit(item, depth + 1, path);
+                    path.truncate(path_len);
+                }
+            }
+        }
+    }
+
+    fn write_report(&self, mut writer: impl Write) -> io::Result<()> {
+        writeln!(writer, "types:")?;
+        for (type_name, count) in &self.type_counts {
+            writeln!(writer, "  {type_name}: {count}")?;
+        }
+
+        writeln!(writer, "keys per level:")?;
+        for (level, count) in self.keys_per_level.iter().enumerate() {
+            writeln!(writer, "  {level}: {count}")?;
+        }
+
+        writeln!(writer, "max depth: {}", self.max_depth)?;
+
+        if let Some(Largest { path, len }) = &self.largest_array {
+            writeln!(
+                writer,
+                "largest array: {} ({len} elements)",
+                display_path(path)
+            )?;
+        }
+        if let Some(Largest { path, len }) = &self.largest_string {
+            writeln!(
+                writer,
+                "largest string: {} ({len} bytes)",
+                display_path(path)
+            )?;
+        }
+
+        if !self.top_level_sizes.is_empty() {
+            writeln!(writer, "top-level key sizes:")?;
+            for (key, size) in &self.top_level_sizes {
+                writeln!(writer, "  {key}: {size} bytes")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Display an empty path as the root of the value.
+fn display_path(path: &str) -> &str {
+    if path.is_empty() {
+        "<root>"
+    } else {
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn collect_stats() {
+        let value = Value::from(serde_json::json!({
+            "name": "wall-a",
+            "runs": [{"ok": true}, {"ok": false, "reason": "timeout"}],
+            "tags": ["a", "b", "c"],
+        }));
+
+        let stats = ValueStats::collect(&value);
+        assert_eq!(
+            stats.type_counts,
+            BTreeMap::from([("array", 2), ("bool", 2), ("object", 3), ("string", 5)])
+        );
+        assert_eq!(stats.keys_per_level, vec![3, 0, 3]);
+        assert_eq!(stats.max_depth, 3);
+        assert_eq!(
+            stats.largest_array,
+            Some(Largest {
+                path: "tags".into(),
+                len: 3
+            })
+        );
+        assert_eq!(
+            stats.largest_string,
+            Some(Largest {
+                path: "runs[1].reason".into(),
+                len: 7
+            })
+        );
+        assert_eq!(
+            stats.top_level_sizes,
+            vec![("runs".into(), 45), ("tags".into(), 13), ("name".into(), 8)]
+        );
+    }
+
+    #[test]
+    fn collect_stats_scalar() {
+        let stats = ValueStats::collect(&Value::Bool(true));
+        assert_eq!(stats.type_counts, BTreeMap::from([("bool", 1)]));
+        assert!(stats.keys_per_level.is_empty());
+        assert_eq!(stats.max_depth, 0);
+        assert_eq!(stats.largest_array, None);
+        assert!(stats.top_level_sizes.is_empty());
+    }
+}
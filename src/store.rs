@@ -0,0 +1,2205 @@
+//! This module contains the storage engine behind the `wall-a` tool, which
+//! stages records in the data directory, archives the staging file once it
+//! grows large enough, and reads back the merged value.
+//!
+//! [`Store`] is the simplest way to use the engine as a library, while
+//! [`State`] gives control over each staged record.
+
+use std::{
+    collections::HashSet,
+    io::{self, BufRead, Write},
+    num::{NonZeroU64, NonZeroUsize},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use jiff::Timestamp;
+
+use self::{
+    keys::KeyNormalization,
+    profile::ReadProfile,
+    rejected::{RecordLocation, RejectedReport},
+    ttl::TtlRules,
+};
+use crate::{
+    archive::{
+        archive_name, list_archive_files, list_archive_files_with_timestamps, modified_timestamp,
+        read_archive_value, read_archive_values, write_archive_value, ArchiveNaming,
+    },
+    manifest::Manifest,
+    sources::{ArchiveSources, SourceLabel},
+    staging::{
+        delete_staging_file, lock_staging_file_exclusive, rewrite_staging_file,
+        rotate_staging_files, source_staging_file_path, staging_file_paths, staging_file_source,
+        StagingFileReader, StagingFileWriter,
+    },
+    value::{
+        self, crdt,
+        merge::{
+            ArrayBehaviorAt, CustomMerge, MergeConfig, MergeMode, MergeSettings, PathMergeSettings,
+        },
+        rollup::NumberRollups,
+        walk::{PathStep, Walk},
+        Value, DEFAULT_MAX_DEPTH, RESERVED_KEY_PREFIX, TOMBSTONE_KEY,
+    },
+};
+
+pub mod compaction;
+pub mod keys;
+pub mod profile;
+pub mod recovery;
+pub mod rejected;
+pub mod repair;
+pub mod ttl;
+pub mod updated;
+
+/// The default size in bytes that the staging file may reach before it is
+/// archived
+pub const DEFAULT_STAGING_LIMIT_BYTES: u64 = 1_000_000;
+
+/// A data directory which records can be appended to and the merged value
+/// read from
+#[derive(Debug)]
+pub struct Store {
+    data_dir: PathBuf,
+    state: State,
+}
+
+impl Store {
+    /// Open the store in the given data directory, creating the directory if
+    /// it does not exist.
+    pub fn open(data_dir: impl Into<PathBuf>, settings: Settings) -> anyhow::Result<Self> {
+        let data_dir = data_dir.into();
+        std::fs::create_dir_all(&data_dir).context("creating data directory")?;
+
+        Ok(Self {
+            state: State::new(data_dir.clone(), settings)?,
+            data_dir,
+        })
+    }
+
+    /// Return the path of the data directory.
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    /// Stage a single JSON record, returning whether it was appended.
+    ///
+    /// Invalid records are returned as [`RecordOutcome::Rejected`], while
+    /// errors from writing to the data directory are returned as `Err`.
+    pub fn append(&mut self, record: &[u8]) -> anyhow::Result<RecordOutcome> {
+        self.state.stage_record(record)
+    }
+
+    /// Flush any buffered records to the staging file.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.state.flush()
+    }
+
+    /// Read and merge everything in the store, including records which are
+    /// still buffered.
+    ///
+    /// Returns `Ok(None)` if nothing has been appended.
+    pub fn read(&mut self) -> anyhow::Result<Option<Value>> {
+        self.state.flush()?;
+
+        read_merged_value(&self.data_dir, self.state.settings.max_nesting_depth)
+    }
+
+    /// Return an iterator over every record in the store, oldest first, so
+    /// that they can be folded some other way than the built-in merge.
+    ///
+    /// Each archive yields a single record, which is the merged value of the
+    /// records it was created from, at the time it was created. Each line of
+    /// the staging files is then yielded as its own record, at the time its
+    /// staging file was last modified.
+    pub fn records(&mut self) -> anyhow::Result<Records> {
+        self.state.flush()?;
+
+        let max_depth = self.state.settings.max_nesting_depth;
+        let archives = list_archive_files_with_timestamps(&self.data_dir)?;
+
+        let mut staging = Vec::new();
+        for staging_file in staging_file_paths(&self.data_dir)? {
+            if !staging_file.exists() {
+                continue;
+            }
+            let timestamp = modified_timestamp(&staging_file)?;
+            StagingFileReader::for_each_value_in(&[staging_file], max_depth, |value| {
+                staging.push((timestamp, value));
+                Ok(())
+            })
+            .context("reading values from staging file")?;
+        }
+
+        Ok(Records {
+            archives: archives.into_iter(),
+            staging: staging.into_iter(),
+            scratch_buffer: Vec::new(),
+            max_depth,
+        })
+    }
+}
+
+/// An iterator over every record in a store, returned by [`Store::records`]
+///
+/// Archives are read one at a time as the iterator advances, while the
+/// staging file is read up front.
+#[derive(Debug)]
+pub struct Records {
+    archives: std::vec::IntoIter<(Timestamp, PathBuf)>,
+    staging: std::vec::IntoIter<(Timestamp, Value)>,
+    scratch_buffer: Vec<u8>,
+    max_depth: usize,
+}
+
+impl Iterator for Records {
+    type Item = anyhow::Result<(Timestamp, Value)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let Some((timestamp, path)) = self.archives.next() else {
+            return self.staging.next().map(Ok);
+        };
+
+        self.scratch_buffer.clear();
+        let value = read_archive_value(&path, &mut self.scratch_buffer, self.max_depth)
+            .with_context(|| format!("reading archive value from '{}'", path.display()));
+
+        Some(value.map(|value| (timestamp, value)))
+    }
+}
+
+/// This enum controls how a [`State`] reacts to a record that it cannot
+/// accept
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    /// Stop reading input and return the error
+    #[default]
+    Abort,
+    /// Log the error, drop the record, and continue with the next one
+    Skip,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "abort" => Self::Abort,
+            "skip" => Self::Skip,
+            x => anyhow::bail!("'{x}' is an unknown option for handling rejected records"),
+        })
+    }
+}
+
+/// The options which control how records are appended
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub staging_limit_bytes: u64,
+    pub max_record_bytes: Option<u64>,
+    pub max_normalized_bytes: Option<u64>,
+    pub max_array_elements: Option<usize>,
+    pub max_object_keys: Option<usize>,
+    pub on_error: ErrorPolicy,
+    pub max_nesting_depth: usize,
+    pub archive_naming: ArchiveNaming,
+    /// Write archives with direct IO, bypassing the page cache
+    pub direct_io: bool,
+    pub dedup_consecutive: bool,
+    /// Drop the records which are byte-identical to an earlier record in the
+    /// staging files being archived
+    pub dedup_on_archive: bool,
+    pub id_field: Option<String>,
+    pub unflatten: bool,
+    pub wrap: Option<Wrap>,
+    pub pre_merge_every: Option<NonZeroU64>,
+    /// Write the staged records to the staging file in batches of this many
+    /// records, each with a single vectored write, instead of buffering them
+    pub write_batch: Option<NonZeroUsize>,
+    /// Write the normalized line of every staged record to stdout
+    pub echo: bool,
+    /// Label the records with their source, staging them in the staging file
+    /// of that source
+    pub source: Option<SourceLabel>,
+    /// Archive the staging file on a background thread, while records are
+    /// appended to a new staging file
+    pub background_archive: bool,
+    /// Merge the records with these settings and array behavior overrides,
+    /// instead of the settings in the manifest
+    pub merge_config: Option<MergeConfig>,
+    /// Archive the staging file on a background thread, and wait at most
+    /// this long for it in [`State::wait_for_archive`] before signaling
+    /// backpressure
+    pub backpressure_wait: Option<Duration>,
+    /// Accept records containing tombstones, which delete the fields they
+    /// are merged over, instead of rejecting their reserved key
+    pub allow_tombstones: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            staging_limit_bytes: DEFAULT_STAGING_LIMIT_BYTES,
+            max_record_bytes: None,
+            max_normalized_bytes: None,
+            max_array_elements: None,
+            max_object_keys: None,
+            on_error: ErrorPolicy::default(),
+            max_nesting_depth: DEFAULT_MAX_DEPTH,
+            archive_naming: ArchiveNaming::default(),
+            direct_io: false,
+            dedup_consecutive: false,
+            dedup_on_archive: false,
+            id_field: None,
+            unflatten: false,
+            wrap: None,
+            pre_merge_every: None,
+            write_batch: None,
+            echo: false,
+            source: None,
+            background_archive: false,
+            backpressure_wait: None,
+            merge_config: None,
+            allow_tombstones: false,
+        }
+    }
+}
+
+/// This enum describes how each record is nested under a top-level key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Wrap {
+    /// Nest every record under the same key
+    Key(String),
+    /// Nest every record under the value of one of its own fields
+    KeyFromField(String),
+}
+
+impl Wrap {
+    /// Return the record nested inside an object under the wrapping key.
+    fn apply(&self, value: Value) -> anyhow::Result<Value> {
+        let key = match self {
+            Wrap::Key(key) => key.clone(),
+            Wrap::KeyFromField(field) => {
+                let field_value = match &value {
+                    Value::Object(fields) => fields
+                        .iter()
+                        .find(|(key, _)| key == field)
+                        .map(|(_, field_value)| field_value),
+                    _ => None,
+                };
+
+                match field_value {
+                    Some(Value::String(key)) | Some(Value::Number(key)) => key.clone(),
+                    Some(Value::Bool(key)) => key.to_string(),
+                    Some(other) => anyhow::bail!(
+                        "field '{field}' used as the wrapping key is {} and not a string, number, \
+                         or bool",
+                        other.type_name()
+                    ),
+                    None => {
+                        anyhow::bail!("record is missing field '{field}' used as the wrapping key")
+                    }
+                }
+            }
+        };
+
+        Ok(Value::Object(vec![(key, value)]))
+    }
+}
+
+/// Return the record with each top-level key containing dots replaced by
+/// nested objects.
+///
+/// A backslash escapes the following character, so `\.` is a literal dot.
+/// Records which are not objects are returned unchanged.
+fn unflatten(value: Value) -> anyhow::Result<Value> {
+    let Value::Object(fields) = value else {
+        return Ok(value);
+    };
+
+    let mut unflattened = Vec::with_capacity(fields.len());
+    for (key, field) in fields {
+        let parts = split_dotted_key(&key)?;
+        insert_nested(&mut unflattened, &parts, field)
+            .with_context(|| format!("unflattening key '{key}'"))?;
+    }
+
+    Ok(Value::Object(unflattened))
+}
+
+/// Split a key on the dots which are not escaped by a backslash.
+fn split_dotted_key(key: &str) -> anyhow::Result<Vec<String>> {
+    let mut parts = vec![String::new()];
+    let mut chars = key.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(escaped) => parts
+                    .last_mut()
+                    .expect("parts is never empty")
+                    .push(escaped),
+                None => anyhow::bail!("key '{key}' ends with an unfinished escape"),
+            },
+            '.' => parts.push(String::new()),
+            c => parts.last_mut().expect("parts is never empty").push(c),
+        }
+    }
+
+    if parts.len() > 1 && parts.iter().any(String::is_empty) {
+        anyhow::bail!("key '{key}' has an empty part between dots");
+    }
+
+    Ok(parts)
+}
+
+/// Insert the value into the object fields under the nested keys, merging
+/// with objects created for earlier keys which share a prefix.
+fn insert_nested(
+    fields: &mut Vec<(String, Value)>,
+    parts: &[String],
+    value: Value,
+) -> anyhow::Result<()> {
+    let (first, rest) = parts.split_first().expect("keys have at least one part");
+    let existing = fields.iter_mut().find(|(key, _)| key == first);
+
+    match (existing, rest.is_empty()) {
+        (None, true) => fields.push((first.clone(), value)),
+        (None, false) => {
+            let mut nested = Vec::new();
+            insert_nested(&mut nested, rest, value)?;
+            fields.push((first.clone(), Value::Object(nested)));
+        }
+        (Some((_, Value::Object(nested))), false) => insert_nested(nested, rest, value)?,
+        (Some(_), _) => anyhow::bail!("'{first}' is given more than once"),
+    }
+
+    Ok(())
+}
+
+/// Counts of what happened to the input records, reported once `append`
+/// finishes.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct AppendSummary {
+    /// Records written to the staging file
+    appended_records: u64,
+    /// Records rejected and skipped because of the error policy
+    skipped_records: u64,
+    /// Records skipped because they were identical to the previous record
+    duplicate_records: u64,
+    /// Records skipped because a record with the same identifier was already
+    /// staged
+    duplicate_ids: u64,
+    /// Records dropped while archiving because they were identical to an
+    /// earlier record in the same staging files
+    archived_duplicates: u64,
+    /// The number of times the staging file was rewritten as a single merged
+    /// line
+    pre_merges: u64,
+    /// The number of times reading input paused because archiving took
+    /// longer than the backpressure wait
+    backpressure_pauses: u64,
+    /// The bytes of the normalized records written to the staging file
+    staged_bytes: u64,
+    /// The archives which were written
+    archives: Vec<PathBuf>,
+    /// The staging files which were deleted once they were archived
+    archived_staging_files: Vec<PathBuf>,
+    /// The file which the skipped records were reported to
+    rejected_report: Option<PathBuf>,
+}
+
+impl AppendSummary {
+    fn log(&self) {
+        tracing::info!(
+            appended_records = %self.appended_records,
+            skipped_records = %self.skipped_records,
+            duplicate_records = %self.duplicate_records,
+            duplicate_ids = %self.duplicate_ids,
+            archived_duplicates = %self.archived_duplicates,
+            pre_merges = %self.pre_merges,
+            backpressure_pauses = %self.backpressure_pauses,
+            staged_bytes = %self.staged_bytes,
+            archives = %self.archives.len(),
+            "Finished appending records"
+        );
+
+        if self.skipped_records > 0 {
+            tracing::warn!(
+                skipped_records = %self.skipped_records,
+                rejected_report = ?self.rejected_report,
+                "Some input records were rejected and skipped"
+            );
+        }
+    }
+
+    /// Record what happened when staging files were archived.
+    fn record_archive(&mut self, outcome: ArchiveOutcome) {
+        self.archived_duplicates += outcome.duplicates;
+        self.archives.extend(outcome.archive);
+        self.archived_staging_files.extend(outcome.staging_files);
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let paths = |paths: &[PathBuf]| {
+            paths
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let mut summary = serde_json::json!({
+            "appended_records": self.appended_records,
+            "skipped_records": self.skipped_records,
+            "duplicate_records": self.duplicate_records,
+            "duplicate_ids": self.duplicate_ids,
+            "archived_duplicates": self.archived_duplicates,
+            "pre_merges": self.pre_merges,
+            "backpressure_pauses": self.backpressure_pauses,
+            "staged_bytes": self.staged_bytes,
+            "archives_created": paths(&self.archives),
+            "staging_files_removed": paths(&self.archived_staging_files),
+        });
+        if let Some(rejected_report) = &self.rejected_report {
+            summary["rejected_report"] = rejected_report.display().to_string().into();
+        }
+
+        summary
+    }
+}
+
+/// What happened to a single record passed to [`State::stage_record`]
+#[derive(Debug)]
+pub enum RecordOutcome {
+    /// The record was written to the staging file
+    Appended,
+    /// The record was skipped because it was identical to the previous record
+    DuplicateRecord,
+    /// The record was skipped because a record with the same identifier was
+    /// already staged
+    DuplicateId,
+    /// The record was not valid, and was not staged
+    Rejected(anyhow::Error),
+}
+
+impl RecordOutcome {
+    /// Return a short name for the outcome, used when reporting it to
+    /// clients.
+    pub fn status(&self) -> &'static str {
+        match self {
+            RecordOutcome::Appended => "appended",
+            RecordOutcome::DuplicateRecord | RecordOutcome::DuplicateId => "duplicate",
+            RecordOutcome::Rejected(_) => "rejected",
+        }
+    }
+}
+
+/// The state of an in-progress `append`, which stages records one at a time
+#[derive(Debug)]
+pub struct State {
+    data_dir: PathBuf,
+    line_bytes: Vec<u8>,
+    /// The path of the staging file for the source of the records
+    staging_path: PathBuf,
+    staging_file: Option<StagingFileWriter>,
+    /// The lines of the batch which is not yet written to the staging file,
+    /// which are the first `batch_len` of these buffers, only used when
+    /// writing in batches. The buffers are kept between batches.
+    batch: Vec<Vec<u8>>,
+    batch_len: usize,
+    added_bytes: u64,
+    settings: Settings,
+    /// The settings used to merge records, along with their overrides, from
+    /// the manifest unless they are given in the [`Settings`]
+    merge_config: MergeConfig,
+    /// How the keys of records are normalized, from the manifest
+    key_normalization: KeyNormalization,
+    /// Which numbers in records are rolled up, from the manifest
+    number_rollups: NumberRollups,
+    /// The normalized bytes of the last staged record, only tracked when
+    /// deduplicating consecutive records
+    previous_record: Option<Vec<u8>>,
+    /// The identifiers of all records in the current staging file, only
+    /// tracked when an identifier field is configured
+    seen_ids: HashSet<String>,
+    /// The merged value of everything in the staging file, and the number of
+    /// records appended since it was last written back, only tracked when
+    /// pre-merging
+    pre_merged: Option<Value>,
+    records_since_pre_merge: u64,
+    /// The length of the staging file when it held only the merged value,
+    /// before `added_bytes` were appended to it, or `None` if it is not
+    /// known
+    pre_merged_len: Option<u64>,
+    /// The report of skipped records, opened when the first record is skipped
+    rejected_report: Option<RejectedReport>,
+    /// Where staged records are echoed, only open when echoing. Stdout is
+    /// line buffered, so each record is passed on as soon as it is staged.
+    echo: Option<io::Stdout>,
+    /// Receives the result of archiving the rotated staging file on a
+    /// background thread, while it is running
+    background_archive: Option<mpsc::Receiver<anyhow::Result<ArchiveOutcome>>>,
+    /// The staging file grew past the limit while the rotated staging file
+    /// was still being archived, so it is archived next
+    archive_due: bool,
+    summary: AppendSummary,
+}
+
+impl State {
+    pub fn new(data_dir: PathBuf, settings: Settings) -> anyhow::Result<Self> {
+        recovery::recover_temp_files(&data_dir).context("recovering leftover temporary files")?;
+        let manifest = Manifest::read(&data_dir)?;
+        let merge_config = settings
+            .merge_config
+            .clone()
+            .unwrap_or_else(|| manifest.merge_settings().into());
+        let settings_echo = settings.echo;
+
+        let staging_path = source_staging_file_path(&data_dir, settings.source.as_ref());
+        let previous_record = if settings.dedup_consecutive {
+            StagingFileReader::read_last_line(&staging_path)
+                .context("reading last record from staging file")?
+        } else {
+            None
+        };
+
+        // Only the staging file is appended to, the rotated staging files are
+        // archived separately
+        let mut seen_ids = HashSet::new();
+        if let Some(id_field) = &settings.id_field {
+            StagingFileReader::for_each_value_in(
+                &[&staging_path],
+                settings.max_nesting_depth,
+                |value| {
+                    if let Some(id) = record_id(&value, id_field) {
+                        seen_ids.insert(id);
+                    }
+                    Ok(())
+                },
+            )
+            .context("reading record identifiers from staging file")?;
+        }
+
+        let pre_merged = if settings.pre_merge_every.is_some() {
+            StagingFileReader::read_merged_file(
+                &staging_path,
+                merge_config.path_settings(),
+                settings.max_nesting_depth,
+            )
+            .context("reading merged value from staging file")?
+        } else {
+            None
+        };
+
+        let mut state = Self {
+            data_dir,
+            line_bytes: Vec::new(),
+            staging_path,
+            staging_file: None,
+            batch: Vec::new(),
+            batch_len: 0,
+            added_bytes: 0,
+            settings,
+            merge_config,
+            key_normalization: manifest.key_normalization,
+            number_rollups: manifest.number_rollups,
+            previous_record,
+            seen_ids,
+            pre_merged,
+            records_since_pre_merge: 0,
+            pre_merged_len: None,
+            rejected_report: None,
+            echo: settings_echo.then(io::stdout),
+            background_archive: None,
+            archive_due: false,
+            summary: AppendSummary::default(),
+        };
+
+        // Finish archiving the staging files rotated by a run which crashed,
+        // before any newer records are archived
+        let leftovers = recovery::orphaned_rotated_staging_files(&state.data_dir)
+            .context("listing leftover rotated staging files")?;
+        state.archive_leftover_staging_files(leftovers)?;
+
+        Ok(state)
+    }
+
+    /// Apply the error policy to a rejected record, either returning the
+    /// error or counting the record as skipped and reporting it.
+    ///
+    /// The record is `None` if it was too long to keep, or was not read as a
+    /// line of JSON.
+    pub fn reject_record(
+        &mut self,
+        err: anyhow::Error,
+        location: RecordLocation,
+        record: Option<&[u8]>,
+    ) -> anyhow::Result<()> {
+        let err = err.context(format!("rejected {location}"));
+        match self.settings.on_error {
+            ErrorPolicy::Abort => Err(err),
+            ErrorPolicy::Skip => {
+                tracing::warn!("Skipping rejected input record: {err:#}");
+                self.summary.skipped_records += 1;
+
+                let report = match &mut self.rejected_report {
+                    Some(report) => report,
+                    None => {
+                        let report = RejectedReport::create(&self.data_dir)?;
+                        self.summary.rejected_report = Some(report.path().to_path_buf());
+                        self.rejected_report.insert(report)
+                    }
+                };
+                report.record(location, record, &err)
+            }
+        }
+    }
+
+    /// Return the reserved keys which records may contain: the registers,
+    /// sets, and tombstones written by the writers in [`MergeMode::Crdt`],
+    /// and otherwise only tombstones if they are allowed.
+    fn allowed_reserved_keys(&self) -> Vec<&'static str> {
+        if self.merge_config.settings.mode == MergeMode::Crdt {
+            vec![crdt::REGISTER_KEY, crdt::SET_KEY, TOMBSTONE_KEY]
+        } else if self.settings.allow_tombstones {
+            vec![TOMBSTONE_KEY]
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn too_long_error(&self) -> anyhow::Error {
+        anyhow::anyhow!(
+            "input record exceeded the maximum record size of {} bytes",
+            self.settings.max_record_bytes.unwrap_or_default()
+        )
+    }
+
+    /// Read lines from the reader and append each one as a record, until the
+    /// reader reaches EOF.
+    ///
+    /// Rejected records are reported with their line number and the byte
+    /// offset of the start of the line in the input.
+    pub fn append_from_reader(&mut self, mut reader: impl BufRead) -> anyhow::Result<()> {
+        let mut line = Vec::new();
+        let mut line_number = 0u64;
+        let mut line_offset = 0u64;
+
+        loop {
+            line.clear();
+            line_number += 1;
+            self.pause_for_archive()?;
+
+            let line_read =
+                read_line_bounded(&mut reader, &mut line, self.settings.max_record_bytes)
+                    .context("reading line from input")?;
+            match line_read {
+                LineRead::Eof => {
+                    tracing::debug!("Reached EOF in input");
+                    return Ok(());
+                }
+                LineRead::Complete(num_bytes) => {
+                    tracing::trace!(%num_bytes, "Read line with non-zero bytes");
+                    let location = RecordLocation::Line {
+                        number: line_number,
+                        offset: line_offset,
+                    };
+                    self.append_record(&line, location)?;
+                    line_offset += num_bytes as u64;
+                }
+                LineRead::TooLong(num_bytes) => {
+                    tracing::trace!(%num_bytes, "Discarded line exceeding record size limit");
+                    let location = RecordLocation::Line {
+                        number: line_number,
+                        offset: line_offset,
+                    };
+                    self.reject_record(self.too_long_error(), location, None)?;
+                    line_offset += num_bytes as u64;
+                }
+            }
+        }
+    }
+
+    /// Log the counts of what happened to the records appended so far.
+    pub fn log_summary(&self) {
+        self.summary.log();
+    }
+
+    /// Return the counts of what happened to the records appended so far,
+    /// and the files which were created and removed, as a JSON object.
+    pub fn summary_json(&self) -> serde_json::Value {
+        self.summary.to_json()
+    }
+
+    /// Flush the buffered records to the staging file and wait for them to
+    /// reach the disk.
+    #[cfg(feature = "kafka")]
+    pub fn sync(&mut self) -> anyhow::Result<()> {
+        self.write_batch()?;
+        StagingFileWriter::sync_if_present(&mut self.staging_file)
+    }
+
+    /// Flush any buffered or batched records to the staging file, and any
+    /// buffered entries to the rejected records report.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.write_batch()?;
+        if let Some(report) = &mut self.rejected_report {
+            report.flush()?;
+        }
+        if let Some(echo) = &mut self.echo {
+            echo.flush().context("flushing echoed records to stdout")?;
+        }
+        StagingFileWriter::flush_if_present(&mut self.staging_file)
+    }
+
+    /// Check on the staging file being archived on a background thread,
+    /// waiting for at most the backpressure wait if the next staging file is
+    /// already full, see [`Settings::backpressure_wait`].
+    ///
+    /// Returns `Ok(false)` if the next staging file is full and the last one
+    /// is still being archived, which callers should signal as backpressure
+    /// instead of taking more records. Errors from archiving are returned
+    /// here.
+    pub fn wait_for_archive(&mut self) -> anyhow::Result<bool> {
+        let wait = match self.settings.backpressure_wait {
+            Some(wait) if self.archive_due => wait,
+            _ => Duration::ZERO,
+        };
+
+        let received = self.receive_archive(Some(wait))?;
+        Ok(received || !self.archive_due || self.settings.backpressure_wait.is_none())
+    }
+
+    /// Like [`Self::wait_for_archive`], but if the next staging file is full
+    /// and the last one is still being archived, pause until it is archived
+    /// instead of returning, for callers which read their own input.
+    pub fn pause_for_archive(&mut self) -> anyhow::Result<()> {
+        if !self.wait_for_archive()? {
+            tracing::warn!(
+                wait = ?self.settings.backpressure_wait,
+                "Archiving is taking longer than the backpressure wait, pausing reading input \
+                 until it finishes"
+            );
+            self.summary.backpressure_pauses += 1;
+            self.receive_archive(None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Wait until every staging file being archived on a background thread
+    /// is archived, returning any error from archiving.
+    pub fn finish_archive(&mut self) -> anyhow::Result<()> {
+        while self.background_archive.is_some() {
+            self.receive_archive(None)?;
+        }
+
+        Ok(())
+    }
+
+    /// Receive the result of archiving on a background thread, waiting for
+    /// at most `timeout`, or until it finishes if there is none, then start
+    /// archiving the staging file if it is due.
+    ///
+    /// Returns `Ok(false)` if it is still being archived.
+    fn receive_archive(&mut self, timeout: Option<Duration>) -> anyhow::Result<bool> {
+        let Some(receiver) = &self.background_archive else {
+            return Ok(true);
+        };
+
+        let panicked = || Err(anyhow::anyhow!("archiving thread panicked"));
+        let result = match timeout {
+            Some(timeout) => match receiver.recv_timeout(timeout) {
+                Ok(result) => result,
+                Err(RecvTimeoutError::Timeout) => return Ok(false),
+                Err(RecvTimeoutError::Disconnected) => panicked(),
+            },
+            None => receiver.recv().unwrap_or_else(|_| panicked()),
+        };
+        self.background_archive = None;
+        let outcome = result.context("archiving staging file in the background")?;
+        self.summary.record_archive(outcome);
+
+        if self.archive_due {
+            self.archive_due = false;
+            self.archive_staging_file()
+                .context("archiving staging file")?;
+        }
+
+        Ok(true)
+    }
+
+    /// Parse a single JSON record and append it to the staging file, applying
+    /// the error policy if the record is rejected.
+    ///
+    /// The location describes where the record came from, and is included in
+    /// the error for rejected records.
+    pub fn append_record(&mut self, record: &[u8], location: RecordLocation) -> anyhow::Result<()> {
+        match self.stage_record(record)? {
+            RecordOutcome::Rejected(err) => self.reject_record(err, location, Some(record)),
+            RecordOutcome::Appended
+            | RecordOutcome::DuplicateRecord
+            | RecordOutcome::DuplicateId => Ok(()),
+        }
+    }
+
+    /// Parse a single JSON record and append it to the staging file, archiving
+    /// the staging file if it grows past the limit.
+    ///
+    /// Invalid records are returned as [`RecordOutcome::Rejected`], while
+    /// errors from writing to the data directory are returned as `Err`.
+    pub fn stage_record(&mut self, record: &[u8]) -> anyhow::Result<RecordOutcome> {
+        self.line_bytes.clear();
+
+        // Surface errors from archiving in the background as soon as possible
+        self.receive_archive(Some(Duration::ZERO))?;
+
+        // The trailing newline does not count towards the record size
+        let record_len = record.strip_suffix(b"\n").unwrap_or(record).len() as u64;
+        if self
+            .settings
+            .max_record_bytes
+            .is_some_and(|max| record_len > max)
+        {
+            return Ok(RecordOutcome::Rejected(self.too_long_error()));
+        }
+
+        let value = match value::from_json_slice(record, self.settings.max_nesting_depth) {
+            Ok(value) => value,
+            Err(err) => {
+                return Ok(RecordOutcome::Rejected(
+                    anyhow::Error::new(err).context("converting line to JSON value"),
+                ))
+            }
+        };
+        tracing::trace!(?value, "Got JSON value");
+
+        let value = if self.settings.unflatten {
+            match unflatten(value) {
+                Ok(value) => value,
+                Err(err) => return Ok(RecordOutcome::Rejected(err)),
+            }
+        } else {
+            value
+        };
+
+        let id = match &self.settings.id_field {
+            Some(id_field) => record_id(&value, id_field),
+            None => None,
+        };
+        if let Some(id) = &id {
+            if self.seen_ids.contains(id) {
+                tracing::trace!(%id, "Skipping record with an identifier that was already staged");
+                self.summary.duplicate_ids += 1;
+                return Ok(RecordOutcome::DuplicateId);
+            }
+        }
+
+        let value = match &self.settings.wrap {
+            Some(wrap) => match wrap.apply(value) {
+                Ok(value) => value,
+                Err(err) => return Ok(RecordOutcome::Rejected(err.context("wrapping record"))),
+            },
+            None => value,
+        };
+        let value = self
+            .key_normalization
+            .apply(value, self.merge_config.settings);
+        let value = self.merge_config.settings.fold_keys(value);
+
+        // Checked after the keys are normalized and folded, which may turn
+        // them into reserved keys, and before numbers are rolled up into
+        // markers
+        if let Err(err) = check_reserved_keys(&value, &self.allowed_reserved_keys()) {
+            return Ok(RecordOutcome::Rejected(err));
+        }
+        let value = self.number_rollups.apply(value, self.merge_config.settings);
+
+        if let Err(err) = check_element_counts(
+            &value,
+            self.settings.max_array_elements,
+            self.settings.max_object_keys,
+        ) {
+            return Ok(RecordOutcome::Rejected(err));
+        }
+
+        serde_json::to_writer(&mut self.line_bytes, &value)
+            .context("converting JSON value to bytes")?;
+        self.line_bytes.push(b'\n');
+        let line_num_bytes = self.line_bytes.len() as u64;
+        tracing::trace!(num_bytes = ?line_num_bytes, "Converted JSON value back to bytes");
+
+        // Like the raw record size, the trailing newline does not count
+        if let Some(max) = self.settings.max_normalized_bytes {
+            if line_num_bytes - 1 > max {
+                return Ok(RecordOutcome::Rejected(anyhow::anyhow!(
+                    "normalized record is {} bytes, more than the maximum of {max} bytes",
+                    line_num_bytes - 1
+                )));
+            }
+        }
+
+        // The identifier is only used up once the record can no longer be
+        // rejected, so that a corrected retry of a rejected record is staged
+        if let Some(id) = id {
+            self.seen_ids.insert(id);
+        }
+
+        if self.settings.dedup_consecutive {
+            if self.previous_record.as_deref() == Some(self.line_bytes.as_slice()) {
+                tracing::trace!("Skipping record identical to the previous record");
+                self.summary.duplicate_records += 1;
+                return Ok(RecordOutcome::DuplicateRecord);
+            }
+
+            let previous_record = self.previous_record.get_or_insert_with(Vec::new);
+            previous_record.clear();
+            previous_record.extend_from_slice(&self.line_bytes);
+        }
+
+        if let Some(echo) = &mut self.echo {
+            echo.write_all(&self.line_bytes)
+                .context("echoing record to stdout")?;
+        }
+
+        if self.settings.pre_merge_every.is_some() {
+            let merged = match self.pre_merged.take() {
+                Some(accum) => self.path_merge_settings().merge(accum, value),
+                None => value,
+            };
+            self.pre_merged = Some(merged);
+            self.records_since_pre_merge += 1;
+        }
+
+        let staging_file =
+            StagingFileWriter::get_mut_or_open(&mut self.staging_file, &self.staging_path)
+                .context("accessing staging file")?;
+        let staging_initial_len = staging_file.initial_len();
+
+        match self.settings.write_batch {
+            Some(batch_size) => {
+                if self.batch_len == self.batch.len() {
+                    self.batch.push(Vec::new());
+                }
+                let line = &mut self.batch[self.batch_len];
+                line.clear();
+                line.extend_from_slice(&self.line_bytes);
+                self.batch_len += 1;
+
+                if self.batch_len >= batch_size.get() {
+                    self.write_batch()?;
+                }
+            }
+            None => staging_file
+                .write_line(&self.line_bytes)
+                .context("writing JSON bytes to staging")?,
+        }
+        self.added_bytes += line_num_bytes;
+        self.summary.staged_bytes += line_num_bytes;
+        self.summary.appended_records += 1;
+        tracing::trace!(%self.added_bytes, %line_num_bytes, "Wrote JSON bytes with newline to staging file");
+
+        if self
+            .settings
+            .pre_merge_every
+            .is_some_and(|every| self.records_since_pre_merge >= every.get())
+        {
+            self.pre_merge_staging_file()?;
+            return Ok(RecordOutcome::Appended);
+        }
+
+        if !self.archive_due
+            && staging_initial_len + self.added_bytes > self.settings.staging_limit_bytes
+        {
+            tracing::info!(
+                staging_file_length_bytes = %staging_initial_len,
+                %self.added_bytes,
+                %self.settings.staging_limit_bytes,
+                "Staging file size has increased past provided limit, going to archive"
+            );
+
+            self.archive_staging_file()
+                .context("archiving staging file")?;
+        }
+
+        Ok(RecordOutcome::Appended)
+    }
+
+    /// Write the records batched by [`Settings::write_batch`] to the staging
+    /// file, if there are any.
+    fn write_batch(&mut self) -> anyhow::Result<()> {
+        if self.batch_len == 0 {
+            return Ok(());
+        }
+
+        let staging_file =
+            StagingFileWriter::get_mut_or_open(&mut self.staging_file, &self.staging_path)
+                .context("accessing staging file")?;
+        staging_file.write_lines(&self.batch[..self.batch_len])?;
+        tracing::trace!(records = %self.batch_len, "Wrote batch of records to staging file");
+        self.batch_len = 0;
+
+        Ok(())
+    }
+
+    /// Replace the staging file with a single line containing the in-memory
+    /// merged value, then continue appending to the new file.
+    ///
+    /// The staging file is locked while it is replaced, so that no writer
+    /// appends to it meanwhile. If it holds anything besides what was merged
+    /// in memory, like the records of other writers, or it was rotated, its
+    /// contents are merged again instead.
+    fn pre_merge_staging_file(&mut self) -> anyhow::Result<()> {
+        self.write_batch()?;
+        StagingFileWriter::flush_if_present(&mut self.staging_file)?;
+        drop(self.staging_file.take());
+
+        let lock = lock_staging_file_exclusive(&self.staging_path, true)
+            .context("locking staging file")?;
+        let staging_len = lock
+            .metadata()
+            .context("reading staging file metadata")?
+            .len();
+        if self.pre_merged_len.map(|len| len + self.added_bytes) != Some(staging_len) {
+            tracing::debug!(
+                %staging_len,
+                "Staging file does not hold only the pre-merged records, merging it again"
+            );
+            self.pre_merged = StagingFileReader::read_merged_file(
+                &self.staging_path,
+                self.path_merge_settings(),
+                self.settings.max_nesting_depth,
+            )
+            .context("reading merged value from staging file")?;
+        }
+
+        let Some(pre_merged) = &self.pre_merged else {
+            self.pre_merged_len = Some(staging_len);
+            return Ok(());
+        };
+        tracing::debug!(
+            heap_bytes = pre_merged.estimated_heap_size(),
+            "Rewriting staging file with pre-merged value"
+        );
+        let pre_merged_len = rewrite_staging_file(&self.staging_path, pre_merged)
+            .context("rewriting staging file with merged value")?;
+        drop(lock);
+        self.pre_merged_len = Some(pre_merged_len);
+        self.records_since_pre_merge = 0;
+        self.summary.pre_merges += 1;
+
+        // The reopened staging file will count the merged line as part of its
+        // initial length
+        self.added_bytes = 0;
+        let staging_file =
+            StagingFileWriter::get_mut_or_open(&mut self.staging_file, &self.staging_path)
+                .context("accessing staging file")?;
+        let staging_len = staging_file.initial_len();
+        tracing::debug!(%staging_len, "Rewrote staging file with merged value");
+
+        if !self.archive_due && staging_len > self.settings.staging_limit_bytes {
+            tracing::info!(
+                %staging_len,
+                %self.settings.staging_limit_bytes,
+                "Merged staging file size is past provided limit, going to archive"
+            );
+
+            self.archive_staging_file()
+                .context("archiving staging file")?;
+        }
+
+        Ok(())
+    }
+
+    /// Rotate the staging file and archive its contents, on a background
+    /// thread if archiving in the background
+    fn archive_staging_file(&mut self) -> anyhow::Result<()> {
+        let background =
+            self.settings.background_archive || self.settings.backpressure_wait.is_some();
+        if background && self.background_archive.is_some() {
+            tracing::info!(
+                "The last staging file is still being archived, archiving this one once it \
+                 finishes"
+            );
+            self.archive_due = true;
+            return Ok(());
+        }
+
+        // Drop the append-only staging file reference if it exists
+        self.write_batch()?;
+        StagingFileWriter::flush_if_present(&mut self.staging_file)?;
+        drop(self.staging_file.take());
+
+        // Set the added bytes to be zero and forget the staged identifiers
+        // since we just closed out the staging file
+        self.added_bytes = 0;
+        self.seen_ids.clear();
+        self.pre_merged = None;
+        self.records_since_pre_merge = 0;
+        self.pre_merged_len = Some(0);
+
+        // This writer is not archiving anything, but writers of other
+        // sources or other processes may be, so only the rotated staging
+        // files old enough to have been left behind by a crashed run are
+        // archived first, to keep the order
+        let leftovers = recovery::orphaned_rotated_staging_files(&self.data_dir)
+            .context("listing leftover rotated staging files")?;
+        self.archive_leftover_staging_files(leftovers)?;
+
+        let job = self.archive_job(rotate_staging_files(
+            &self.data_dir,
+            self.settings.source.as_ref(),
+        )?);
+        if !background {
+            let outcome = job.run()?;
+            self.summary.record_archive(outcome);
+            return Ok(());
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("wall-a-archive".into())
+            .spawn(move || {
+                // The receiver is only gone if the state was dropped, which
+                // waits for the result first
+                let _ = sender.send(job.run());
+            })
+            .context("starting archiving thread")?;
+        self.background_archive = Some(receiver);
+
+        Ok(())
+    }
+
+    /// Return the settings used to merge records, along with their
+    /// overrides.
+    fn path_merge_settings(&self) -> PathMergeSettings<'_> {
+        self.merge_config.path_settings()
+    }
+
+    fn archive_job(&self, staging_files: Vec<PathBuf>) -> ArchiveJob {
+        ArchiveJob {
+            data_dir: self.data_dir.clone(),
+            staging_files,
+            max_nesting_depth: self.settings.max_nesting_depth,
+            merge_config: self.merge_config.clone(),
+            archive_naming: self.settings.archive_naming,
+            direct_io: self.settings.direct_io,
+            dedup: self.settings.dedup_on_archive,
+        }
+    }
+
+    /// Archive the rotated staging files left behind by an earlier run, in
+    /// order.
+    fn archive_leftover_staging_files(&mut self, rotated: Vec<PathBuf>) -> anyhow::Result<()> {
+        for rotated_path in rotated {
+            tracing::warn!(
+                rotated_staging_file = %rotated_path.display(),
+                "Archiving rotated staging file left behind by an earlier run"
+            );
+            let outcome = self
+                .archive_job(vec![rotated_path])
+                .run()
+                .context("archiving leftover rotated staging file")?;
+            self.summary.record_archive(outcome);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        if let Err(err) = self.write_batch() {
+            tracing::error!("{err:#}");
+        }
+        // The staging file is only deleted after its archive is complete, so
+        // an archive cut short by the process exiting would be merged twice
+        if let Err(err) = self.finish_archive() {
+            tracing::error!("{err:#}");
+        }
+    }
+}
+
+/// What happened when staging files were archived
+#[derive(Debug)]
+struct ArchiveOutcome {
+    /// The archive which was written, unless there was nothing to archive
+    archive: Option<PathBuf>,
+    /// The staging files which were deleted
+    staging_files: Vec<PathBuf>,
+    /// The number of duplicate records which were dropped
+    duplicates: u64,
+}
+
+/// Archiving a staging file, apart from the [`State`] so that it can run on
+/// a background thread
+#[derive(Debug)]
+struct ArchiveJob {
+    data_dir: PathBuf,
+    /// The rotated staging files to archive together, in the order they are
+    /// merged. They all hold records from the same source.
+    staging_files: Vec<PathBuf>,
+    max_nesting_depth: usize,
+    merge_config: MergeConfig,
+    archive_naming: ArchiveNaming,
+    direct_io: bool,
+    /// Drop records which are byte-identical to an earlier one, see
+    /// [`Settings::dedup_on_archive`]
+    dedup: bool,
+}
+
+impl ArchiveJob {
+    /// Write the merged value of the staging files to a new archive, then
+    /// delete the staging files.
+    fn run(self) -> anyhow::Result<ArchiveOutcome> {
+        let path_settings = self.merge_config.path_settings();
+        let (staging_value, duplicates) = if self.dedup {
+            StagingFileReader::read_merged_unique_files(
+                &self.staging_files,
+                path_settings,
+                self.max_nesting_depth,
+            )
+        } else {
+            StagingFileReader::read_merged_files(
+                &self.staging_files,
+                path_settings,
+                self.max_nesting_depth,
+            )
+            .map(|value| (value, 0))
+        }
+        .context("opening staging file for archiving")?;
+        if duplicates > 0 {
+            tracing::info!(
+                %duplicates,
+                "Dropped records identical to an earlier record in the staging file"
+            );
+        }
+
+        let Some(mut staging_value) = staging_value else {
+            // No values in staging file
+            tracing::warn!("Staging file was empty, not continuing with archiving");
+            return self.finish(None, duplicates);
+        };
+
+        let ttl_rules = TtlRules::read(&self.data_dir)?;
+        if !ttl_rules.is_empty() {
+            let mut last_updated = ttl_rules.tracker();
+            // The merged value does not say which file each part came from,
+            // so all of it was updated when the newest file was
+            let mut modified = Vec::with_capacity(self.staging_files.len());
+            for staging_file in &self.staging_files {
+                modified.push(modified_timestamp(staging_file)?);
+            }
+            let modified = modified.into_iter().max().unwrap_or_else(Timestamp::now);
+            last_updated.record(modified, &staging_value);
+            last_updated.expire(&mut staging_value, Timestamp::now());
+        }
+
+        // Archived arrays are limited too, so that they stay bounded on disk
+        Manifest::read(&self.data_dir)?
+            .array_rules
+            .apply(&mut staging_value);
+
+        // Tombstones are only needed to hide fields in older archives, so
+        // without any they are dropped along with the fields they deleted.
+        // Records merged without an order may still need to hide fields in
+        // archives written later, so they keep every tombstone.
+        let staging_value = if self.merge_config.settings.mode == MergeMode::Ordered
+            && list_archive_files(&self.data_dir)?.is_empty()
+        {
+            staging_value.remove_tombstones()
+        } else {
+            Some(staging_value)
+        };
+        let Some(staging_value) = staging_value else {
+            tracing::info!("Staging file only deleted values, not writing an archive");
+            return self.finish(None, duplicates);
+        };
+
+        let archive_path = write_archive_value(
+            &self.data_dir,
+            staging_value,
+            self.archive_naming,
+            self.direct_io,
+        )
+        .context("writing CBOR value to archive")?;
+
+        if let Some(source) = self
+            .staging_files
+            .first()
+            .and_then(|path| staging_file_source(path))
+        {
+            let mut sources = ArchiveSources::read(&self.data_dir)?;
+            sources.insert(archive_name(&archive_path), source);
+            sources.write(&self.data_dir)?;
+        }
+
+        self.finish(Some(archive_path), duplicates)
+    }
+
+    /// Delete the staging files, once their value is archived.
+    fn finish(self, archive: Option<PathBuf>, duplicates: u64) -> anyhow::Result<ArchiveOutcome> {
+        self.delete_staging_files()?;
+
+        Ok(ArchiveOutcome {
+            archive,
+            staging_files: self.staging_files,
+            duplicates,
+        })
+    }
+
+    fn delete_staging_files(&self) -> anyhow::Result<()> {
+        for staging_file in &self.staging_files {
+            delete_staging_file(staging_file).context("cleaning up staging file")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Return the identifier of a record, which is the serialized JSON of the
+/// given top-level field, with the keys of its objects sorted so that the
+/// order they were written in does not matter.
+///
+/// Returns `None` if the record is not an object or does not have the field.
+fn record_id(value: &Value, id_field: &str) -> Option<String> {
+    let Value::Object(fields) = value else {
+        return None;
+    };
+
+    let (_, id) = fields.iter().find(|(key, _)| key == id_field)?;
+    let mut id = id.clone();
+    id.walk_mut(&mut |_: &[PathStep<'_>], part: &mut Value| {
+        if let Value::Object(fields) = part {
+            fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+        }
+        Walk::Continue
+    });
+
+    Some(serde_json::to_string(&id).expect("serializing to a string cannot fail"))
+}
+
+/// Return an error naming the first object key which starts with
+/// [`RESERVED_KEY_PREFIX`] and is not one of the allowed keys.
+fn check_reserved_keys(value: &Value, allowed: &[&str]) -> anyhow::Result<()> {
+    fn check(value: &Value, path: &mut String, allowed: &[&str]) -> anyhow::Result<()> {
+        let path_len = path.len();
+
+        match value {
+            Value::Array(elements) => {
+                for (index, element) in elements.iter().enumerate() {
+                    path.push_str(&format!("[{index}]"));
+                    check(element, path, allowed)?;
+                    path.truncate(path_len);
+                }
+            }
+            Value::Object(fields) => {
+                for (key, value) in fields {
+                    if key.starts_with(RESERVED_KEY_PREFIX) && !allowed.contains(&key.as_str()) {
+                        let at = match path.as_str() {
+                            "" => "the top level".to_string(),
+                            path => format!("'{path}'"),
+                        };
+                        anyhow::bail!(
+                            "key '{key}' at {at} is reserved for the markers of wall-a, and \
+                             cannot be appended"
+                        );
+                    }
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+                    path.push_str(key);
+                    check(value, path, allowed)?;
+                    path.truncate(path_len);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    check(value, &mut String::new(), allowed)
+}
+
+/// Return an error naming the first array with more elements, or object with
+/// more keys, than the limits allow.
+fn check_element_counts(
+    value: &Value,
+    max_array_elements: Option<usize>,
+    max_object_keys: Option<usize>,
+) -> anyhow::Result<()> {
+    fn check(
+        value: &Value,
+        path: &mut String,
+        max_array_elements: Option<usize>,
+        max_object_keys: Option<usize>,
+    ) -> anyhow::Result<()> {
+        let at = |path: &str| match path {
+            "" => "the top level".to_string(),
+            path => format!("'{path}'"),
+        };
+        let path_len = path.len();
+
+        match value {
+            Value::Array(elements) => {
+                if let Some(max) = max_array_elements.filter(|&max| elements.len() > max) {
+                    anyhow::bail!(
+                        "array at {} has {} elements, more than the maximum of {max}",
+                        at(path),
+                        elements.len()
+                    );
+                }
+                for (index, element) in elements.iter().enumerate() {
+                    path.push_str(&format!("[{index}]"));
+                    check(element, path, max_array_elements, max_object_keys)?;
+                    path.truncate(path_len);
+                }
+            }
+            Value::Object(fields) => {
+                if let Some(max) = max_object_keys.filter(|&max| fields.len() > max) {
+                    anyhow::bail!(
+                        "object at {} has {} keys, more than the maximum of {max}",
+                        at(path),
+                        fields.len()
+                    );
+                }
+                for (key, value) in fields {
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+                    path.push_str(key);
+                    check(value, path, max_array_elements, max_object_keys)?;
+                    path.truncate(path_len);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    if max_array_elements.is_none() && max_object_keys.is_none() {
+        return Ok(());
+    }
+    check(
+        value,
+        &mut String::new(),
+        max_array_elements,
+        max_object_keys,
+    )
+}
+
+/// The outcome of reading a single line with [`read_line_bounded`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum LineRead {
+    /// The reader had no more data
+    Eof,
+    /// A full line was read, containing the given number of bytes
+    Complete(usize),
+    /// The line was longer than the limit and was discarded, the given
+    /// number of bytes were consumed from the reader
+    TooLong(usize),
+}
+
+/// Read a single line from the reader into `buf`, without buffering more than
+/// `max_bytes` of it.
+///
+/// If the line is longer than the limit, the rest of it is consumed and
+/// discarded so that the next read starts at the beginning of the next line.
+fn read_line_bounded(
+    reader: &mut impl BufRead,
+    buf: &mut Vec<u8>,
+    max_bytes: Option<u64>,
+) -> io::Result<LineRead> {
+    let max_bytes = max_bytes.map_or(usize::MAX, |max| usize::try_from(max).unwrap_or(usize::MAX));
+    let mut num_bytes = 0;
+    let mut too_long = false;
+
+    loop {
+        let available = match reader.fill_buf() {
+            Ok(available) => available,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        if available.is_empty() {
+            break;
+        }
+
+        let (chunk, done) = match available.iter().position(|b| *b == b'\n') {
+            Some(newline_index) => (&available[..=newline_index], true),
+            None => (available, false),
+        };
+        let chunk_len = chunk.len();
+        // The trailing newline does not count towards the record size
+        let record_len = chunk_len - usize::from(done);
+
+        if !too_long {
+            if buf.len() + record_len > max_bytes {
+                too_long = true;
+                buf.clear();
+            } else {
+                buf.extend_from_slice(chunk);
+            }
+        }
+
+        reader.consume(chunk_len);
+        num_bytes += chunk_len;
+
+        if done {
+            break;
+        }
+    }
+
+    Ok(if num_bytes == 0 {
+        LineRead::Eof
+    } else if too_long {
+        LineRead::TooLong(num_bytes)
+    } else {
+        LineRead::Complete(num_bytes)
+    })
+}
+
+/// Read and merge all the archived values and the staging file values in the
+/// data directory, then remove any tombstones and fields expired by the TTL
+/// rules.
+///
+/// Returns `Ok(None)` if there is no data in either.
+pub fn read_merged_value(data_dir: &Path, max_depth: usize) -> anyhow::Result<Option<Value>> {
+    read_merged_value_inner(
+        data_dir,
+        max_depth,
+        ReadOptions::default(),
+        &mut Vec::new(),
+        &mut ReadProfile::default(),
+    )
+}
+
+/// Options for how [`read_merged_value_with`] reads the archives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReadOptions<'a> {
+    /// Leave out archives which fail their checksums or cannot be decoded,
+    /// instead of failing the whole read
+    pub skip_corrupt: bool,
+    /// Verify the checksums of every archive body, which is the default.
+    ///
+    /// Without it corrupted archives may be read as the wrong value, so it
+    /// should only be turned off for trusted archives on a filesystem which
+    /// checksums its data.
+    pub verify_checksums: bool,
+    /// Only read the archives and staging files holding records from this
+    /// source, see [`crate::sources`]
+    pub source: Option<&'a SourceLabel>,
+    /// Merge the arrays at some paths with other behaviors than the one in
+    /// the manifest. Only the merges made while reading use them, so the
+    /// records of an archive are still merged with each other the way they
+    /// were when it was archived.
+    pub array_behaviors: &'a [ArrayBehaviorAt],
+    /// Merge with these settings instead of the ones in the manifest. Like
+    /// the array behaviors, only the merges made while reading use them.
+    pub merge_settings: Option<MergeSettings>,
+    /// Merge the fields matched by a pattern with a function, see
+    /// [`MergeSettings::with_custom`]. Like the array behaviors, only the
+    /// merges made while reading use them.
+    pub custom_merges: &'a [CustomMerge],
+}
+
+impl Default for ReadOptions<'_> {
+    fn default() -> Self {
+        Self {
+            skip_corrupt: false,
+            verify_checksums: true,
+            source: None,
+            array_behaviors: &[],
+            merge_settings: None,
+            custom_merges: &[],
+        }
+    }
+}
+
+impl ReadOptions<'_> {
+    /// Remove the archives which do not hold records from the source to
+    /// read, if there is one.
+    fn retain_source_archives<T>(
+        &self,
+        data_dir: &Path,
+        archives: &mut Vec<T>,
+        archive_path: impl Fn(&T) -> &Path,
+    ) -> anyhow::Result<()> {
+        let Some(source) = self.source else {
+            return Ok(());
+        };
+
+        let sources = ArchiveSources::read(data_dir)?;
+        archives
+            .retain(|archive| sources.label(&archive_name(archive_path(archive))) == Some(source));
+
+        Ok(())
+    }
+
+    /// Return the paths of the staging files to read, which are only those
+    /// holding records from the source to read, if there is one.
+    fn staging_file_paths(&self, data_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = staging_file_paths(data_dir)?;
+        if let Some(source) = self.source {
+            paths.retain(|path| staging_file_source(path).as_ref() == Some(source));
+        }
+
+        Ok(paths)
+    }
+}
+
+/// An archive file which was left out of the merged value because it could
+/// not be read, see [`ReadOptions::skip_corrupt`]
+#[derive(Debug)]
+pub struct SkippedArchive {
+    /// The path of the archive file
+    pub path: PathBuf,
+    /// Why the archive could not be read, like a checksum mismatch
+    pub error: anyhow::Error,
+}
+
+/// Detects an archive with the same body as the archive read before it,
+/// which happens when writing an archive is retried or an archive is copied
+/// in by replication.
+///
+/// Merging a repeated archive again would only duplicate the elements of
+/// arrays merged with [`ArrayBehavior::Concat`](crate::value::merge::ArrayBehavior::Concat),
+/// since every other merge rule gives the same result when a value is merged
+/// twice in a row. Archives which are not consecutive are never compared,
+/// since an archive identical to an older one may be meant to override the
+/// archives in between.
+#[derive(Debug, Default)]
+pub struct RepeatedArchives {
+    previous_body: Option<blake3::Hash>,
+}
+
+impl RepeatedArchives {
+    /// Return true if the archive at the path, with the given hash of its
+    /// body, repeats the archive before it and should not be merged.
+    pub fn is_repeat(&mut self, path: &Path, body_hash: blake3::Hash) -> bool {
+        let repeat = self.previous_body.replace(body_hash) == Some(body_hash);
+        if repeat {
+            tracing::info!(
+                archive_file = %path.display(),
+                "Skipping archive identical to the archive before it"
+            );
+        }
+
+        repeat
+    }
+}
+
+/// Like [`read_merged_value`], but with options for how the archives are
+/// read.
+///
+/// Returns the archives which were left out along with the merged value.
+pub fn read_merged_value_with(
+    data_dir: &Path,
+    max_depth: usize,
+    options: ReadOptions,
+) -> anyhow::Result<(Option<Value>, Vec<SkippedArchive>)> {
+    read_merged_value_profiled(data_dir, max_depth, options, &mut ReadProfile::default())
+}
+
+/// Like [`read_merged_value_with`], but also measure the bytes read and the
+/// time spent reading, decoding, and merging each archive in `profile`.
+pub fn read_merged_value_profiled(
+    data_dir: &Path,
+    max_depth: usize,
+    options: ReadOptions,
+    profile: &mut ReadProfile,
+) -> anyhow::Result<(Option<Value>, Vec<SkippedArchive>)> {
+    let mut skipped = Vec::new();
+    let value = read_merged_value_inner(data_dir, max_depth, options, &mut skipped, profile)?;
+
+    Ok((value, skipped))
+}
+
+fn read_merged_value_inner(
+    data_dir: &Path,
+    max_depth: usize,
+    options: ReadOptions,
+    skipped: &mut Vec<SkippedArchive>,
+    profile: &mut ReadProfile,
+) -> anyhow::Result<Option<Value>> {
+    let ttl_rules = TtlRules::read(data_dir)?;
+    if !ttl_rules.is_empty() {
+        return read_unexpired_value(data_dir, max_depth, &ttl_rules, options, skipped, profile);
+    }
+
+    let manifest = Manifest::read(data_dir)?;
+    let merge_settings = options
+        .merge_settings
+        .unwrap_or_else(|| manifest.merge_settings());
+    let path_settings = PathMergeSettings {
+        settings: merge_settings,
+        array_behaviors: options.array_behaviors,
+        custom_merges: options.custom_merges,
+    };
+
+    let mut archive_files = list_archive_files(data_dir)?;
+    options.retain_source_archives(data_dir, &mut archive_files, |path| path)?;
+    let mut scratch_buffer = Vec::<u8>::new();
+    let mut archive_values = read_archive_values(
+        &archive_files,
+        &mut scratch_buffer,
+        max_depth,
+        options.verify_checksums,
+    );
+
+    let mut archived_value = None;
+    let mut repeated = RepeatedArchives::default();
+    loop {
+        let started = Instant::now();
+        let Some((path, result)) = archive_values.next() else {
+            break;
+        };
+        profile.record_decode(path, started);
+
+        let Some((value, body_hash)) = skip_corrupt_archive(path, result, options, skipped)
+            .context("collecting and merging all archived values")?
+        else {
+            continue;
+        };
+
+        if !repeated.is_repeat(path, body_hash) {
+            let _span = merge_archive_span(path).entered();
+            let started = Instant::now();
+            archived_value = path_settings.merge_optional(archived_value, Some(value));
+            profile.record_merge(started);
+        }
+    }
+
+    let staging_files = options.staging_file_paths(data_dir)?;
+    let started = Instant::now();
+    let staging_value =
+        StagingFileReader::read_merged_files(&staging_files, path_settings, max_depth)
+            .context("reading merged value from staging file")?;
+    profile.record_staging(&staging_files, started);
+
+    Ok(path_settings
+        .merge_optional(archived_value, staging_value)
+        .and_then(|value| merge_settings.resolve(value))
+        .map(|mut value| {
+            manifest.array_rules.apply(&mut value);
+            value
+        }))
+}
+
+/// Like [`read_merged_value`], but also track when the fields matched by the
+/// TTL rules were last updated, so that the expired ones can be removed.
+fn read_unexpired_value(
+    data_dir: &Path,
+    max_depth: usize,
+    ttl_rules: &TtlRules,
+    options: ReadOptions,
+    skipped: &mut Vec<SkippedArchive>,
+    profile: &mut ReadProfile,
+) -> anyhow::Result<Option<Value>> {
+    let manifest = Manifest::read(data_dir)?;
+    let merge_settings = options
+        .merge_settings
+        .unwrap_or_else(|| manifest.merge_settings());
+    let path_settings = PathMergeSettings {
+        settings: merge_settings,
+        array_behaviors: options.array_behaviors,
+        custom_merges: options.custom_merges,
+    };
+    let mut last_updated = ttl_rules.tracker();
+
+    let mut archives = list_archive_files_with_timestamps(data_dir)?;
+    options.retain_source_archives(data_dir, &mut archives, |(_, path)| path)?;
+    let (timestamps, archive_files): (Vec<_>, Vec<_>) = archives.into_iter().unzip();
+    let mut archived_value = None;
+    let mut repeated = RepeatedArchives::default();
+    let mut scratch_buffer = Vec::<u8>::new();
+    let mut archive_values = read_archive_values(
+        &archive_files,
+        &mut scratch_buffer,
+        max_depth,
+        options.verify_checksums,
+    );
+    for timestamp in timestamps {
+        let started = Instant::now();
+        let Some((path, result)) = archive_values.next() else {
+            break;
+        };
+        profile.record_decode(path, started);
+
+        let Some((value, body_hash)) = skip_corrupt_archive(path, result, options, skipped)? else {
+            continue;
+        };
+
+        // A repeated archive still counts as an update of its fields
+        last_updated.record(timestamp, &value);
+        if !repeated.is_repeat(path, body_hash) {
+            let _span = merge_archive_span(path).entered();
+            let started = Instant::now();
+            archived_value = path_settings.merge_optional(archived_value, Some(value));
+            profile.record_merge(started);
+        }
+    }
+
+    let mut staging_value = None;
+    for staging_file in options.staging_file_paths(data_dir)? {
+        let started = Instant::now();
+        let value = StagingFileReader::read_merged_file(&staging_file, path_settings, max_depth)
+            .context("reading merged value from staging file")?;
+        profile.record_staging(std::slice::from_ref(&staging_file), started);
+        let Some(value) = value else {
+            continue;
+        };
+
+        // The staging file may have been archived since it was read, which
+        // means it was modified just now
+        let timestamp = modified_timestamp(&staging_file).unwrap_or_else(|_| Timestamp::now());
+        last_updated.record(timestamp, &value);
+        staging_value = path_settings.merge_optional(staging_value, Some(value));
+    }
+
+    let mut value = path_settings.merge_optional(archived_value, staging_value);
+    if let Some(value) = &mut value {
+        last_updated.expire(value, Timestamp::now());
+    }
+
+    Ok(value
+        .and_then(|value| merge_settings.resolve(value))
+        .map(|mut value| {
+            manifest.array_rules.apply(&mut value);
+            value
+        }))
+}
+
+/// Return the span of merging the value of an archive into the values of the
+/// archives before it. Closing the span logs the time spent merging.
+fn merge_archive_span(path: &Path) -> tracing::Span {
+    tracing::debug_span!("merge_archive", archive = %archive_name(path))
+}
+
+/// Return the value of the archive file and the hash of its body, or if the
+/// options skip corrupt archives and the archive could not be read, record it
+/// in `skipped` and return `Ok(None)`.
+fn skip_corrupt_archive(
+    path: &Path,
+    result: anyhow::Result<(Value, blake3::Hash)>,
+    options: ReadOptions,
+    skipped: &mut Vec<SkippedArchive>,
+) -> anyhow::Result<Option<(Value, blake3::Hash)>> {
+    match result {
+        Ok(read) => Ok(Some(read)),
+        Err(error) if options.skip_corrupt => {
+            tracing::warn!(archive_file = %path.display(), "Skipping corrupt archive: {error:#}");
+            skipped.push(SkippedArchive {
+                path: path.to_path_buf(),
+                error,
+            });
+            Ok(None)
+        }
+        Err(error) => {
+            Err(error.context(format!("reading archive value from '{}'", path.display())))
+        }
+    }
+}
+
+/// Read and merge all the archived values in the data directory, in order,
+/// with the merge settings from the manifest, skipping repeated archives like
+/// [`read_merged_value`].
+///
+/// Returns `Ok(None)` if there are no archive files.
+pub fn collect_archived_values(
+    scratch_buffer: &mut Vec<u8>,
+    data_dir: &Path,
+    max_depth: usize,
+) -> anyhow::Result<Option<Value>> {
+    let archive_files = list_archive_files(data_dir)?;
+    let mut archive_values = read_archive_values(&archive_files, scratch_buffer, max_depth, true);
+
+    let Some((first_path, first_result)) = archive_values.next() else {
+        // The directory was empty or did not exist
+        return Ok(None);
+    };
+
+    let mut repeated = RepeatedArchives::default();
+    let (mut accum, body_hash) = first_result.context("reading first archive value")?;
+    repeated.is_repeat(first_path, body_hash);
+
+    let merge_settings = Manifest::read(data_dir)?.merge_settings();
+
+    for (path, result) in archive_values {
+        let (value, body_hash) = result.context("reading archive value")?;
+
+        if !repeated.is_repeat(path, body_hash) {
+            accum = merge_settings.merge(accum, value);
+        }
+    }
+
+    Ok(Some(accum))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+    use crate::value::merge::KeyCase;
+
+    #[test]
+    fn bounded_line_within_limit() {
+        let mut reader = io::Cursor::new(b"{\"a\":1}\n{\"b\":2}".to_vec());
+        let mut buf = Vec::new();
+
+        assert_eq!(
+            read_line_bounded(&mut reader, &mut buf, Some(7)).unwrap(),
+            LineRead::Complete(8)
+        );
+        assert_eq!(buf, b"{\"a\":1}\n");
+
+        buf.clear();
+        assert_eq!(
+            read_line_bounded(&mut reader, &mut buf, Some(7)).unwrap(),
+            LineRead::Complete(7)
+        );
+        assert_eq!(buf, b"{\"b\":2}");
+
+        buf.clear();
+        assert_eq!(
+            read_line_bounded(&mut reader, &mut buf, Some(7)).unwrap(),
+            LineRead::Eof
+        );
+    }
+
+    #[test]
+    fn bounded_line_too_long_is_discarded() {
+        // Use a tiny buffer so the long line spans multiple `fill_buf` calls
+        let mut reader =
+            io::BufReader::with_capacity(4, io::Cursor::new(b"[1,2,3,4,5,6,7,8]\n[9]\n".to_vec()));
+        let mut buf = Vec::new();
+
+        assert_eq!(
+            read_line_bounded(&mut reader, &mut buf, Some(8)).unwrap(),
+            LineRead::TooLong(18)
+        );
+        assert!(buf.is_empty());
+
+        assert_eq!(
+            read_line_bounded(&mut reader, &mut buf, Some(8)).unwrap(),
+            LineRead::Complete(4)
+        );
+        assert_eq!(buf, b"[9]\n");
+    }
+
+    #[test]
+    fn record_id_from_field() {
+        let value = Value::from(serde_json::json!({"request_id": "abc", "n": 1}));
+        assert_eq!(record_id(&value, "request_id"), Some("\"abc\"".into()));
+        assert_eq!(record_id(&value, "missing"), None);
+
+        let value = Value::from(serde_json::json!({"request_id": {"host": "a", "seq": 2}}));
+        assert_eq!(
+            record_id(&value, "request_id"),
+            Some(r#"{"host":"a","seq":2}"#.into())
+        );
+        // The keys are sorted, at every level
+        let reordered = Value::from(serde_json::json!({"request_id": {"seq": 2, "host": "a"}}));
+        assert_eq!(
+            record_id(&reordered, "request_id"),
+            record_id(&value, "request_id")
+        );
+        let value =
+            Value::from(serde_json::json!({"request_id": [{"b": 1, "a": {"d": 2, "c": 3}}]}));
+        assert_eq!(
+            record_id(&value, "request_id"),
+            Some(r#"[{"a":{"c":3,"d":2},"b":1}]"#.into())
+        );
+
+        let value = Value::from(serde_json::json!(["request_id"]));
+        assert_eq!(record_id(&value, "request_id"), None);
+    }
+
+    #[test]
+    fn limit_element_counts() {
+        let value = Value::from(serde_json::json!({"a": {"b": [1, 2, 3]}, "c": [[1, 2]]}));
+        check_element_counts(&value, Some(3), Some(2)).unwrap();
+        check_element_counts(&value, None, None).unwrap();
+
+        let err = check_element_counts(&value, Some(2), None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "array at 'a.b' has 3 elements, more than the maximum of 2"
+        );
+        let err = check_element_counts(&value, None, Some(1)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "object at the top level has 2 keys, more than the maximum of 1"
+        );
+        let err = check_element_counts(&value, Some(1), Some(2)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "array at 'a.b' has 3 elements, more than the maximum of 1"
+        );
+        let value = Value::from(serde_json::json!({"c": [[1, 2]]}));
+        let err = check_element_counts(&value, Some(1), None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "array at 'c[0]' has 2 elements, more than the maximum of 1"
+        );
+    }
+
+    #[test]
+    fn reject_reserved_keys() {
+        let value = Value::from(serde_json::json!({"a": [{"b": {"$wall-a:unset": true}}]}));
+        check_reserved_keys(&value, &[TOMBSTONE_KEY]).unwrap();
+        let err = check_reserved_keys(&value, &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "key '$wall-a:unset' at 'a[0].b' is reserved for the markers of wall-a, and cannot be \
+             appended"
+        );
+        let value = Value::from(serde_json::json!({"$wall-a:rollup": 1, "$wall-a": 2}));
+        let err = check_reserved_keys(&value, &[TOMBSTONE_KEY]).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("'$wall-a:rollup' at the top level"),
+            "{err}"
+        );
+
+        let data_dir = crate::test_dir::TempDir::new();
+        let stage = |settings: Settings, record: &str| {
+            let mut state = State::new(data_dir.path().to_path_buf(), settings).unwrap();
+            let outcome = state.stage_record(record.as_bytes()).unwrap();
+            state.flush().unwrap();
+            outcome
+        };
+        let tombstone = r#"{"a": {"$wall-a:unset": true}}"#;
+        let register = r#"{"a": {"$wall-a:lww": [1, "x"]}}"#;
+
+        assert!(matches!(
+            stage(Settings::default(), tombstone),
+            RecordOutcome::Rejected(_)
+        ));
+        let allow_tombstones = Settings {
+            allow_tombstones: true,
+            ..Settings::default()
+        };
+        assert!(matches!(
+            stage(allow_tombstones.clone(), tombstone),
+            RecordOutcome::Appended
+        ));
+        assert!(matches!(
+            stage(allow_tombstones, register),
+            RecordOutcome::Rejected(_)
+        ));
+        // Keys which only become reserved once folded are rejected too
+        let fold_keys = Settings {
+            merge_config: Some(MergeConfig::from(MergeSettings {
+                key_case: KeyCase::Insensitive,
+                ..MergeSettings::default()
+            })),
+            ..Settings::default()
+        };
+        assert!(matches!(
+            stage(fold_keys, r#"{"a": {"$WALL-A:UNSET": true}}"#),
+            RecordOutcome::Rejected(_)
+        ));
+
+        // The writers of CRDT records write registers, sets, and tombstones
+        let crdt = Settings {
+            merge_config: Some(MergeConfig::from(MergeSettings {
+                mode: MergeMode::Crdt,
+                ..MergeSettings::default()
+            })),
+            ..Settings::default()
+        };
+        assert!(matches!(
+            stage(crdt.clone(), register),
+            RecordOutcome::Appended
+        ));
+        assert!(matches!(
+            stage(crdt, r#"{"a": {"$wall-a:truncated": {}}}"#),
+            RecordOutcome::Rejected(_)
+        ));
+    }
+
+    #[test]
+    fn wrap_records() {
+        let value = Value::from(serde_json::json!({"host": "edge-1", "cpu": 0.5}));
+
+        assert_eq!(
+            Wrap::Key("metrics".into()).apply(value.clone()).unwrap(),
+            Value::from(serde_json::json!({"metrics": {"host": "edge-1", "cpu": 0.5}}))
+        );
+        assert_eq!(
+            Wrap::KeyFromField("host".into())
+                .apply(value.clone())
+                .unwrap(),
+            Value::from(serde_json::json!({"edge-1": {"host": "edge-1", "cpu": 0.5}}))
+        );
+
+        let err = Wrap::KeyFromField("region".into())
+            .apply(value)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "record is missing field 'region' used as the wrapping key"
+        );
+    }
+
+    #[test]
+    fn unflatten_records() {
+        let value = Value::from(serde_json::json!({
+            "a.b.c": 1,
+            "a.b.d": 2,
+            "plain": true,
+            r"dotted\.key.x": null,
+            r"back\\slash": "s",
+        }));
+        assert_eq!(
+            unflatten(value).unwrap(),
+            Value::from(serde_json::json!({
+                "a": {"b": {"c": 1, "d": 2}},
+                "plain": true,
+                "dotted.key": {"x": null},
+                r"back\slash": "s",
+            }))
+        );
+
+        let value = Value::from(serde_json::json!([{"a.b": 1}]));
+        assert_eq!(unflatten(value.clone()).unwrap(), value);
+
+        let err = unflatten(Value::from(serde_json::json!({"a": 1, "a.b": 2}))).unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "unflattening key 'a.b': 'a' is given more than once"
+        );
+        assert!(unflatten(Value::from(serde_json::json!({"a..b": 1}))).is_err());
+        assert!(unflatten(Value::from(serde_json::json!({r"a\": 1}))).is_err());
+    }
+
+    #[test]
+    fn bounded_line_no_limit() {
+        let mut reader = io::Cursor::new(vec![b'a'; 10_000]);
+        let mut buf = Vec::new();
+
+        assert_eq!(
+            read_line_bounded(&mut reader, &mut buf, None).unwrap(),
+            LineRead::Complete(10_000)
+        );
+        assert_eq!(buf.len(), 10_000);
+    }
+
+    #[test]
+    fn only_consecutive_archives_repeat() {
+        let path = Path::new("archived/000000.bin");
+        let (first, second) = (blake3::hash(b"first"), blake3::hash(b"second"));
+        let mut repeated = RepeatedArchives::default();
+
+        assert!(!repeated.is_repeat(path, first));
+        assert!(repeated.is_repeat(path, first));
+        assert!(!repeated.is_repeat(path, second));
+        assert!(!repeated.is_repeat(path, first));
+    }
+}
@@ -0,0 +1,434 @@
+//! This module contains the compaction of the oldest archives in the data
+//! directory into a single archive, which bounds the number of archives that
+//! every read merges without rewriting the recent ones.
+//!
+//! Archives are merged in order, so merging the oldest ones ahead of time
+//! does not change the merged value, and the compacted archive takes their
+//! place in the order, see [`write_compacted_archive_values`].
+//!
+//! With a target size the compacted value is split into several archives by
+//! its top-level keys, which share the place of a single compacted archive
+//! in the order. Since each key is in only one of them, the order they are
+//! merged in only decides the order of the keys.
+
+use std::{
+    fs, mem,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use jiff::Timestamp;
+
+use super::{recovery::recover_temp_files, ttl::TtlRules, RepeatedArchives};
+use crate::{
+    archive::{
+        archive_name, list_archive_files_with_timestamps, read_archive_metadata,
+        read_archive_values, write_compacted_archive_values,
+    },
+    format::ObjectArchiveLen,
+    manifest::Manifest,
+    sources::ArchiveSources,
+    value::{
+        merge::{MergeConfig, MergeMode},
+        Value,
+    },
+};
+
+/// Which of the oldest archives to compact
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveSelection {
+    /// All of the archives
+    All,
+    /// This many of the oldest archives
+    Oldest(usize),
+    /// The oldest archives created before this time, up to the first one
+    /// which was not
+    Before(Timestamp),
+}
+
+impl ArchiveSelection {
+    /// Return how many of the archives, with the times they were created in
+    /// the order they are merged, are selected.
+    fn count(self, created: &[Timestamp]) -> usize {
+        match self {
+            Self::All => created.len(),
+            Self::Oldest(count) => count.min(created.len()),
+            Self::Before(before) => created
+                .iter()
+                .take_while(|created| **created < before)
+                .count(),
+        }
+    }
+}
+
+/// What a compaction did
+#[derive(Debug, Default)]
+pub struct CompactionOutcome {
+    /// The archives which replaced the compacted archives, in the order they
+    /// are read, which is empty if fewer than two archives were selected
+    pub archives: Vec<PathBuf>,
+    /// The archives which were replaced
+    pub compacted: Vec<PathBuf>,
+    /// The total size in bytes of the replaced archives
+    pub compacted_bytes: u64,
+    /// The total size in bytes of the archives which replaced them
+    pub archive_bytes: u64,
+}
+
+/// Merge the selected oldest archives in the data directory into a single
+/// archive which replaces them, or with a `target_size` in bytes into as many
+/// archives of at most that size as it takes, see [`split_by_key`].
+///
+/// The archives of an earlier compaction which was split are only compacted
+/// together, so the selection is shortened to leave out any of them which
+/// are not all selected.
+///
+/// Archives holding records from different sources are never compacted
+/// together, and neither are any archives in a data directory with TTL
+/// rules, since the compacted archive would lose when each field was last
+/// updated.
+///
+/// The archives are merged with the given merge settings, or with the ones
+/// in the manifest.
+pub fn compact_archives(
+    data_dir: &Path,
+    selection: ArchiveSelection,
+    merge_config: Option<&MergeConfig>,
+    target_size: Option<u64>,
+    max_depth: usize,
+    direct_io: bool,
+) -> anyhow::Result<CompactionOutcome> {
+    recover_temp_files(data_dir).context("recovering leftover temporary files")?;
+
+    if !TtlRules::read(data_dir)?.is_empty() {
+        anyhow::bail!(
+            "the data directory has TTL rules, which need to know when each archive was written, \
+             so its archives cannot be compacted"
+        );
+    }
+
+    let (created, archives): (Vec<_>, Vec<_>) = list_archive_files_with_timestamps(data_dir)?
+        .into_iter()
+        .unzip();
+    let mut count = selection.count(&created);
+    if let Some(next) = archives.get(count) {
+        // The archives which a compaction was split into share a sequence
+        // number, and a compacted archive could not take the place of only
+        // some of them
+        let next_sequence = archive_sequence(next)?;
+        while count > 0 && archive_sequence(&archives[count - 1])? == next_sequence {
+            count -= 1;
+        }
+    }
+    let archives = &archives[..count];
+    if archives.len() < 2 {
+        tracing::info!(
+            selected = archives.len(),
+            "Fewer than two archives selected, nothing to compact"
+        );
+        return Ok(CompactionOutcome::default());
+    }
+
+    let sources = ArchiveSources::read(data_dir)?;
+    let first_label = sources.label(&archive_name(&archives[0]));
+    if let Some(path) = archives
+        .iter()
+        .find(|path| sources.label(&archive_name(path)) != first_label)
+    {
+        anyhow::bail!(
+            "the archive '{}' holds records from a different source than '{}', so they cannot be \
+             compacted together",
+            path.display(),
+            archives[0].display()
+        );
+    }
+
+    let mut compacted_bytes = 0;
+    for path in archives {
+        compacted_bytes += fs::metadata(path)
+            .with_context(|| format!("reading metadata of '{}'", path.display()))?
+            .len();
+    }
+
+    let manifest = Manifest::read(data_dir)?;
+    let path_settings = match merge_config {
+        Some(merge_config) => merge_config.path_settings(),
+        None => manifest.merge_settings().into(),
+    };
+    let merge_settings = path_settings.settings;
+    let mut scratch_buffer = Vec::new();
+    let mut merged = None;
+    let mut repeated = RepeatedArchives::default();
+    for (path, result) in read_archive_values(archives, &mut scratch_buffer, max_depth, true) {
+        let (value, body_hash) =
+            result.with_context(|| format!("reading archive value from '{}'", path.display()))?;
+        if !repeated.is_repeat(path, body_hash) {
+            merged = path_settings.merge_optional(merged, Some(value));
+        }
+    }
+    let Some(mut value) = merged else {
+        unreachable!("at least two archives were read");
+    };
+
+    manifest.array_rules.apply(&mut value);
+    // No archive is older than the compacted archive, so like the first
+    // archive it does not need tombstones to hide their fields
+    if merge_settings.mode == MergeMode::Ordered && !value.is_tombstone() {
+        value = value
+            .remove_tombstones()
+            .expect("only a tombstone is removed entirely");
+    }
+
+    let values = match target_size {
+        Some(target_size) => split_by_key(value, target_size),
+        None => vec![value],
+    };
+    let new_archives = write_compacted_archive_values(data_dir, archives, values, direct_io)
+        .context("writing compacted archives")?;
+    let mut archive_bytes = 0;
+    for path in &new_archives {
+        archive_bytes += fs::metadata(path)
+            .context("reading metadata of compacted archive")?
+            .len();
+    }
+
+    Ok(CompactionOutcome {
+        archives: new_archives,
+        compacted: archives.to_vec(),
+        compacted_bytes,
+        archive_bytes,
+    })
+}
+
+fn archive_sequence(path: &Path) -> anyhow::Result<Option<u64>> {
+    Ok(read_archive_metadata(path)
+        .with_context(|| format!("reading metadata of '{}'", path.display()))?
+        .sequence())
+}
+
+/// Split an object into objects holding consecutive runs of its top-level
+/// keys, each of which fits in an archive of at most `target_size` bytes,
+/// except for a key which does not fit in one on its own. Any other value is
+/// returned as it is.
+fn split_by_key(value: Value, target_size: u64) -> Vec<Value> {
+    let Value::Object(fields) = value else {
+        return vec![value];
+    };
+
+    let mut parts = Vec::new();
+    let mut part = Vec::new();
+    let mut part_len = ObjectArchiveLen::default();
+    for field in fields {
+        let field_len = minicbor::len(&field) as u64;
+        part_len = part_len.with_entry(&field.0, field_len);
+        if part_len.get() > target_size && !part.is_empty() {
+            parts.push(Value::Object(mem::take(&mut part)));
+            part_len = ObjectArchiveLen::default().with_entry(&field.0, field_len);
+        }
+        if part_len.get() > target_size {
+            tracing::warn!(
+                key = %field.0,
+                archive_bytes = part_len.get(),
+                "The key does not fit in an archive of the target size on its own"
+            );
+        }
+        part.push(field);
+    }
+    if !part.is_empty() || parts.is_empty() {
+        parts.push(Value::Object(part));
+    }
+
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        archive::{list_archive_files, write_archive_value, ArchiveNaming},
+        format::encode_archive,
+        store::read_merged_value,
+        test_dir::TempDir,
+        value::DEFAULT_MAX_DEPTH,
+    };
+
+    /// Write an archive of each value to the data directory, in order.
+    fn write_archives(data_dir: &Path, values: impl IntoIterator<Item = serde_json::Value>) {
+        for value in values {
+            write_archive_value(data_dir, value.into(), ArchiveNaming::Sequence, false).unwrap();
+        }
+    }
+
+    fn merged_value(data_dir: &Path) -> Value {
+        read_merged_value(data_dir, DEFAULT_MAX_DEPTH)
+            .unwrap()
+            .unwrap()
+    }
+
+    fn compact(
+        data_dir: &Path,
+        selection: ArchiveSelection,
+        target_size: Option<u64>,
+    ) -> anyhow::Result<CompactionOutcome> {
+        compact_archives(
+            data_dir,
+            selection,
+            None,
+            target_size,
+            DEFAULT_MAX_DEPTH,
+            false,
+        )
+    }
+
+    #[test]
+    fn select_oldest_archives() {
+        let created = [
+            "2024-06-19T19:00:00Z",
+            "2024-06-19T20:00:00Z",
+            "2024-06-19T19:30:00Z",
+        ]
+        .map(|created| created.parse::<Timestamp>().unwrap());
+
+        assert_eq!(ArchiveSelection::All.count(&created), 3);
+        assert_eq!(ArchiveSelection::Oldest(2).count(&created), 2);
+        assert_eq!(ArchiveSelection::Oldest(50).count(&created), 3);
+
+        let before = |time: &str| ArchiveSelection::Before(time.parse().unwrap());
+        assert_eq!(before("2024-06-19T18:00:00Z").count(&created), 0);
+        // Only a prefix is selected, even if a later archive is older
+        assert_eq!(before("2024-06-19T19:45:00Z").count(&created), 1);
+        assert_eq!(before("2024-06-19T21:00:00Z").count(&created), 3);
+    }
+
+    #[test]
+    fn split_objects_by_key() {
+        let value = Value::from(serde_json::json!({
+            "a": "x".repeat(100),
+            "b": "x".repeat(100),
+            "c": "x".repeat(300),
+            "d": 1,
+            "e": 2,
+        }));
+        let archive_len = |value: &Value| encode_archive(value, 0, 0).unwrap().len() as u64;
+        let target_size = archive_len(&Value::from(serde_json::json!({
+            "a": "x".repeat(100),
+            "b": "x".repeat(100),
+        })));
+
+        let parts = split_by_key(value.clone(), target_size);
+        let keys = parts
+            .iter()
+            .map(|part| match part {
+                Value::Object(fields) => fields.iter().map(|(key, _)| key.as_str()).collect(),
+                _ => unreachable!("only objects are split"),
+            })
+            .collect::<Vec<Vec<_>>>();
+        // The key which does not fit on its own gets an archive of its own
+        assert_eq!(keys, [vec!["a", "b"], vec!["c"], vec!["d", "e"]]);
+        assert_eq!(archive_len(&parts[0]), target_size);
+        assert!(archive_len(&parts[1]) > target_size);
+        assert!(archive_len(&parts[2]) <= target_size);
+
+        let merged = parts.into_iter().flat_map(|part| match part {
+            Value::Object(fields) => fields,
+            _ => unreachable!("only objects are split"),
+        });
+        assert_eq!(Value::Object(merged.collect()), value);
+
+        assert_eq!(split_by_key(value.clone(), u64::MAX), [value]);
+        assert_eq!(split_by_key(Value::Bool(true), 1), [Value::Bool(true)]);
+        assert_eq!(
+            split_by_key(Value::Object(Vec::new()), 1),
+            [Value::Object(Vec::new())]
+        );
+    }
+
+    #[test]
+    fn compaction_keeps_the_merged_value() {
+        for target_size in [None, Some(200)] {
+            let data_dir = TempDir::new();
+            write_archives(
+                data_dir.path(),
+                [
+                    serde_json::json!({"a": [1], "b": {"x": 1}, "c": "x".repeat(150)}),
+                    serde_json::json!({"a": [2], "b": {"y": 2}, "d": "y".repeat(150)}),
+                    serde_json::json!({"b": {"x": {"$wall-a:unset": true}}, "e": null}),
+                    serde_json::json!({"f": "z".repeat(150)}),
+                ],
+            );
+            let before = merged_value(data_dir.path());
+
+            let outcome = compact(data_dir.path(), ArchiveSelection::All, target_size).unwrap();
+            assert_eq!(outcome.compacted.len(), 4);
+            match target_size {
+                Some(_) => assert!(outcome.archives.len() > 1, "{:?}", outcome.archives),
+                None => assert_eq!(outcome.archives.len(), 1),
+            }
+            assert_eq!(
+                list_archive_files(data_dir.path()).unwrap(),
+                outcome.archives
+            );
+            assert_eq!(merged_value(data_dir.path()), before);
+        }
+    }
+
+    #[test]
+    fn compaction_refuses_mixed_sources_and_ttl_rules() {
+        let data_dir = TempDir::new();
+        write_archives(
+            data_dir.path(),
+            [serde_json::json!({"a": 1}), serde_json::json!({"b": 2})],
+        );
+        let archives = list_archive_files(data_dir.path()).unwrap();
+        let mut sources = ArchiveSources::read(data_dir.path()).unwrap();
+        sources.insert(archive_name(&archives[1]), "edge-1".parse().unwrap());
+        sources.write(data_dir.path()).unwrap();
+
+        let err = compact(data_dir.path(), ArchiveSelection::All, None).unwrap_err();
+        assert!(err.to_string().contains("different source"), "{err}");
+        assert_eq!(list_archive_files(data_dir.path()).unwrap(), archives);
+
+        let data_dir = TempDir::new();
+        write_archives(
+            data_dir.path(),
+            [serde_json::json!({"a": 1}), serde_json::json!({"b": 2})],
+        );
+        TtlRules::write(data_dir.path(), &["sessions.*  12h".to_string()]).unwrap();
+
+        let err = compact(data_dir.path(), ArchiveSelection::All, None).unwrap_err();
+        assert!(err.to_string().contains("TTL rules"), "{err}");
+        assert_eq!(list_archive_files(data_dir.path()).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn split_compaction_is_never_partly_selected() {
+        let data_dir = TempDir::new();
+        write_archives(
+            data_dir.path(),
+            [
+                serde_json::json!({"a": "x".repeat(150), "b": "x".repeat(150)}),
+                serde_json::json!({"c": "x".repeat(150), "d": "x".repeat(150)}),
+            ],
+        );
+        let split = compact(data_dir.path(), ArchiveSelection::All, Some(200))
+            .unwrap()
+            .archives;
+        assert!(split.len() > 2, "{split:?}");
+        write_archives(data_dir.path(), [serde_json::json!({"e": 1})]);
+        let before = merged_value(data_dir.path());
+
+        // Taking only some of the split archives leaves all of them out
+        for count in 1..split.len() {
+            let outcome = compact(data_dir.path(), ArchiveSelection::Oldest(count), None).unwrap();
+            assert!(outcome.compacted.is_empty(), "{:?}", outcome.compacted);
+            assert!(outcome.archives.is_empty(), "{:?}", outcome.archives);
+        }
+
+        let outcome =
+            compact(data_dir.path(), ArchiveSelection::Oldest(split.len()), None).unwrap();
+        assert_eq!(outcome.compacted, split);
+        assert_eq!(outcome.archives.len(), 1);
+        assert_eq!(list_archive_files(data_dir.path()).unwrap().len(), 2);
+        assert_eq!(merged_value(data_dir.path()), before);
+    }
+}
@@ -0,0 +1,205 @@
+//! This module contains the normalization of object keys in records as they
+//! are staged, so that producers which spell a key slightly differently do
+//! not add new keys to the merged value forever.
+//!
+//! The steps are configured in the `MANIFEST` and applied in a fixed order:
+//! surrounding whitespace is trimmed (`normalize_keys  trim`), keys are put
+//! in Unicode normalization form C (`normalize_keys  nfc`), and then keys are
+//! renamed (`rename_key  <from>  <to>`). Every object in the record is
+//! normalized, at any depth, and fields whose keys become the same are merged
+//! in the order they appear.
+
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use unicode_normalization::UnicodeNormalization;
+
+use crate::value::{merge::MergeSettings, Value};
+
+/// A step of the key normalization which is turned on by `normalize_keys`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum KeyNormalizationStep {
+    /// Remove whitespace around the key
+    Trim,
+    /// Put the key in Unicode normalization form C
+    Nfc,
+}
+
+impl FromStr for KeyNormalizationStep {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "trim" => Self::Trim,
+            "nfc" => Self::Nfc,
+            x => anyhow::bail!("'{x}' is an unknown option for normalizing keys"),
+        })
+    }
+}
+
+impl fmt::Display for KeyNormalizationStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Trim => "trim",
+            Self::Nfc => "nfc",
+        })
+    }
+}
+
+/// A key which is renamed wherever it appears, written like `hostname  host`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyRename {
+    /// The key after trimming and normalization
+    pub from: String,
+    /// The key it is renamed to
+    pub to: String,
+}
+
+impl FromStr for KeyRename {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((from, to)) = s.split_once("  ") else {
+            anyhow::bail!("'{s}' is not a key followed by two spaces and the key to rename it to");
+        };
+        let (from, to) = (from.trim(), to.trim());
+        if from.is_empty() || to.is_empty() {
+            anyhow::bail!("'{s}' has an empty key");
+        }
+
+        Ok(Self {
+            from: from.to_string(),
+            to: to.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for KeyRename {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}  {}", self.from, self.to)
+    }
+}
+
+/// How the keys of staged records are normalized
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyNormalization {
+    /// The steps turned on, from the `normalize_keys` settings
+    pub steps: Vec<KeyNormalizationStep>,
+    /// The keys to rename, from the `rename_key` settings
+    pub renames: Vec<KeyRename>,
+}
+
+impl KeyNormalization {
+    /// Return true if keys are left as they are.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty() && self.renames.is_empty()
+    }
+
+    /// Turn on a step, if it is not on already.
+    pub fn insert_step(&mut self, step: KeyNormalizationStep) {
+        if !self.steps.contains(&step) {
+            self.steps.push(step);
+        }
+    }
+
+    /// Add a key to rename, replacing any rename of the same key.
+    pub fn insert_rename(&mut self, rename: KeyRename) {
+        match self
+            .renames
+            .iter_mut()
+            .find(|existing| existing.from == rename.from)
+        {
+            Some(existing) => *existing = rename,
+            None => self.renames.push(rename),
+        }
+    }
+
+    /// Normalize every key in the value, merging fields whose keys collide
+    /// with the merge settings.
+    pub fn apply(&self, value: Value, merge_settings: MergeSettings) -> Value {
+        if self.is_empty() {
+            return value;
+        }
+
+        match value {
+            Value::Object(fields) => {
+                let mut normalized: Vec<(String, Value)> = Vec::with_capacity(fields.len());
+                let mut indices = HashMap::<String, usize>::with_capacity(fields.len());
+
+                for (key, value) in fields {
+                    let key = self.normalize(key);
+                    let value = self.apply(value, merge_settings);
+                    match indices.get(&key) {
+                        Some(&index) => {
+                            let accum = std::mem::replace(&mut normalized[index].1, Value::Null);
+                            normalized[index].1 = merge_settings.merge(accum, value);
+                        }
+                        None => {
+                            indices.insert(key.clone(), normalized.len());
+                            normalized.push((key, value));
+                        }
+                    }
+                }
+
+                Value::Object(normalized)
+            }
+            Value::Array(elements) => Value::Array(
+                elements
+                    .into_iter()
+                    .map(|element| self.apply(element, merge_settings))
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+
+    /// Return the normalized form of a single key.
+    fn normalize(&self, mut key: String) -> String {
+        if self.steps.contains(&KeyNormalizationStep::Trim) {
+            let trimmed = key.trim();
+            if trimmed.len() != key.len() {
+                key = trimmed.to_string();
+            }
+        }
+        if self.steps.contains(&KeyNormalizationStep::Nfc) {
+            key = key.nfc().collect();
+        }
+
+        match self.renames.iter().find(|rename| rename.from == key) {
+            Some(rename) => rename.to.clone(),
+            None => key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_keys() {
+        let mut normalization = KeyNormalization::default();
+        normalization.insert_step("trim".parse().unwrap());
+        normalization.insert_step("nfc".parse().unwrap());
+        normalization.insert_step("trim".parse().unwrap());
+        normalization.insert_rename("hostname  host".parse().unwrap());
+        assert_eq!(normalization.steps.len(), 2);
+        assert_eq!(normalization.renames[0].to_string(), "hostname  host");
+
+        let value = Value::from(serde_json::json!({
+            " host ": {"tags": ["a"]},
+            "hostname": {"tags": ["b"]},
+            "cafe\u{301}": [{" x": 1}],
+        }));
+        assert_eq!(
+            normalization.apply(value, MergeSettings::default()),
+            Value::from(serde_json::json!({
+                "host": {"tags": ["a", "b"]},
+                "caf\u{e9}": [{"x": 1}],
+            }))
+        );
+
+        assert!("upper".parse::<KeyNormalizationStep>().is_err());
+        assert!("hostname".parse::<KeyRename>().is_err());
+        assert!("hostname    ".parse::<KeyRename>().is_err());
+    }
+}
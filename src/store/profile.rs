@@ -0,0 +1,82 @@
+//! This module contains the measurements taken while reading the merged
+//! value, which `read --profile` reports to show where a slow or large read
+//! spends its time.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// The bytes read and the time spent reading the merged value, see
+/// [`read_merged_value_profiled`](super::read_merged_value_profiled)
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ReadProfile {
+    /// The archives in the order they were merged
+    pub archives: Vec<ArchiveProfile>,
+    /// The number of bytes in the staging files which were read
+    pub staging_bytes: u64,
+    /// The time spent reading and merging the records of the staging files
+    pub staging_time: Duration,
+}
+
+/// The measurements of a single archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveProfile {
+    /// The path of the archive file
+    pub path: PathBuf,
+    /// The length of the archive file
+    pub bytes: u64,
+    /// The time spent reading the archive file and decoding its value
+    pub decode: Duration,
+    /// The time spent merging the value into the values of the archives
+    /// before it
+    pub merge: Duration,
+}
+
+impl ReadProfile {
+    /// Return the total number of bytes read from the archive and staging
+    /// files.
+    pub fn total_bytes(&self) -> u64 {
+        self.archives
+            .iter()
+            .map(|archive| archive.bytes)
+            .sum::<u64>()
+            + self.staging_bytes
+    }
+
+    /// Record the time spent reading and decoding the archive at the path,
+    /// which started at `started`.
+    pub(super) fn record_decode(&mut self, path: &Path, started: Instant) {
+        let decode = started.elapsed();
+        self.archives.push(ArchiveProfile {
+            path: path.to_path_buf(),
+            bytes: file_len(path),
+            decode,
+            merge: Duration::ZERO,
+        });
+    }
+
+    /// Record the time spent merging the archive recorded last, which
+    /// started at `started`.
+    pub(super) fn record_merge(&mut self, started: Instant) {
+        if let Some(archive) = self.archives.last_mut() {
+            archive.merge = started.elapsed();
+        }
+    }
+
+    /// Record the staging files which were read, and the time spent reading
+    /// them, which started at `started`.
+    pub(super) fn record_staging(&mut self, paths: &[PathBuf], started: Instant) {
+        self.staging_time += started.elapsed();
+        self.staging_bytes += paths.iter().map(|path| file_len(path)).sum::<u64>();
+    }
+}
+
+/// Return the length of the file, or 0 if it was removed since it was read,
+/// like a staging file which was archived.
+fn file_len(path: &Path) -> u64 {
+    fs::metadata(path)
+        .map(|metadata| metadata.len())
+        .unwrap_or(0)
+}
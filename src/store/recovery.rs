@@ -0,0 +1,218 @@
+//! This module contains the recovery of temporary files left behind in the
+//! data directory by a run which crashed.
+//!
+//! Every file in the data directory is written to a temporary file ending in
+//! `.tmp` first, then moved over the real file. A leftover temporary archive
+//! which passes its checksums was complete and is moved into place, while any
+//! other leftover temporary file is removed, since the file it would have
+//! replaced is still intact.
+//!
+//! A staging file is renamed aside before it is archived, and only deleted
+//! once its archive is written, so a leftover rotated staging file is read
+//! along with the staging file until the next `append` archives it.
+//!
+//! A compaction which crashed after its compacted archives were all in place
+//! is finished, and one which crashed before is abandoned, see
+//! [`crate::archive::CompactionJournal`].
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+
+use crate::{
+    archive::{
+        archive_dir, compaction_journal_path, finalize_archive, verify_archive_checksum,
+        CompactionJournal,
+    },
+    checksums::record_archive,
+    staging::list_rotated_staging_files,
+};
+
+/// Temporary files modified more recently than this may belong to a command
+/// which is still running, and are left alone
+const MIN_ORPHAN_AGE: Duration = Duration::from_secs(60);
+
+/// Finalize or remove the temporary files left behind by crashed runs in the
+/// data directory.
+pub fn recover_temp_files(data_dir: &Path) -> anyhow::Result<()> {
+    recover_compaction(data_dir)?;
+
+    for temp_path in orphaned_temp_files(data_dir)? {
+        tracing::info!(temp_file = %temp_path.display(), "Removing leftover temporary file");
+        remove_temp_file(&temp_path)?;
+    }
+
+    let mut scratch_buffer = Vec::new();
+    for temp_path in orphaned_temp_files(&archive_dir(data_dir))? {
+        let Some(archive_path) = final_path(&temp_path) else {
+            continue;
+        };
+
+        scratch_buffer.clear();
+        match verify_archive_checksum(&temp_path, &mut scratch_buffer) {
+            Ok(_) if archive_path.exists() => {
+                tracing::info!(
+                    temp_file = %temp_path.display(),
+                    "Removing leftover temporary archive, which was already finalized"
+                );
+                remove_temp_file(&temp_path)?;
+            }
+            Ok(entry) => {
+                tracing::warn!(
+                    temp_file = %temp_path.display(),
+                    "Finalizing leftover temporary archive, which is complete"
+                );
+                record_archive(data_dir, &archive_path, entry)
+                    .context("recording archive file in CHECKSUMS")?;
+                finalize_archive(&temp_path, &archive_path)?;
+            }
+            Err(err) => {
+                tracing::warn!(
+                    temp_file = %temp_path.display(),
+                    "Removing leftover temporary archive, which is incomplete: {err:#}"
+                );
+                remove_temp_file(&temp_path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Finish or abandon the compaction left behind by a crashed run in the data
+/// directory, if there is one.
+fn recover_compaction(data_dir: &Path) -> anyhow::Result<()> {
+    let Some(journal) = CompactionJournal::read(data_dir)? else {
+        return Ok(());
+    };
+
+    if journal.is_committed(data_dir) {
+        tracing::warn!(
+            archive_files = ?journal.compacted_names().collect::<Vec<_>>(),
+            "Finishing interrupted compaction, whose compacted archives are in place"
+        );
+        return journal.finish(data_dir);
+    }
+
+    let modified = match fs::metadata(compaction_journal_path(data_dir))
+        .and_then(|metadata| metadata.modified())
+    {
+        Ok(modified) => modified,
+        // The compaction may have finished meanwhile
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context("reading modification time of COMPACTION file"),
+    };
+    if is_orphaned(modified, SystemTime::now()) {
+        tracing::warn!(
+            archive_files = ?journal.compacted_names().collect::<Vec<_>>(),
+            "Abandoning interrupted compaction, whose compacted archives are incomplete"
+        );
+        journal.abandon(data_dir)?;
+    }
+
+    Ok(())
+}
+
+/// Return the rotated staging files left behind by crashed runs in the data
+/// directory, oldest first, up to the first one which is recent enough that a
+/// running command may still be archiving it.
+pub fn orphaned_rotated_staging_files(data_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let now = SystemTime::now();
+    let mut orphaned = Vec::new();
+    for path in list_rotated_staging_files(data_dir)? {
+        let modified = match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            // Another command may have archived it first
+            Err(err) if err.kind() == ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err).context("reading modification time of rotated staging file")
+            }
+        };
+        // The later ones are archived after this one, to keep the order
+        if !is_orphaned(modified, now) {
+            break;
+        }
+        orphaned.push(path);
+    }
+
+    Ok(orphaned)
+}
+
+/// Return the temporary files in the directory which are old enough that no
+/// running command is still writing them.
+fn orphaned_temp_files(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => {
+            return Err(err).with_context(|| format!("reading entries of '{}'", dir.display()));
+        }
+    };
+
+    let now = SystemTime::now();
+    let mut temp_files = Vec::new();
+    for entry in entries {
+        let entry = entry.context("reading directory entry")?;
+        let path = entry.path();
+        if !path.extension().is_some_and(|extension| extension == "tmp") {
+            continue;
+        }
+
+        let modified = entry
+            .metadata()
+            .and_then(|metadata| metadata.modified())
+            .context("reading modification time of temporary file")?;
+        if is_orphaned(modified, now) {
+            temp_files.push(path);
+        }
+    }
+
+    Ok(temp_files)
+}
+
+fn is_orphaned(modified: SystemTime, now: SystemTime) -> bool {
+    now.duration_since(modified)
+        .is_ok_and(|age| age >= MIN_ORPHAN_AGE)
+}
+
+/// Return the path of the file a temporary file would have replaced.
+fn final_path(temp_path: &Path) -> Option<PathBuf> {
+    let name = temp_path.file_name()?.to_str()?.strip_suffix(".tmp")?;
+    (!name.is_empty()).then(|| temp_path.with_file_name(name))
+}
+
+fn remove_temp_file(temp_path: &Path) -> anyhow::Result<()> {
+    match fs::remove_file(temp_path) {
+        Ok(()) => Ok(()),
+        // Another command may have recovered it first
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => {
+            Err(err).with_context(|| format!("removing temporary file '{}'", temp_path.display()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_file_paths() {
+        assert_eq!(
+            final_path(Path::new("data/archived/2024-06-19-19-22-45.bin.tmp")),
+            Some(PathBuf::from("data/archived/2024-06-19-19-22-45.bin"))
+        );
+        assert_eq!(final_path(Path::new("data/archived/.tmp")), None);
+        assert_eq!(final_path(Path::new("data/CHECKSUMS")), None);
+
+        let now = SystemTime::now();
+        assert!(is_orphaned(now - Duration::from_secs(120), now));
+        assert!(!is_orphaned(now - Duration::from_secs(5), now));
+        assert!(!is_orphaned(now + Duration::from_secs(5), now));
+    }
+}
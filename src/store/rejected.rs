@@ -0,0 +1,168 @@
+//! This module contains the report of input records which were rejected and
+//! skipped by `append --on-error skip`.
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde_json::json;
+
+use crate::{archive::archive_timestamp, manifest::Manifest};
+
+/// Return the path of the directory containing the rejected record reports.
+pub fn rejected_dir(data_dir: &Path) -> PathBuf {
+    data_dir.join("rejected")
+}
+
+/// Where an input record came from, used when reporting it as rejected
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RecordLocation {
+    /// A line of the input, with the byte offset of the start of the line
+    Line { number: u64, offset: u64 },
+    /// A message from a partition of a Kafka topic
+    #[cfg(feature = "kafka")]
+    Kafka { partition: i32, offset: i64 },
+    /// A syslog message, numbered in the order it was received
+    #[cfg(feature = "syslog")]
+    Syslog { number: u64 },
+}
+
+impl fmt::Display for RecordLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordLocation::Line { number, offset } => {
+                write!(f, "input line {number} starting at byte {offset}")
+            }
+            #[cfg(feature = "kafka")]
+            RecordLocation::Kafka { partition, offset } => {
+                write!(f, "Kafka message at partition {partition} offset {offset}")
+            }
+            #[cfg(feature = "syslog")]
+            RecordLocation::Syslog { number } => write!(f, "syslog message {number}"),
+        }
+    }
+}
+
+/// A JSON lines file with one entry for each rejected record
+#[derive(Debug)]
+pub struct RejectedReport {
+    path: PathBuf,
+    writer: BufWriter<File>,
+}
+
+impl RejectedReport {
+    /// Open the report file named for the current time in the rejected
+    /// directory, appending to it if it already exists.
+    pub fn create(data_dir: &Path) -> anyhow::Result<Self> {
+        let dir = rejected_dir(data_dir);
+        fs::create_dir_all(&dir).context("creating rejected records directory")?;
+
+        let time_zone = Manifest::read(data_dir)?.time_zone()?;
+        let path = dir.join(format!("{}.jsonl", archive_timestamp(time_zone.as_ref())?));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("opening rejected records report '{}'", path.display()))?;
+
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Return the path of the report file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Write an entry for a rejected record. The record is `None` if it was
+    /// too long to keep.
+    pub fn record(
+        &mut self,
+        location: RecordLocation,
+        record: Option<&[u8]>,
+        err: &anyhow::Error,
+    ) -> anyhow::Result<()> {
+        serde_json::to_writer(&mut self.writer, &report_entry(location, record, err))
+            .context("writing rejected records report")?;
+        writeln!(self.writer).context("writing rejected records report")?;
+
+        Ok(())
+    }
+
+    /// Flush the buffered entries to the report file.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.writer
+            .flush()
+            .context("flushing rejected records report")
+    }
+}
+
+/// Return the report entry for a rejected record, with the fields of its
+/// location, the raw record as a string, and the error.
+fn report_entry(
+    location: RecordLocation,
+    record: Option<&[u8]>,
+    err: &anyhow::Error,
+) -> serde_json::Value {
+    let mut entry = match location {
+        RecordLocation::Line { number, offset } => json!({ "line": number, "offset": offset }),
+        #[cfg(feature = "kafka")]
+        RecordLocation::Kafka { partition, offset } => {
+            json!({ "partition": partition, "offset": offset })
+        }
+        #[cfg(feature = "syslog")]
+        RecordLocation::Syslog { number } => json!({ "message": number }),
+    };
+
+    let record = record.map(|record| {
+        String::from_utf8_lossy(record.strip_suffix(b"\n").unwrap_or(record)).into_owned()
+    });
+    entry["record"] = record.into();
+    entry["error"] = format!("{err:#}").into();
+
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_entries() {
+        let err = anyhow::anyhow!("expected value").context("converting line to JSON value");
+
+        assert_eq!(
+            report_entry(
+                RecordLocation::Line {
+                    number: 3,
+                    offset: 41
+                },
+                Some(b"{oops\n"),
+                &err
+            ),
+            json!({
+                "line": 3,
+                "offset": 41,
+                "record": "{oops",
+                "error": "converting line to JSON value: expected value",
+            })
+        );
+        assert_eq!(
+            report_entry(
+                RecordLocation::Line {
+                    number: 4,
+                    offset: 2000
+                },
+                None,
+                &err
+            )["record"],
+            serde_json::Value::Null
+        );
+    }
+}
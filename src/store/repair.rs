@@ -0,0 +1,115 @@
+//! This module contains the repair of corrupted archives, which replaces each
+//! one with an archive holding the top-level keys that could be salvaged from
+//! its intact blocks, see [`salvage_archive`].
+//!
+//! The replacement takes the place of the corrupted archive the same way a
+//! compacted archive takes the place of the archives it merged, see
+//! [`write_compacted_archive_value`], so that reads stop failing on the
+//! archive and merge the older values of the lost keys instead.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    slice,
+};
+
+use anyhow::Context;
+
+use super::recovery::recover_temp_files;
+use crate::{
+    archive::{list_archive_files, verify_archive_checksum, write_compacted_archive_value},
+    format::{salvage_archive, Salvage},
+    value::Value,
+};
+
+/// What happened to a corrupted archive
+#[derive(Debug)]
+pub enum Repair {
+    /// The intact keys were salvaged
+    Salvaged {
+        /// The corrupted archive
+        archive: PathBuf,
+        /// Why the archive does not match its checksums
+        problem: String,
+        /// The archive which replaced it, unless it was a dry run
+        replacement: Option<PathBuf>,
+        /// The keys which were salvaged, in the order of the object
+        salvaged_keys: Vec<String>,
+        /// The keys which were lost, in the order of the object
+        lost_keys: Vec<String>,
+    },
+    /// Nothing could be salvaged, so the archive was left in place
+    Unsalvageable {
+        /// The corrupted archive
+        archive: PathBuf,
+        /// Why the archive does not match its checksums
+        problem: String,
+        /// Why nothing could be salvaged
+        error: anyhow::Error,
+    },
+}
+
+/// Find the archives in the data directory which do not match their
+/// checksums, and replace each one with the keys salvaged from it, or with
+/// `dry_run` only report what would be salvaged.
+pub fn repair_archives(
+    data_dir: &Path,
+    max_depth: usize,
+    dry_run: bool,
+    direct_io: bool,
+) -> anyhow::Result<Vec<Repair>> {
+    recover_temp_files(data_dir).context("recovering leftover temporary files")?;
+
+    let mut repairs = Vec::new();
+    let mut scratch_buffer = Vec::new();
+    for archive in list_archive_files(data_dir)? {
+        scratch_buffer.clear();
+        let Err(err) = verify_archive_checksum(&archive, &mut scratch_buffer) else {
+            continue;
+        };
+        let problem = format!("{err:#}");
+        tracing::info!(archive = %archive.display(), "Salvaging corrupted archive: {problem}");
+
+        let bytes = fs::read(&archive)
+            .with_context(|| format!("reading archive file '{}'", archive.display()))?;
+        let Salvage { value, lost_keys } = match salvage_archive(&bytes, max_depth) {
+            Ok(salvage) => salvage,
+            Err(error) => {
+                repairs.push(Repair::Unsalvageable {
+                    archive,
+                    problem,
+                    error,
+                });
+                continue;
+            }
+        };
+
+        let salvaged_keys = match &value {
+            Value::Object(fields) => fields.iter().map(|(key, _)| key.clone()).collect(),
+            _ => Vec::new(),
+        };
+        let replacement = if dry_run {
+            None
+        } else {
+            Some(
+                write_compacted_archive_value(
+                    data_dir,
+                    slice::from_ref(&archive),
+                    value,
+                    direct_io,
+                )
+                .with_context(|| format!("replacing archive '{}'", archive.display()))?,
+            )
+        };
+
+        repairs.push(Repair::Salvaged {
+            archive,
+            problem,
+            replacement,
+            salvaged_keys,
+            lost_keys,
+        });
+    }
+
+    Ok(repairs)
+}
@@ -0,0 +1,264 @@
+//! This module contains the time-to-live rules which expire old parts of the
+//! stored value.
+//!
+//! The rules are read from the `TTL` file in the data directory, one
+//! `<pattern>  <duration>` pair per line, like `sessions.*  12h`. A pattern is
+//! a sequence of object keys separated by dots, where `*` matches any key. A
+//! duration is a whole number followed by `s`, `m`, `h`, or `d`. Lines which
+//! are empty or start with `#` are ignored.
+//!
+//! A value matched by a pattern is dropped once no record has updated it, or
+//! anything inside it, for longer than the duration.
+
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::Context;
+use jiff::Timestamp;
+
+use crate::{
+    atomic_file::write_atomically,
+    value::{pattern::KeyPattern, Value},
+};
+
+fn ttl_file_path(data_dir: &Path) -> PathBuf {
+    data_dir.join("TTL")
+}
+
+/// A pattern of object keys and how long the values it matches live after
+/// they were last updated
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TtlRule {
+    pattern: KeyPattern,
+    ttl: Duration,
+}
+
+impl FromStr for TtlRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((pattern, ttl)) = s.split_once("  ") else {
+            anyhow::bail!("expected '<pattern>  <duration>'");
+        };
+
+        Ok(Self {
+            pattern: pattern.parse()?,
+            ttl: parse_duration(ttl.trim())?,
+        })
+    }
+}
+
+/// Parse a duration like `90s`, `15m`, `12h`, or `7d`.
+fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let unit_start = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (amount, unit) = s.split_at(unit_start);
+
+    let amount: u64 = amount
+        .parse()
+        .with_context(|| format!("'{s}' does not start with a whole number"))?;
+    let unit_seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        x => anyhow::bail!("'{x}' is an unknown unit for a duration"),
+    };
+
+    Ok(Duration::from_secs(amount.saturating_mul(unit_seconds)))
+}
+
+/// The time-to-live rules of a data directory
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TtlRules {
+    rules: Vec<TtlRule>,
+}
+
+impl TtlRules {
+    /// Read the rules from the data directory, returning no rules if the
+    /// `TTL` file does not exist.
+    pub fn read(data_dir: &Path) -> anyhow::Result<Self> {
+        let contents = match fs::read_to_string(ttl_file_path(data_dir)) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err).context("reading TTL file"),
+        };
+
+        Self::parse(&contents)
+    }
+
+    fn parse(contents: &str) -> anyhow::Result<Self> {
+        let rules = contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !(line.trim().is_empty() || line.starts_with('#')))
+            .map(|(line_index, line)| {
+                line.parse()
+                    .with_context(|| format!("parsing line {} of TTL file", line_index + 1))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { rules })
+    }
+
+    /// Replace the `TTL` file in the data directory with the given rules,
+    /// each like `sessions.*  12h`, after checking that they parse.
+    pub fn write(data_dir: &Path, rules: &[String]) -> anyhow::Result<()> {
+        let contents = rules
+            .iter()
+            .map(|rule| format!("{rule}\n"))
+            .collect::<String>();
+        Self::parse(&contents)?;
+
+        write_atomically(&ttl_file_path(data_dir), contents.as_bytes()).context("writing TTL file")
+    }
+
+    /// Return true if there are no rules, so nothing ever expires.
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Start tracking when the fields matched by these rules were last
+    /// updated.
+    pub fn tracker(&self) -> LastUpdated<'_> {
+        LastUpdated {
+            rules: self,
+            timestamps: HashMap::new(),
+        }
+    }
+}
+
+/// The time that each field matched by the [`TtlRules`] was last updated by
+/// a record
+#[derive(Debug)]
+pub struct LastUpdated<'r> {
+    rules: &'r TtlRules,
+    timestamps: HashMap<Vec<String>, Timestamp>,
+}
+
+impl LastUpdated<'_> {
+    /// Record that the fields in the value were updated at the given time.
+    ///
+    /// Records must be given in time order.
+    pub fn record(&mut self, timestamp: Timestamp, value: &Value) {
+        for rule in &self.rules.rules {
+            for path in rule.pattern.matches(value) {
+                self.timestamps.insert(path, timestamp);
+            }
+        }
+    }
+
+    /// Remove the fields of the value which have not been updated for longer
+    /// than the rule which matches them allows.
+    pub fn expire(&self, value: &mut Value, now: Timestamp) {
+        for rule in &self.rules.rules {
+            for path in rule.pattern.matches(value) {
+                let expired = self.timestamps.get(&path).is_some_and(|updated| {
+                    now.as_second().saturating_sub(updated.as_second())
+                        > i64::try_from(rule.ttl.as_secs()).unwrap_or(i64::MAX)
+                });
+
+                if expired {
+                    remove_field(value, &path);
+                }
+            }
+        }
+    }
+}
+
+/// Remove the field at the end of the path of keys, if it exists.
+fn remove_field(value: &mut Value, path: &[String]) {
+    let Some((last, parents)) = path.split_last() else {
+        return;
+    };
+
+    let parent = parents.iter().try_fold(value, |target, key| match target {
+        Value::Object(fields) => fields
+            .iter_mut()
+            .find(|(field, _)| field == key)
+            .map(|(_, value)| value),
+        _ => None,
+    });
+
+    if let Some(Value::Object(fields)) = parent {
+        fields.retain(|(key, _)| key != last);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(value: serde_json::Value) -> Value {
+        Value::from(value)
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rules = TtlRules::parse("# sessions expire\nsessions.*  12h\n\ncache  90s\n").unwrap();
+        assert_eq!(
+            rules.rules,
+            vec![
+                TtlRule {
+                    pattern: "sessions.*".parse().unwrap(),
+                    ttl: Duration::from_secs(12 * 60 * 60),
+                },
+                TtlRule {
+                    pattern: "cache".parse().unwrap(),
+                    ttl: Duration::from_secs(90),
+                },
+            ]
+        );
+
+        let err = TtlRules::parse("a  1h\na  1y").unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "parsing line 2 of TTL file: 'y' is an unknown unit for a duration"
+        );
+        assert!(TtlRules::parse("a 1h").is_err());
+        assert!(TtlRules::parse("a..b  1h").is_err());
+        assert!(TtlRules::parse("a  h").is_err());
+    }
+
+    #[test]
+    fn expire_old_fields() {
+        let rules = TtlRules::parse("sessions.*  1h").unwrap();
+        let start: Timestamp = "2024-06-19T12:00:00Z".parse().unwrap();
+        let later: Timestamp = "2024-06-19T12:30:00Z".parse().unwrap();
+
+        let mut tracker = rules.tracker();
+        tracker.record(
+            start,
+            &json(serde_json::json!({"sessions": {"a": {"user": 1}, "b": {"user": 2}}, "n": 1})),
+        );
+        tracker.record(
+            later,
+            &json(serde_json::json!({"sessions": {"b": {"seen": true}}})),
+        );
+
+        let mut value = json(serde_json::json!({
+            "sessions": {"a": {"user": 1}, "b": {"user": 2, "seen": true}},
+            "n": 1,
+        }));
+
+        tracker.expire(&mut value, "2024-06-19T12:45:00Z".parse().unwrap());
+        assert_eq!(
+            value,
+            json(serde_json::json!({
+                "sessions": {"a": {"user": 1}, "b": {"user": 2, "seen": true}},
+                "n": 1,
+            }))
+        );
+
+        tracker.expire(&mut value, "2024-06-19T13:15:00Z".parse().unwrap());
+        assert_eq!(
+            value,
+            json(serde_json::json!({"sessions": {"b": {"user": 2, "seen": true}}, "n": 1}))
+        );
+    }
+}
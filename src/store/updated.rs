@@ -0,0 +1,241 @@
+//! This module contains the tree of times when each part of the stored value
+//! was last updated.
+//!
+//! The tree has the same objects as the merged value, and every other value
+//! (including arrays) is replaced by the time it was last updated, as a
+//! string like `2024-06-19T19:22:45Z`. Records in an archive count as updated
+//! when the archive was created, and records in the staging file when the
+//! staging file was last written.
+//!
+//! The provenance tree is built the same way, except that every other value
+//! is replaced by the source of the record which last updated it and the
+//! time, see [`read_provenance`].
+
+use std::path::Path;
+
+use anyhow::Context;
+use jiff::Timestamp;
+
+use crate::{
+    archive::{
+        archive_name, list_archive_files_with_timestamps, modified_timestamp, read_archive_value,
+    },
+    manifest::Manifest,
+    staging::{staging_file_paths, StagingFileReader},
+    value::{
+        crdt,
+        merge::{ArrayBehavior, KeyCase, MergeMode, MergeSettings, NullBehavior, UnionKeep},
+        Value,
+    },
+};
+
+/// Objects are merged key by key like the stored values, and everything else
+/// is replaced by the newer time.
+const TREE_MERGE: MergeSettings = MergeSettings {
+    array_behavior: ArrayBehavior::Replace,
+    null_behavior: NullBehavior::Merge,
+    union_keep: UnionKeep::First,
+    key_case: KeyCase::Sensitive,
+    mode: MergeMode::Ordered,
+};
+
+/// Replace every part of the value except objects and tombstones with the
+/// stamp, which must not be an object.
+///
+/// The registers and sets of [`crdt`] are replaced as a whole, like arrays.
+fn stamp(value: &Value, stamp_value: &Value) -> Value {
+    match value {
+        Value::Object(_) if value.is_tombstone() => Value::tombstone(),
+        _ if crdt::is_marker(value) => stamp_value.clone(),
+        Value::Object(fields) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| (key.clone(), stamp(value, stamp_value)))
+                .collect(),
+        ),
+        _ => stamp_value.clone(),
+    }
+}
+
+/// Read the time that each part of the merged value in the data directory
+/// was last updated.
+///
+/// The tree may contain parts which are not in the merged value, like fields
+/// expired by the TTL rules, see [`restrict`] to remove them. Returns
+/// `Ok(None)` if there is no data.
+pub fn read_last_updated(data_dir: &Path, max_depth: usize) -> anyhow::Result<Option<Value>> {
+    read_stamped(data_dir, max_depth, |_, timestamp| {
+        Value::String(timestamp.to_string())
+    })
+}
+
+/// Read the source and time of the record which last updated each part of
+/// the merged value in the data directory.
+///
+/// Every part of the tree except objects is a `[source, time]` array, where
+/// the source is the name of an archive or `staging` for the staging file.
+/// See [`annotate`] to combine it with the merged value. Returns `Ok(None)` if
+/// there is no data.
+pub fn read_provenance(data_dir: &Path, max_depth: usize) -> anyhow::Result<Option<Value>> {
+    read_stamped(data_dir, max_depth, |archive_path, timestamp| {
+        let source = archive_path.map_or_else(|| "staging".to_string(), archive_name);
+        Value::Array(vec![
+            Value::String(source),
+            Value::String(timestamp.to_string()),
+        ])
+    })
+}
+
+/// Merge the archives and the staging files with each part replaced by the
+/// stamp for its archive, or for a staging file if the path is `None`.
+fn read_stamped(
+    data_dir: &Path,
+    max_depth: usize,
+    stamp_for: impl Fn(Option<&Path>, Timestamp) -> Value,
+) -> anyhow::Result<Option<Value>> {
+    let mut scratch_buffer = Vec::<u8>::new();
+
+    // Archives and the staging file are merged separately, like the values
+    let mut archived_tree = None;
+    for (timestamp, path) in list_archive_files_with_timestamps(data_dir)? {
+        scratch_buffer.clear();
+        let value = read_archive_value(&path, &mut scratch_buffer, max_depth)
+            .context("reading archive value")?;
+
+        let tree = stamp(&value, &stamp_for(Some(&path), timestamp));
+        archived_tree = TREE_MERGE.merge_optional(archived_tree, Some(tree));
+    }
+
+    let merge_settings = Manifest::read(data_dir)?.merge_settings();
+    let mut staging_tree = None;
+    for staging_file in staging_file_paths(data_dir)? {
+        let tree = StagingFileReader::read_merged_file(&staging_file, merge_settings, max_depth)
+            .context("reading merged value from staging file")?
+            .map(|value| {
+                // The staging file may have been archived since it was read,
+                // which means it was modified just now
+                let timestamp =
+                    modified_timestamp(&staging_file).unwrap_or_else(|_| Timestamp::now());
+                stamp(&value, &stamp_for(None, timestamp))
+            });
+        staging_tree = TREE_MERGE.merge_optional(staging_tree, tree);
+    }
+
+    Ok(TREE_MERGE
+        .merge_optional(archived_tree, staging_tree)
+        .and_then(Value::remove_tombstones))
+}
+
+/// Remove the fields of the tree which are not in the value, so that it only
+/// describes the value.
+pub fn restrict(tree: &Value, value: &Value) -> Value {
+    match (tree, value) {
+        (Value::Object(tree_fields), Value::Object(fields)) => Value::Object(
+            fields
+                .iter()
+                .filter_map(|(key, value)| {
+                    let (_, tree) = tree_fields.iter().find(|(tree_key, _)| tree_key == key)?;
+                    Some((key.clone(), restrict(tree, value)))
+                })
+                .collect(),
+        ),
+        (tree, _) => tree.clone(),
+    }
+}
+
+/// Wrap every part of the value except objects like
+/// `{"value": ..., "source": ..., "at": ...}`, with the source and time from
+/// the provenance tree, see [`read_provenance`].
+///
+/// Parts missing from the tree get a `null` source and time.
+pub fn annotate(tree: &Value, value: &Value) -> Value {
+    match (tree, value) {
+        (Value::Object(tree_fields), Value::Object(fields)) => Value::Object(
+            fields
+                .iter()
+                .map(|(key, value)| {
+                    let tree = tree_fields
+                        .iter()
+                        .find(|(tree_key, _)| tree_key == key)
+                        .map_or(&Value::Null, |(_, tree)| tree);
+                    (key.clone(), annotate(tree, value))
+                })
+                .collect(),
+        ),
+        (tree, value) => {
+            let (source, at) = match tree {
+                Value::Array(stamp) if stamp.len() == 2 => (stamp[0].clone(), stamp[1].clone()),
+                _ => (Value::Null, Value::Null),
+            };
+            Value::Object(vec![
+                ("value".into(), value.clone()),
+                ("source".into(), source),
+                ("at".into(), at),
+            ])
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(value: serde_json::Value) -> Value {
+        Value::from(value)
+    }
+
+    #[test]
+    fn newer_records_update_their_fields() {
+        let first = stamp(
+            &json(serde_json::json!({"a": 1, "b": {"c": [1], "d": null}})),
+            &Value::String("t1".into()),
+        );
+        let second = stamp(
+            &Value::Object(vec![
+                ("b".into(), json(serde_json::json!({"c": [2]}))),
+                ("e".into(), Value::tombstone()),
+            ]),
+            &Value::String("t2".into()),
+        );
+
+        let tree = TREE_MERGE.merge(first, second).remove_tombstones();
+        assert_eq!(
+            tree,
+            Some(json(serde_json::json!({
+                "a": "t1",
+                "b": {"c": "t2", "d": "t1"},
+            })))
+        );
+    }
+
+    #[test]
+    fn restrict_to_value() {
+        let tree = json(serde_json::json!({"a": "t1", "b": {"c": "t2", "d": "t1"}}));
+        let value = json(serde_json::json!({"b": {"c": [1, 2]}}));
+
+        assert_eq!(
+            restrict(&tree, &value),
+            json(serde_json::json!({"b": {"c": "t2"}}))
+        );
+    }
+
+    #[test]
+    fn annotate_leaves_with_provenance() {
+        let tree = json(serde_json::json!({
+            "a": ["archive-1.bin", "t1"],
+            "b": {"c": ["staging", "t2"]},
+        }));
+        let value = json(serde_json::json!({"a": [1, 2], "b": {"c": "x", "d": 3}}));
+
+        assert_eq!(
+            annotate(&tree, &value),
+            json(serde_json::json!({
+                "a": {"value": [1, 2], "source": "archive-1.bin", "at": "t1"},
+                "b": {
+                    "c": {"value": "x", "source": "staging", "at": "t2"},
+                    "d": {"value": 3, "source": null, "at": null},
+                },
+            }))
+        );
+    }
+}
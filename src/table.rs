@@ -0,0 +1,260 @@
+//! This module contains the conversion of merged values into tables of
+//! flattened columns, used by the columnar output formats.
+
+use crate::{query::Path, value::Value};
+
+/// The type shared by every present value of a [`Column`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ColumnKind {
+    Bool,
+    /// Numbers which are all integers that fit in 64 bits
+    Int,
+    Float,
+    /// Strings, or a mix of types which are all written as text
+    String,
+}
+
+/// One leaf path of the rows of a [`Table`], with the value at that path in
+/// each row if there is one
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column<'v> {
+    pub path: Path,
+    pub kind: ColumnKind,
+    pub values: Vec<Option<&'v Value>>,
+}
+
+/// A set of rows split into one column for each leaf path found in any row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Table<'v> {
+    pub num_rows: usize,
+    pub columns: Vec<Column<'v>>,
+}
+
+/// Return the elements of the array at the path as rows, or the whole value
+/// as a single row if there is no path.
+pub fn rows_at<'v>(value: &'v Value, path: Option<&Path>) -> anyhow::Result<&'v [Value]> {
+    let Some(path) = path else {
+        return Ok(std::slice::from_ref(value));
+    };
+
+    match path.lookup(value) {
+        Some(Value::Array(elements)) => Ok(elements),
+        Some(other) => anyhow::bail!(
+            "'{path}' is {} and not an array, it cannot be used as rows",
+            other.type_name()
+        ),
+        None => anyhow::bail!("'{path}' is not present in the merged value"),
+    }
+}
+
+impl<'v> Table<'v> {
+    /// Split the rows into columns, in the order their paths are first seen.
+    ///
+    /// `null` values are treated as missing.
+    pub fn from_rows(rows: &'v [Value]) -> Self {
+        let mut columns = indexmap::IndexMap::<String, Column<'v>>::new();
+
+        for (row_index, row) in rows.iter().enumerate() {
+            for (path, leaf) in Path::leaves(row) {
+                if matches!(leaf, Value::Null) {
+                    continue;
+                }
+
+                let column = columns.entry(path.to_string()).or_insert_with(|| Column {
+                    path,
+                    kind: ColumnKind::of(leaf),
+                    values: vec![None; rows.len()],
+                });
+                column.kind = column.kind.widen(ColumnKind::of(leaf));
+                column.values[row_index] = Some(leaf);
+            }
+        }
+
+        Self {
+            num_rows: rows.len(),
+            columns: columns.into_values().collect(),
+        }
+    }
+}
+
+impl ColumnKind {
+    fn of(value: &Value) -> Self {
+        match value {
+            Value::Bool(_) => ColumnKind::Bool,
+            Value::Number(n) if n.parse::<i64>().is_ok() => ColumnKind::Int,
+            Value::Number(_) => ColumnKind::Float,
+            _ => ColumnKind::String,
+        }
+    }
+
+    /// Return the kind which can hold the values of both kinds.
+    fn widen(self, other: Self) -> Self {
+        match (self, other) {
+            (a, b) if a == b => a,
+            (ColumnKind::Int | ColumnKind::Float, ColumnKind::Int | ColumnKind::Float) => {
+                ColumnKind::Float
+            }
+            _ => ColumnKind::String,
+        }
+    }
+}
+
+/// Return the value as text for a string column, where strings are written
+/// without quotes and anything else as compact JSON.
+pub fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(s) | Value::Number(s) => s.clone(),
+        other => serde_json::to_string(other).expect("serializing a value cannot fail"),
+    }
+}
+
+/// Convert the table into an Arrow record batch with a nullable column for
+/// each column of the table.
+#[cfg(feature = "arrow")]
+pub fn to_record_batch(table: &Table) -> anyhow::Result<arrow_array::RecordBatch> {
+    use std::sync::Arc;
+
+    use arrow_array::{ArrayRef, BooleanArray, Float64Array, Int64Array, StringArray};
+    use arrow_schema::{DataType, Field, Schema};
+
+    let mut fields = Vec::with_capacity(table.columns.len());
+    let mut arrays = Vec::<ArrayRef>::with_capacity(table.columns.len());
+
+    for column in &table.columns {
+        let values = column.values.iter();
+        let (data_type, array): (_, ArrayRef) = match column.kind {
+            ColumnKind::Bool => (
+                DataType::Boolean,
+                Arc::new(
+                    values
+                        .map(|value| match value {
+                            Some(Value::Bool(b)) => Some(*b),
+                            _ => None,
+                        })
+                        .collect::<BooleanArray>(),
+                ),
+            ),
+            ColumnKind::Int => (
+                DataType::Int64,
+                Arc::new(
+                    values
+                        .map(|value| match value {
+                            Some(Value::Number(n)) => n.parse().ok(),
+                            _ => None,
+                        })
+                        .collect::<Int64Array>(),
+                ),
+            ),
+            ColumnKind::Float => (
+                DataType::Float64,
+                Arc::new(
+                    values
+                        .map(|value| match value {
+                            Some(Value::Number(n)) => n.parse().ok(),
+                            _ => None,
+                        })
+                        .collect::<Float64Array>(),
+                ),
+            ),
+            ColumnKind::String => (
+                DataType::Utf8,
+                Arc::new(
+                    values
+                        .map(|value| value.map(value_text))
+                        .collect::<StringArray>(),
+                ),
+            ),
+        };
+
+        fields.push(Field::new(column.path.to_string(), data_type, true));
+        arrays.push(array);
+    }
+
+    let schema = Arc::new(Schema::new(fields));
+    let options = arrow_array::RecordBatchOptions::new().with_row_count(Some(table.num_rows));
+
+    Ok(arrow_array::RecordBatch::try_new_with_options(
+        schema, arrays, &options,
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn columns_of_rows() {
+        let rows = match Value::from(serde_json::json!([
+            {"id": 1, "ok": true, "score": 2, "tags": ["a"]},
+            {"id": 2, "ok": null, "score": 2.5, "mixed": "x"},
+            {"id": 3, "mixed": 4, "nested": {"deep": "y"}},
+        ])) {
+            Value::Array(rows) => rows,
+            _ => unreachable!(),
+        };
+
+        let table = Table::from_rows(&rows);
+        assert_eq!(table.num_rows, 3);
+
+        let columns: Vec<_> = table
+            .columns
+            .iter()
+            .map(|column| {
+                let present = column.values.iter().filter(|v| v.is_some()).count();
+                (column.path.to_string(), column.kind, present)
+            })
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                ("id".to_string(), ColumnKind::Int, 3),
+                ("ok".to_string(), ColumnKind::Bool, 1),
+                ("score".to_string(), ColumnKind::Float, 2),
+                ("tags[0]".to_string(), ColumnKind::String, 1),
+                ("mixed".to_string(), ColumnKind::String, 2),
+                ("nested.deep".to_string(), ColumnKind::String, 1),
+            ]
+        );
+    }
+
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn record_batch_of_table() {
+        use arrow_array::{cast::AsArray, types::Int64Type, Array};
+
+        let value = Value::from(serde_json::json!([{"id": 1, "name": "a"}, {"id": 2}]));
+        let rows = rows_at(&value, Some(&"@".parse().unwrap())).unwrap();
+
+        let batch = to_record_batch(&Table::from_rows(rows)).unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        assert_eq!(
+            batch
+                .column(0)
+                .as_primitive::<Int64Type>()
+                .values()
+                .to_vec(),
+            vec![1, 2]
+        );
+        assert_eq!(batch.column(1).as_string::<i32>().value(0), "a");
+        assert!(batch.column(1).is_null(1));
+    }
+
+    #[test]
+    fn rows_at_path() {
+        let value = Value::from(serde_json::json!({"events": [1, 2], "name": "x"}));
+
+        assert_eq!(rows_at(&value, None).unwrap(), std::slice::from_ref(&value));
+        assert_eq!(
+            rows_at(&value, Some(&"events".parse().unwrap()))
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let err = rows_at(&value, Some(&"name".parse().unwrap())).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'name' is string and not an array, it cannot be used as rows"
+        );
+    }
+}
@@ -0,0 +1,130 @@
+//! This module contains the reports of `read --template`, which render a
+//! template written in the Jinja syntax of [`minijinja`] with the merged
+//! value.
+
+use std::{io, path::Path};
+
+use anyhow::Context;
+use minijinja::{path_loader, Environment};
+
+use crate::value::Value;
+
+/// A template file, along with the other templates in its directory which it
+/// may include, import, or extend
+#[derive(Debug)]
+pub struct Template {
+    env: Environment<'static>,
+    name: String,
+}
+
+impl Template {
+    /// Load and parse the template file, so that a syntax error is reported
+    /// before any data is read.
+    ///
+    /// Templates whose names end in `.html` or `.xml` escape the values they
+    /// write.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            anyhow::bail!(
+                "the template path '{}' does not end in a UTF-8 file name",
+                path.display()
+            );
+        };
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+
+        let mut env = Environment::new();
+        env.set_loader(path_loader(dir));
+        // Included templates like a row of a table usually end in a newline
+        // which belongs in the report
+        env.set_keep_trailing_newline(true);
+        env.get_template(name)
+            .with_context(|| format!("loading template '{}'", path.display()))?;
+
+        Ok(Self {
+            env,
+            name: name.to_string(),
+        })
+    }
+
+    /// Render the template with the merged value, which is the variable
+    /// `value`. If the merged value is an object, each of its fields is
+    /// also a variable, unless it is named `value`.
+    pub fn render(&self, writer: impl io::Write, value: &Value) -> anyhow::Result<()> {
+        let template = self
+            .env
+            .get_template(&self.name)
+            .expect("the template was loaded");
+
+        let fields = match value {
+            Value::Object(fields) => fields.as_slice(),
+            _ => &[],
+        };
+        let context = fields
+            .iter()
+            .filter(|(key, _)| key != "value")
+            .map(|(key, field)| (key.as_str(), minijinja::Value::from_serialize(field)))
+            .chain([("value", minijinja::Value::from_serialize(value))])
+            .collect::<minijinja::Value>();
+
+        template
+            .render_captured_to(context, writer)
+            .with_context(|| format!("rendering template '{}'", self.name))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(source: &str, name: &str, value: serde_json::Value) -> anyhow::Result<String> {
+        let mut env = Environment::new();
+        env.add_template_owned(name.to_string(), source.to_string())?;
+        let template = Template {
+            env,
+            name: name.to_string(),
+        };
+
+        let mut output = Vec::new();
+        template.render(&mut output, &Value::from(value))?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn render_reports() {
+        let value = serde_json::json!({
+            "title": "<nightly>",
+            "runs": {"b": {"errors": 2}, "a": {"errors": 0}},
+            "value": "shadowed",
+        });
+        let source = "# {{ title }}\n\
+                      {% for name, run in runs|items %}- {{ name }}: {{ run.errors }}\n{% endfor %}\
+                      {{ value.value }} {{ value.runs.a|tojson }}";
+
+        assert_eq!(
+            render(source, "report.md", value.clone()).unwrap(),
+            "# <nightly>\n- b: 2\n- a: 0\nshadowed {\"errors\":0}"
+        );
+        assert!(render("{{ title }}", "report.html", value.clone())
+            .unwrap()
+            .contains("&lt;nightly&gt;"));
+
+        // A merged value which is not an object is only `value`
+        assert_eq!(
+            render(
+                "{{ value|length }}",
+                "count.txt",
+                serde_json::json!([1, 2, 3])
+            )
+            .unwrap(),
+            "3"
+        );
+
+        let err = render("{{ runs.a.errors + title }}", "bad.txt", value).unwrap_err();
+        assert!(format!("{err:#}").starts_with("rendering template 'bad.txt': "));
+    }
+}
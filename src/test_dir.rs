@@ -0,0 +1,43 @@
+//! This module contains a temporary directory for the tests which need a data
+//! directory on the filesystem.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// A directory under the system temporary directory with a name unique to
+/// this test run, which is removed along with its contents when dropped
+#[derive(Debug)]
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create a new empty temporary directory.
+    pub fn new() -> Self {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let path = env::temp_dir().join(format!(
+            "wall-a-test-{}-{}",
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        // A directory left behind by an earlier run with the same process ID
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).expect("creating temporary directory");
+
+        Self(path)
+    }
+
+    /// Return the path of the temporary directory.
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
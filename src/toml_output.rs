@@ -0,0 +1,271 @@
+//! This module contains the TOML output of `read --format toml`.
+//!
+//! TOML has no null, so fields which are null are left out of their table,
+//! and an array which contains null cannot be written at all. Arrays whose
+//! elements are all objects are written as arrays of tables, and any other
+//! array is written inline, where TOML allows its elements to be of mixed
+//! types. Integers which do not fit in the 64-bit integers of TOML are
+//! written as floats.
+
+use std::{fmt::Write as _, io};
+
+use anyhow::Context;
+
+use crate::{
+    query::{Path, Segment},
+    value::Value,
+};
+
+/// Write the value as a TOML document, whose root table is the value.
+///
+/// Nothing is written if the value cannot be represented.
+pub fn write_toml(mut writer: impl io::Write, value: &Value) -> anyhow::Result<()> {
+    let Value::Object(fields) = value else {
+        anyhow::bail!(
+            "merged value is {} and not an object, it cannot be written as TOML",
+            value.type_name()
+        );
+    };
+
+    let mut document = String::new();
+    write_table(&mut document, &[], fields, &Path::default())?;
+    writer
+        .write_all(document.as_bytes())
+        .context("writing TOML document")
+}
+
+/// Write the fields of the table whose header has the given keys: first the
+/// key/value pairs, then a section for each field which is a table or array
+/// of tables, since TOML puts every pair of a table before its sections.
+fn write_table(
+    out: &mut String,
+    header: &[String],
+    fields: &[(String, Value)],
+    path: &Path,
+) -> anyhow::Result<()> {
+    let mut sections = Vec::new();
+    for (name, field) in fields {
+        let field_path = path.join(Segment::Key(name.clone()));
+        match field {
+            Value::Null => {}
+            Value::Object(_) => sections.push((name, field, field_path)),
+            Value::Array(elements) if is_array_of_tables(elements) => {
+                sections.push((name, field, field_path))
+            }
+            _ => {
+                out.push_str(&key_text(name));
+                out.push_str(" = ");
+                write_inline(out, field, &field_path)?;
+                out.push('\n');
+            }
+        }
+    }
+
+    for (name, field, field_path) in sections {
+        let mut header = header.to_vec();
+        header.push(key_text(name));
+        let header_text = header.join(".");
+
+        match field {
+            Value::Object(fields) => {
+                section_break(out);
+                writeln!(out, "[{header_text}]")?;
+                write_table(out, &header, fields, &field_path)?;
+            }
+            Value::Array(elements) => {
+                for (index, element) in elements.iter().enumerate() {
+                    let Value::Object(fields) = element else {
+                        unreachable!("an array of tables only contains objects");
+                    };
+                    section_break(out);
+                    writeln!(out, "[[{header_text}]]")?;
+                    write_table(
+                        out,
+                        &header,
+                        fields,
+                        &field_path.join(Segment::Index(index)),
+                    )?;
+                }
+            }
+            _ => unreachable!("only tables and arrays of tables have sections"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Return true if the array is written as an array of tables, with a
+/// `[[header]]` section for each element.
+fn is_array_of_tables(elements: &[Value]) -> bool {
+    !elements.is_empty()
+        && elements
+            .iter()
+            .all(|element| matches!(element, Value::Object(_)))
+}
+
+/// Separate a section header from the lines before it.
+fn section_break(out: &mut String) {
+    if !out.is_empty() {
+        out.push('\n');
+    }
+}
+
+/// Write a value on a single line, with objects as inline tables.
+fn write_inline(out: &mut String, value: &Value, path: &Path) -> anyhow::Result<()> {
+    match value {
+        Value::Null => {
+            anyhow::bail!("'{path}' is null, which TOML cannot represent as an element of an array")
+        }
+        Value::Bool(value) => write!(out, "{value}")?,
+        Value::Number(number) => write_number(out, number),
+        Value::String(value) => write_string(out, value),
+        Value::Array(elements) => {
+            out.push('[');
+            for (index, element) in elements.iter().enumerate() {
+                if index > 0 {
+                    out.push_str(", ");
+                }
+                write_inline(out, element, &path.join(Segment::Index(index)))?;
+            }
+            out.push(']');
+        }
+        Value::Object(fields) => {
+            let mut fields = fields.iter().filter(|(_, field)| *field != Value::Null);
+            let Some((name, field)) = fields.next() else {
+                out.push_str("{}");
+                return Ok(());
+            };
+
+            out.push_str("{ ");
+            out.push_str(&key_text(name));
+            out.push_str(" = ");
+            write_inline(out, field, &path.join(Segment::Key(name.clone())))?;
+            for (name, field) in fields {
+                out.push_str(", ");
+                out.push_str(&key_text(name));
+                out.push_str(" = ");
+                write_inline(out, field, &path.join(Segment::Key(name.clone())))?;
+            }
+            out.push_str(" }");
+        }
+    }
+
+    Ok(())
+}
+
+/// Write a JSON number as a TOML integer or float, which share the syntax of
+/// JSON numbers except for integers larger than 64 bits.
+fn write_number(out: &mut String, number: &str) {
+    let integer = !number.contains(['.', 'e', 'E']);
+    if integer && number.parse::<i64>().is_err() {
+        match number.parse::<f64>() {
+            Ok(float) => write!(out, "{float:?}").expect("writing to a string cannot fail"),
+            Err(_) => out.push_str(number),
+        }
+    } else {
+        out.push_str(number);
+    }
+}
+
+/// Return the key as written in TOML, quoted unless it is a bare key of
+/// letters, digits, `_`, and `-`.
+fn key_text(key: &str) -> String {
+    let bare = !key.is_empty()
+        && key
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-');
+    if bare {
+        key.to_string()
+    } else {
+        let mut quoted = String::new();
+        write_string(&mut quoted, key);
+        quoted
+    }
+}
+
+/// Write a string as a TOML basic string, escaping quotes, backslashes, and
+/// control characters.
+fn write_string(out: &mut String, value: &str) {
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => {
+                write!(out, "\\u{:04X}", c as u32).expect("writing to a string cannot fail")
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toml(value: serde_json::Value) -> anyhow::Result<String> {
+        let mut output = Vec::new();
+        write_toml(&mut output, &Value::from(value))?;
+        Ok(String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn write_documents() {
+        let value = serde_json::json!({
+            "name": "edge \"42\"",
+            "missing": null,
+            "big": 9223372036854775808u64,
+            "ratio": 0.5,
+            "mixed": [1, "two", [3.0], {"four": 4, "none": null}],
+            "a key": true,
+            "owner": {"team": "ingest", "contact": {"email": "a@b.c\n"}},
+            "servers": [{"host": "a", "ports": [80, 443]}, {"host": "b", "tags": {}}],
+            "after": 1,
+        });
+
+        assert_eq!(
+            toml(value).unwrap(),
+            r#"name = "edge \"42\""
+big = 9.223372036854776e18
+ratio = 0.5
+mixed = [1, "two", [3.0], { four = 4 }]
+"a key" = true
+after = 1
+
+[owner]
+team = "ingest"
+
+[owner.contact]
+email = "a@b.c\n"
+
+[[servers]]
+host = "a"
+ports = [80, 443]
+
+[[servers]]
+host = "b"
+
+[servers.tags]
+"#
+        );
+    }
+
+    #[test]
+    fn reject_unrepresentable_values() {
+        let err = toml(serde_json::json!({"runs": [{"ok": [1, null]}]})).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'runs[0].ok[1]' is null, which TOML cannot represent as an element of an array"
+        );
+
+        let err = toml(serde_json::json!([1])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "merged value is array and not an object, it cannot be written as TOML"
+        );
+    }
+}
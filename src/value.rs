@@ -1,11 +1,58 @@
 //! The Value enum, a loosely typed way of representing any valid JSON value.
 
+#[cfg(feature = "arbitrary")]
+pub mod arbitrary;
+pub mod arrays;
+pub mod cbor;
+pub mod crdt;
 pub mod merge;
+pub mod pattern;
+pub mod preview;
+pub mod rollup;
 mod serde;
+pub mod walk;
 
 use std::fmt::Debug;
+use std::io;
+use std::mem;
 use std::vec::Vec;
 
+/// The default limit on how many arrays and objects may be nested inside each
+/// other when parsing or decoding a [`Value`].
+///
+/// This matches the recursion limit that `serde_json` applies to JSON input.
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// The only key of the object which marks a field as deleted, see
+/// [`Value::tombstone`]
+pub const TOMBSTONE_KEY: &str = "$wall-a:unset";
+
+/// The prefix of the keys which wall-a uses for its markers, like
+/// [`TOMBSTONE_KEY`], which appended records may not contain
+pub const RESERVED_KEY_PREFIX: &str = "$wall-a:";
+
+/// An error from parsing JSON, which includes the line and column in the input
+/// and the path to the part of the value where parsing failed
+pub type JsonError = serde_path_to_error::Error<serde_json::Error>;
+
+/// Parse a [`Value`] from a slice of JSON bytes, returning an error if arrays
+/// and objects are nested more than `max_depth` levels deep.
+///
+/// The `max_depth` replaces the recursion limit of `serde_json`, which would
+/// otherwise reject values nested 128 levels deep or more.
+pub fn from_json_slice(bytes: &[u8], max_depth: usize) -> Result<Value, JsonError> {
+    let mut deserializer = serde_json::Deserializer::from_slice(bytes);
+    deserializer.disable_recursion_limit();
+    let mut track = serde_path_to_error::Track::new();
+
+    Value::deserialize_with_max_depth(
+        serde_path_to_error::Deserializer::new(&mut deserializer, &mut track),
+        max_depth,
+    )
+    .and_then(|value| deserializer.end().map(|()| value))
+    .map_err(|err| JsonError::new(track.path(), err))
+}
+
 /// Represents any valid JSON value.
 #[derive(
     Default,
@@ -44,6 +91,152 @@ pub enum Value {
     Object(#[n(0)] Vec<(String, Value)>),
 }
 
+impl Value {
+    /// Return the name of the JSON type of this value.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Null => "null",
+            Value::Bool(_) => "bool",
+            Value::Number(_) => "number",
+            Value::String(_) => "string",
+            Value::Array(_) => "array",
+            Value::Object(_) => "object",
+        }
+    }
+
+    /// Return the number of keys of an object, or 0 for every other value.
+    pub fn key_count(&self) -> usize {
+        match self {
+            Value::Object(fields) => fields.len(),
+            _ => 0,
+        }
+    }
+
+    /// Return the marker which deletes a field when merged over it, written
+    /// `{"$wall-a:unset": true}`.
+    ///
+    /// A tombstone stays in merged values so that it also hides the field in
+    /// values merged before it, and is removed from the final value with
+    /// [`Value::remove_tombstones`].
+    pub fn tombstone() -> Value {
+        Value::Object(vec![(TOMBSTONE_KEY.into(), Value::Bool(true))])
+    }
+
+    /// Return true if this value is the marker returned by
+    /// [`Value::tombstone`].
+    pub fn is_tombstone(&self) -> bool {
+        matches!(self, Value::Object(fields) if matches!(
+            fields.as_slice(),
+            [(key, Value::Bool(true))] if key == TOMBSTONE_KEY
+        ))
+    }
+
+    /// Remove every object field and array element which is a tombstone,
+    /// returning `None` if the whole value is one.
+    pub fn remove_tombstones(self) -> Option<Value> {
+        if self.is_tombstone() {
+            return None;
+        }
+
+        Some(match self {
+            Value::Array(elements) => Value::Array(
+                elements
+                    .into_iter()
+                    .filter_map(Value::remove_tombstones)
+                    .collect(),
+            ),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .filter_map(|(key, value)| Some((key, value.remove_tombstones()?)))
+                    .collect(),
+            ),
+            value => value,
+        })
+    }
+
+    /// Return the number of bytes this value takes up when serialized as
+    /// compact JSON.
+    pub fn json_len(&self) -> u64 {
+        let mut counter = ByteCounter(0);
+        serde_json::to_writer(&mut counter, self)
+            .expect("serializing to a byte counter cannot fail");
+        counter.0
+    }
+
+    /// Return an estimate of the number of bytes this value holds on the
+    /// heap, from the capacity of its strings and vectors and of everything
+    /// nested in them, leaving out the value itself and allocator overhead.
+    ///
+    /// Unlike [`Value::json_len`], this is the memory the value takes up
+    /// while it is merged.
+    pub fn estimated_heap_size(&self) -> usize {
+        match self {
+            Value::Null | Value::Bool(_) => 0,
+            Value::Number(text) | Value::String(text) => text.capacity(),
+            Value::Array(elements) => {
+                elements.capacity() * mem::size_of::<Value>()
+                    + elements
+                        .iter()
+                        .map(Value::estimated_heap_size)
+                        .sum::<usize>()
+            }
+            Value::Object(fields) => {
+                fields.capacity() * mem::size_of::<(String, Value)>()
+                    + fields
+                        .iter()
+                        .map(|(key, value)| key.capacity() + value.estimated_heap_size())
+                        .sum::<usize>()
+            }
+        }
+    }
+
+    /// Look up a value by a JSON Pointer (RFC 6901), like `/metrics/errors/0`.
+    ///
+    /// The empty pointer refers to the whole value. Returns `None` if the
+    /// pointer is malformed or nothing exists at that location.
+    pub fn pointer(&self, pointer: &str) -> Option<&Value> {
+        if pointer.is_empty() {
+            return Some(self);
+        }
+
+        let tokens = pointer.strip_prefix('/')?.split('/');
+
+        tokens.into_iter().try_fold(self, |target, token| {
+            let token = token.replace("~1", "/").replace("~0", "~");
+
+            match target {
+                Value::Object(fields) => fields
+                    .iter()
+                    .find(|(key, _)| *key == token)
+                    .map(|(_, value)| value),
+                Value::Array(elements) => {
+                    // Leading zeros and signs are not valid array indices
+                    if token.starts_with('+') || (token.len() > 1 && token.starts_with('0')) {
+                        return None;
+                    }
+                    elements.get(token.parse::<usize>().ok()?)
+                }
+                _ => None,
+            }
+        })
+    }
+}
+
+/// A writer which discards all bytes, only counting how many were written.
+struct ByteCounter(u64);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 impl From<serde_json::Value> for Value {
     fn from(value: serde_json::Value) -> Self {
         match value {
@@ -92,3 +285,70 @@ impl TryFrom<Value> for serde_json::Value {
         Ok(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pointer_lookup() {
+        let value = Value::from(serde_json::json!({
+            "metrics": {"errors": ["a", "b"], "a/b": {"~c": true}},
+        }));
+
+        assert_eq!(value.pointer(""), Some(&value));
+        assert_eq!(
+            value.pointer("/metrics/errors/1"),
+            Some(&Value::String("b".into()))
+        );
+        assert_eq!(value.pointer("/metrics/a~1b/~0c"), Some(&Value::Bool(true)));
+        assert_eq!(value.pointer("/metrics/errors/01"), None);
+        assert_eq!(value.pointer("/metrics/errors/2"), None);
+        assert_eq!(value.pointer("/missing"), None);
+        assert_eq!(value.pointer("metrics"), None);
+    }
+
+    #[test]
+    fn remove_tombstones() {
+        let value = Value::Object(vec![
+            ("a".into(), Value::Number("1".into())),
+            ("b".into(), Value::tombstone()),
+            (
+                "c".into(),
+                Value::Array(vec![Value::tombstone(), Value::Bool(true)]),
+            ),
+        ]);
+
+        assert_eq!(
+            value.remove_tombstones(),
+            Some(Value::from(serde_json::json!({"a": 1, "c": [true]})))
+        );
+        assert_eq!(Value::tombstone().remove_tombstones(), None);
+        // Only an object with exactly the tombstone key and `true` is one
+        let not_tombstone = Value::from(serde_json::json!({"$wall-a:unset": false}));
+        assert!(!not_tombstone.is_tombstone());
+    }
+
+    #[test]
+    fn estimated_heap_size() {
+        assert_eq!(Value::Null.estimated_heap_size(), 0);
+        assert_eq!(
+            Value::String(String::with_capacity(10)).estimated_heap_size(),
+            10
+        );
+
+        let mut elements = Vec::with_capacity(4);
+        elements.push(Value::Number("12".into()));
+        elements.push(Value::Bool(true));
+        let array = Value::Array(elements);
+        assert_eq!(array.estimated_heap_size(), 4 * mem::size_of::<Value>() + 2);
+
+        let array_size = array.estimated_heap_size();
+        // Cloning would shrink the capacity of the array to its length
+        let object = Value::Object(vec![("key".into(), array)]);
+        assert_eq!(
+            object.estimated_heap_size(),
+            mem::size_of::<(String, Value)>() + 3 + array_size
+        );
+    }
+}
@@ -0,0 +1,252 @@
+//! Generating arbitrary [`Value`]s, which is only available with the
+//! `arbitrary` feature.
+//!
+//! The generated values are always valid JSON: numbers are valid JSON number
+//! text and object keys are unique. Their size is bounded so that merging or
+//! encoding them stays cheap, see [`Bounds`].
+
+use arbitrary::{Arbitrary, Unstructured};
+
+use super::Value;
+
+/// The limits on the size of a generated [`Value`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Bounds {
+    /// The maximum number of levels that arrays and objects are nested
+    pub max_depth: usize,
+    /// The maximum number of elements in each array or object
+    pub max_len: usize,
+}
+
+impl Default for Bounds {
+    fn default() -> Self {
+        Self {
+            max_depth: 4,
+            max_len: 8,
+        }
+    }
+}
+
+impl Value {
+    /// Generate an arbitrary value within the given bounds.
+    pub fn arbitrary_with_bounds(
+        u: &mut Unstructured<'_>,
+        bounds: Bounds,
+    ) -> arbitrary::Result<Self> {
+        // Only scalars are generated at the maximum depth
+        let kinds = if bounds.max_depth == 0 { 4 } else { 6 };
+
+        Ok(match u.choose_index(kinds)? {
+            0 => Value::Null,
+            1 => Value::Bool(u.arbitrary()?),
+            2 => Value::Number(arbitrary_number(u)?),
+            3 => Value::String(u.arbitrary()?),
+            kind => {
+                let inner = Bounds {
+                    max_depth: bounds.max_depth - 1,
+                    ..bounds
+                };
+                let len = u.int_in_range(0..=bounds.max_len)?;
+
+                if kind == 4 {
+                    Value::Array(
+                        (0..len)
+                            .map(|_| Value::arbitrary_with_bounds(u, inner))
+                            .collect::<arbitrary::Result<_>>()?,
+                    )
+                } else {
+                    let mut fields: Vec<(String, Value)> = Vec::with_capacity(len);
+                    for _ in 0..len {
+                        let key: String = u.arbitrary()?;
+                        let value = Value::arbitrary_with_bounds(u, inner)?;
+                        if !fields.iter().any(|(existing, _)| *existing == key) {
+                            fields.push((key, value));
+                        }
+                    }
+                    Value::Object(fields)
+                }
+            }
+        })
+    }
+}
+
+/// Generate the text of an integer or a floating point number, in the form it
+/// has when parsed from JSON.
+///
+/// Floating point numbers are multiples of 1/256 within the range of an `i16`,
+/// which have few enough digits to be written and parsed without losing
+/// precision.
+fn arbitrary_number(u: &mut Unstructured<'_>) -> arbitrary::Result<String> {
+    if u.arbitrary()? {
+        return Ok(u.arbitrary::<i64>()?.to_string());
+    }
+
+    Ok((f64::from(u.arbitrary::<i16>()?) / 256.0).to_string())
+}
+
+impl<'a> Arbitrary<'a> for Value {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Value::arbitrary_with_bounds(u, Bounds::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::{decode_archive, encode_archive},
+        value::{
+            from_json_slice,
+            merge::{ArrayBehavior, KeyCase, MergeMode, MergeSettings, NullBehavior, UnionKeep},
+            DEFAULT_MAX_DEPTH,
+        },
+    };
+
+    /// Return a fixed sequence of pseudo-random bytes, so that the tests are
+    /// repeatable.
+    fn bytes() -> Vec<u8> {
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        (0..64 * 1024)
+            .map(|_| {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn values(count: usize) -> Vec<Value> {
+        let bytes = bytes();
+        let mut u = Unstructured::new(&bytes);
+        (0..count).map(|_| u.arbitrary().unwrap()).collect()
+    }
+
+    /// Generate a value with the same arrays and objects as the template, but
+    /// with some object keys missing and different scalars.
+    fn reshape(u: &mut Unstructured<'_>, template: &Value) -> Value {
+        match template {
+            Value::Array(values) => {
+                Value::Array(values.iter().map(|value| reshape(u, value)).collect())
+            }
+            Value::Object(fields) => Value::Object(
+                fields
+                    .iter()
+                    .filter_map(|(key, value)| {
+                        let keep: bool = u.arbitrary().unwrap();
+                        keep.then(|| (key.clone(), reshape(u, value)))
+                    })
+                    .collect(),
+            ),
+            _ => {
+                let scalar = Bounds {
+                    max_depth: 0,
+                    ..Bounds::default()
+                };
+                Value::arbitrary_with_bounds(u, scalar).unwrap()
+            }
+        }
+    }
+
+    fn all_settings() -> Vec<MergeSettings> {
+        let mut settings = Vec::new();
+        for array_behavior in [
+            ArrayBehavior::Concat,
+            ArrayBehavior::Merge,
+            ArrayBehavior::Union,
+            ArrayBehavior::Replace,
+        ] {
+            for null_behavior in [NullBehavior::Merge, NullBehavior::Ignore] {
+                settings.push(MergeSettings {
+                    array_behavior,
+                    null_behavior,
+                    union_keep: UnionKeep::First,
+                    key_case: KeyCase::Sensitive,
+                    mode: MergeMode::Ordered,
+                });
+            }
+        }
+        settings
+    }
+
+    #[test]
+    fn json_and_archive_round_trip() {
+        for value in values(200) {
+            let json = serde_json::to_vec(&value).unwrap();
+            assert_eq!(from_json_slice(&json, DEFAULT_MAX_DEPTH).unwrap(), value);
+
+            let archive = encode_archive(&value, 0, 0).unwrap();
+            assert_eq!(decode_archive(&archive, DEFAULT_MAX_DEPTH).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn merge_is_associative() {
+        // A value of a different type replaces everything merged before it, so
+        // `(a + b) + c` is `c` while `a + (b + c)` is `a + c` if only `b` is a
+        // different type. The same is true of null with `NullBehavior::Merge`,
+        // so the values share a shape and nulls are ignored.
+        let settings = all_settings()
+            .into_iter()
+            .filter(|settings| settings.null_behavior == NullBehavior::Ignore)
+            .collect::<Vec<_>>();
+
+        let bytes = bytes();
+        let mut u = Unstructured::new(&bytes[bytes.len() / 2..]);
+        for a in values(50) {
+            let b = reshape(&mut u, &a);
+            let c = reshape(&mut u, &a);
+
+            for settings in &settings {
+                assert_eq!(
+                    settings.merge(settings.merge(a.clone(), b.clone()), c.clone()),
+                    settings.merge(a.clone(), settings.merge(b.clone(), c.clone())),
+                    "{settings:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn crdt_merge_ignores_order() {
+        let settings = MergeSettings {
+            mode: MergeMode::Crdt,
+            ..MergeSettings::default()
+        };
+        // Merged objects have their keys sorted, so the order of keys is
+        // ignored when comparing
+        let merge = |a: &Value, b: &Value| {
+            serde_json::Value::try_from(settings.merge(a.clone(), b.clone())).unwrap()
+        };
+
+        let values = values(60);
+        for abc in values.chunks_exact(3) {
+            let [a, b, c] = [&abc[0], &abc[1], &abc[2]];
+            let ab = settings.merge(a.clone(), b.clone());
+            let bc = settings.merge(b.clone(), c.clone());
+
+            assert_eq!(merge(a, b), merge(b, a));
+            assert_eq!(merge(&ab, c), merge(a, &bc));
+            assert_eq!(merge(a, a), serde_json::Value::try_from(a.clone()).unwrap());
+        }
+    }
+
+    #[test]
+    fn merge_is_idempotent() {
+        // Concatenating or taking the union of an array with itself changes it
+        let settings = all_settings().into_iter().filter(|settings| {
+            matches!(
+                settings.array_behavior,
+                ArrayBehavior::Merge | ArrayBehavior::Replace
+            )
+        });
+
+        let values = values(100);
+        for settings in settings {
+            for value in &values {
+                assert_eq!(settings.merge(value.clone(), value.clone()), *value);
+            }
+        }
+    }
+}
@@ -0,0 +1,318 @@
+//! This module contains the rules applied to the arrays of a merged value,
+//! like limiting their length so that event-style arrays merged with
+//! [`ArrayBehavior::Concat`](super::merge::ArrayBehavior::Concat) do not grow
+//! without bound, or sorting them so that they come out the same however
+//! their elements were merged.
+
+use std::{fmt, str::FromStr};
+
+use anyhow::Context;
+
+use super::{crdt, pattern::KeyPattern, Value};
+
+/// This enum controls which elements are kept when an array is longer than
+/// its limit
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum ArrayKeep {
+    /// Keep the elements at the end, which were merged most recently
+    #[default]
+    Newest,
+    /// Keep the elements at the start
+    Oldest,
+}
+
+impl FromStr for ArrayKeep {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "newest" => Self::Newest,
+            "oldest" => Self::Oldest,
+            x => anyhow::bail!("'{x}' is an unknown option for the array elements to keep"),
+        })
+    }
+}
+
+impl fmt::Display for ArrayKeep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Newest => "newest",
+            Self::Oldest => "oldest",
+        })
+    }
+}
+
+/// The maximum length of the arrays matched by a pattern, written like
+/// `events.*  100`, or of every array without a pattern, written like `100`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArrayLimit {
+    /// The pattern of keys leading to the arrays, or `None` for every array
+    pub pattern: Option<KeyPattern>,
+    /// The maximum number of elements
+    pub max_len: usize,
+}
+
+impl FromStr for ArrayLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, max_len) = match s.split_once("  ") {
+            Some((pattern, max_len)) => (Some(pattern.parse()?), max_len),
+            None => (None, s),
+        };
+        let max_len = max_len
+            .trim()
+            .parse()
+            .with_context(|| format!("'{}' is not an array length", max_len.trim()))?;
+
+        Ok(Self { pattern, max_len })
+    }
+}
+
+impl fmt::Display for ArrayLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.pattern {
+            Some(pattern) => write!(f, "{pattern}  {}", self.max_len),
+            None => write!(f, "{}", self.max_len),
+        }
+    }
+}
+
+/// How to sort the arrays matched by a pattern, written like `tags` to sort
+/// the elements themselves or like `items.*  id` to sort object elements by
+/// the value of a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArraySort {
+    /// The pattern of keys leading to the arrays
+    pub pattern: KeyPattern,
+    /// The key of the object elements to sort by, or `None` to sort by the
+    /// whole elements
+    pub key: Option<String>,
+}
+
+impl ArraySort {
+    /// Sort the elements in a fixed order of values, first by type and then
+    /// by contents. Elements without the key come first, in their merged
+    /// order.
+    fn sort(&self, elements: &mut [Value]) {
+        match &self.key {
+            Some(key) => elements.sort_by(|a, b| match (field(a, key), field(b, key)) {
+                (Some(a), Some(b)) => crdt::compare(a, b),
+                (a, b) => a.is_some().cmp(&b.is_some()),
+            }),
+            None => elements.sort_by(crdt::compare),
+        }
+    }
+}
+
+/// Return the value of the key if the element is an object which has it.
+fn field<'v>(element: &'v Value, key: &str) -> Option<&'v Value> {
+    match element {
+        Value::Object(fields) => fields
+            .iter()
+            .find(|(field, _)| field == key)
+            .map(|(_, value)| value),
+        _ => None,
+    }
+}
+
+impl FromStr for ArraySort {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, key) = match s.split_once("  ") {
+            Some((pattern, key)) => (pattern, Some(key.trim())),
+            None => (s, None),
+        };
+        if key == Some("") {
+            anyhow::bail!("the key to sort by is empty");
+        }
+
+        Ok(Self {
+            pattern: pattern.trim().parse()?,
+            key: key.map(str::to_string),
+        })
+    }
+}
+
+impl fmt::Display for ArraySort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.key {
+            Some(key) => write!(f, "{}  {key}", self.pattern),
+            None => write!(f, "{}", self.pattern),
+        }
+    }
+}
+
+/// The rules applied to the arrays of a merged value
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ArrayRules {
+    /// The limits on the length of arrays, where a limit with a pattern takes
+    /// precedence over the limit for every array
+    pub limits: Vec<ArrayLimit>,
+    /// Which elements are kept when an array is too long
+    pub keep: ArrayKeep,
+    /// How to sort arrays, after they are limited
+    pub sorts: Vec<ArraySort>,
+}
+
+impl ArrayRules {
+    /// Return true if there are no rules, so applying them changes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty() && self.sorts.is_empty()
+    }
+
+    /// Add a limit, replacing any limit with the same pattern.
+    pub fn insert_limit(&mut self, limit: ArrayLimit) {
+        match self
+            .limits
+            .iter_mut()
+            .find(|existing| existing.pattern == limit.pattern)
+        {
+            Some(existing) => *existing = limit,
+            None => self.limits.push(limit),
+        }
+    }
+
+    /// Add a way to sort arrays, replacing any with the same pattern.
+    pub fn insert_sort(&mut self, sort: ArraySort) {
+        match self
+            .sorts
+            .iter_mut()
+            .find(|existing| existing.pattern == sort.pattern)
+        {
+            Some(existing) => *existing = sort,
+            None => self.sorts.push(sort),
+        }
+    }
+
+    /// Apply the rules to every array in the value.
+    pub fn apply(&self, value: &mut Value) {
+        if !self.is_empty() {
+            self.apply_at(value, &mut Some(Vec::new()));
+        }
+    }
+
+    /// Apply the rules to the value at the given path of keys, which is
+    /// `None` inside an array, where patterns cannot match.
+    fn apply_at(&self, value: &mut Value, path: &mut Option<Vec<String>>) {
+        match value {
+            Value::Object(fields) => {
+                for (key, value) in fields {
+                    if let Some(path) = path {
+                        path.push(key.clone());
+                    }
+                    self.apply_at(value, path);
+                    if let Some(path) = path {
+                        path.pop();
+                    }
+                }
+            }
+            Value::Array(elements) => {
+                if let Some(max_len) = self.max_len(path.as_deref()) {
+                    let excess = elements.len().saturating_sub(max_len);
+                    match self.keep {
+                        ArrayKeep::Newest => drop(elements.drain(..excess)),
+                        ArrayKeep::Oldest => elements.truncate(max_len),
+                    }
+                }
+
+                let sort = path.as_ref().and_then(|path| {
+                    self.sorts
+                        .iter()
+                        .find(|sort| sort.pattern.matches_path(path))
+                });
+                if let Some(sort) = sort {
+                    sort.sort(elements);
+                }
+
+                for element in elements {
+                    self.apply_at(element, &mut None);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Return the limit for the array at the path of keys.
+    fn max_len(&self, path: Option<&[String]>) -> Option<usize> {
+        let matching = path.and_then(|path| {
+            self.limits.iter().find(|limit| {
+                limit
+                    .pattern
+                    .as_ref()
+                    .is_some_and(|pattern| pattern.matches_path(path))
+            })
+        });
+
+        matching
+            .or_else(|| self.limits.iter().find(|limit| limit.pattern.is_none()))
+            .map(|limit| limit.max_len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(value: serde_json::Value) -> Value {
+        Value::from(value)
+    }
+
+    #[test]
+    fn limit_array_lengths() {
+        let mut rules = ArrayRules::default();
+        rules.insert_limit("3".parse().unwrap());
+        rules.insert_limit("events.*  2".parse().unwrap());
+        rules.insert_limit("events.*  1".parse().unwrap());
+        assert_eq!(rules.limits.len(), 2);
+        assert_eq!(rules.limits[1].to_string(), "events.*  1");
+
+        let mut value = json(serde_json::json!({
+            "events": {"login": [1, 2, 3, 4]},
+            "tags": [1, 2, 3, 4, [1, 2, 3, 4]],
+        }));
+        rules.apply(&mut value);
+        assert_eq!(
+            value,
+            json(serde_json::json!({
+                "events": {"login": [4]},
+                "tags": [3, 4, [2, 3, 4]],
+            }))
+        );
+
+        rules.keep = ArrayKeep::Oldest;
+        let mut value = json(serde_json::json!({"tags": [1, 2, 3, 4]}));
+        rules.apply(&mut value);
+        assert_eq!(value, json(serde_json::json!({"tags": [1, 2, 3]})));
+
+        assert!("events.*  many".parse::<ArrayLimit>().is_err());
+        assert!("-1".parse::<ArrayLimit>().is_err());
+    }
+
+    #[test]
+    fn sort_arrays() {
+        let mut rules = ArrayRules::default();
+        rules.insert_sort("tags".parse().unwrap());
+        rules.insert_sort("items.*  id".parse().unwrap());
+        rules.insert_limit("items.*  3".parse().unwrap());
+        assert_eq!(rules.sorts[1].to_string(), "items.*  id");
+
+        let mut value = json(serde_json::json!({
+            "tags": ["b", 10, "a", 9, null],
+            "items": {"x": [{"id": 3}, {"id": 2}, {"name": "none"}, {"id": 1}]},
+            "other": [2, 1],
+        }));
+        rules.apply(&mut value);
+        assert_eq!(
+            value,
+            json(serde_json::json!({
+                "tags": [null, 9, 10, "a", "b"],
+                "items": {"x": [{"name": "none"}, {"id": 1}, {"id": 2}]},
+                "other": [2, 1],
+            }))
+        );
+
+        assert!("tags  ".parse::<ArraySort>().is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! This module contains the CBOR decoding path for [`Value`], guarded by a
+//! nesting depth limit.
+
+use anyhow::Context;
+use minicbor::{data::Type, Decoder};
+
+use super::Value;
+
+/// The number of CBOR nesting levels that a single level of [`Value`]
+/// nesting can produce.
+///
+/// The derived encoding wraps every variant in an array of `[index, [fields]]`
+/// and object entries are encoded as `[key, value]` pairs inside the field
+/// array, so an object nests 4 CBOR arrays deep before reaching its values.
+const CBOR_LEVELS_PER_VALUE_LEVEL: usize = 4;
+
+/// Decode a [`Value`] from CBOR bytes, returning an error if arrays and
+/// objects are nested more than `max_depth` levels deep.
+///
+/// The nesting of the input is checked without recursion before decoding, so
+/// that the (recursive) derived decoder never sees a document deep enough to
+/// exhaust the stack.
+pub fn from_cbor_slice(bytes: &[u8], max_depth: usize) -> anyhow::Result<Value> {
+    let max_cbor_depth = max_depth
+        .saturating_mul(CBOR_LEVELS_PER_VALUE_LEVEL)
+        .saturating_add(CBOR_LEVELS_PER_VALUE_LEVEL);
+    check_nesting_depth(bytes, max_cbor_depth).context("checking CBOR nesting depth")?;
+
+    let mut decoder = Decoder::new(bytes);
+    let value = decoder.decode().context("decoding CBOR value")?;
+
+    Ok(value)
+}
+
+/// Decode an entry of an object, a key along with its value, from CBOR bytes
+/// which must hold exactly the entry, with the same nesting depth limit as
+/// [`from_cbor_slice`].
+pub fn field_from_cbor_slice(bytes: &[u8], max_depth: usize) -> anyhow::Result<(String, Value)> {
+    let max_cbor_depth = max_depth
+        .saturating_mul(CBOR_LEVELS_PER_VALUE_LEVEL)
+        .saturating_add(CBOR_LEVELS_PER_VALUE_LEVEL);
+    check_nesting_depth(bytes, max_cbor_depth).context("checking CBOR nesting depth")?;
+
+    let mut decoder = Decoder::new(bytes);
+    let field = decoder.decode().context("decoding CBOR object entry")?;
+    if decoder.position() != bytes.len() {
+        anyhow::bail!(
+            "object entry ends at byte {} of {} bytes",
+            decoder.position(),
+            bytes.len()
+        );
+    }
+
+    Ok(field)
+}
+
+/// Walk the first CBOR data item in `bytes` and return an error if it
+/// contains arrays or maps nested more than `max_depth` levels deep.
+fn check_nesting_depth(bytes: &[u8], max_depth: usize) -> anyhow::Result<()> {
+    let mut decoder = Decoder::new(bytes);
+    // The number of items remaining in each open container, `None` for
+    // indefinite length containers which are closed by a break marker.
+    let mut open_containers: Vec<Option<u64>> = Vec::new();
+
+    loop {
+        let item_complete = match decoder.datatype()? {
+            Type::Array | Type::ArrayIndef => {
+                let len = decoder.array()?;
+                open_container(&mut open_containers, len, max_depth)?
+            }
+            Type::Map | Type::MapIndef => {
+                let len = decoder.map()?.map(|len| len.saturating_mul(2));
+                open_container(&mut open_containers, len, max_depth)?
+            }
+            Type::Tag => {
+                // A tag applies to the following data item, so it does not
+                // complete an item itself
+                decoder.tag()?;
+                false
+            }
+            Type::Break => {
+                match open_containers.pop() {
+                    Some(None) => {}
+                    _ => {
+                        anyhow::bail!("unexpected break marker at position {}", decoder.position())
+                    }
+                }
+                decoder.set_position(decoder.position() + 1);
+                true
+            }
+            _ => {
+                decoder.skip()?;
+                true
+            }
+        };
+
+        if item_complete && complete_item(&mut open_containers) {
+            return Ok(());
+        }
+    }
+}
+
+/// Record a newly opened container, returning true if it was empty and so is
+/// already complete.
+fn open_container(
+    open_containers: &mut Vec<Option<u64>>,
+    len: Option<u64>,
+    max_depth: usize,
+) -> anyhow::Result<bool> {
+    if len == Some(0) {
+        return Ok(true);
+    }
+
+    if open_containers.len() >= max_depth {
+        anyhow::bail!("exceeded the maximum nesting depth");
+    }
+    open_containers.push(len);
+
+    Ok(false)
+}
+
+/// Mark one item as complete in the innermost open container, closing any
+/// containers that this fills.
+///
+/// Returns true once the top-level data item is complete.
+fn complete_item(open_containers: &mut Vec<Option<u64>>) -> bool {
+    loop {
+        match open_containers.last_mut() {
+            None => return true,
+            Some(None) => return false,
+            Some(Some(remaining)) => {
+                *remaining -= 1;
+                if *remaining > 0 {
+                    return false;
+                }
+                open_containers.pop();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested_arrays(depth: usize) -> Value {
+        (0..depth).fold(Value::Null, |inner, _| Value::Array(vec![inner]))
+    }
+
+    fn nested_objects(depth: usize) -> Value {
+        (0..depth).fold(Value::Null, |inner, _| {
+            Value::Object(vec![("key".into(), inner)])
+        })
+    }
+
+    #[test]
+    fn decode_within_max_depth() {
+        for value in [
+            Value::Null,
+            Value::String("hello".into()),
+            nested_arrays(10),
+            nested_objects(10),
+        ] {
+            let bytes = minicbor::to_vec(&value).unwrap();
+            assert_eq!(from_cbor_slice(&bytes, 10).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn decode_past_max_depth() {
+        let bytes = minicbor::to_vec(nested_arrays(200)).unwrap();
+        let err = from_cbor_slice(&bytes, 128).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("maximum nesting depth"),
+            "{err:#}"
+        );
+
+        let bytes = minicbor::to_vec(nested_objects(200)).unwrap();
+        let err = from_cbor_slice(&bytes, 128).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("maximum nesting depth"),
+            "{err:#}"
+        );
+    }
+
+    #[test]
+    fn nesting_depth_indefinite_containers() {
+        // [_ [_ ], 1]
+        let bytes = [0x9f, 0x9f, 0xff, 0x01, 0xff];
+        assert!(check_nesting_depth(&bytes, 2).is_ok());
+        assert!(check_nesting_depth(&bytes, 1).is_err());
+    }
+}
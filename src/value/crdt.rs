@@ -0,0 +1,355 @@
+//! Merging values so that the result does not depend on the order of the
+//! records, used by [`MergeMode::Crdt`](super::merge::MergeMode::Crdt).
+//!
+//! Records may contain two kinds of markers, which are kept in stored values
+//! and replaced by [`resolve`] when they are read:
+//!  - A last-writer-wins register, written
+//!    `{"$wall-a:lww": [<timestamp>, <value>]}` where the timestamp is an
+//!    integer like the milliseconds since the Unix epoch. The value with the
+//!    greatest timestamp wins.
+//!  - An observed-remove set, written
+//!    `{"$wall-a:orset": {"add": {<tag>: <value>, ...}, "remove": [<tag>, ...]}}`
+//!    where each tag uniquely identifies one addition of an element, like
+//!    `"node-1:42"`. An element is in the set if its tag was added and not
+//!    removed, so a removal only affects the additions it has observed.
+//!
+//! Objects are merged key by key. Any other values which conflict are
+//! resolved by keeping the greatest one in a fixed order of values, so the
+//! result is still the same in any order, but not necessarily the most
+//! recent.
+
+use std::cmp::Ordering;
+
+use itertools::Itertools;
+
+use super::Value;
+
+/// The only key of the object which marks a last-writer-wins register
+pub const REGISTER_KEY: &str = "$wall-a:lww";
+/// The only key of the object which marks an observed-remove set
+pub const SET_KEY: &str = "$wall-a:orset";
+
+/// Return a last-writer-wins register holding the value, written at the given
+/// timestamp.
+pub fn register(timestamp: i64, value: Value) -> Value {
+    Value::Object(vec![(
+        REGISTER_KEY.into(),
+        Value::Array(vec![Value::Number(timestamp.to_string()), value]),
+    )])
+}
+
+/// Return true if the value is a register or a set.
+pub fn is_marker(value: &Value) -> bool {
+    matches!(kind(value), Kind::Register | Kind::Set)
+}
+
+/// The parts of a value which are merged differently, in increasing order of
+/// precedence when two values of different kinds are merged
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Kind {
+    Plain,
+    Object,
+    Set,
+    Register,
+}
+
+fn kind(value: &Value) -> Kind {
+    if as_register(value).is_some() {
+        Kind::Register
+    } else if as_set(value).is_some() {
+        Kind::Set
+    } else if matches!(value, Value::Object(_)) && !value.is_tombstone() {
+        Kind::Object
+    } else {
+        Kind::Plain
+    }
+}
+
+/// Return the only field of the object if it has the given key.
+fn marker<'v>(value: &'v Value, marker_key: &str) -> Option<&'v Value> {
+    match value {
+        Value::Object(fields) => match fields.as_slice() {
+            [(key, inner)] if key == marker_key => Some(inner),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Return the timestamp and value of a register.
+fn as_register(value: &Value) -> Option<(i64, &Value)> {
+    match marker(value, REGISTER_KEY)? {
+        Value::Array(parts) => match parts.as_slice() {
+            [Value::Number(timestamp), value] => Some((timestamp.parse().ok()?, value)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The added elements by tag and the removed tags of a set
+type SetParts<'v> = (&'v [(String, Value)], &'v [Value]);
+
+/// Return the parts of a set.
+fn as_set(value: &Value) -> Option<SetParts<'_>> {
+    let Value::Object(fields) = marker(value, SET_KEY)? else {
+        return None;
+    };
+
+    let mut added: &[(String, Value)] = &[];
+    let mut removed: &[Value] = &[];
+    for (key, value) in fields {
+        match (key.as_str(), value) {
+            ("add", Value::Object(fields)) => added = fields,
+            ("remove", Value::Array(tags)) => removed = tags,
+            _ => return None,
+        }
+    }
+
+    Some((added, removed))
+}
+
+/// Merge two values so that the result is the same whichever is given first.
+///
+/// Merging is also associative and idempotent, so records may be merged in
+/// any order and any number of times.
+pub fn merge(a: Value, b: Value) -> Value {
+    let (a_kind, b_kind) = (kind(&a), kind(&b));
+    if a_kind != b_kind {
+        return if a_kind > b_kind { a } else { b };
+    }
+
+    match a_kind {
+        Kind::Plain => greatest(a, b),
+        Kind::Register => {
+            let ordering = {
+                let (a_timestamp, a_value) = as_register(&a).expect("kind is register");
+                let (b_timestamp, b_value) = as_register(&b).expect("kind is register");
+                a_timestamp
+                    .cmp(&b_timestamp)
+                    .then_with(|| compare(a_value, b_value))
+            };
+
+            if ordering == Ordering::Less {
+                b
+            } else {
+                a
+            }
+        }
+        Kind::Set => {
+            let (a_added, a_removed) = as_set(&a).expect("kind is set");
+            let (b_added, b_removed) = as_set(&b).expect("kind is set");
+
+            let added = merge_fields(a_added.to_vec(), b_added.to_vec(), greatest);
+            let removed = a_removed
+                .iter()
+                .chain(b_removed)
+                .cloned()
+                .sorted_by(compare)
+                .dedup()
+                .collect();
+
+            Value::Object(vec![(
+                SET_KEY.into(),
+                Value::Object(vec![
+                    ("add".into(), Value::Object(added)),
+                    ("remove".into(), Value::Array(removed)),
+                ]),
+            )])
+        }
+        Kind::Object => {
+            let (Value::Object(a), Value::Object(b)) = (a, b) else {
+                unreachable!("kind is object")
+            };
+
+            Value::Object(merge_fields(a, b, merge))
+        }
+    }
+}
+
+/// Take the union of the fields, sorted by key, merging the values of fields
+/// with the same key.
+fn merge_fields(
+    a: Vec<(String, Value)>,
+    b: Vec<(String, Value)>,
+    merge_values: fn(Value, Value) -> Value,
+) -> Vec<(String, Value)> {
+    a.into_iter()
+        .chain(b)
+        .sorted_by(|(a, _), (b, _)| a.cmp(b))
+        .coalesce(|(a_key, a_value), (b_key, b_value)| {
+            if a_key == b_key {
+                Ok((a_key, merge_values(a_value, b_value)))
+            } else {
+                Err(((a_key, a_value), (b_key, b_value)))
+            }
+        })
+        .collect()
+}
+
+fn greatest(a: Value, b: Value) -> Value {
+    if compare(&a, &b) == Ordering::Less {
+        b
+    } else {
+        a
+    }
+}
+
+/// Compare two values in a fixed order, first by type and then by contents.
+///
+/// Objects are compared as if their fields were sorted by key.
+pub(crate) fn compare(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) => 4,
+            Value::Object(_) => 5,
+        }
+    }
+
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => {
+            let parse = |n: &str| n.parse::<f64>().unwrap_or(f64::NAN);
+            parse(a).total_cmp(&parse(b)).then_with(|| a.cmp(b))
+        }
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Array(a), Value::Array(b)) => a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| compare(a, b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Value::Object(a), Value::Object(b)) => {
+            let a = a.iter().sorted_by(|(a, _), (b, _)| a.cmp(b));
+            let b = b.iter().sorted_by(|(a, _), (b, _)| a.cmp(b));
+            a.zip_longest(b)
+                .map(|pair| match pair.left_and_right() {
+                    (Some((a_key, a)), Some((b_key, b))) => {
+                        a_key.cmp(b_key).then_with(|| compare(a, b))
+                    }
+                    (Some(_), None) => Ordering::Greater,
+                    (None, _) => Ordering::Less,
+                })
+                .find(|ordering| ordering.is_ne())
+                .unwrap_or(Ordering::Equal)
+        }
+        (a, b) => rank(a).cmp(&rank(b)),
+    }
+}
+
+/// Replace every register with its value and every set with an array of its
+/// elements, ordered by tag.
+///
+/// The result may still contain tombstones, like a register holding a
+/// tombstone to delete a field, see [`Value::remove_tombstones`].
+pub fn resolve(value: Value) -> Value {
+    if let Some((_, inner)) = as_register(&value) {
+        return resolve(inner.clone());
+    }
+    if let Some((added, removed)) = as_set(&value) {
+        return Value::Array(
+            added
+                .iter()
+                .filter(|(tag, _)| {
+                    !removed
+                        .iter()
+                        .any(|removed| matches!(removed, Value::String(removed) if removed == tag))
+                })
+                .map(|(_, element)| resolve(element.clone()))
+                .collect(),
+        );
+    }
+
+    match value {
+        Value::Array(elements) => Value::Array(elements.into_iter().map(resolve).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key, resolve(value)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(value: serde_json::Value) -> Value {
+        Value::from(value)
+    }
+
+    /// Merge the values in every order, checking that the result is the same.
+    fn merge_all(values: &[Value]) -> Value {
+        let merged = values
+            .iter()
+            .permutations(values.len())
+            .map(|order| {
+                order
+                    .into_iter()
+                    .cloned()
+                    .reduce(merge)
+                    .expect("there is at least one value")
+            })
+            .collect::<Vec<_>>();
+
+        assert!(merged.iter().all_equal(), "{merged:?}");
+        resolve(merged[0].clone())
+    }
+
+    #[test]
+    fn last_writer_wins() {
+        let merged = merge_all(&[
+            json(serde_json::json!({"a": {"$wall-a:lww": [2, "second"]}, "b": 1})),
+            json(serde_json::json!({"a": {"$wall-a:lww": [3, "third"]}})),
+            json(serde_json::json!({"a": {"$wall-a:lww": [1, "first"]}, "c": true})),
+        ]);
+
+        assert_eq!(
+            merged,
+            json(serde_json::json!({"a": "third", "b": 1, "c": true}))
+        );
+    }
+
+    #[test]
+    fn register_deletes_field() {
+        let merged = merge_all(&[
+            json(serde_json::json!({"a": {"$wall-a:lww": [1, "value"]}, "b": 1})),
+            Value::Object(vec![("a".into(), register(2, Value::tombstone()))]),
+        ]);
+
+        assert_eq!(
+            merged.remove_tombstones(),
+            Some(json(serde_json::json!({"b": 1})))
+        );
+    }
+
+    #[test]
+    fn observed_remove_set() {
+        let merged = merge_all(&[
+            json(
+                serde_json::json!({"tags": {"$wall-a:orset": {"add": {"n1:1": "x", "n1:2": "y"}}}}),
+            ),
+            json(serde_json::json!({"tags": {"$wall-a:orset": {"add": {"n2:1": "x"}}}})),
+            json(serde_json::json!({"tags": {"$wall-a:orset": {"remove": ["n1:1"]}}})),
+        ]);
+
+        assert_eq!(merged, json(serde_json::json!({"tags": ["y", "x"]})));
+    }
+
+    #[test]
+    fn plain_conflicts_are_deterministic() {
+        let merged = merge_all(&[
+            json(serde_json::json!({"a": 1, "b": [1, 2]})),
+            json(serde_json::json!({"a": "one", "b": null})),
+            json(serde_json::json!({"a": 2, "b": {"c": 1}})),
+        ]);
+
+        assert_eq!(merged, json(serde_json::json!({"a": "one", "b": {"c": 1}})));
+    }
+}
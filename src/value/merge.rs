@@ -4,19 +4,52 @@ use std::{collections::HashMap, str::FromStr};
 
 use indexmap::IndexSet;
 use itertools::{EitherOrBoth, Itertools};
+use serde::{Deserialize, Serialize};
 
-use super::Value;
+use super::{crdt, pattern::KeyPattern, rollup, Value};
 
 /// This struct defines how JSON & CBOR values are merged
-#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+///
+/// It is (de)serialized with the same names as the settings in the
+/// `MANIFEST`, like `array_behavior = "union"`, where missing settings are
+/// the default.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct MergeSettings {
     /// This field controls how arrays are merged
     pub array_behavior: ArrayBehavior,
     /// This field controls how null values are merged
     pub null_behavior: NullBehavior,
+    /// This field controls which duplicate is kept by
+    /// [`ArrayBehavior::Union`]
+    pub union_keep: UnionKeep,
+    /// This field controls whether object keys which differ only by case are
+    /// the same key
+    pub key_case: KeyCase,
+    /// This field controls whether the order of the values matters, the
+    /// other settings are ignored by [`MergeMode::Crdt`]
+    pub mode: MergeMode,
 }
 
 impl MergeSettings {
+    /// Return these settings along with a function which merges the values
+    /// at the paths matched by the pattern instead, see [`CustomMerge`].
+    ///
+    /// More custom merges can be added with [`MergeConfig::with_custom`].
+    pub fn with_custom(self, pattern: KeyPattern, merge: CustomMergeFn) -> MergeConfig {
+        MergeConfig::from(self).with_custom(pattern, merge)
+    }
+
+    /// Merge two values which may not be present, returning `None` only if
+    /// neither is.
+    pub fn merge_optional(self, accum: Option<Value>, value: Option<Value>) -> Option<Value> {
+        match (accum, value) {
+            (None, None) => None,
+            (None, Some(value)) | (Some(value), None) => Some(value),
+            (Some(accum), Some(value)) => Some(self.merge(accum, value)),
+        }
+    }
+
     /// Merge two JSON values together, favouring the second value as the more
     /// recent.
     ///
@@ -28,10 +61,46 @@ impl MergeSettings {
     ///  - If the second value is `null`, then the [`NullBehavior`] controls the
     ///    merge behavior
     ///  - Otherwise, the second value is used
+    ///
+    /// A [tombstone](Value::tombstone) is not merged like an object: as the
+    /// second value it replaces the first, and as the first value it is
+    /// replaced by the second. Two [rollups](rollup) are combined.
+    #[cfg_attr(
+        feature = "store",
+        tracing::instrument(
+            level = "trace",
+            skip_all,
+            fields(accum_keys = accum.key_count(), value_keys = value.key_count())
+        )
+    )]
     pub fn merge(self, accum: Value, value: Value) -> Value {
+        self.merge_values(accum, value)
+    }
+
+    /// Like [`Self::merge`], but without a span, since it is called again for
+    /// every nested value.
+    fn merge_values(self, accum: Value, value: Value) -> Value {
+        if self.mode == MergeMode::Crdt {
+            return crdt::merge(accum, value);
+        }
+        if value.is_tombstone() {
+            return value;
+        }
+        if let Some(merged) = rollup::merge(&accum, &value) {
+            return merged;
+        }
+
         match (accum, value) {
+            // Null is handled by the null behavior below
+            (accum, value) if accum.is_tombstone() && value != Value::Null => value,
             // For all shared keys, merge
             (Value::Object(mut accum), Value::Object(value)) => {
+                let value = if self.key_case == KeyCase::Insensitive {
+                    accum = self.fold_fields(accum);
+                    self.fold_fields(value)
+                } else {
+                    value
+                };
                 let mut keys = HashMap::with_capacity(accum.len().max(value.len()));
 
                 for (accum_index, (key, _)) in accum.iter().enumerate() {
@@ -80,19 +149,34 @@ impl MergeSettings {
                         .zip_longest(value.iter())
                         .map(|pair| match pair {
                             EitherOrBoth::Both(accum, value) => {
-                                self.merge(accum.clone(), value.clone())
+                                self.merge_values(accum.clone(), value.clone())
                             }
                             EitherOrBoth::Left(value) | EitherOrBoth::Right(value) => value.clone(),
                         })
                         .collect(),
                     // Move all values through a hashset to get the unique set
-                    ArrayBehavior::Union => accum
-                        .iter()
-                        .chain(value.iter())
-                        .collect::<IndexSet<_>>()
-                        .into_iter()
-                        .cloned()
-                        .collect::<Vec<_>>(),
+                    ArrayBehavior::Union => match self.union_keep {
+                        UnionKeep::First => accum
+                            .iter()
+                            .chain(value.iter())
+                            .collect::<IndexSet<_>>()
+                            .into_iter()
+                            .cloned()
+                            .collect::<Vec<_>>(),
+                        // Walk backwards so the last occurrence is the one kept
+                        UnionKeep::Last => {
+                            let mut values = accum
+                                .iter()
+                                .chain(value.iter())
+                                .rev()
+                                .collect::<IndexSet<_>>()
+                                .into_iter()
+                                .cloned()
+                                .collect::<Vec<_>>();
+                            values.reverse();
+                            values
+                        }
+                    },
                     // Take newer value
                     ArrayBehavior::Replace => value,
                 };
@@ -107,10 +191,402 @@ impl MergeSettings {
             (_, value) => value,
         }
     }
+
+    /// Return the rule which [`merge`](Self::merge) applies to the two values
+    /// at the top level.
+    pub fn rule(self, accum: &Value, value: &Value) -> MergeRule {
+        if self.mode == MergeMode::Crdt {
+            return MergeRule::Crdt;
+        }
+        if value.is_tombstone() {
+            return MergeRule::Delete;
+        }
+        if rollup::merge(accum, value).is_some() {
+            return MergeRule::Rollup;
+        }
+
+        match (accum, value) {
+            (accum, value) if accum.is_tombstone() && *value != Value::Null => MergeRule::Undelete,
+            (Value::Object(_), Value::Object(_)) => MergeRule::Objects,
+            (Value::Array(_), Value::Array(_)) => MergeRule::Arrays(self.array_behavior),
+            (_, Value::Null) => MergeRule::Null(self.null_behavior),
+            _ => MergeRule::Replace,
+        }
+    }
+
+    /// Fold every object key in the value to lowercase with
+    /// [`KeyCase::Insensitive`], merging fields whose keys collide in the
+    /// order they appear. Otherwise, or with [`MergeMode::Crdt`], the value
+    /// is returned unchanged.
+    pub fn fold_keys(self, value: Value) -> Value {
+        if self.key_case == KeyCase::Sensitive || self.mode == MergeMode::Crdt {
+            return value;
+        }
+
+        match value {
+            Value::Object(fields) => Value::Object(
+                self.fold_fields(
+                    fields
+                        .into_iter()
+                        .map(|(key, value)| (key, self.fold_keys(value)))
+                        .collect(),
+                ),
+            ),
+            Value::Array(elements) => Value::Array(
+                elements
+                    .into_iter()
+                    .map(|element| self.fold_keys(element))
+                    .collect(),
+            ),
+            value => value,
+        }
+    }
+
+    /// Fold the keys of the fields to lowercase, without descending into
+    /// their values, merging fields whose keys collide.
+    fn fold_fields(self, fields: Vec<(String, Value)>) -> Vec<(String, Value)> {
+        let mut folded: Vec<(String, Value)> = Vec::with_capacity(fields.len());
+        let mut indices = HashMap::<String, usize>::with_capacity(fields.len());
+
+        for (key, value) in fields {
+            let key = key.to_lowercase();
+            match indices.get(&key) {
+                Some(&index) => {
+                    let accum = std::mem::replace(&mut folded[index].1, Value::Null);
+                    folded[index].1 = self.merge_values(accum, value);
+                }
+                None => {
+                    indices.insert(key.clone(), folded.len());
+                    folded.push((key, value));
+                }
+            }
+        }
+
+        folded
+    }
+
+    /// Turn a merged value into the value that is read, removing any
+    /// tombstones and resolving any [rollups](rollup) and, with
+    /// [`MergeMode::Crdt`], resolving the registers and sets. With [`KeyCase::Insensitive`], any keys which were not folded
+    /// while merging are folded first.
+    ///
+    /// Returns `None` if the whole value was deleted.
+    pub fn resolve(self, value: Value) -> Option<Value> {
+        match self.mode {
+            MergeMode::Ordered => self
+                .fold_keys(value)
+                .remove_tombstones()
+                .map(rollup::resolve),
+            MergeMode::Crdt => crdt::resolve(value).remove_tombstones(),
+        }
+    }
 }
 
-/// This enum describes how array values are merged
+/// An array behavior for the arrays at the paths matched by a pattern, which
+/// overrides [`MergeSettings::array_behavior`], written like `events=concat`
+/// or `hosts.*.tags=union`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArrayBehaviorAt {
+    /// The pattern of keys leading to the arrays
+    pub pattern: KeyPattern,
+    /// How the arrays are merged
+    pub behavior: ArrayBehavior,
+}
+
+impl FromStr for ArrayBehaviorAt {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || {
+            let Some((pattern, behavior)) = s.rsplit_once('=') else {
+                anyhow::bail!("expected a pattern followed by '=<behavior>'");
+            };
+
+            Ok(Self {
+                pattern: pattern.parse()?,
+                behavior: behavior.parse()?,
+            })
+        };
+
+        parse().map_err(|err: anyhow::Error| anyhow::anyhow!("invalid array behavior '{s}': {err}"))
+    }
+}
+
+/// A function which merges an older value with a newer one
+pub type CustomMergeFn = fn(&Value, &Value) -> Value;
+
+/// A function which merges the values at the paths matched by a pattern,
+/// which overrides every merge rule for them, so that libraries can merge
+/// parts of the values with their own logic, like keeping the greatest
+/// version or the latest timestamp
+#[derive(Debug, Clone)]
+pub struct CustomMerge {
+    /// The pattern of keys leading to the values
+    pub pattern: KeyPattern,
+    /// How the values are merged, called with the older value and then the
+    /// newer one
+    pub merge: CustomMergeFn,
+}
+
+impl PartialEq for CustomMerge {
+    /// Compare the patterns and the addresses of the functions. A function
+    /// may not have a single address, so equal custom merges may compare as
+    /// unequal.
+    fn eq(&self, other: &Self) -> bool {
+        self.pattern == other.pattern && self.merge as usize == other.merge as usize
+    }
+}
+
+impl Eq for CustomMerge {}
+
+/// The [`MergeSettings`] along with the array behaviors and custom merges
+/// which override them at some paths
 #[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct PathMergeSettings<'a> {
+    /// The settings for every part of the values without an override
+    pub settings: MergeSettings,
+    /// The overrides, where the first one which matches an array is used
+    pub array_behaviors: &'a [ArrayBehaviorAt],
+    /// The custom merges, where the first one which matches a field is used,
+    /// before any array behavior
+    pub custom_merges: &'a [CustomMerge],
+}
+
+impl From<MergeSettings> for PathMergeSettings<'_> {
+    fn from(settings: MergeSettings) -> Self {
+        Self {
+            settings,
+            array_behaviors: &[],
+            custom_merges: &[],
+        }
+    }
+}
+
+/// The [`MergeSettings`] along with the array behaviors which override them
+/// at some paths, like [`PathMergeSettings`] but owning the overrides, so
+/// that they can be loaded from a settings file.
+///
+/// The overrides are (de)serialized as an `array_behaviors` list next to the
+/// settings, each with a `pattern` and a `behavior`.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeConfig {
+    /// The settings for every part of the values without an override
+    #[serde(flatten)]
+    pub settings: MergeSettings,
+    /// The overrides, where the first one which matches an array is used
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub array_behaviors: Vec<ArrayBehaviorAt>,
+    /// The custom merges, which are never (de)serialized
+    #[serde(skip)]
+    pub custom_merges: Vec<CustomMerge>,
+}
+
+impl From<MergeSettings> for MergeConfig {
+    fn from(settings: MergeSettings) -> Self {
+        Self {
+            settings,
+            ..Self::default()
+        }
+    }
+}
+
+impl MergeConfig {
+    /// Add a function which merges the values at the paths matched by the
+    /// pattern, after the custom merges which were already added.
+    pub fn with_custom(mut self, pattern: KeyPattern, merge: CustomMergeFn) -> Self {
+        self.custom_merges.push(CustomMerge { pattern, merge });
+        self
+    }
+
+    /// Return the settings along with the overrides, to merge with.
+    pub fn path_settings(&self) -> PathMergeSettings<'_> {
+        PathMergeSettings {
+            settings: self.settings,
+            array_behaviors: &self.array_behaviors,
+            custom_merges: &self.custom_merges,
+        }
+    }
+}
+
+impl PathMergeSettings<'_> {
+    /// Merge two values which may not be present, like
+    /// [`MergeSettings::merge_optional`].
+    pub fn merge_optional(self, accum: Option<Value>, value: Option<Value>) -> Option<Value> {
+        match (accum, value) {
+            (None, None) => None,
+            (None, Some(value)) | (Some(value), None) => Some(value),
+            (Some(accum), Some(value)) => Some(self.merge(accum, value)),
+        }
+    }
+
+    /// Merge two values like [`MergeSettings::merge`], except for the fields
+    /// at the paths of the custom merges, which are merged with their
+    /// function when both values have the field and neither is a
+    /// [tombstone](Value::tombstone), and the arrays at the paths of the
+    /// overrides, which are merged with the behavior of the override. The
+    /// overrides are ignored by [`MergeMode::Crdt`].
+    pub fn merge(self, accum: Value, mut value: Value) -> Value {
+        if (self.array_behaviors.is_empty() && self.custom_merges.is_empty())
+            || self.settings.mode == MergeMode::Crdt
+        {
+            return self.settings.merge(accum, value);
+        }
+
+        // The fields matched by an override are taken out of the newer value
+        // and merged on their own, then put in place of the older fields
+        // once the rest of the values are merged
+        let mut arrays = Vec::new();
+        for custom in self.custom_merges {
+            for path in custom.pattern.matches(&value) {
+                let (Some(newer), Some(older)) = (field_at(&value, &path), field_at(&accum, &path))
+                else {
+                    continue;
+                };
+                if newer.is_tombstone() || older.is_tombstone() {
+                    continue;
+                }
+                let merged = (custom.merge)(older, newer);
+                take_field(&mut value, &path);
+                arrays.push((path, merged));
+            }
+        }
+        for at in self.array_behaviors {
+            for path in at.pattern.matches(&value) {
+                let (Some(Value::Array(_)), Some(Value::Array(older))) =
+                    (field_at(&value, &path), field_at(&accum, &path))
+                else {
+                    continue;
+                };
+                let older = Value::Array(older.clone());
+                let newer = take_field(&mut value, &path);
+                let settings = MergeSettings {
+                    array_behavior: at.behavior,
+                    ..self.settings
+                };
+                arrays.push((path, settings.merge(older, newer)));
+            }
+        }
+
+        let mut merged = self.settings.merge(accum, value);
+        // A field inside another overridden field is put back after it
+        for (path, array) in arrays.into_iter().rev() {
+            if let Some(field) = field_at_mut(&mut merged, &path) {
+                *field = array;
+            }
+        }
+
+        merged
+    }
+}
+
+/// Return the field at the path of keys, if every step is an object which
+/// has the key.
+fn field_at<'v>(value: &'v Value, path: &[String]) -> Option<&'v Value> {
+    path.iter().try_fold(value, |value, key| match value {
+        Value::Object(fields) => fields
+            .iter()
+            .find(|(field, _)| field == key)
+            .map(|(_, value)| value),
+        _ => None,
+    })
+}
+
+fn field_at_mut<'v>(value: &'v mut Value, path: &[String]) -> Option<&'v mut Value> {
+    path.iter().try_fold(value, |value, key| match value {
+        Value::Object(fields) => fields
+            .iter_mut()
+            .find(|(field, _)| field == key)
+            .map(|(_, value)| value),
+        _ => None,
+    })
+}
+
+/// Remove the field at the path of keys, which must be present, and return
+/// it.
+fn take_field(value: &mut Value, path: &[String]) -> Value {
+    let (key, parent) = path.split_last().expect("the path of a field is not empty");
+    let Some(Value::Object(fields)) = field_at_mut(value, parent) else {
+        unreachable!("the parent of a field is an object");
+    };
+    let position = fields
+        .iter()
+        .position(|(field, _)| field == key)
+        .expect("the field is present");
+
+    fields.remove(position).1
+}
+
+/// This enum describes which rule [`MergeSettings::merge`] applies to two
+/// values
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MergeRule {
+    /// The values are merged as CRDTs, see [`crdt`]
+    Crdt,
+    /// The second value is a tombstone, which replaces the first
+    Delete,
+    /// The first value is a tombstone, which is replaced by the second
+    Undelete,
+    /// Both values are rollups, which are combined
+    Rollup,
+    /// Both values are objects, which are merged key by key
+    Objects,
+    /// Both values are arrays, which are merged by the [`ArrayBehavior`]
+    Arrays(ArrayBehavior),
+    /// The second value is `null`, which is merged by the [`NullBehavior`]
+    Null(NullBehavior),
+    /// The second value replaces the first
+    Replace,
+}
+
+impl std::fmt::Display for MergeRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Crdt => "merged as CRDTs",
+            Self::Delete => "deleted by tombstone",
+            Self::Undelete => "replaced tombstone",
+            Self::Rollup => "combined rollups",
+            Self::Objects => "merged objects by key",
+            Self::Arrays(ArrayBehavior::Concat) => "concatenated arrays",
+            Self::Arrays(ArrayBehavior::Merge) => "merged arrays by index",
+            Self::Arrays(ArrayBehavior::Union) => "took union of arrays",
+            Self::Arrays(ArrayBehavior::Replace) => "replaced array",
+            Self::Null(NullBehavior::Ignore) => "ignored null",
+            Self::Null(NullBehavior::Merge) => "replaced with null",
+            Self::Replace => "replaced",
+        })
+    }
+}
+
+/// This enum describes whether values are merged in the order they were
+/// written
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum MergeMode {
+    /// Merge values in order, favouring the more recent one
+    #[default]
+    Ordered,
+    /// Merge values so that the result is the same in any order, see
+    /// [`crdt`]
+    Crdt,
+}
+
+impl FromStr for MergeMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "ordered" => Self::Ordered,
+            "crdt" => Self::Crdt,
+            x => anyhow::bail!("'{x}' is an unknown option for the merge mode"),
+        })
+    }
+}
+
+/// This enum describes how array values are merged
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[non_exhaustive]
 pub enum ArrayBehavior {
     /// Concatenate arrays
@@ -138,8 +614,63 @@ impl FromStr for ArrayBehavior {
     }
 }
 
+/// This enum controls which occurrence of a duplicate element is kept when
+/// arrays are merged with [`ArrayBehavior::Union`]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum UnionKeep {
+    /// Keep the first occurrence, so an element stays where it was first
+    /// merged
+    #[default]
+    First,
+    /// Keep the last occurrence, so an element moves to where it was most
+    /// recently merged
+    Last,
+}
+
+impl FromStr for UnionKeep {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "first" => Self::First,
+            "last" => Self::Last,
+            x => anyhow::bail!("'{x}' is an unknown option for the duplicate to keep"),
+        })
+    }
+}
+
+/// This enum controls whether object keys are matched by case when merging,
+/// which is ignored by [`MergeMode::Crdt`]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum KeyCase {
+    /// Keys which differ by case are different keys
+    #[default]
+    Sensitive,
+    /// Keys which differ only by case are the same key, and every key is
+    /// folded to its lowercase form, like `host` for `Host`, so the merged
+    /// value does not depend on which spelling was seen first
+    Insensitive,
+}
+
+impl FromStr for KeyCase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "sensitive" => Self::Sensitive,
+            "insensitive" => Self::Insensitive,
+            x => anyhow::bail!("'{x}' is an unknown option for matching key case"),
+        })
+    }
+}
+
 /// This enum conrtols how `null` values are merged
-#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 #[non_exhaustive]
 pub enum NullBehavior {
     ///  The content's null value properties will be merged
@@ -187,8 +718,10 @@ mod tests {
 
     #[test]
     fn ignore_null_behavior() {
-        let mut settings = MergeSettings::default();
-        settings.null_behavior = NullBehavior::Ignore;
+        let settings = MergeSettings {
+            null_behavior: NullBehavior::Ignore,
+            ..MergeSettings::default()
+        };
 
         assert_eq!(settings.merge(json!("hello"), Value::Null), json!("hello"));
         assert_eq!(settings.merge(Value::Null, Value::Null), Value::Null);
@@ -198,6 +731,43 @@ mod tests {
         );
     }
 
+    #[test]
+    fn tombstones_delete_fields() {
+        let settings = MergeSettings::default();
+        let unset_b = Value::Object(vec![("b".into(), Value::tombstone())]);
+
+        let merged = settings.merge(json!({"a": 1, "b": {"c": 2}}), unset_b.clone());
+        assert_eq!(
+            merged,
+            Value::Object(vec![
+                ("a".into(), json!(1)),
+                ("b".into(), Value::tombstone())
+            ])
+        );
+        // The tombstone also hides the field in values merged before it
+        assert_eq!(
+            settings
+                .merge(json!({"b": {"d": 3}}), merged.clone())
+                .remove_tombstones(),
+            Some(json!({"a": 1}))
+        );
+        // A later value is not merged with the deleted one
+        assert_eq!(
+            settings.merge(merged, json!({"b": {"e": 4}})),
+            json!({"a": 1, "b": {"e": 4}})
+        );
+
+        assert_eq!(settings.merge(Value::tombstone(), Value::Null), Value::Null);
+        let ignore_null = MergeSettings {
+            null_behavior: NullBehavior::Ignore,
+            ..settings
+        };
+        assert_eq!(
+            ignore_null.merge(Value::tombstone(), Value::Null),
+            Value::tombstone()
+        );
+    }
+
     #[test]
     fn default_settings_merge_arrays() {
         let settings = MergeSettings::default();
@@ -228,8 +798,10 @@ mod tests {
 
     #[test]
     fn union_array_behavior() {
-        let mut settings = MergeSettings::default();
-        settings.array_behavior = ArrayBehavior::Union;
+        let settings = MergeSettings {
+            array_behavior: ArrayBehavior::Union,
+            ..MergeSettings::default()
+        };
 
         assert_eq!(settings.merge(json!([]), json!([])), json!([]));
         assert_eq!(
@@ -250,12 +822,73 @@ mod tests {
             ),
             json!([{"hello":"sun"}, {"goodbye":"moon"}])
         );
+
+        let keep_last = MergeSettings {
+            union_keep: UnionKeep::Last,
+            ..settings
+        };
+        assert_eq!(
+            keep_last.merge(json!(["a", "b", "c", "a"]), json!(["d", "b"])),
+            json!(["c", "a", "d", "b"])
+        );
+        assert_eq!(keep_last.merge(json!([]), json!([])), json!([]));
+    }
+
+    #[test]
+    fn case_insensitive_keys() {
+        let settings = MergeSettings {
+            key_case: KeyCase::Insensitive,
+            ..MergeSettings::default()
+        };
+
+        assert_eq!(
+            settings.merge(
+                json!({"Host": {"Name": "a", "port": 1}, "other": 1}),
+                json!({"host": {"name": "b"}, "HOST": {"PORT": 2}})
+            ),
+            json!({"host": {"name": "b", "port": 2}, "other": 1})
+        );
+        assert_eq!(
+            settings.fold_keys(json!([{"Tags": ["A"], "tags": ["b"]}])),
+            json!([{"tags": ["A", "b"]}])
+        );
+        assert_eq!(
+            settings.resolve(json!({"Host": 1, "host": Value::tombstone()})),
+            Some(json!({}))
+        );
+
+        let crdt = MergeSettings {
+            mode: MergeMode::Crdt,
+            ..settings
+        };
+        assert_eq!(crdt.fold_keys(json!({"Host": 1})), json!({"Host": 1}));
+    }
+
+    #[test]
+    fn merge_optional_values() {
+        let settings = MergeSettings::default();
+
+        assert_eq!(settings.merge_optional(None, None), None);
+        assert_eq!(
+            settings.merge_optional(Some(json!({"a": "1"})), None),
+            Some(json!({"a": "1"}))
+        );
+        assert_eq!(
+            settings.merge_optional(None, Some(json!({"b": "2"}))),
+            Some(json!({"b": "2"}))
+        );
+        assert_eq!(
+            settings.merge_optional(Some(json!({"a": "1"})), Some(json!({"b": "2"}))),
+            Some(json!({"a": "1", "b": "2"}))
+        );
     }
 
     #[test]
     fn merge_array_behavior() {
-        let mut settings = MergeSettings::default();
-        settings.array_behavior = ArrayBehavior::Merge;
+        let settings = MergeSettings {
+            array_behavior: ArrayBehavior::Merge,
+            ..MergeSettings::default()
+        };
 
         assert_eq!(settings.merge(json!([]), json!([])), json!([]));
         assert_eq!(
@@ -282,8 +915,10 @@ mod tests {
 
     #[test]
     fn replace_array_behavior() {
-        let mut settings = MergeSettings::default();
-        settings.array_behavior = ArrayBehavior::Replace;
+        let settings = MergeSettings {
+            array_behavior: ArrayBehavior::Replace,
+            ..MergeSettings::default()
+        };
 
         assert_eq!(settings.merge(json!([]), json!([])), json!([]));
         assert_eq!(
@@ -373,4 +1008,130 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn merge_rules() {
+        let settings = MergeSettings::default();
+
+        assert_eq!(settings.rule(&json!(1), &json!(2)), MergeRule::Replace);
+        assert_eq!(
+            settings.rule(&json!({"a": 1}), &json!({"b": 2})),
+            MergeRule::Objects
+        );
+        assert_eq!(
+            settings.rule(&json!([1]), &json!([2])),
+            MergeRule::Arrays(ArrayBehavior::Concat)
+        );
+        assert_eq!(
+            settings.rule(&json!([1]), &Value::Null),
+            MergeRule::Null(NullBehavior::Merge)
+        );
+        assert_eq!(
+            settings.rule(&json!({"a": 1}), &Value::tombstone()),
+            MergeRule::Delete
+        );
+        assert_eq!(
+            settings.rule(&Value::tombstone(), &json!({"a": 1})),
+            MergeRule::Undelete
+        );
+        assert_eq!(
+            settings.rule(&Value::tombstone(), &Value::Null),
+            MergeRule::Null(NullBehavior::Merge)
+        );
+
+        let crdt = MergeSettings {
+            mode: MergeMode::Crdt,
+            ..settings
+        };
+        assert_eq!(crdt.rule(&json!(1), &json!(2)), MergeRule::Crdt);
+        assert_eq!(
+            MergeRule::Arrays(ArrayBehavior::Union).to_string(),
+            "took union of arrays"
+        );
+    }
+
+    #[test]
+    fn path_array_behaviors() {
+        let array_behaviors = [
+            "events=replace",
+            "hosts.*.tags=union",
+            "hosts.b.tags=replace",
+        ]
+        .map(|s| s.parse::<ArrayBehaviorAt>().unwrap());
+        let settings = PathMergeSettings {
+            array_behaviors: &array_behaviors,
+            ..PathMergeSettings::default()
+        };
+
+        assert_eq!(
+            settings.merge(
+                json!({"events": [1], "log": [1], "hosts": {"a": {"tags": ["x"]}, "b": {"tags": ["x"]}}}),
+                json!({"events": [2], "log": [2], "hosts": {"a": {"tags": ["x", "y"]}, "b": {"tags": ["z"]}}, "new": [3]})
+            ),
+            json!({"events": [2], "log": [1, 2], "hosts": {"a": {"tags": ["x", "y"]}, "b": {"tags": ["x", "z"]}}, "new": [3]})
+        );
+        // An override only applies when both values have an array there
+        assert_eq!(
+            settings.merge(json!({"events": 1}), json!({"events": [2]})),
+            json!({"events": [2]})
+        );
+        assert_eq!(
+            settings.merge_optional(None, Some(json!({"events": [2]}))),
+            Some(json!({"events": [2]}))
+        );
+
+        let err = "events=sideways".parse::<ArrayBehaviorAt>().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid array behavior 'events=sideways': 'sideways' is an unknown option for \
+             merging array values"
+        );
+        assert!("events".parse::<ArrayBehaviorAt>().is_err());
+    }
+
+    #[test]
+    fn custom_merges() {
+        fn longest(older: &Value, newer: &Value) -> Value {
+            match (older, newer) {
+                (Value::String(older), Value::String(newer)) if older.len() > newer.len() => {
+                    Value::String(older.clone())
+                }
+                _ => newer.clone(),
+            }
+        }
+
+        let config = MergeSettings::default()
+            .with_custom("hosts.*.name".parse().unwrap(), longest)
+            .with_custom("tags".parse().unwrap(), |older, _| older.clone());
+        let settings = config.path_settings();
+
+        assert_eq!(
+            settings.merge(
+                json!({"hosts": {"a": {"name": "alpha", "up": true}, "b": {"name": "b"}}, "tags": [1]}),
+                json!({"hosts": {"a": {"name": "a", "up": false}, "b": {"name": "beta"}}, "tags": [2]})
+            ),
+            json!({"hosts": {"a": {"name": "alpha", "up": false}, "b": {"name": "beta"}}, "tags": [1]})
+        );
+        // A custom merge only applies when both values have the field
+        assert_eq!(
+            settings.merge(json!({"hosts": {}}), json!({"hosts": {"a": {"name": "a"}}})),
+            json!({"hosts": {"a": {"name": "a"}}})
+        );
+        assert_eq!(
+            settings.merge(json!({"tags": [1]}), json!({"tags": Value::tombstone()})),
+            json!({"tags": Value::tombstone()})
+        );
+
+        // The custom merges come before the array behaviors
+        let config = MergeConfig {
+            array_behaviors: vec!["tags=union".parse().unwrap()],
+            ..config
+        };
+        assert_eq!(
+            config
+                .path_settings()
+                .merge(json!({"tags": [1]}), json!({"tags": [2]})),
+            json!({"tags": [1]})
+        );
+    }
 }
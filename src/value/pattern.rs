@@ -0,0 +1,151 @@
+//! Patterns of object keys which select parts of a value, like `sessions.*`.
+//!
+//! A pattern is a sequence of object keys separated by dots, where `*`
+//! matches any key. Patterns only pass through objects, never arrays.
+
+use std::{fmt, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use super::Value;
+
+/// A single step of a [`KeyPattern`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PatternSegment {
+    /// Match the field with this key
+    Key(String),
+    /// Match every field
+    Any,
+}
+
+/// A pattern of object keys, like `sessions.*`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPattern {
+    segments: Vec<PatternSegment>,
+}
+
+impl KeyPattern {
+    /// Return the key paths of the fields in the value which match the
+    /// pattern.
+    pub fn matches(&self, value: &Value) -> Vec<Vec<String>> {
+        let mut matches = vec![(Vec::new(), value)];
+
+        for segment in &self.segments {
+            matches = matches
+                .into_iter()
+                .flat_map(|(path, value)| {
+                    let Value::Object(fields) = value else {
+                        return Vec::new();
+                    };
+
+                    fields
+                        .iter()
+                        .filter(|(key, _)| segment.matches(key))
+                        .map(|(key, value)| {
+                            let mut path = path.clone();
+                            path.push(key.clone());
+                            (path, value)
+                        })
+                        .collect()
+                })
+                .collect();
+        }
+
+        matches.into_iter().map(|(path, _)| path).collect()
+    }
+
+    /// Return true if the pattern matches the given path of keys.
+    pub fn matches_path(&self, path: &[impl AsRef<str>]) -> bool {
+        self.segments.len() == path.len()
+            && self
+                .segments
+                .iter()
+                .zip(path)
+                .all(|(segment, key)| segment.matches(key.as_ref()))
+    }
+}
+
+impl PatternSegment {
+    fn matches(&self, key: &str) -> bool {
+        match self {
+            Self::Key(expected) => key == expected,
+            Self::Any => true,
+        }
+    }
+}
+
+impl FromStr for KeyPattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let segments = s
+            .split('.')
+            .map(|key| match key {
+                "" => anyhow::bail!("'{s}' has an empty key"),
+                "*" => Ok(PatternSegment::Any),
+                key => Ok(PatternSegment::Key(key.to_string())),
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { segments })
+    }
+}
+
+impl fmt::Display for KeyPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, segment) in self.segments.iter().enumerate() {
+            if index > 0 {
+                f.write_str(".")?;
+            }
+            match segment {
+                PatternSegment::Key(key) => f.write_str(key)?,
+                PatternSegment::Any => f.write_str("*")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Serialize for KeyPattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyPattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        pattern.parse().map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_patterns() {
+        let pattern: KeyPattern = "sessions.*".parse().unwrap();
+        assert_eq!(pattern.to_string(), "sessions.*");
+
+        let value = Value::from(serde_json::json!({
+            "sessions": {"a": 1, "b": [2]},
+            "other": {"a": 3},
+        }));
+        assert_eq!(
+            pattern.matches(&value),
+            vec![
+                vec!["sessions".to_string(), "a".to_string()],
+                vec!["sessions".to_string(), "b".to_string()],
+            ]
+        );
+
+        assert!(pattern.matches_path(&["sessions", "x"]));
+        assert!(!pattern.matches_path(&["sessions"]));
+        assert!(!pattern.matches_path(&["other", "x"]));
+
+        assert!("a..b".parse::<KeyPattern>().is_err());
+        assert!("".parse::<KeyPattern>().is_err());
+    }
+}
@@ -0,0 +1,280 @@
+//! This module contains the shortening of large values into previews which
+//! are quick to skim, by eliding deeply nested structure and truncating long
+//! strings.
+//!
+//! By default the elided parts are replaced by strings meant for people, like
+//! `{...}`, which cannot be told apart from real data. With
+//! [`Preview::markers`], each one is instead replaced by a marker object like
+//! `{"$wall-a:truncated": {"type": "string", "len": 5000, "prefix": "..."}}`,
+//! which [`Truncation::from_value`] reads back.
+
+use super::Value;
+
+/// The only key of the object which marks a truncated part of a preview, see
+/// [`Truncation`]
+pub const TRUNCATED_KEY: &str = "$wall-a:truncated";
+
+/// The string which replaces an object nested deeper than the limit
+pub const ELIDED_OBJECT: &str = "{...}";
+
+/// The string which replaces an array nested deeper than the limit
+pub const ELIDED_ARRAY: &str = "[...]";
+
+/// The marker added to the end of a truncated string
+pub const ELLIPSIS: &str = "…";
+
+/// The limits of a preview of a value
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Preview {
+    /// The number of levels of arrays and objects to keep, where deeper
+    /// arrays and objects are replaced by [`ELIDED_ARRAY`] and
+    /// [`ELIDED_OBJECT`]
+    pub max_depth: Option<usize>,
+    /// The number of characters to keep of each string, where longer strings
+    /// are cut short and end with [`ELLIPSIS`]
+    pub max_string_len: Option<usize>,
+    /// Whether to replace the truncated parts with [`Truncation`] markers
+    /// instead of the strings meant for people
+    pub markers: bool,
+}
+
+/// A part of a value which was left out of a preview, written as a marker
+/// object with [`TRUNCATED_KEY`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Truncation {
+    /// An array with `len` elements, written like
+    /// `{"$wall-a:truncated": {"type": "array", "len": 12}}`
+    Array { len: usize },
+    /// An object with `len` keys, written like
+    /// `{"$wall-a:truncated": {"type": "object", "len": 3}}`
+    Object { len: usize },
+    /// A string of `len` characters which starts with `prefix`, written like
+    /// `{"$wall-a:truncated": {"type": "string", "len": 5000, "prefix": "ab"}}`
+    String { len: usize, prefix: String },
+}
+
+impl Truncation {
+    /// Return the marker object for the truncated part.
+    pub fn to_value(&self) -> Value {
+        let (type_name, len) = match self {
+            Self::Array { len } => ("array", len),
+            Self::Object { len } => ("object", len),
+            Self::String { len, .. } => ("string", len),
+        };
+
+        let mut fields = vec![
+            ("type".to_string(), Value::String(type_name.into())),
+            ("len".to_string(), Value::Number(len.to_string())),
+        ];
+        if let Self::String { prefix, .. } = self {
+            fields.push(("prefix".to_string(), Value::String(prefix.clone())));
+        }
+
+        Value::Object(vec![(TRUNCATED_KEY.into(), Value::Object(fields))])
+    }
+
+    /// Read a marker object back, returning `None` if the value is not one.
+    pub fn from_value(value: &Value) -> Option<Self> {
+        let Value::Object(fields) = value else {
+            return None;
+        };
+        let [(key, Value::Object(marker))] = fields.as_slice() else {
+            return None;
+        };
+        if key != TRUNCATED_KEY {
+            return None;
+        }
+
+        let field = |name: &str| {
+            marker
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value)
+        };
+        let len = match field("len")? {
+            Value::Number(len) => len.parse().ok()?,
+            _ => return None,
+        };
+
+        match field("type")? {
+            Value::String(type_name) if type_name == "array" => Some(Self::Array { len }),
+            Value::String(type_name) if type_name == "object" => Some(Self::Object { len }),
+            Value::String(type_name) if type_name == "string" => match field("prefix")? {
+                Value::String(prefix) => Some(Self::String {
+                    len,
+                    prefix: prefix.clone(),
+                }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl Preview {
+    /// Return true if the preview has no limits, so it would not change the
+    /// value.
+    pub fn is_empty(&self) -> bool {
+        self.max_depth.is_none() && self.max_string_len.is_none()
+    }
+
+    /// Shorten the value to fit the limits.
+    ///
+    /// A value at the top level has a depth of zero, so a `max_depth` of zero
+    /// elides the whole value if it is an array or object.
+    pub fn apply(&self, value: Value) -> Value {
+        if self.is_empty() {
+            return value;
+        }
+
+        self.apply_at(value, 0)
+    }
+
+    fn apply_at(&self, value: Value, depth: usize) -> Value {
+        let elided = self.max_depth.is_some_and(|max_depth| depth >= max_depth);
+
+        match value {
+            Value::Array(elements) if elided && self.markers => Truncation::Array {
+                len: elements.len(),
+            }
+            .to_value(),
+            Value::Object(fields) if elided && self.markers => {
+                Truncation::Object { len: fields.len() }.to_value()
+            }
+            Value::Array(_) if elided => Value::String(ELIDED_ARRAY.into()),
+            Value::Object(_) if elided => Value::String(ELIDED_OBJECT.into()),
+            Value::Array(elements) => Value::Array(
+                elements
+                    .into_iter()
+                    .map(|element| self.apply_at(element, depth + 1))
+                    .collect(),
+            ),
+            Value::Object(fields) => Value::Object(
+                fields
+                    .into_iter()
+                    .map(|(key, value)| (key, self.apply_at(value, depth + 1)))
+                    .collect(),
+            ),
+            Value::String(string) => match self.max_string_len {
+                Some(max_len) => self.truncate(string, max_len),
+                None => Value::String(string),
+            },
+            value => value,
+        }
+    }
+
+    /// Cut the string short after `max_len` characters, ending it with
+    /// [`ELLIPSIS`] or replacing it with a marker if anything was removed.
+    fn truncate(&self, mut string: String, max_len: usize) -> Value {
+        let Some((index, _)) = string.char_indices().nth(max_len) else {
+            return Value::String(string);
+        };
+
+        if self.markers {
+            let len = string.chars().count();
+            string.truncate(index);
+            return Truncation::String {
+                len,
+                prefix: string,
+            }
+            .to_value();
+        }
+
+        string.truncate(index);
+        string.push_str(ELLIPSIS);
+        Value::String(string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(value: serde_json::Value) -> Value {
+        Value::from(value)
+    }
+
+    #[test]
+    fn preview_values() {
+        let value = json(serde_json::json!({
+            "name": "wall-a",
+            "nested": {"deeper": {"a": 1}, "list": [[1], 2]},
+            "note": "héllo world",
+        }));
+
+        let preview = Preview {
+            max_depth: Some(2),
+            max_string_len: Some(5),
+            markers: false,
+        };
+        assert_eq!(
+            preview.apply(value.clone()),
+            json(serde_json::json!({
+                "name": "wall-…",
+                "nested": {"deeper": "{...}", "list": "[...]"},
+                "note": "héllo…",
+            }))
+        );
+
+        let preview = Preview {
+            max_depth: Some(0),
+            max_string_len: None,
+            markers: false,
+        };
+        assert_eq!(
+            preview.apply(value.clone()),
+            json(serde_json::json!("{...}"))
+        );
+        assert_eq!(Preview::default().apply(value.clone()), value);
+    }
+
+    #[test]
+    fn truncation_markers() {
+        let value = json(serde_json::json!({
+            "nested": {"deeper": {"a": 1, "b": 2}, "list": [1, 2, 3]},
+            "note": "héllo world",
+            "short": "hi",
+        }));
+
+        let preview = Preview {
+            max_depth: Some(2),
+            max_string_len: Some(5),
+            markers: true,
+        };
+        let previewed = preview.apply(value);
+        assert_eq!(
+            previewed,
+            json(serde_json::json!({
+                "nested": {
+                    "deeper": {"$wall-a:truncated": {"type": "object", "len": 2}},
+                    "list": {"$wall-a:truncated": {"type": "array", "len": 3}},
+                },
+                "note": {"$wall-a:truncated": {"type": "string", "len": 11, "prefix": "héllo"}},
+                "short": "hi",
+            }))
+        );
+
+        // The markers read back the same after a trip through JSON
+        let json_text = serde_json::to_string(&previewed).unwrap();
+        let parsed = json(serde_json::from_str(&json_text).unwrap());
+        let Value::Object(fields) = &parsed else {
+            panic!("expected an object");
+        };
+        assert_eq!(
+            Truncation::from_value(&fields[1].1),
+            Some(Truncation::String {
+                len: 11,
+                prefix: "héllo".into()
+            })
+        );
+        assert_eq!(
+            Truncation::from_value(parsed.pointer("/nested/list").unwrap()),
+            Some(Truncation::Array { len: 3 })
+        );
+        assert_eq!(Truncation::from_value(&fields[2].1), None);
+        assert_eq!(
+            Truncation::from_value(&json(serde_json::json!({"$wall-a:truncated": 1}))),
+            None
+        );
+    }
+}
@@ -0,0 +1,328 @@
+//! This module contains the rollups of numbers, which turn the numbers
+//! staged at configured paths into an aggregate of every number merged
+//! there, so that a data directory can be used as a simple metrics store.
+//!
+//! The paths are configured in the `MANIFEST` with `rollup_numbers
+//! <pattern>`. As a record is staged, each number at a matching path is
+//! replaced with a marker like `{"$wall-a:rollup": {"count": 1, "sum": 12,
+//! "min": 12, "max": 12}}`, and two markers are merged by combining their
+//! aggregates. Combining does not depend on how the records were grouped
+//! into archives, so the markers are kept in archives and only resolved into
+//! the read value, which also has the `mean`.
+
+use std::{cmp::Ordering, fmt};
+
+use super::{
+    merge::{MergeMode, MergeSettings},
+    pattern::KeyPattern,
+    Value,
+};
+
+/// The only key of the object which marks a rolled up number, see
+/// [`NumberRollups`]
+pub const ROLLUP_KEY: &str = "$wall-a:rollup";
+
+/// The patterns of keys leading to the numbers which are rolled up, from the
+/// `rollup_numbers` settings
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NumberRollups {
+    pub patterns: Vec<KeyPattern>,
+}
+
+impl NumberRollups {
+    /// Add a pattern, unless it is already present.
+    pub fn insert(&mut self, pattern: KeyPattern) {
+        if !self.patterns.contains(&pattern) {
+            self.patterns.push(pattern);
+        }
+    }
+
+    /// Replace every number in the record at a path matched by a pattern
+    /// with a rollup of just that number. Numbers inside arrays are never
+    /// matched, and with [`MergeMode::Crdt`] the record is returned
+    /// unchanged, since CRDTs may merge the same record more than once.
+    pub fn apply(&self, mut value: Value, merge_settings: MergeSettings) -> Value {
+        if self.patterns.is_empty() || merge_settings.mode == MergeMode::Crdt {
+            return value;
+        }
+
+        self.apply_at(&mut value, &mut Vec::new());
+        value
+    }
+
+    fn apply_at(&self, value: &mut Value, path: &mut Vec<String>) {
+        match value {
+            Value::Object(fields) => {
+                for (key, field) in fields {
+                    path.push(key.clone());
+                    self.apply_at(field, path);
+                    path.pop();
+                }
+            }
+            Value::Number(number)
+                if self
+                    .patterns
+                    .iter()
+                    .any(|pattern| pattern.matches_path(path)) =>
+            {
+                if let Some(rollup) = Rollup::of(number) {
+                    *value = rollup.to_marker();
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Merge two values if both are rollup markers, returning the marker of the
+/// combined rollup, or `None` if they cannot be combined.
+pub fn merge(accum: &Value, value: &Value) -> Option<Value> {
+    let combined = Rollup::from_marker(accum)?.combine(&Rollup::from_marker(value)?)?;
+    Some(combined.to_marker())
+}
+
+/// Replace every rollup marker in the value with its aggregate, an object
+/// with the `count`, `sum`, `min`, `max`, and `mean` of the numbers.
+pub fn resolve(value: Value) -> Value {
+    if let Some(rollup) = Rollup::from_marker(&value) {
+        return rollup.to_aggregate();
+    }
+
+    match value {
+        Value::Array(elements) => Value::Array(elements.into_iter().map(resolve).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, field)| (key, resolve(field)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+/// The aggregate of the numbers merged at a path
+#[derive(Debug, Clone, PartialEq)]
+struct Rollup {
+    count: u64,
+    sum: Number,
+    min: Number,
+    max: Number,
+}
+
+impl Rollup {
+    /// Return the rollup of a single number.
+    fn of(number: &str) -> Option<Self> {
+        let number = Number::parse(number)?;
+        Some(Self {
+            count: 1,
+            sum: number.clone(),
+            min: number.clone(),
+            max: number,
+        })
+    }
+
+    /// Return the combined rollup, or `None` if the sum is too large to
+    /// represent.
+    fn combine(&self, other: &Self) -> Option<Self> {
+        let min = match self.min.compare(&other.min) {
+            Ordering::Greater => &other.min,
+            _ => &self.min,
+        };
+        let max = match self.max.compare(&other.max) {
+            Ordering::Less => &other.max,
+            _ => &self.max,
+        };
+
+        Some(Self {
+            count: self.count.checked_add(other.count)?,
+            sum: self.sum.add(&other.sum)?,
+            min: min.clone(),
+            max: max.clone(),
+        })
+    }
+
+    fn from_marker(value: &Value) -> Option<Self> {
+        let Value::Object(fields) = value else {
+            return None;
+        };
+        let [(key, Value::Object(fields))] = fields.as_slice() else {
+            return None;
+        };
+        if key != ROLLUP_KEY || fields.len() != 4 {
+            return None;
+        }
+
+        let number = |name: &str| match fields.iter().find(|(key, _)| key == name) {
+            Some((_, Value::Number(number))) => Number::parse(number),
+            _ => None,
+        };
+        let Number::Integer(count) = number("count")? else {
+            return None;
+        };
+
+        Some(Self {
+            count: u64::try_from(count).ok()?,
+            sum: number("sum")?,
+            min: number("min")?,
+            max: number("max")?,
+        })
+    }
+
+    fn to_marker(&self) -> Value {
+        Value::Object(vec![(ROLLUP_KEY.to_string(), self.fields())])
+    }
+
+    fn to_aggregate(&self) -> Value {
+        let Value::Object(mut fields) = self.fields() else {
+            unreachable!("the fields of a rollup are an object");
+        };
+        let mean = self.sum.as_f64() / self.count as f64;
+        if let Some(mean) = serde_json::Number::from_f64(mean) {
+            fields.push(("mean".to_string(), Value::Number(mean.to_string())));
+        }
+
+        Value::Object(fields)
+    }
+
+    fn fields(&self) -> Value {
+        Value::Object(vec![
+            ("count".to_string(), Value::Number(self.count.to_string())),
+            ("sum".to_string(), Value::Number(self.sum.to_string())),
+            ("min".to_string(), Value::Number(self.min.to_string())),
+            ("max".to_string(), Value::Number(self.max.to_string())),
+        ])
+    }
+}
+
+/// A number in a rollup, which stays an integer while every number added to
+/// it is one and the sum fits in 64 bits
+#[derive(Debug, Clone, PartialEq)]
+enum Number {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Number {
+    fn parse(number: &str) -> Option<Self> {
+        match number.parse() {
+            Ok(integer) => Some(Self::Integer(integer)),
+            Err(_) => number
+                .parse()
+                .ok()
+                .filter(|float: &f64| float.is_finite())
+                .map(Self::Float),
+        }
+    }
+
+    fn as_f64(&self) -> f64 {
+        match *self {
+            Self::Integer(integer) => integer as f64,
+            Self::Float(float) => float,
+        }
+    }
+
+    fn add(&self, other: &Self) -> Option<Self> {
+        if let (Self::Integer(a), Self::Integer(b)) = (self, other) {
+            if let Some(sum) = a.checked_add(*b) {
+                return Some(Self::Integer(sum));
+            }
+        }
+
+        Some(Self::Float(self.as_f64() + other.as_f64())).filter(|sum| sum.as_f64().is_finite())
+    }
+
+    fn compare(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (Self::Integer(a), Self::Integer(b)) => a.cmp(b),
+            _ => self.as_f64().total_cmp(&other.as_f64()),
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Integer(integer) => write!(f, "{integer}"),
+            Self::Float(float) => match serde_json::Number::from_f64(*float) {
+                Some(float) => write!(f, "{float}"),
+                None => unreachable!("rollups only contain finite numbers"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rollups(patterns: &[&str]) -> NumberRollups {
+        let mut rollups = NumberRollups::default();
+        for pattern in patterns {
+            rollups.insert(pattern.parse().unwrap());
+        }
+        rollups
+    }
+
+    #[test]
+    fn merge_rollups() {
+        let rollups = rollups(&["latency.*", "errors"]);
+        let settings = MergeSettings::default();
+        let records = [
+            json!({"latency": {"get": 12, "put": 3.5}, "errors": 1, "host": 7}),
+            json!({"latency": {"get": 4}, "errors": "none", "host": 8}),
+            json!({"latency": {"get": 20, "put": -1}, "errors": 2, "tags": [3]}),
+        ];
+
+        let merged = records
+            .into_iter()
+            .map(|record| rollups.apply(Value::from(record), settings))
+            .reduce(|accum, value| settings.merge(accum, value))
+            .unwrap();
+        let resolved = settings.resolve(merged.clone()).unwrap();
+
+        assert_eq!(
+            resolved,
+            Value::from(json!({
+                "latency": {
+                    "get": {"count": 3, "sum": 36, "min": 4, "max": 20, "mean": 12.0},
+                    "put": {"count": 2, "sum": 2.5, "min": -1, "max": 3.5, "mean": 1.25},
+                },
+                // A value which is not a number replaces the rollup, which
+                // starts again from the next number
+                "errors": {"count": 1, "sum": 2, "min": 2, "max": 2, "mean": 2.0},
+                "host": 8,
+                "tags": [3],
+            }))
+        );
+
+        // Rollups are combined the same way however the records are grouped
+        let again = settings.merge(
+            rollups.apply(Value::from(json!({"latency": {"get": 1}})), settings),
+            merged,
+        );
+        assert_eq!(
+            settings.resolve(again).unwrap().pointer("/latency/get"),
+            Some(&Value::from(
+                json!({"count": 4, "sum": 37, "min": 1, "max": 20, "mean": 9.25})
+            ))
+        );
+
+        // An integer sum which overflows continues as a float
+        let big = Rollup::of(&i64::MAX.to_string()).unwrap();
+        assert_eq!(
+            big.combine(&big).unwrap().sum,
+            Number::Float(2.0 * i64::MAX as f64)
+        );
+
+        let crdt = MergeSettings {
+            mode: MergeMode::Crdt,
+            ..MergeSettings::default()
+        };
+        assert_eq!(
+            rollups.apply(Value::from(json!({"errors": 1})), crdt),
+            Value::from(json!({"errors": 1}))
+        );
+    }
+}
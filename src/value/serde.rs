@@ -2,11 +2,11 @@ use std::{fmt, result, string::String, vec::Vec};
 
 use indexmap::IndexMap;
 use serde::{
-    de::{Deserialize, MapAccess, SeqAccess, Visitor},
+    de::{self, Deserialize, DeserializeSeed, MapAccess, SeqAccess, Visitor},
     ser::Serialize,
 };
 
-use super::Value;
+use super::{Value, DEFAULT_MAX_DEPTH};
 
 impl<'de> Deserialize<'de> for Value {
     #[inline]
@@ -14,97 +14,145 @@ impl<'de> Deserialize<'de> for Value {
     where
         D: serde::Deserializer<'de>,
     {
-        struct ValueVisitor;
+        Value::deserialize_with_max_depth(deserializer, DEFAULT_MAX_DEPTH)
+    }
+}
 
-        impl<'de> Visitor<'de> for ValueVisitor {
-            type Value = Value;
+impl Value {
+    /// Deserialize a value, returning an error if arrays and objects are nested
+    /// more than `max_depth` levels deep.
+    pub fn deserialize_with_max_depth<'de, D>(
+        deserializer: D,
+        max_depth: usize,
+    ) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        ValueSeed {
+            remaining_depth: max_depth,
+        }
+        .deserialize(deserializer)
+    }
+}
 
-            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-                formatter.write_str("any valid JSON value")
-            }
+/// This struct deserializes a [`Value`] while tracking how many more levels of
+/// nesting are allowed.
+#[derive(Debug, Copy, Clone)]
+struct ValueSeed {
+    remaining_depth: usize,
+}
 
-            #[inline]
-            fn visit_bool<E>(self, value: bool) -> Result<Self::Value, E> {
-                Ok(Value::Bool(value))
-            }
+impl ValueSeed {
+    /// Return the seed for the elements of an array or object, or an error if
+    /// no more nesting is allowed.
+    fn nested<E: de::Error>(self) -> Result<Self, E> {
+        match self.remaining_depth.checked_sub(1) {
+            Some(remaining_depth) => Ok(Self { remaining_depth }),
+            None => Err(E::custom("exceeded the maximum nesting depth")),
+        }
+    }
+}
 
-            #[inline]
-            fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E> {
-                Ok(Value::Number(value.to_string()))
-            }
+impl<'de> DeserializeSeed<'de> for ValueSeed {
+    type Value = Value;
 
-            #[inline]
-            fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E> {
-                Ok(Value::Number(value.to_string()))
-            }
+    #[inline]
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(self)
+    }
+}
 
-            #[inline]
-            fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E> {
-                Ok(Value::Number(value.to_string()))
-            }
+impl<'de> Visitor<'de> for ValueSeed {
+    type Value = Value;
 
-            #[inline]
-            fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
-            where
-                E: serde::de::Error,
-            {
-                self.visit_string(String::from(value))
-            }
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("any valid JSON value")
+    }
 
-            #[inline]
-            fn visit_string<E>(self, value: String) -> Result<Self::Value, E> {
-                Ok(Value::String(value))
-            }
+    #[inline]
+    fn visit_bool<E>(self, value: bool) -> Result<Self::Value, E> {
+        Ok(Value::Bool(value))
+    }
 
-            #[inline]
-            fn visit_none<E>(self) -> Result<Self::Value, E> {
-                Ok(Value::Null)
-            }
+    #[inline]
+    fn visit_i64<E>(self, value: i64) -> Result<Self::Value, E> {
+        Ok(Value::Number(value.to_string()))
+    }
 
-            #[inline]
-            fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
-            where
-                D: serde::Deserializer<'de>,
-            {
-                Deserialize::deserialize(deserializer)
-            }
+    #[inline]
+    fn visit_u64<E>(self, value: u64) -> Result<Self::Value, E> {
+        Ok(Value::Number(value.to_string()))
+    }
 
-            #[inline]
-            fn visit_unit<E>(self) -> Result<Self::Value, E> {
-                Ok(Value::Null)
-            }
+    #[inline]
+    fn visit_f64<E>(self, value: f64) -> Result<Self::Value, E> {
+        Ok(Value::Number(value.to_string()))
+    }
 
-            #[inline]
-            fn visit_seq<V>(self, mut visitor: V) -> Result<Self::Value, V::Error>
-            where
-                V: SeqAccess<'de>,
-            {
-                let mut vec = Vec::new();
+    #[inline]
+    fn visit_str<E>(self, value: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        self.visit_string(String::from(value))
+    }
 
-                while let Some(elem) = visitor.next_element()? {
-                    vec.push(elem);
-                }
+    #[inline]
+    fn visit_string<E>(self, value: String) -> Result<Self::Value, E> {
+        Ok(Value::String(value))
+    }
 
-                Ok(Value::Array(vec))
-            }
+    #[inline]
+    fn visit_none<E>(self) -> Result<Self::Value, E> {
+        Ok(Value::Null)
+    }
 
-            fn visit_map<V>(self, mut visitor: V) -> Result<Self::Value, V::Error>
-            where
-                V: MapAccess<'de>,
-            {
-                let mut map = IndexMap::with_capacity(visitor.size_hint().unwrap_or(0));
+    #[inline]
+    fn visit_some<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        self.deserialize(deserializer)
+    }
 
-                // While there are entries remaining in the input, add them
-                // into our map.
-                while let Some((key, value)) = visitor.next_entry()? {
-                    map.insert(key, value);
-                }
+    #[inline]
+    fn visit_unit<E>(self) -> Result<Self::Value, E> {
+        Ok(Value::Null)
+    }
 
-                Ok(Value::Object(map.into_iter().collect::<Vec<_>>()))
-            }
+    #[inline]
+    fn visit_seq<V>(self, mut visitor: V) -> Result<Self::Value, V::Error>
+    where
+        V: SeqAccess<'de>,
+    {
+        let seed = self.nested()?;
+        let mut vec = Vec::new();
+
+        while let Some(elem) = visitor.next_element_seed(seed)? {
+            vec.push(elem);
         }
 
-        deserializer.deserialize_any(ValueVisitor)
+        Ok(Value::Array(vec))
+    }
+
+    fn visit_map<V>(self, mut visitor: V) -> Result<Self::Value, V::Error>
+    where
+        V: MapAccess<'de>,
+    {
+        let seed = self.nested()?;
+        let mut map = IndexMap::with_capacity(visitor.size_hint().unwrap_or(0));
+
+        // While there are entries remaining in the input, add them
+        // into our map.
+        while let Some(key) = visitor.next_key::<String>()? {
+            let value = visitor.next_value_seed(seed)?;
+            map.insert(key, value);
+        }
+
+        Ok(Value::Object(map.into_iter().collect::<Vec<_>>()))
     }
 }
 
@@ -117,7 +165,12 @@ impl Serialize for Value {
         match self {
             Value::Null => serializer.serialize_unit(),
             Value::Bool(b) => serializer.serialize_bool(*b),
-            Value::Number(n) => n.serialize(serializer),
+            // Write numbers as numbers, only falling back to a string if the
+            // stored text is somehow not a valid JSON number
+            Value::Number(n) => match n.parse::<serde_json::Number>() {
+                Ok(number) => number.serialize(serializer),
+                Err(_) => n.serialize(serializer),
+            },
             Value::String(s) => serializer.serialize_str(s),
             Value::Array(v) => v.serialize(serializer),
             Value::Object(m) => {
@@ -132,3 +185,101 @@ impl Serialize for Value {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nested_arrays(depth: usize) -> String {
+        format!("{}{}", "[".repeat(depth), "]".repeat(depth))
+    }
+
+    #[test]
+    fn numbers_serialize_as_numbers() {
+        let value = crate::value::from_json_slice(br#"{"a": 1, "b": -0.5, "c": 1e3}"#, 2).unwrap();
+        assert_eq!(
+            serde_json::to_string(&value).unwrap(),
+            r#"{"a":1,"b":-0.5,"c":1000}"#
+        );
+    }
+
+    #[test]
+    fn staged_numbers_written_as_strings_read_back() {
+        // Staging files written before numbers were written as numbers hold
+        // them as strings, which still read back as the strings they were
+        let line = r#"{"a":"1","b":["-0.5"]}"#;
+        let value = crate::value::from_json_slice(line.as_bytes(), 3).unwrap();
+        assert_eq!(
+            value,
+            Value::Object(vec![
+                ("a".into(), Value::String("1".into())),
+                ("b".into(), Value::Array(vec![Value::String("-0.5".into())])),
+            ])
+        );
+        assert_eq!(serde_json::to_string(&value).unwrap(), line);
+    }
+
+    #[test]
+    fn deserialize_within_max_depth() {
+        let value = crate::value::from_json_slice(nested_arrays(3).as_bytes(), 3).unwrap();
+        assert_eq!(
+            value,
+            Value::Array(vec![Value::Array(vec![Value::Array(vec![])])])
+        );
+
+        let value = crate::value::from_json_slice(br#"{"a": {"b": 1}}"#, 2).unwrap();
+        assert_eq!(
+            value,
+            Value::Object(vec![(
+                "a".into(),
+                Value::Object(vec![("b".into(), Value::Number("1".into()))])
+            )])
+        );
+
+        assert_eq!(
+            crate::value::from_json_slice(b"\"scalar\"", 0).unwrap(),
+            Value::String("scalar".into())
+        );
+
+        // Deeper than the recursion limit of serde_json
+        for depth in [DEFAULT_MAX_DEPTH, 200] {
+            let value =
+                crate::value::from_json_slice(nested_arrays(depth).as_bytes(), 200).unwrap();
+            let mut levels = 0;
+            let mut value = &value;
+            while let Value::Array(elements) = value {
+                levels += 1;
+                match elements.as_slice() {
+                    [element] => value = element,
+                    _ => break,
+                }
+            }
+            assert_eq!(levels, depth);
+        }
+    }
+
+    #[test]
+    fn deserialize_past_max_depth() {
+        let err = crate::value::from_json_slice(nested_arrays(4).as_bytes(), 3).unwrap_err();
+        assert!(err.to_string().contains("maximum nesting depth"), "{err}");
+
+        let err = crate::value::from_json_slice(br#"{"a": {"b": 1}}"#, 1).unwrap_err();
+        assert!(err.to_string().contains("maximum nesting depth"), "{err}");
+
+        let err = crate::value::from_json_slice(nested_arrays(201).as_bytes(), 200).unwrap_err();
+        assert!(err.to_string().contains("maximum nesting depth"), "{err}");
+    }
+
+    #[test]
+    fn errors_include_path_and_position() {
+        let err = crate::value::from_json_slice(br#"{"a": [1, {"b": tru}]}"#, 4).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "a[1].b: expected ident at line 1 column 20"
+        );
+        assert_eq!(err.inner().column(), 20);
+
+        let err = crate::value::from_json_slice(br#"{"a": 1} x"#, 4).unwrap_err();
+        assert_eq!(err.to_string(), "trailing characters at line 1 column 10");
+    }
+}
@@ -0,0 +1,261 @@
+//! This module contains the depth-first walk over the parts of a value, where
+//! a [`Visitor`] is shown each part along with the path to it from the root,
+//! so that redacting, truncating, or pruning values and collecting stats
+//! about them do not each need their own recursion.
+
+use std::fmt;
+
+use super::Value;
+
+/// A single step of the path from the root of a value to one of its parts
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PathStep<'a> {
+    /// The field with this key of an object
+    Key(&'a str),
+    /// The element at this index of an array
+    Index(usize),
+}
+
+impl fmt::Display for PathStep<'_> {
+    /// Write the step like it appears in a path, `.key` or `[0]`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Key(key) => write!(f, ".{key}"),
+            Self::Index(index) => write!(f, "[{index}]"),
+        }
+    }
+}
+
+/// What the walk does after a part of the value was visited
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Walk {
+    /// Go on to the elements or fields of the part, then the parts after it
+    #[default]
+    Continue,
+    /// Leave out the elements or fields of the part, and go on to the parts
+    /// after it
+    Skip,
+    /// End the walk
+    Stop,
+}
+
+/// A visitor of the parts of a value, see [`Value::walk`]
+pub trait Visitor {
+    /// Visit a part of the value at the given path, before its elements or
+    /// fields.
+    fn visit(&mut self, path: &[PathStep<'_>], value: &Value) -> Walk;
+}
+
+impl<F> Visitor for F
+where
+    F: FnMut(&[PathStep<'_>], &Value) -> Walk,
+{
+    fn visit(&mut self, path: &[PathStep<'_>], value: &Value) -> Walk {
+        self(path, value)
+    }
+}
+
+/// A visitor which may modify the parts of a value, see [`Value::walk_mut`]
+pub trait VisitorMut {
+    /// Visit a part of the value at the given path, before its elements or
+    /// fields. The part may be changed or replaced, and the walk goes on to
+    /// the elements or fields it has afterwards.
+    fn visit_mut(&mut self, path: &[PathStep<'_>], value: &mut Value) -> Walk;
+}
+
+impl<F> VisitorMut for F
+where
+    F: FnMut(&[PathStep<'_>], &mut Value) -> Walk,
+{
+    fn visit_mut(&mut self, path: &[PathStep<'_>], value: &mut Value) -> Walk {
+        self(path, value)
+    }
+}
+
+impl Value {
+    /// Visit this value and every part of it, depth first, where each part
+    /// is visited before its elements or fields, which are visited in order.
+    pub fn walk(&self, visitor: &mut impl Visitor) {
+        walk(self, &mut Vec::new(), visitor);
+    }
+
+    /// Like [`Value::walk`], but the visitor may modify each part before its
+    /// elements or fields are visited, like removing the fields which should
+    /// not be visited.
+    pub fn walk_mut(&mut self, visitor: &mut impl VisitorMut) {
+        walk_mut(self, &mut Vec::new(), visitor);
+    }
+}
+
+/// Walk the value at the path, returning false if the walk was stopped.
+fn walk<'v>(value: &'v Value, path: &mut Vec<PathStep<'v>>, visitor: &mut impl Visitor) -> bool {
+    match visitor.visit(path, value) {
+        Walk::Continue => {}
+        Walk::Skip => return true,
+        Walk::Stop => return false,
+    }
+
+    match value {
+        Value::Array(elements) => {
+            for (index, element) in elements.iter().enumerate() {
+                path.push(PathStep::Index(index));
+                let walked = walk(element, path, visitor);
+                path.pop();
+                if !walked {
+                    return false;
+                }
+            }
+        }
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                path.push(PathStep::Key(key));
+                let walked = walk(value, path, visitor);
+                path.pop();
+                if !walked {
+                    return false;
+                }
+            }
+        }
+        _ => {}
+    }
+
+    true
+}
+
+/// Walk the value at the path, returning false if the walk was stopped.
+fn walk_mut<'v>(
+    value: &'v mut Value,
+    path: &mut Vec<PathStep<'v>>,
+    visitor: &mut impl VisitorMut,
+) -> bool {
+    match visitor.visit_mut(path, value) {
+        Walk::Continue => {}
+        Walk::Skip => return true,
+        Walk::Stop => return false,
+    }
+
+    match value {
+        Value::Array(elements) => {
+            for (index, element) in elements.iter_mut().enumerate() {
+                path.push(PathStep::Index(index));
+                let walked = walk_mut(element, path, visitor);
+                path.pop();
+                if !walked {
+                    return false;
+                }
+            }
+        }
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                path.push(PathStep::Key(key));
+                let walked = walk_mut(value, path, visitor);
+                path.pop();
+                if !walked {
+                    return false;
+                }
+            }
+        }
+        _ => {}
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn display_path(path: &[PathStep<'_>]) -> String {
+        path.iter().map(PathStep::to_string).collect()
+    }
+
+    #[test]
+    fn walk_parts() {
+        let value = Value::from(serde_json::json!({
+            "a": {"b": [1, {"c": true}]},
+            "d": {"e": "x"},
+            "f": null,
+        }));
+
+        let mut paths = Vec::new();
+        value.walk(&mut |path: &[PathStep<'_>], value: &Value| {
+            paths.push(format!("{} {}", display_path(path), value.type_name()));
+            match path {
+                [PathStep::Key("d")] => Walk::Skip,
+                _ => Walk::Continue,
+            }
+        });
+        assert_eq!(
+            paths,
+            [
+                " object",
+                ".a object",
+                ".a.b array",
+                ".a.b[0] number",
+                ".a.b[1] object",
+                ".a.b[1].c bool",
+                ".d object",
+                ".f null",
+            ]
+        );
+
+        let mut visited = 0;
+        value.walk(&mut |path: &[PathStep<'_>], _: &Value| {
+            visited += 1;
+            if path.len() == 3 {
+                Walk::Stop
+            } else {
+                Walk::Continue
+            }
+        });
+        assert_eq!(visited, 4);
+    }
+
+    #[test]
+    fn walk_mut_parts() {
+        let mut value = Value::from(serde_json::json!({
+            "user": {"password": "hunter2", "name": "a"},
+            "sessions": [{"password": "x", "token": "y"}],
+            "password": {"nested": "z"},
+        }));
+
+        // Redact every password, without visiting inside the replaced parts,
+        // and drop every token before it is visited
+        let mut visited = Vec::new();
+        value.walk_mut(&mut |path: &[PathStep<'_>], value: &mut Value| {
+            visited.push(display_path(path));
+            if let Value::Object(fields) = value {
+                fields.retain(|(key, _)| key != "token");
+            }
+            match path.last() {
+                Some(PathStep::Key("password")) => {
+                    *value = Value::String("<redacted>".into());
+                    Walk::Skip
+                }
+                _ => Walk::Continue,
+            }
+        });
+
+        assert_eq!(
+            value,
+            Value::from(serde_json::json!({
+                "user": {"password": "<redacted>", "name": "a"},
+                "sessions": [{"password": "<redacted>"}],
+                "password": "<redacted>",
+            }))
+        );
+        assert_eq!(
+            visited,
+            [
+                "",
+                ".user",
+                ".user.password",
+                ".user.name",
+                ".sessions",
+                ".sessions[0]",
+                ".sessions[0].password",
+                ".password",
+            ]
+        );
+    }
+}
@@ -0,0 +1,549 @@
+//! This module contains the implementation of the `verify` CLI command
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+
+use crate::{
+    archive::{archive_name, list_archive_files, verify_archive_checksum},
+    checksums::{ChecksumEntry, ChecksumManifest},
+    format::check_archive,
+    human::{ColorChoice, Style},
+    output::Output,
+    value::DEFAULT_MAX_DEPTH,
+};
+
+/// The `verify` sub-command checks the integrity of every archive file and
+/// cross-checks the archive directory against the `CHECKSUMS` manifest.
+///
+/// When stdout is a terminal each archive is marked with a green check or a
+/// red cross, followed by a summary of the problems found. With the global
+/// `--output json` or `--report`, the findings are written as a JSON object
+/// for automation, with the status, expected and actual checksum and size,
+/// and problem of each archive.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "verify")]
+pub struct VerifyCommand {
+    /// also decode each archive and check that the value encodes back to a
+    /// body of the same length, catching bodies that were written wrongly
+    /// but still match their checksums.
+    #[argh(switch)]
+    deep: bool,
+    /// with --deep, check that the value encodes back to exactly the same
+    /// bytes as the body.
+    #[argh(switch)]
+    round_trip: bool,
+    /// with --deep, the maximum number of levels that arrays and objects may
+    /// be nested in the archived values.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+    /// whether to write the report for humans and in color, either "auto"
+    /// (the default) when stdout is a terminal, "always", or "never" for a
+    /// report without color.
+    #[argh(option, default = "ColorChoice::Auto")]
+    color: ColorChoice,
+    /// also write the JSON report to this file, which is written even when
+    /// problems are found.
+    #[argh(option)]
+    report: Option<PathBuf>,
+}
+
+impl VerifyCommand {
+    /// This function executes the verify command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf, output: Output) -> anyhow::Result<()> {
+        if self.round_trip && !self.deep {
+            anyhow::bail!("--round-trip requires --deep");
+        }
+
+        let deep = self.deep.then_some(DeepCheck {
+            max_depth: self.max_nesting_depth,
+            round_trip: self.round_trip,
+        });
+        let findings = verify_data_dir(&data_dir, deep)?;
+
+        if let Some(report_path) = &self.report {
+            let mut report = serde_json::to_vec(&json_report(&data_dir, &findings))
+                .context("converting verify report to JSON")?;
+            report.push(b'\n');
+            fs::write(report_path, report)
+                .with_context(|| format!("writing verify report to '{}'", report_path.display()))?;
+        }
+
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+
+        if output.is_json() {
+            serde_json::to_writer(&mut handle, &json_report(&data_dir, &findings))
+                .context("writing verify report to stdout")?;
+            writeln!(handle)
+        } else {
+            match self.color.stdout_style() {
+                Some(style) => write_human_report(&mut handle, &findings, style),
+                None => findings
+                    .iter()
+                    .try_for_each(|finding| writeln!(handle, "{finding}")),
+            }
+        }
+        .context("writing verify report to stdout")?;
+
+        let num_problems = findings
+            .iter()
+            .filter(|finding| finding.is_problem())
+            .count();
+        if num_problems > 0 {
+            anyhow::bail!("found {num_problems} problem(s) in the data directory");
+        }
+
+        Ok(())
+    }
+}
+
+/// The result of checking a single archive file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Finding {
+    /// The archive is intact and matches the manifest, which is `expected`
+    /// unless there is no manifest
+    Ok {
+        name: String,
+        expected: Option<ChecksumEntry>,
+        actual: ChecksumEntry,
+    },
+    /// The archive body does not match its own checksum, or could not be
+    /// read, with what the manifest expected if it lists the archive
+    Corrupt {
+        name: String,
+        error: String,
+        expected: Option<ChecksumEntry>,
+    },
+    /// The archive is intact, but is not listed in the manifest
+    Foreign { name: String, actual: ChecksumEntry },
+    /// The archive is listed in the manifest, but is not present
+    Missing {
+        name: String,
+        expected: ChecksumEntry,
+    },
+    /// The archive is intact, but its checksum or size differ from the
+    /// manifest
+    Mismatch {
+        name: String,
+        expected: ChecksumEntry,
+        actual: ChecksumEntry,
+    },
+}
+
+impl Finding {
+    /// Return true if this finding is a problem with the data directory.
+    pub fn is_problem(&self) -> bool {
+        !matches!(self, Finding::Ok { .. })
+    }
+
+    /// Return the kind of the finding, like `ok` or `corrupt`.
+    pub fn status(&self) -> &'static str {
+        match self {
+            Finding::Ok { .. } => "ok",
+            Finding::Corrupt { .. } => "corrupt",
+            Finding::Foreign { .. } => "foreign",
+            Finding::Missing { .. } => "missing",
+            Finding::Mismatch { .. } => "mismatch",
+        }
+    }
+
+    /// Return the filename of the archive.
+    pub fn name(&self) -> &str {
+        match self {
+            Finding::Ok { name, .. }
+            | Finding::Corrupt { name, .. }
+            | Finding::Foreign { name, .. }
+            | Finding::Missing { name, .. }
+            | Finding::Mismatch { name, .. } => name,
+        }
+    }
+
+    /// Return the checksum and size listed in the manifest, if any.
+    pub fn expected(&self) -> Option<ChecksumEntry> {
+        match self {
+            Finding::Ok { expected, .. } | Finding::Corrupt { expected, .. } => *expected,
+            Finding::Foreign { .. } => None,
+            Finding::Missing { expected, .. } | Finding::Mismatch { expected, .. } => {
+                Some(*expected)
+            }
+        }
+    }
+
+    /// Return the checksum and size of the archive file, if it is intact.
+    pub fn actual(&self) -> Option<ChecksumEntry> {
+        match self {
+            Finding::Ok { actual, .. }
+            | Finding::Foreign { actual, .. }
+            | Finding::Mismatch { actual, .. } => Some(*actual),
+            Finding::Corrupt { .. } | Finding::Missing { .. } => None,
+        }
+    }
+
+    /// Return the finding as a JSON object, leaving out the checksums and
+    /// problem which it does not have.
+    pub fn to_json(&self) -> serde_json::Value {
+        let entry = |entry: ChecksumEntry| {
+            serde_json::json!({
+                "checksum": format!("{:08x}", entry.checksum),
+                "bytes": entry.len,
+            })
+        };
+
+        let mut finding = serde_json::json!({
+            "name": self.name(),
+            "status": self.status(),
+        });
+        if let Some(expected) = self.expected() {
+            finding["expected"] = entry(expected);
+        }
+        if let Some(actual) = self.actual() {
+            finding["actual"] = entry(actual);
+        }
+        if let Some(problem) = self.problem() {
+            finding["problem"] = problem.into();
+        }
+
+        finding
+    }
+
+    /// Return what is wrong with the archive, or `None` if it is intact.
+    pub fn problem(&self) -> Option<String> {
+        match self {
+            Finding::Ok { .. } => None,
+            Finding::Corrupt { error, .. } => Some(error.clone()),
+            Finding::Foreign { .. } => Some("not listed in CHECKSUMS".to_string()),
+            Finding::Missing { .. } => Some("listed in CHECKSUMS but not present".to_string()),
+            Finding::Mismatch {
+                expected, actual, ..
+            } => Some(format!(
+                "expected checksum {:08x} and size {}, found checksum {:08x} and size {}",
+                expected.checksum, expected.len, actual.checksum, actual.len
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:<10}{}", self.status(), self.name())?;
+        match self.problem() {
+            Some(problem) => write!(f, ": {problem}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Write each finding with a green check or a red cross, followed by the
+/// number of intact archives and problems.
+fn write_human_report(
+    mut writer: impl Write,
+    findings: &[Finding],
+    style: Style,
+) -> io::Result<()> {
+    let name_width = findings
+        .iter()
+        .map(|finding| finding.name().len())
+        .max()
+        .unwrap_or(0);
+    for finding in findings {
+        let status = format!("{:<8}", finding.status());
+        let (mark, status) = if finding.is_problem() {
+            (style.red("✗"), style.red(&status))
+        } else {
+            (style.green("✓"), style.green(&status))
+        };
+        match finding.problem() {
+            Some(problem) => writeln!(
+                writer,
+                "{mark} {status}  {:<name_width$}  {problem}",
+                finding.name()
+            )?,
+            None => writeln!(writer, "{mark} {status}  {}", finding.name())?,
+        }
+    }
+
+    let num_problems = findings
+        .iter()
+        .filter(|finding| finding.is_problem())
+        .count();
+    let num_ok = findings.len() - num_problems;
+    let summary = format!("{num_ok} archive(s) ok, {num_problems} problem(s)");
+    if num_problems > 0 {
+        writeln!(writer, "{}", style.red(&summary))
+    } else {
+        writeln!(writer, "{}", style.green(&summary))
+    }
+}
+
+/// Return the report of the findings in the data directory as a JSON object,
+/// with the number of intact archives and problems.
+fn json_report(data_dir: &Path, findings: &[Finding]) -> serde_json::Value {
+    let num_problems = findings
+        .iter()
+        .filter(|finding| finding.is_problem())
+        .count();
+
+    serde_json::json!({
+        "data_dir": data_dir.display().to_string(),
+        "ok": findings.len() - num_problems,
+        "problems": num_problems,
+        "archives": findings.iter().map(Finding::to_json).collect::<Vec<_>>(),
+    })
+}
+
+/// The checks of the decoded value of each archive made by `verify --deep`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeepCheck {
+    /// The maximum nesting depth of the archived values
+    pub max_depth: usize,
+    /// Whether the value must encode back to exactly the same bytes
+    pub round_trip: bool,
+}
+
+impl DeepCheck {
+    /// Check the archive file at the given path, returning its checksum and
+    /// length like [`verify_archive_checksum`].
+    fn check(&self, archive_path: &Path) -> anyhow::Result<ChecksumEntry> {
+        let bytes = fs::read(archive_path).context("reading archive file")?;
+        let metadata = check_archive(&bytes, self.max_depth, self.round_trip)?;
+
+        Ok(ChecksumEntry {
+            checksum: metadata.checksum(),
+            len: bytes.len() as u64,
+        })
+    }
+}
+
+/// Check every archive file in the data directory, then compare the set of
+/// archive files against the manifest.
+///
+/// If there is no manifest, then only the archive checksums are checked. With
+/// a [`DeepCheck`], each archive is also decoded.
+pub fn verify_data_dir(data_dir: &Path, deep: Option<DeepCheck>) -> anyhow::Result<Vec<Finding>> {
+    let manifest = ChecksumManifest::read(data_dir)?;
+    if manifest.is_none() {
+        tracing::warn!("No CHECKSUMS file present, only verifying archive checksums");
+    }
+
+    let mut findings = Vec::new();
+    let mut scratch_buffer = Vec::new();
+    let mut present = BTreeSet::new();
+
+    for path in list_archive_files(data_dir)? {
+        scratch_buffer.clear();
+        let name = archive_name(&path);
+        let expected = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.get(&name))
+            .copied();
+
+        let result = match &deep {
+            Some(deep) => deep.check(&path),
+            None => verify_archive_checksum(&path, &mut scratch_buffer),
+        };
+        let actual = match result {
+            Ok(actual) => actual,
+            Err(err) => {
+                findings.push(Finding::Corrupt {
+                    name: name.clone(),
+                    error: format!("{err:#}"),
+                    expected,
+                });
+                present.insert(name);
+                continue;
+            }
+        };
+
+        let finding = match (&manifest, expected) {
+            (Some(_), None) => Finding::Foreign {
+                name: name.clone(),
+                actual,
+            },
+            (_, Some(expected)) if expected != actual => Finding::Mismatch {
+                name: name.clone(),
+                expected,
+                actual,
+            },
+            (_, expected) => Finding::Ok {
+                name: name.clone(),
+                expected,
+                actual,
+            },
+        };
+        findings.push(finding);
+        present.insert(name);
+    }
+
+    if let Some(manifest) = &manifest {
+        for (name, expected) in manifest.iter() {
+            if !present.contains(name) {
+                findings.push(Finding::Missing {
+                    name: name.to_string(),
+                    expected: *expected,
+                });
+            }
+        }
+    }
+
+    Ok(findings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn findings() -> Vec<Finding> {
+        vec![
+            Finding::Ok {
+                name: "2024-06-19-19-22-45.bin".into(),
+                expected: None,
+                actual: ChecksumEntry {
+                    checksum: 0xabc,
+                    len: 64,
+                },
+            },
+            Finding::Missing {
+                name: "2024-06-20-19-22-45.bin".into(),
+                expected: ChecksumEntry {
+                    checksum: 0x1,
+                    len: 10,
+                },
+            },
+            Finding::Mismatch {
+                name: "2024-06-21-19-22-45.bin".into(),
+                expected: ChecksumEntry {
+                    checksum: 0xdead_beef,
+                    len: 100,
+                },
+                actual: ChecksumEntry {
+                    checksum: 0x1234,
+                    len: 90,
+                },
+            },
+        ]
+    }
+
+    #[test]
+    fn plain_findings() {
+        let lines = findings()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "ok        2024-06-19-19-22-45.bin",
+                "missing   2024-06-20-19-22-45.bin: listed in CHECKSUMS but not present",
+                "mismatch  2024-06-21-19-22-45.bin: expected checksum deadbeef and size 100, \
+                 found checksum 00001234 and size 90",
+            ]
+        );
+    }
+
+    #[test]
+    fn human_report() {
+        let mut output = Vec::new();
+        write_human_report(&mut output, &findings(), Style { color: false }).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "✓ ok        2024-06-19-19-22-45.bin\n\
+             ✗ missing   2024-06-20-19-22-45.bin  listed in CHECKSUMS but not present\n\
+             ✗ mismatch  2024-06-21-19-22-45.bin  expected checksum deadbeef and size 100, found \
+             checksum 00001234 and size 90\n\
+             1 archive(s) ok, 2 problem(s)\n"
+        );
+
+        let mut output = Vec::new();
+        write_human_report(&mut output, &findings()[..1], Style { color: true }).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\x1b[32m✓\x1b[0m \x1b[32mok      \x1b[0m  2024-06-19-19-22-45.bin\n\
+             \x1b[32m1 archive(s) ok, 0 problem(s)\x1b[0m\n"
+        );
+    }
+
+    #[test]
+    fn json_findings() {
+        let report = json_report(Path::new("data"), &findings());
+        assert_eq!(
+            report,
+            serde_json::json!({
+                "data_dir": "data",
+                "ok": 1,
+                "problems": 2,
+                "archives": [
+                    {
+                        "name": "2024-06-19-19-22-45.bin",
+                        "status": "ok",
+                        "actual": {"checksum": "00000abc", "bytes": 64},
+                    },
+                    {
+                        "name": "2024-06-20-19-22-45.bin",
+                        "status": "missing",
+                        "expected": {"checksum": "00000001", "bytes": 10},
+                        "problem": "listed in CHECKSUMS but not present",
+                    },
+                    {
+                        "name": "2024-06-21-19-22-45.bin",
+                        "status": "mismatch",
+                        "expected": {"checksum": "deadbeef", "bytes": 100},
+                        "actual": {"checksum": "00001234", "bytes": 90},
+                        "problem": "expected checksum deadbeef and size 100, found checksum \
+                                    00001234 and size 90",
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn json_corrupt_and_foreign_findings() {
+        let findings = [
+            Finding::Corrupt {
+                name: "2024-06-19-19-22-45.bin".into(),
+                error: "block 0 does not match its checksum".into(),
+                expected: Some(ChecksumEntry {
+                    checksum: 0xabc,
+                    len: 64,
+                }),
+            },
+            Finding::Foreign {
+                name: "2024-06-20-19-22-45.bin".into(),
+                actual: ChecksumEntry {
+                    checksum: 0x1,
+                    len: 10,
+                },
+            },
+        ];
+        assert_eq!(
+            json_report(Path::new("data"), &findings),
+            serde_json::json!({
+                "data_dir": "data",
+                "ok": 0,
+                "problems": 2,
+                "archives": [
+                    {
+                        "name": "2024-06-19-19-22-45.bin",
+                        "status": "corrupt",
+                        "expected": {"checksum": "00000abc", "bytes": 64},
+                        "problem": "block 0 does not match its checksum",
+                    },
+                    {
+                        "name": "2024-06-20-19-22-45.bin",
+                        "status": "foreign",
+                        "actual": {"checksum": "00000001", "bytes": 10},
+                        "problem": "not listed in CHECKSUMS",
+                    },
+                ],
+            })
+        );
+    }
+}
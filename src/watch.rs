@@ -0,0 +1,195 @@
+//! This module contains the implementation of the `watch` CLI command
+
+use std::{
+    fs,
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+
+use crate::{
+    archive::list_archive_files,
+    manifest::Manifest,
+    staging::{staging_file_paths, StagingFileReader},
+    store::{collect_archived_values, read_merged_value, ttl::TtlRules},
+    value::{Value, DEFAULT_MAX_DEPTH},
+};
+
+/// The `watch` sub-command polls the data directory, and writes the merged
+/// value to stdout as a line of JSON every time the staging file or archive
+/// files change.
+#[derive(Debug, PartialEq, FromArgs)]
+#[argh(subcommand, name = "watch")]
+pub struct WatchCommand {
+    /// how often to check the data directory for changes, in milliseconds.
+    #[argh(option, default = "1000")]
+    interval_ms: u64,
+    /// the maximum number of levels that arrays and objects may be nested in
+    /// the stored values, deeper values are rejected as an error.
+    #[argh(option, default = "DEFAULT_MAX_DEPTH")]
+    max_nesting_depth: usize,
+}
+
+impl WatchCommand {
+    /// This function executes the watch command.
+    #[tracing::instrument]
+    pub fn execute(self, data_dir: PathBuf) -> anyhow::Result<()> {
+        let interval = Duration::from_millis(self.interval_ms);
+        let mut watcher = Watcher::new(data_dir, self.max_nesting_depth);
+
+        let stdout = io::stdout();
+        let mut handle = stdout.lock();
+
+        loop {
+            match watcher.poll() {
+                Ok(Some(value)) => {
+                    serde_json::to_writer(&mut handle, &value)
+                        .context("writing merged value to stdout")?;
+                    writeln!(handle).context("writing merged value to stdout")?;
+                    handle.flush().context("flushing stdout")?;
+                }
+                Ok(None) => {}
+                // The data directory may be read while `append` is in the
+                // middle of writing it, so try again on the next poll
+                Err(err) => tracing::warn!("Failed to read data directory, retrying: {err:#}"),
+            }
+
+            thread::sleep(interval);
+        }
+    }
+}
+
+/// The length and modification time of a file, used to detect changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FileStamp {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    /// Return the stamp of the file at the given path, or `None` if it does
+    /// not exist.
+    fn of(path: &Path) -> io::Result<Option<Self>> {
+        match fs::metadata(path) {
+            Ok(metadata) => Ok(Some(Self {
+                len: metadata.len(),
+                modified: metadata.modified().ok(),
+            })),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// The state of the data directory files at a single point in time
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Snapshot {
+    /// The path and stamp of every staging file, in read order
+    staging: Vec<(PathBuf, Option<FileStamp>)>,
+    /// The path and stamp of every archive file, in read order
+    archives: Vec<(PathBuf, Option<FileStamp>)>,
+}
+
+impl Snapshot {
+    fn take(data_dir: &Path) -> anyhow::Result<Self> {
+        let staging = staging_file_paths(data_dir)?
+            .into_iter()
+            .map(|path| {
+                let stamp = FileStamp::of(&path)
+                    .with_context(|| format!("reading metadata of '{}'", path.display()))?;
+                Ok((path, stamp))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let archives = list_archive_files(data_dir)?
+            .into_iter()
+            .map(|path| {
+                let stamp = FileStamp::of(&path)
+                    .with_context(|| format!("reading metadata of '{}'", path.display()))?;
+                Ok((path, stamp))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Self { staging, archives })
+    }
+}
+
+/// This struct tracks the data directory between polls.
+///
+/// The merged value of the archive files is cached, so that when only the
+/// staging file changes the archives are not read again.
+#[derive(Debug)]
+struct Watcher {
+    data_dir: PathBuf,
+    max_depth: usize,
+    scratch_buffer: Vec<u8>,
+    /// The snapshot of the last emitted value, or `None` before the first
+    last_snapshot: Option<Snapshot>,
+    /// The merged value of the archives in `last_snapshot`
+    archived_value: Option<Value>,
+}
+
+impl Watcher {
+    fn new(data_dir: PathBuf, max_depth: usize) -> Self {
+        Self {
+            data_dir,
+            max_depth,
+            scratch_buffer: Vec::new(),
+            last_snapshot: None,
+            archived_value: None,
+        }
+    }
+
+    /// Check the data directory for changes, returning the new merged value
+    /// if anything changed.
+    ///
+    /// If the data directory has no data at all, then `Value::Null` is
+    /// returned.
+    fn poll(&mut self) -> anyhow::Result<Option<Value>> {
+        let snapshot = Snapshot::take(&self.data_dir)?;
+        if self.last_snapshot.as_ref() == Some(&snapshot) {
+            return Ok(None);
+        }
+
+        // Expiring fields needs the time each archive updated them, so the
+        // merged value of the archives cannot be reused
+        if !TtlRules::read(&self.data_dir)?.is_empty() {
+            let value = read_merged_value(&self.data_dir, self.max_depth)?;
+            self.last_snapshot = Some(snapshot);
+            return Ok(Some(value.unwrap_or(Value::Null)));
+        }
+
+        let archives_changed = self
+            .last_snapshot
+            .as_ref()
+            .map_or(true, |last| last.archives != snapshot.archives);
+        if archives_changed {
+            tracing::debug!(
+                archives = snapshot.archives.len(),
+                "Archive files changed, reading all archives"
+            );
+            self.scratch_buffer.clear();
+            self.archived_value =
+                collect_archived_values(&mut self.scratch_buffer, &self.data_dir, self.max_depth)
+                    .context("collecting and merging all archived values")?;
+        }
+
+        let staging_value = StagingFileReader::read_merged_value(&self.data_dir, self.max_depth)
+            .context("reading merged value from staging file")?;
+
+        let manifest = Manifest::read(&self.data_dir)?;
+        let merge_settings = manifest.merge_settings();
+        let mut value = merge_settings
+            .merge_optional(self.archived_value.clone(), staging_value)
+            .and_then(|value| merge_settings.resolve(value))
+            .unwrap_or(Value::Null);
+        manifest.array_rules.apply(&mut value);
+        self.last_snapshot = Some(snapshot);
+
+        Ok(Some(value))
+    }
+}